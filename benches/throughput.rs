@@ -0,0 +1,222 @@
+// Throughput of the server at two levels, each reported as ops/sec and p99 latency:
+//  - in-process: requests run straight against the keyspace from several threads, which shows
+//    the cost of the locking and the executors on their own. It is run twice, with the keyspace
+//    in a single shard as a baseline and then split into BENCH_SHARDS, to show what sharding buys,
+//    and once more with INCRs alone, which read and write the same value under one lock
+//  - socket: pipelined SET/GET from several connections to a server on an ephemeral port, which
+//    adds the parsing, the event loops and the network
+//
+// Run with `cargo bench`. The defaults are small enough to finish in a few seconds; each can be
+// changed from the environment, e.g.
+//   BENCH_KEYS=100000 BENCH_VALUE_SIZE=1024 BENCH_THREADS=8 cargo bench
+// The keys and the mix of reads and writes come from a fixed seed, so runs with the same
+// settings do the same work and their numbers can be compared.
+
+use bytes::Bytes;
+use redis_in_rust::{Config, Server};
+use std::env;
+use std::thread;
+use std::time::{Duration, Instant};
+
+struct Settings {
+    // how many distinct keys the requests are spread over
+    keys: usize,
+    value_size: usize,
+    // percentage of the requests that are GETs, the rest being SETs
+    reads: u32,
+    threads: usize,
+    shards: usize,
+    // requests made by each thread, in-process
+    requests_per_thread: usize,
+    connections: usize,
+    // requests sent by each connection, in batches of `pipeline`
+    requests_per_connection: usize,
+    pipeline: usize,
+}
+
+impl Settings {
+    fn from_env() -> Settings {
+        Settings {
+            keys: setting("BENCH_KEYS", 1000),
+            value_size: setting("BENCH_VALUE_SIZE", 64),
+            reads: setting("BENCH_READS", 80),
+            threads: setting("BENCH_THREADS", 4),
+            shards: setting("BENCH_SHARDS", Config::default().keyspace_shards).max(1),
+            requests_per_thread: setting("BENCH_REQUESTS", 200_000),
+            connections: setting("BENCH_CONNECTIONS", 4),
+            requests_per_connection: setting("BENCH_SOCKET_REQUESTS", 50_000),
+            pipeline: setting("BENCH_PIPELINE", 16).max(1),
+        }
+    }
+}
+
+fn setting<T: std::str::FromStr>(name: &str, default: T) -> T {
+    match env::var(name) {
+        Ok(value) => value.parse().unwrap_or_else(|_| panic!("{} should be a number, not {:?}", name, value)),
+        Err(_) => default,
+    }
+}
+
+fn main() {
+    // `cargo bench` passes `--bench`, and any filter given after `--`; neither applies here
+    let settings = Settings::from_env();
+    println!(
+        "{} keys, {} byte values, {}% reads",
+        settings.keys, settings.value_size, settings.reads
+    );
+    in_process(&settings, 1, "SET/GET", Random::request);
+    in_process(&settings, settings.shards, "SET/GET", Random::request);
+    in_process(&settings, settings.shards, "INCR", Random::increment);
+    socket(&settings);
+}
+
+// `next_request` picks each request a thread makes
+fn in_process(settings: &Settings, shards: usize, mix: &str, next_request: fn(&mut Random, &Settings, &Bytes) -> Vec<Bytes>) {
+    let server = bind(settings, shards);
+    let value = Bytes::from(vec![b'x'; settings.value_size]);
+    for key in 0..settings.keys {
+        server.execute(&[Bytes::from_static(b"SET"), key_name(key), value.clone()]);
+    }
+
+    let started = Instant::now();
+    let latencies: Vec<Vec<Duration>> = thread::scope(|scope| {
+        let workers: Vec<_> = (0..settings.threads)
+            .map(|thread| {
+                let (server, value) = (&server, value.clone());
+                scope.spawn(move || {
+                    let mut random = Random::new(thread as u64);
+                    let mut latencies = Vec::with_capacity(settings.requests_per_thread);
+                    for _ in 0..settings.requests_per_thread {
+                        let request = next_request(&mut random, settings, &value);
+                        let start = Instant::now();
+                        server.execute(&request);
+                        latencies.push(start.elapsed());
+                    }
+                    latencies
+                })
+            })
+            .collect();
+        workers.into_iter().map(|worker| worker.join().unwrap()).collect()
+    });
+    report(
+        &format!("in-process {}, {} threads, {} shards", mix, settings.threads, shards),
+        started.elapsed(),
+        latencies,
+        1,
+    );
+}
+
+fn socket(settings: &Settings) {
+    let server = bind(settings, settings.shards).spawn().unwrap();
+    let client = redis::Client::open(format!("redis://{}/", server.local_addr())).unwrap();
+    let value = vec![b'x'; settings.value_size];
+    let mut connection = client.get_connection().unwrap();
+    for keys in (0..settings.keys).collect::<Vec<_>>().chunks(1000) {
+        let mut pipeline = redis::pipe();
+        for key in keys {
+            pipeline.set(key_name(*key).as_ref(), &value).ignore();
+        }
+        pipeline.query::<()>(&mut connection).unwrap();
+    }
+
+    let started = Instant::now();
+    let latencies: Vec<Vec<Duration>> = thread::scope(|scope| {
+        let workers: Vec<_> = (0..settings.connections)
+            .map(|thread| {
+                let (client, value) = (&client, Bytes::from(value.clone()));
+                scope.spawn(move || {
+                    let mut connection = client.get_connection().unwrap();
+                    let mut random = Random::new(thread as u64);
+                    let batches = settings.requests_per_connection.div_ceil(settings.pipeline);
+                    let mut latencies = Vec::with_capacity(batches);
+                    for _ in 0..batches {
+                        let mut pipeline = redis::pipe();
+                        for _ in 0..settings.pipeline {
+                            let request = random.request(settings, &value);
+                            let command = pipeline.cmd(std::str::from_utf8(&request[0]).unwrap());
+                            for argument in &request[1..] {
+                                command.arg(argument.as_ref());
+                            }
+                        }
+                        let start = Instant::now();
+                        pipeline.query::<redis::Value>(&mut connection).unwrap();
+                        latencies.push(start.elapsed());
+                    }
+                    latencies
+                })
+            })
+            .collect();
+        workers.into_iter().map(|worker| worker.join().unwrap()).collect()
+    });
+    report(
+        &format!("socket, {} connections, pipeline {}", settings.connections, settings.pipeline),
+        started.elapsed(),
+        latencies,
+        settings.pipeline,
+    );
+    server.shutdown();
+}
+
+fn bind(settings: &Settings, shards: usize) -> Server {
+    let config = Config {
+        host: "127.0.0.1".to_string(),
+        port: 0,
+        thread_pool_size: settings.threads.max(1),
+        keyspace_shards: shards,
+        ..Config::default()
+    };
+    Server::bind(config).unwrap()
+}
+
+fn key_name(key: usize) -> Bytes {
+    Bytes::from(format!("key:{}", key))
+}
+
+// Kept apart from the keys holding values, which INCR would refuse
+fn counter_name(key: usize) -> Bytes {
+    Bytes::from(format!("counter:{}", key))
+}
+
+// Each latency covers `per_latency` requests: a whole pipeline, over a socket
+fn report(name: &str, elapsed: Duration, latencies: Vec<Vec<Duration>>, per_latency: usize) {
+    let mut latencies: Vec<Duration> = latencies.into_iter().flatten().collect();
+    latencies.sort_unstable();
+    let requests = latencies.len() * per_latency;
+    let p99 = latencies[(latencies.len() * 99 / 100).min(latencies.len() - 1)];
+    println!(
+        "{:<40} {:>12.0} ops/sec   p99 {:>10.1}us{}",
+        name,
+        requests as f64 / elapsed.as_secs_f64(),
+        p99.as_secs_f64() * 1_000_000.0,
+        if per_latency > 1 { " per batch" } else { "" }
+    );
+}
+
+// xorshift, so the benchmark needs nothing beyond the crates the server already uses
+struct Random(u64);
+
+impl Random {
+    fn new(seed: u64) -> Random {
+        Random(0x9E37_79B9_7F4A_7C15 ^ (seed + 1).wrapping_mul(0xBF58_476D_1CE4_E5B9))
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn request(&mut self, settings: &Settings, value: &Bytes) -> Vec<Bytes> {
+        let key = key_name(self.next() as usize % settings.keys.max(1));
+        if (self.next() % 100) < settings.reads as u64 {
+            vec![Bytes::from_static(b"GET"), key]
+        } else {
+            vec![Bytes::from_static(b"SET"), key, value.clone()]
+        }
+    }
+
+    fn increment(&mut self, settings: &Settings, _: &Bytes) -> Vec<Bytes> {
+        vec![Bytes::from_static(b"INCR"), counter_name(self.next() as usize % settings.keys.max(1))]
+    }
+}
@@ -1,41 +1,456 @@
+pub(crate) mod options;
+pub(crate) mod table;
+
+use crate::config::SizeLimits;
+use crate::index::Key;
+use bytes::Bytes;
 use std::convert::From;
-#[derive(Debug)]
-pub struct ParserError {
-    message: String,
+use std::fmt;
+
+// How much of the attempted command's arguments is echoed back in an unknown command error
+const UNKNOWN_COMMAND_ARGS_LIMIT: usize = 128;
+// Longer than any command name, so every real command can be upper-cased on the stack
+const MAX_COMMAND_NAME_LEN: usize = 32;
+// Values at least this long are stored as slices of the request they arrived in
+const SHARE_VALUES_FROM: usize = 16 * 1024;
+
+// Why a request couldn't be turned into a command. Offsets are the position in the read buffer
+// of the byte where parsing failed.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParserError {
+    // There was nothing to parse
+    Empty,
+    // The request is well-formed so far, but at least `missing_bytes` more are needed before it can be parsed
+    Incomplete { missing_bytes: usize },
+    // A header didn't start with the expected '*' or '$'
+    InvalidPrefix { expected: u8, found: u8, offset: usize },
+    // A size header wasn't a valid number
+    InvalidLength { offset: usize },
+    // CRLF was expected but something else was found
+    InvalidTerminator { offset: usize },
+    // An element, or the array, isn't the size its header declared
+    LengthMismatch { offset: usize },
+    // The command name must be UTF-8
+    NotUtf8Command { offset: usize },
+    // A declared size is over one of the configured limits
+    TooLarge { limit: Limit, offset: usize },
+    // The command, or its subcommand as e.g. "object|freq", was given too many or too few arguments
+    WrongNumberOfArguments(String),
+    // An option or argument the command doesn't recognise
+    Syntax,
+    // An argument that had to be a 64-bit integer, or a float
+    NotAnInteger,
+    NotAFloat,
+    // The framing was fine, but the command itself is wrong in some other way
+    Command(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Limit {
+    BulkLength,
+    MultibulkLength,
+    QueryBuffer,
 }
 
 impl ParserError {
     pub fn new(message: &str) -> Self {
-        ParserError {
-            message: message.to_string(),
+        ParserError::Command(message.to_string())
+    }
+    pub fn get_message(&self) -> String {
+        self.to_string()
+    }
+    // The RESP framing itself is broken, so nothing after this point in the stream can be trusted
+    pub fn is_protocol_error(&self) -> bool {
+        !matches!(
+            self,
+            ParserError::Incomplete { .. }
+                | ParserError::WrongNumberOfArguments(_)
+                | ParserError::Syntax
+                | ParserError::NotAnInteger
+                | ParserError::NotAFloat
+                | ParserError::Command(_)
+        )
+    }
+    // Every parse error is a plain ERR on the wire, protocol errors included
+    pub fn error_class(&self) -> &'static str {
+        "ERR"
+    }
+    pub fn get_offset(&self) -> Option<usize> {
+        match self {
+            ParserError::InvalidPrefix { offset, .. }
+            | ParserError::InvalidLength { offset }
+            | ParserError::InvalidTerminator { offset }
+            | ParserError::LengthMismatch { offset }
+            | ParserError::NotUtf8Command { offset }
+            | ParserError::TooLarge { offset, .. } => Some(*offset),
+            _ => None,
         }
     }
-    pub fn get_message(&self) -> &str {
-        &self.message
+}
+
+impl fmt::Display for ParserError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParserError::Empty => write!(f, "Request is empty"),
+            ParserError::Incomplete { missing_bytes } => {
+                write!(f, "Request is incomplete, waiting for {} more bytes", missing_bytes)
+            }
+            ParserError::InvalidPrefix { expected, found, offset } => write!(
+                f,
+                "expected '{}', got '{}' at byte {}",
+                *expected as char,
+                found.escape_ascii(),
+                offset
+            ),
+            ParserError::InvalidLength { offset } => write!(f, "invalid length at byte {}", offset),
+            ParserError::InvalidTerminator { offset } => write!(f, "expected CRLF at byte {}", offset),
+            ParserError::LengthMismatch { offset } => {
+                write!(f, "length does not match the header at byte {}", offset)
+            }
+            ParserError::NotUtf8Command { offset } => write!(f, "invalid UTF-8 at byte {}", offset),
+            ParserError::TooLarge { limit, .. } => match limit {
+                Limit::BulkLength => write!(f, "invalid bulk length"),
+                Limit::MultibulkLength => write!(f, "invalid multibulk length"),
+                Limit::QueryBuffer => write!(f, "request is larger than client-query-buffer-limit"),
+            },
+            ParserError::WrongNumberOfArguments(command) => {
+                write!(f, "wrong number of arguments for '{}' command", command)
+            }
+            ParserError::Syntax => write!(f, "syntax error"),
+            ParserError::NotAnInteger => write!(f, "value is not an integer or out of range"),
+            ParserError::NotAFloat => write!(f, "value is not a valid float"),
+            ParserError::Command(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for ParserError {}
+
+// A command name upper-cased into a fixed buffer, so dispatching on it doesn't allocate.
+// A name too long to be any command is left empty, which matches nothing.
+pub struct CommandName {
+    buffer: [u8; MAX_COMMAND_NAME_LEN],
+    len: usize,
+}
+
+impl CommandName {
+    pub fn new(name: &[u8]) -> CommandName {
+        let mut buffer = [0u8; MAX_COMMAND_NAME_LEN];
+        let len = if name.len() <= MAX_COMMAND_NAME_LEN { name.len() } else { 0 };
+        for (upper, byte) in buffer.iter_mut().zip(&name[..len]) {
+            *upper = byte.to_ascii_uppercase();
+        }
+        CommandName { buffer, len }
+    }
+
+    pub fn as_str(&self) -> &str {
+        std::str::from_utf8(&self.buffer[..self.len]).unwrap_or("")
+    }
+}
+
+// Keys, channels and the like are still held as Strings, so they have to be UTF-8
+pub fn text_argument(argument: &[u8]) -> Result<&str, ParserError> {
+    std::str::from_utf8(argument).map_err(|_| ParserError::new("invalid argument, expected UTF-8 text"))
+}
+
+// The keys of a request, found as Redis's key specs describe them: from argument `first` to
+// argument `last`, counted back from the end when negative, every `step` arguments
+pub fn key_arguments(command: &[Bytes], first: usize, last: isize, step: usize) -> Result<Vec<Key>, ParserError> {
+    let last = if last < 0 { command.len() as isize + last } else { last } as usize;
+    (first..=last.min(command.len() - 1))
+        .step_by(step)
+        .map(|position| text_argument(&command[position]).map(Key::from))
+        .collect()
+}
+
+// Arguments are slices of the buffer the request was read into, which stays allocated for as
+// long as any of them does. A small value is copied out before being stored, so it can't pin
+// a whole read buffer; a large one, where the copy would cost, is kept as it is.
+pub fn stored_value(argument: &Bytes) -> Bytes {
+    if argument.len() < SHARE_VALUES_FROM {
+        Bytes::copy_from_slice(argument)
+    } else {
+        argument.clone()
     }
 }
 
-#[derive(Debug)]
-pub struct ExecutionError {
-    message: String,
+// An argument that has to be a whole number, as INCRBY's increment or EXPIRE's seconds are
+pub fn integer_argument(argument: &[u8]) -> Result<i64, ExecutionError> {
+    std::str::from_utf8(argument)
+        .ok()
+        .and_then(|text| text.parse::<i64>().ok())
+        .ok_or(ExecutionError::NotAnInteger)
+}
+
+// Checks a command's keys and the arguments it would store against the configured limits, before
+// anything is copied into the keyspace. Every argument after the key counts as an element.
+pub fn check_sizes<'a>(
+    limits: &SizeLimits,
+    mut keys: impl Iterator<Item = &'a [u8]>,
+    arguments: &[Bytes],
+) -> Result<(), ParserError> {
+    if keys.any(|key| key.len() > limits.max_key_length()) {
+        return Err(ParserError::new(&format!(
+            "key is longer than max-key-length ({} bytes)",
+            limits.max_key_length()
+        )));
+    }
+    if arguments.iter().any(|argument| argument.len() > limits.max_value_length()) {
+        return Err(ParserError::new(&format!(
+            "value is longer than max-value-length ({} bytes)",
+            limits.max_value_length()
+        )));
+    }
+    if arguments.len() > limits.max_container_elements() {
+        return Err(ParserError::new(&format!(
+            "too many elements, max-container-elements is {}",
+            limits.max_container_elements()
+        )));
+    }
+    Ok(())
+}
+
+// Checks the number of words in a request, including the command name, using Redis's arity
+// convention: a positive arity is the exact count, a negative one the minimum
+pub fn check_arity(command: &[Bytes], arity: i32) -> Result<(), ParserError> {
+    let words = command.len() as i32;
+    let valid = if arity >= 0 { words == arity } else { words >= -arity };
+    if valid {
+        Ok(())
+    } else {
+        Err(wrong_number_of_arguments(&command[0]))
+    }
+}
+
+pub fn wrong_number_of_arguments(command: &[u8]) -> ParserError {
+    ParserError::WrongNumberOfArguments(String::from_utf8_lossy(command).to_lowercase())
+}
+
+// For an option or argument the command doesn't recognise
+pub fn syntax_error() -> ParserError {
+    ParserError::Syntax
+}
+
+// For a command used on a key holding a type of value it doesn't apply to
+pub fn wrong_type() -> ExecutionError {
+    ExecutionError::WrongType
+}
+
+// e.g. unknown command 'FOO', with args beginning with: 'bar', 'baz'
+// Arguments are quoted, escaped so control characters can't reach the client raw,
+// and cut off once UNKNOWN_COMMAND_ARGS_LIMIT characters have been shown
+pub fn unknown_command(command: &[Bytes]) -> ExecutionError {
+    let name: String = escape(&command[0]).chars().take(UNKNOWN_COMMAND_ARGS_LIMIT).collect();
+    if command.len() < 2 {
+        return ExecutionError::new(&format!("unknown command '{}'", name));
+    }
+    let mut budget = UNKNOWN_COMMAND_ARGS_LIMIT;
+    let mut args: Vec<String> = Vec::new();
+    for arg in &command[1..] {
+        if budget == 0 {
+            break;
+        }
+        let shown: String = escape(arg).chars().take(budget).collect();
+        budget -= shown.chars().count();
+        args.push(format!("'{}'", shown));
+    }
+    ExecutionError::new(&format!(
+        "unknown command '{}', with args beginning with: {}",
+        name,
+        args.join(", ")
+    ))
+}
+
+fn escape(text: &[u8]) -> String {
+    let text = String::from_utf8_lossy(text);
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if c.is_control() {
+            escaped.extend(c.escape_default());
+        } else {
+            escaped.push(c);
+        }
+    }
+    escaped
+}
+
+// Why a command failed. Each is sent to the client as its error class followed by its message,
+// e.g. "-WRONGTYPE Operation against a key holding the wrong kind of value".
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExecutionError {
+    // A key holds a type of value the command doesn't apply to
+    WrongType,
+    // The command needs a key that doesn't exist, as RENAME's source does
+    NoSuchKey,
+    // A value or argument that had to be a 64-bit integer, or a float
+    NotAnInteger,
+    NotAFloat,
+    // An increment or decrement that would go past what a 64-bit integer holds; says which
+    OutOfRange(&'static str),
+    // An index past either end of a list, as LSET's
+    IndexOutOfRange,
+    // A count that had to be 0 or more, as LPOP's
+    NotPositive,
+    // A time to live that isn't in the future, or is too far in it; says which command was given it
+    InvalidExpireTime(&'static str),
+    // An option or argument the command doesn't recognise
+    Syntax,
+    // Every thread for long-running commands is taken, and too many are waiting for one
+    #[cfg_attr(feature = "async", allow(dead_code))] // only the event loop bounds the queue
+    Busy,
+    // The target of a command that won't replace a key already exists
+    #[allow(dead_code)] // for COPY and RESTORE, neither of which exist yet
+    BusyKey,
+    // Used memory is over maxmemory and the eviction policy frees nothing
+    OutOfMemory,
+    // HELLO was asked for a protocol version other than 2 or 3
+    NoProto,
+    // The request was wrong before it got as far as running, e.g. its arity
+    Request(ParserError),
+    // Anything else, reported as ERR with the message given
+    Custom(String),
 }
 
 impl ExecutionError {
     pub fn new(message: &str) -> Self {
-        ExecutionError {
-            message: message.to_string(),
+        ExecutionError::Custom(message.to_string())
+    }
+    pub fn get_message(&self) -> String {
+        self.to_string()
+    }
+    // The first word of the error reply, which clients can branch on
+    pub fn error_class(&self) -> &'static str {
+        match self {
+            ExecutionError::WrongType => "WRONGTYPE",
+            ExecutionError::Busy => "BUSY",
+            ExecutionError::BusyKey => "BUSYKEY",
+            ExecutionError::OutOfMemory => "OOM",
+            ExecutionError::NoProto => "NOPROTO",
+            ExecutionError::Request(error) => error.error_class(),
+            _ => "ERR",
+        }
+    }
+}
+
+impl fmt::Display for ExecutionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExecutionError::WrongType => write!(f, "Operation against a key holding the wrong kind of value"),
+            ExecutionError::NoSuchKey => write!(f, "no such key"),
+            ExecutionError::NotAnInteger => write!(f, "value is not an integer or out of range"),
+            ExecutionError::NotAFloat => write!(f, "value is not a valid float"),
+            ExecutionError::OutOfRange(change) => write!(f, "{} would overflow", change),
+            ExecutionError::IndexOutOfRange => write!(f, "index out of range"),
+            ExecutionError::NotPositive => write!(f, "value is out of range, must be positive"),
+            ExecutionError::InvalidExpireTime(command) => write!(f, "invalid expire time in '{}' command", command.to_lowercase()),
+            ExecutionError::Syntax => write!(f, "syntax error"),
+            ExecutionError::Busy => write!(f, "server busy, too many long-running commands waiting"),
+            ExecutionError::BusyKey => write!(f, "Target key name already exists."),
+            ExecutionError::OutOfMemory => write!(f, "command not allowed when used memory > 'maxmemory'."),
+            ExecutionError::NoProto => write!(f, "unsupported protocol version"),
+            ExecutionError::Request(error) => write!(f, "{}", error),
+            ExecutionError::Custom(message) => write!(f, "{}", message),
         }
     }
-    pub fn get_message(&self) -> &str {
-        &self.message
+}
+
+impl std::error::Error for ExecutionError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ExecutionError::Request(error) => Some(error),
+            _ => None,
+        }
     }
 }
 
+// A parser error with a counterpart here becomes it, so callers match the one variant whether
+// the problem was found while parsing or while running
 impl From<ParserError> for ExecutionError {
     fn from(e: ParserError) -> Self {
-        ExecutionError {
-            message: e.message,
+        match e {
+            ParserError::Syntax => ExecutionError::Syntax,
+            ParserError::NotAnInteger => ExecutionError::NotAnInteger,
+            ParserError::NotAFloat => ExecutionError::NotAFloat,
+            other => ExecutionError::Request(other),
         }
     }
 }
 
+
+// Builds a request as the tokenizer would hand it over
+#[cfg(test)]
+pub fn request(words: &[&str]) -> Vec<Bytes> {
+    words.iter().map(|word| Bytes::copy_from_slice(word.as_bytes())).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn given_unknown_command_without_args_when_formatted_then_names_command() {
+        let error = unknown_command(&request(&["FOO"]));
+        assert_eq!(error.get_message(), "unknown command 'FOO'");
+    }
+
+    #[test]
+    fn given_unknown_command_with_args_when_formatted_then_args_are_quoted() {
+        let error = unknown_command(&request(&["FOO", "bar", "baz"]));
+        assert_eq!(error.get_message(), "unknown command 'FOO', with args beginning with: 'bar', 'baz'");
+    }
+
+    #[test]
+    fn given_unknown_command_with_control_characters_when_formatted_then_escaped() {
+        let error = unknown_command(&request(&["FOO", "a\r\n+OK"]));
+        assert_eq!(error.get_message(), "unknown command 'FOO', with args beginning with: 'a\\r\\n+OK'");
+    }
+
+    #[test]
+    fn given_long_args_when_formatted_then_truncated() {
+        let long = "x".repeat(200);
+        let error = unknown_command(&request(&["FOO", &long, "next"]));
+        let expected = format!("unknown command 'FOO', with args beginning with: '{}'", "x".repeat(128));
+        assert_eq!(error.get_message(), expected);
+    }
+
+    #[test]
+    fn given_large_value_when_stored_then_shares_the_request_buffer() {
+        let request = Bytes::from(vec![b'x'; SHARE_VALUES_FROM + 16]);
+        let large = request.slice(16..);
+        assert_eq!(stored_value(&large).as_ptr(), large.as_ptr());
+        let small = request.slice(16..32);
+        assert_ne!(stored_value(&small).as_ptr(), small.as_ptr());
+        assert_eq!(stored_value(&small), small);
+    }
+
+    #[test]
+    fn given_command_name_when_upper_cased_then_matches_without_allocating() {
+        assert_eq!(CommandName::new(b"get").as_str(), "GET");
+        assert_eq!(CommandName::new(b"RPush").as_str(), "RPUSH");
+        assert_eq!(CommandName::new(&[b'x'; 64]).as_str(), "");
+        assert_eq!(CommandName::new(b"\xff").as_str(), "");
+    }
+
+    #[test]
+    fn given_key_spec_when_keys_found_then_at_the_positions_it_names() {
+        assert_eq!(key_arguments(&request(&["RENAME", "a", "b"]), 1, 2, 1).unwrap(), [Key::from("a"), Key::from("b")]);
+        assert_eq!(key_arguments(&request(&["MSET", "a", "1", "b", "2"]), 1, -1, 2).unwrap(), [Key::from("a"), Key::from("b")]);
+        assert_eq!(key_arguments(&request(&["DEL", "a", "b", "c"]), 1, -1, 1).unwrap(), [Key::from("a"), Key::from("b"), Key::from("c")]);
+        assert!(key_arguments(&[Bytes::from_static(b"GET"), Bytes::from_static(b"\xff")], 1, 1, 1).is_err());
+    }
+
+    #[test]
+    fn given_sizes_around_the_limits_when_checked_then_only_those_over_rejected() {
+        let config = crate::config::Config { max_key_length: 4, max_value_length: 8, max_container_elements: 2, ..Default::default() };
+        let limits = SizeLimits::new(&config);
+        let check = |key: &str, arguments: &[&str]| check_sizes(&limits, [key.as_bytes()].into_iter(), &request(arguments));
+
+        assert!(check("abcd", &["12345678", "x"]).is_ok());
+        let error = check("abcde", &[]).unwrap_err();
+        assert_eq!(error.get_message(), "key is longer than max-key-length (4 bytes)");
+        let error = check("key", &["123456789"]).unwrap_err();
+        assert_eq!(error.get_message(), "value is longer than max-value-length (8 bytes)");
+        let error = check("key", &["a", "b", "c"]).unwrap_err();
+        assert_eq!(error.get_message(), "too many elements, max-container-elements is 2");
+    }
+}
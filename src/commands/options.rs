@@ -0,0 +1,147 @@
+// The optional keywords that may follow a command's fixed arguments, such as SET's NX and GET.
+// They can come in any order, some take a value, and some rule others out. Each command declares
+// the ones it accepts, and parsing stops at the first word that isn't one of them, leaving the rest
+// to the command.
+
+use crate::commands::{syntax_error, ParserError};
+use bytes::Bytes;
+
+#[derive(Debug)]
+pub struct OptionSpec {
+    // in upper case; matched ignoring case
+    pub name: &'static str,
+    pub takes_value: bool,
+    // options that can't be given with this one, checked whichever comes first
+    pub conflicts_with: &'static [&'static str],
+}
+
+pub const fn flag(name: &'static str, conflicts_with: &'static [&'static str]) -> OptionSpec {
+    OptionSpec { name, takes_value: false, conflicts_with }
+}
+
+pub const fn with_value(name: &'static str, conflicts_with: &'static [&'static str]) -> OptionSpec {
+    OptionSpec { name, takes_value: true, conflicts_with }
+}
+
+// The options found, in the order they were given
+#[derive(Debug, Default)]
+pub struct Options<'a> {
+    found: Vec<(&'static str, Option<&'a Bytes>)>,
+}
+
+impl<'a> Options<'a> {
+    pub fn has(&self, name: &str) -> bool {
+        self.found.iter().any(|(found, _)| *found == name)
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &'static str> + '_ {
+        self.found.iter().map(|(name, _)| *name)
+    }
+
+    pub fn value(&self, name: &str) -> Option<&'a Bytes> {
+        self.found.iter().find(|(found, _)| *found == name).and_then(|(_, value)| *value)
+    }
+
+    pub fn integer(&self, name: &str) -> Result<Option<i64>, ParserError> {
+        let Some(value) = self.value(name) else {
+            return Ok(None);
+        };
+        std::str::from_utf8(value)
+            .ok()
+            .and_then(|text| text.parse::<i64>().ok())
+            .map(Some)
+            .ok_or(ParserError::NotAnInteger)
+    }
+}
+
+// Reads options from the front of `tokens`, returning them with whatever followed them. An option
+// given twice, one that conflicts with another, or one missing its value is a syntax error, as in Redis.
+pub fn parse<'a>(specs: &[OptionSpec], tokens: &'a [Bytes]) -> Result<(Options<'a>, &'a [Bytes]), ParserError> {
+    let mut options = Options::default();
+    let mut next = 0;
+    while let Some(token) = tokens.get(next) {
+        let Some(spec) = specs.iter().find(|spec| spec.name.as_bytes().eq_ignore_ascii_case(token)) else {
+            break;
+        };
+        let conflicts = |(found, _): &(&'static str, Option<&Bytes>)| {
+            *found == spec.name || spec.conflicts_with.contains(found) || conflicts_with(specs, found, spec.name)
+        };
+        if options.found.iter().any(conflicts) {
+            return Err(syntax_error());
+        }
+        let value = if spec.takes_value {
+            next += 1;
+            Some(tokens.get(next).ok_or_else(syntax_error)?)
+        } else {
+            None
+        };
+        options.found.push((spec.name, value));
+        next += 1;
+    }
+    Ok((options, &tokens[next..]))
+}
+
+fn conflicts_with(specs: &[OptionSpec], name: &str, other: &str) -> bool {
+    specs.iter().any(|spec| spec.name == name && spec.conflicts_with.contains(&other))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::request;
+
+    // a little like SET's, with one each way round for the conflicts
+    const SPECS: [OptionSpec; 5] = [
+        flag("NX", &["XX"]),
+        flag("XX", &[]),
+        flag("GET", &[]),
+        with_value("EX", &["PX"]),
+        with_value("PX", &[]),
+    ];
+
+    #[test]
+    fn given_options_in_any_order_when_parsed_then_all_found_and_rest_left() {
+        let tokens = request(&["get", "EX", "10", "nx", "member", "GET"]);
+        let (options, rest) = parse(&SPECS, &tokens).unwrap();
+        assert_eq!(options.names().collect::<Vec<_>>(), ["GET", "EX", "NX"]);
+        assert!(options.has("NX") && !options.has("XX"));
+        assert_eq!(options.integer("EX").unwrap(), Some(10));
+        assert_eq!(options.integer("PX").unwrap(), None);
+        // parsing stops at the first word that isn't an option, even if one follows it
+        assert_eq!(rest, &request(&["member", "GET"])[..]);
+
+        let (options, rest) = parse(&SPECS, &[]).unwrap();
+        assert_eq!(options.names().count(), 0);
+        assert!(rest.is_empty());
+    }
+
+    #[test]
+    fn given_option_twice_when_parsed_then_syntax_error() {
+        for words in [&["NX", "nx"][..], &["EX", "1", "GET", "EX", "2"]] {
+            let error = parse(&SPECS, &request(words)).unwrap_err();
+            assert_eq!(error, ParserError::Syntax);
+        }
+    }
+
+    #[test]
+    fn given_conflicting_options_when_parsed_then_syntax_error_whichever_first() {
+        for words in [&["NX", "XX"][..], &["XX", "NX"], &["PX", "1", "EX", "1"], &["EX", "1", "GET", "PX", "1"]] {
+            let error = parse(&SPECS, &request(words)).unwrap_err();
+            assert_eq!(error, ParserError::Syntax, "{:?}", words);
+        }
+    }
+
+    #[test]
+    fn given_option_missing_its_value_when_parsed_then_syntax_error() {
+        let error = parse(&SPECS, &request(&["NX", "EX"])).unwrap_err();
+        assert_eq!(error, ParserError::Syntax);
+    }
+
+    #[test]
+    fn given_value_not_a_number_when_read_as_integer_then_redis_message() {
+        let tokens = request(&["EX", "ten"]);
+        let (options, _) = parse(&SPECS, &tokens).unwrap();
+        let error = options.integer("EX").unwrap_err();
+        assert_eq!(error, ParserError::NotAnInteger);
+    }
+}
@@ -0,0 +1,272 @@
+// Every command the server knows, with what can be told about it before it is parsed: how many
+// words it takes, whether it changes the keyspace, and where its keys are. Requests are checked
+// against it before any executor sees them, and COMMAND reports it as Redis does.
+
+use crate::commands::{wrong_number_of_arguments, ExecutionError, ParserError};
+use crate::index::{KeyType, LockType};
+use crate::resp::Value;
+use bytes::Bytes;
+
+#[derive(Debug)]
+pub struct CommandSpec {
+    pub name: &'static str,
+    // Redis's convention, counting the name: exact when positive, a minimum when negative
+    pub arity: i32,
+    // as COMMAND reports them, e.g. "write", "readonly", "denyoom", "fast"
+    pub flags: &'static [&'static str],
+    // the type of key it works on; Index for the commands on keys of any type, Undefined for
+    // those that don't use keys at all
+    pub key_type: KeyType,
+    // where its keys are: from the first to the last, counted from the end when negative,
+    // every `key_step` arguments. All 0 when it has none.
+    pub first_key: usize,
+    pub last_key: isize,
+    pub key_step: usize,
+}
+
+impl CommandSpec {
+    pub fn has_flag(&self, flag: &str) -> bool {
+        self.flags.contains(&flag)
+    }
+
+    // The commands on keys in general that name none, working on them all instead
+    pub fn is_whole_keyspace(&self) -> bool {
+        self.key_type == KeyType::Index && self.first_key == 0
+    }
+
+    // Commands that may change a key need its shard to themselves
+    pub fn lock_type(&self) -> LockType {
+        if self.has_flag("write") { LockType::Write } else { LockType::Read }
+    }
+
+    fn info(&self) -> Value {
+        let status = |flag: &&'static str| Value::SimpleString(Bytes::from_static(flag.as_bytes()));
+        Value::Array(vec![
+            Value::BulkString(Bytes::from(self.name.to_lowercase())),
+            Value::Integer(self.arity as i64),
+            Value::Set(self.flags.iter().map(status).collect()),
+            Value::Integer(self.first_key as i64),
+            Value::Integer(self.last_key as i64),
+            Value::Integer(self.key_step as i64),
+            // ACL categories, tips, key specs and subcommands, none of which are described yet
+            Value::Set(Vec::new()),
+            Value::Set(Vec::new()),
+            Value::Array(Vec::new()),
+            Value::Array(Vec::new()),
+        ])
+    }
+}
+
+const fn keyless(name: &'static str, arity: i32, flags: &'static [&'static str]) -> CommandSpec {
+    CommandSpec { name, arity, flags, key_type: KeyType::Undefined, first_key: 0, last_key: 0, key_step: 0 }
+}
+
+const fn one_key(name: &'static str, arity: i32, flags: &'static [&'static str], key_type: KeyType) -> CommandSpec {
+    CommandSpec { name, arity, flags, key_type, first_key: 1, last_key: 1, key_step: 1 }
+}
+
+// For the commands on every key at once, such as KEYS and FLUSHDB, which name none
+const fn whole_keyspace(name: &'static str, arity: i32, flags: &'static [&'static str]) -> CommandSpec {
+    CommandSpec { name, arity, flags, key_type: KeyType::Index, first_key: 0, last_key: 0, key_step: 0 }
+}
+
+const READ_FAST: &[&str] = &["readonly", "fast"];
+const GROW_FAST: &[&str] = &["write", "denyoom", "fast"];
+const SUBSCRIBER: &[&str] = &["pubsub", "noscript", "loading", "stale"];
+
+static COMMANDS: [CommandSpec; 68] = [
+    one_key("GET", 2, READ_FAST, KeyType::String),
+    CommandSpec { name: "MGET", arity: -2, flags: READ_FAST, key_type: KeyType::String, first_key: 1, last_key: -1, key_step: 1 },
+    one_key("SET", -3, &["write", "denyoom"], KeyType::String),
+    one_key("SETNX", 3, GROW_FAST, KeyType::String),
+    one_key("SETEX", 4, &["write", "denyoom"], KeyType::String),
+    one_key("PSETEX", 4, &["write", "denyoom"], KeyType::String),
+    one_key("GETSET", 3, GROW_FAST, KeyType::String),
+    CommandSpec { name: "MSET", arity: -3, flags: &["write", "denyoom"], key_type: KeyType::String, first_key: 1, last_key: -1, key_step: 2 },
+    CommandSpec { name: "MSETNX", arity: -3, flags: &["write", "denyoom"], key_type: KeyType::String, first_key: 1, last_key: -1, key_step: 2 },
+    one_key("INCR", 2, GROW_FAST, KeyType::String),
+    one_key("INCRBY", 3, GROW_FAST, KeyType::String),
+    one_key("DECR", 2, GROW_FAST, KeyType::String),
+    one_key("DECRBY", 3, GROW_FAST, KeyType::String),
+    one_key("LLEN", 2, READ_FAST, KeyType::List),
+    one_key("LINDEX", 3, &["readonly"], KeyType::List),
+    one_key("LRANGE", 4, &["readonly"], KeyType::List),
+    one_key("LPOS", -3, &["readonly"], KeyType::List),
+    one_key("LSET", 4, &["write", "denyoom"], KeyType::List),
+    one_key("LREM", 4, &["write"], KeyType::List),
+    one_key("LTRIM", 4, &["write"], KeyType::List),
+    one_key("LINSERT", 5, &["write", "denyoom"], KeyType::List),
+    CommandSpec { name: "LMOVE", arity: 5, flags: &["write", "denyoom"], key_type: KeyType::List, first_key: 1, last_key: 2, key_step: 1 },
+    CommandSpec { name: "RPOPLPUSH", arity: 3, flags: &["write", "denyoom"], key_type: KeyType::List, first_key: 1, last_key: 2, key_step: 1 },
+    one_key("RPUSH", -3, GROW_FAST, KeyType::List),
+    one_key("LPUSH", -3, GROW_FAST, KeyType::List),
+    one_key("RPOP", -2, &["write", "fast"], KeyType::List),
+    one_key("LPOP", -2, &["write", "fast"], KeyType::List),
+    one_key("HSET", -4, GROW_FAST, KeyType::Hash),
+    one_key("HGET", 3, READ_FAST, KeyType::Hash),
+    one_key("HDEL", -3, &["write", "fast"], KeyType::Hash),
+    one_key("HEXISTS", 3, READ_FAST, KeyType::Hash),
+    one_key("HGETALL", 2, &["readonly"], KeyType::Hash),
+    one_key("HKEYS", 2, &["readonly"], KeyType::Hash),
+    one_key("HVALS", 2, &["readonly"], KeyType::Hash),
+    one_key("HLEN", 2, READ_FAST, KeyType::Hash),
+    one_key("HEXPIRE", -6, &["write", "fast"], KeyType::Hash),
+    one_key("HPEXPIRE", -6, &["write", "fast"], KeyType::Hash),
+    one_key("HTTL", -5, READ_FAST, KeyType::Hash),
+    one_key("HPTTL", -5, READ_FAST, KeyType::Hash),
+    one_key("HPERSIST", -5, &["write", "fast"], KeyType::Hash),
+    one_key("EXISTS", 2, READ_FAST, KeyType::Index),
+    one_key("DEL", 2, &["write"], KeyType::Index),
+    one_key("EXPIRE", 3, &["write", "fast"], KeyType::Index),
+    one_key("PEXPIRE", 3, &["write", "fast"], KeyType::Index),
+    one_key("EXPIREAT", 3, &["write", "fast"], KeyType::Index),
+    one_key("PEXPIREAT", 3, &["write", "fast"], KeyType::Index),
+    one_key("TTL", 2, READ_FAST, KeyType::Index),
+    one_key("PTTL", 2, READ_FAST, KeyType::Index),
+    one_key("PERSIST", 2, &["write", "fast"], KeyType::Index),
+    CommandSpec { name: "RENAME", arity: 3, flags: &["write"], key_type: KeyType::Index, first_key: 1, last_key: 2, key_step: 1 },
+    // OBJECT's key follows its subcommand
+    CommandSpec { name: "OBJECT", arity: -2, flags: &["readonly"], key_type: KeyType::Index, first_key: 2, last_key: 2, key_step: 1 },
+    whole_keyspace("KEYS", 2, &["readonly"]),
+    whole_keyspace("DBSIZE", 1, READ_FAST),
+    whole_keyspace("FLUSHDB", -1, &["write"]),
+    whole_keyspace("FLUSHALL", -1, &["write"]),
+    keyless("HELLO", -1, &["noscript", "loading", "stale", "fast"]),
+    keyless("PING", -1, &["fast"]),
+    keyless("QUIT", -1, &["noscript", "loading", "stale", "fast"]),
+    keyless("SUBSCRIBE", -2, SUBSCRIBER),
+    keyless("PSUBSCRIBE", -2, SUBSCRIBER),
+    keyless("UNSUBSCRIBE", -1, SUBSCRIBER),
+    keyless("PUNSUBSCRIBE", -1, SUBSCRIBER),
+    keyless("PUBLISH", 3, &["pubsub", "loading", "stale", "fast"]),
+    keyless("INFO", -1, &["loading", "stale"]),
+    keyless("DEBUG", -2, &["admin", "noscript", "loading", "stale"]),
+    keyless("MEMORY", -2, &[]),
+    keyless("CONFIG", -2, &[]),
+    keyless("COMMAND", -1, &["loading", "stale"]),
+];
+
+pub fn lookup(name: &[u8]) -> Option<&'static CommandSpec> {
+    COMMANDS.iter().find(|spec| spec.name.as_bytes().eq_ignore_ascii_case(name))
+}
+
+// The check every request goes through before it is parsed any further, so the arity error is
+// worded the same for every command. A command not in the table is left to be reported as unknown.
+pub fn validate(command: &[Bytes]) -> Result<Option<&'static CommandSpec>, ParserError> {
+    let Some(spec) = lookup(&command[0]) else {
+        return Ok(None);
+    };
+    let words = command.len() as i32;
+    let valid = if spec.arity >= 0 { words == spec.arity } else { words >= -spec.arity };
+    if !valid {
+        return Err(wrong_number_of_arguments(&command[0]));
+    }
+    Ok(Some(spec))
+}
+
+// Some client libraries send an optional argument they were given no value for as a null bulk
+// string ($-1). No command takes one, so the request is turned down with an ordinary error, and the
+// connection kept, rather than run with the argument left out or made empty.
+pub fn check_not_null(command: &[Option<Bytes>]) -> Result<(), ParserError> {
+    match command.iter().position(Option::is_none) {
+        None => Ok(()),
+        Some(0) => Err(ParserError::new("null command name")),
+        Some(position) => {
+            let name = String::from_utf8_lossy(command[0].as_deref().unwrap_or_default()).to_lowercase();
+            Err(ParserError::new(&format!("null argument {} given to '{}' command", position, name)))
+        }
+    }
+}
+
+pub fn is_command_supported(command: &[u8]) -> bool {
+    command.eq_ignore_ascii_case(b"COMMAND")
+}
+
+pub fn execute_command(request: &[Bytes]) -> Result<Value, ExecutionError> {
+    // support syntax: COMMAND
+    //                 COMMAND COUNT
+    //                 COMMAND INFO [command-name ...]
+    let Some(subcommand) = request.get(1) else {
+        return Ok(Value::Array(COMMANDS.iter().map(CommandSpec::info).collect()));
+    };
+    if subcommand.eq_ignore_ascii_case(b"COUNT") && request.len() == 2 {
+        Ok(Value::Integer(COMMANDS.len() as i64))
+    } else if subcommand.eq_ignore_ascii_case(b"INFO") {
+        // with no names, every command is described
+        let infos = if request.len() == 2 {
+            COMMANDS.iter().map(CommandSpec::info).collect()
+        } else {
+            request[2..].iter().map(|name| lookup(name).map_or(Value::Null, CommandSpec::info)).collect()
+        };
+        Ok(Value::Array(infos))
+    } else if subcommand.eq_ignore_ascii_case(b"COUNT") {
+        Err(ParserError::WrongNumberOfArguments("command|count".to_string()).into())
+    } else {
+        Err(ExecutionError::new(&format!(
+            "unknown subcommand '{}'. Try COMMAND HELP.",
+            String::from_utf8_lossy(subcommand)
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::request;
+    use crate::resp::Protocol;
+    use crate::config::Config;
+    use crate::controller::Databases;
+    use crate::executor::CommandExecutor;
+    use std::sync::Arc;
+
+    #[test]
+    fn given_wrong_argument_counts_when_validated_then_errors_worded_alike() {
+        for words in [&["GET"][..], &["GET", "a", "b"], &["SET", "a"], &["LLEN"], &["RENAME", "a"], &["publish", "channel"]] {
+            let error = validate(&request(words)).unwrap_err();
+            assert_eq!(error, ParserError::WrongNumberOfArguments(words[0].to_lowercase()));
+        }
+    }
+
+    #[test]
+    fn given_variadic_command_when_validated_then_minimum_admitted_and_more() {
+        assert!(validate(&request(&["RPUSH", "list"])).is_err());
+        for words in [&["RPUSH", "list", "a"][..], &["RPUSH", "list", "a", "b"], &["rpush", "list", "a", "b", "c"]] {
+            assert_eq!(validate(&request(words)).unwrap().unwrap().name, "RPUSH");
+        }
+        assert!(validate(&request(&["NOSUCHCOMMAND"])).unwrap().is_none());
+    }
+
+    #[test]
+    fn given_table_when_flags_read_then_lock_type_follows_write() {
+        assert_eq!(lookup(b"get").unwrap().lock_type(), LockType::Read);
+        assert_eq!(lookup(b"SET").unwrap().lock_type(), LockType::Write);
+        assert!(lookup(b"SET").unwrap().has_flag("denyoom"));
+        assert!(!lookup(b"DEL").unwrap().has_flag("denyoom"));
+    }
+
+    #[test]
+    fn given_registered_executors_when_their_commands_looked_up_then_in_table_with_their_type() {
+        let databases = Databases::new(&Config::default());
+        for executor in [databases.string.clone() as Arc<dyn CommandExecutor>, databases.list.clone(), databases.hash.clone()] {
+            for name in executor.supported_commands() {
+                let spec = lookup(name.as_bytes()).unwrap_or_else(|| panic!("{} isn't in the table", name));
+                assert_eq!(spec.key_type, executor.key_type(), "{}", name);
+            }
+        }
+    }
+
+    #[test]
+    fn given_command_info_when_requested_then_described_as_redis_does() {
+        let reply = execute_command(&request(&["COMMAND", "INFO", "get", "nosuch", "rename"])).unwrap().encode(Protocol::Resp2);
+        let get = "*10\r\n$3\r\nget\r\n:2\r\n*2\r\n+readonly\r\n+fast\r\n:1\r\n:1\r\n:1\r\n*0\r\n*0\r\n*0\r\n*0\r\n";
+        let rename = "*10\r\n$6\r\nrename\r\n:3\r\n*1\r\n+write\r\n:1\r\n:2\r\n:1\r\n*0\r\n*0\r\n*0\r\n*0\r\n";
+        assert_eq!(reply, format!("*3\r\n{}$-1\r\n{}", get, rename).as_bytes());
+
+        let reply = execute_command(&request(&["COMMAND", "COUNT"])).unwrap().encode(Protocol::Resp2);
+        assert_eq!(reply, format!(":{}\r\n", COMMANDS.len()).as_bytes());
+        let reply = execute_command(&request(&["COMMAND"])).unwrap().encode(Protocol::Resp3);
+        assert!(reply.starts_with(format!("*{}\r\n*10\r\n$3\r\nget\r\n:2\r\n~2\r\n", COMMANDS.len()).as_bytes()));
+        let error = execute_command(&request(&["COMMAND", "DOCS"])).unwrap_err();
+        assert_eq!(error.get_message(), "unknown subcommand 'DOCS'. Try COMMAND HELP.");
+    }
+}
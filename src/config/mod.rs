@@ -0,0 +1,745 @@
+// Server settings, read from a config file (app.properties unless another is named) and the
+// command line, which takes precedence. Anything not set in either takes Redis's default.
+// A few can be changed while the server runs, with CONFIG SET.
+
+use crate::commands::{ExecutionError, ParserError};
+use crate::memory::EvictionPolicy;
+use crate::resp::Value;
+use bytes::Bytes;
+use log::LevelFilter;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+const HOME: &str = "127.0.0.1";
+const DEFAULT_PORT: u16 = 6379;
+const DEFAULT_THREAD_POOL_SIZE: usize = 4;
+const DEFAULT_THREAD_POOL_QUEUE_SIZE: usize = 1024;
+const DEFAULT_BLOCKING_THREAD_POOL_SIZE: usize = 4;
+const DEFAULT_KEYSPACE_SHARDS: usize = 16;
+const DEFAULT_PROTO_MAX_BULK_LEN: usize = 512 * 1024 * 1024;
+const DEFAULT_PROTO_MAX_MULTIBULK_LEN: usize = 1024 * 1024;
+const DEFAULT_CLIENT_QUERY_BUFFER_LIMIT: usize = 1024 * 1024 * 1024;
+const DEFAULT_MAX_KEY_LENGTH: usize = 64 * 1024;
+const DEFAULT_MAX_VALUE_LENGTH: usize = DEFAULT_PROTO_MAX_BULK_LEN;
+const DEFAULT_MAX_CONTAINER_ELEMENTS: usize = DEFAULT_PROTO_MAX_MULTIBULK_LEN;
+const DEFAULT_MAXMEMORY: usize = 0;
+const DEFAULT_MAXMEMORY_SAMPLES: usize = 5;
+// As Redis's defaults: a key needs about a million uses to reach the top of the LFU counter, and
+// loses one from it for every minute it goes unused
+const DEFAULT_LFU_LOG_FACTOR: u32 = 10;
+const DEFAULT_LFU_DECAY_TIME: u64 = 1;
+// The classes of keyspace event notify-keyspace-events accepts, as in Redis, though only
+// evictions (e) are published so far
+const KEYSPACE_EVENT_FLAGS: &str = "KEg$lshzxetmdnA";
+const DEFAULT_MAXCLIENTS: usize = 10000;
+const DEFAULT_TIMEOUT: u64 = 0;
+const DEFAULT_TCP_KEEPALIVE: u64 = 300;
+const DEFAULT_BIND_RETRIES: u32 = 0;
+const DEFAULT_SLOW_COMMAND_LOG_THRESHOLD: i64 = 10;
+const DEFAULT_SLOW_COMMAND_LOG_RATE: u32 = 10;
+const DEFAULT_NORMAL_OUTPUT_BUFFER_LIMIT: OutputBufferLimit = OutputBufferLimit { hard: 0, soft: 0, soft_seconds: 0 };
+const DEFAULT_PUBSUB_OUTPUT_BUFFER_LIMIT: OutputBufferLimit =
+    OutputBufferLimit { hard: 32 * 1024 * 1024, soft: 8 * 1024 * 1024, soft_seconds: 60 };
+const DEFAULT_DIR: &str = "./";
+const DEFAULT_DBFILENAME: &str = "dump.rdb";
+const DEFAULT_LOGLEVEL: LevelFilter = LevelFilter::Info;
+const DEFAULT_CONFIG_FILE: &str = "app.properties";
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    // One or more addresses to listen on, separated by spaces, e.g. "127.0.0.1 ::1"
+    pub host: String,
+    pub port: u16,
+    pub thread_pool_size: usize,
+    // The most jobs waiting for a free thread; anything submitted past that waits for room
+    pub thread_pool_queue_size: usize,
+    // Threads set aside for long-running commands, e.g. DEBUG SLEEP, so they don't hold up the
+    // threads serving clients; more than that many at once wait for one to be free
+    pub blocking_thread_pool_size: usize,
+    // How many parts the keyspace is split into, each locked on its own, so commands on keys in
+    // different parts don't wait for each other
+    pub keyspace_shards: usize,
+    // The largest bulk string a client may send, checked before any room is made for it
+    pub proto_max_bulk_len: usize,
+    // The most arguments (including the command name) a single request may declare
+    pub proto_max_multibulk_len: usize,
+    // The most bytes a single request may take up, across all of its arguments
+    pub client_query_buffer_limit: usize,
+    // The longest key name, the longest value and the most elements a single command may add to
+    // a container, checked as each command is built
+    pub max_key_length: usize,
+    pub max_value_length: usize,
+    pub max_container_elements: usize,
+    // The most memory the keys may take before some are evicted, or writes refused, as the
+    // policy says; 0 is no limit. The policy looks at `maxmemory_samples` keys to choose each one.
+    pub maxmemory: usize,
+    pub maxmemory_policy: EvictionPolicy,
+    pub maxmemory_samples: usize,
+    // How slowly a key's LFU counter rises as it is used, and how many minutes it takes to fall
+    // by one while unused (0 is never), for the LFU policies
+    pub lfu_log_factor: u32,
+    pub lfu_decay_time: u64,
+    // Which keyspace events are published, in Redis's notation, e.g. "Ee"; empty is none
+    pub notify_keyspace_events: String,
+    // The most clients connected at once; any more are turned away with an error
+    pub maxclients: usize,
+    // Seconds a client may go without sending a complete command before it is closed; 0 is never
+    pub timeout: u64,
+    // Seconds a connection may be silent before the OS starts probing whether the client is
+    // still there; 0 turns keepalive off
+    pub tcp_keepalive: u64,
+    // How many more times to try binding an address that is in use, e.g. by the connections of
+    // a server that was just restarted; 0 gives up straight away
+    pub bind_retries: u32,
+    // A Unix socket to listen on as well as the TCP addresses
+    pub unixsocket: Option<String>,
+    // Where the database is saved, and its file name there
+    pub dir: String,
+    pub dbfilename: String,
+    // The password clients must AUTH with; none means they needn't
+    pub requirepass: Option<String>,
+    // Redis's levels are mapped onto the log crate's: debug, verbose (info), notice (info),
+    // warning and nothing
+    pub loglevel: LevelFilter,
+    // Milliseconds a command may take before it is logged as a warning; negative turns the
+    // warnings off, 0 logs every command
+    pub slow_command_log_threshold: i64,
+    // The most slow command warnings logged in a second, the rest are only counted
+    pub slow_command_log_rate: u32,
+    // How much output may be waiting for a client before it is disconnected, for clients with
+    // no subscriptions and for subscribers
+    pub client_output_buffer_limit_normal: OutputBufferLimit,
+    pub client_output_buffer_limit_pubsub: OutputBufferLimit,
+}
+
+// A client is disconnected once the output waiting to be sent to it is over `hard`, or has
+// stayed over `soft` for `soft_seconds`. A limit of 0 is no limit. Written "hard soft seconds",
+// e.g. "32mb 8mb 60", as in Redis's client-output-buffer-limit.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OutputBufferLimit {
+    pub hard: usize,
+    pub soft: usize,
+    pub soft_seconds: u64,
+}
+
+impl FromStr for OutputBufferLimit {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let words: Vec<&str> = value.split_whitespace().collect();
+        let [hard, soft, soft_seconds] = words[..] else {
+            return Err(());
+        };
+        Ok(OutputBufferLimit {
+            hard: parse_memory(hard).ok_or(())?,
+            soft: parse_memory(soft).ok_or(())?,
+            soft_seconds: soft_seconds.parse().map_err(|_| ())?,
+        })
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            host: HOME.to_string(),
+            port: DEFAULT_PORT,
+            thread_pool_size: DEFAULT_THREAD_POOL_SIZE,
+            thread_pool_queue_size: DEFAULT_THREAD_POOL_QUEUE_SIZE,
+            blocking_thread_pool_size: DEFAULT_BLOCKING_THREAD_POOL_SIZE,
+            keyspace_shards: DEFAULT_KEYSPACE_SHARDS,
+            proto_max_bulk_len: DEFAULT_PROTO_MAX_BULK_LEN,
+            proto_max_multibulk_len: DEFAULT_PROTO_MAX_MULTIBULK_LEN,
+            client_query_buffer_limit: DEFAULT_CLIENT_QUERY_BUFFER_LIMIT,
+            max_key_length: DEFAULT_MAX_KEY_LENGTH,
+            max_value_length: DEFAULT_MAX_VALUE_LENGTH,
+            max_container_elements: DEFAULT_MAX_CONTAINER_ELEMENTS,
+            maxmemory: DEFAULT_MAXMEMORY,
+            maxmemory_policy: EvictionPolicy::default(),
+            maxmemory_samples: DEFAULT_MAXMEMORY_SAMPLES,
+            lfu_log_factor: DEFAULT_LFU_LOG_FACTOR,
+            lfu_decay_time: DEFAULT_LFU_DECAY_TIME,
+            notify_keyspace_events: String::new(),
+            maxclients: DEFAULT_MAXCLIENTS,
+            timeout: DEFAULT_TIMEOUT,
+            tcp_keepalive: DEFAULT_TCP_KEEPALIVE,
+            bind_retries: DEFAULT_BIND_RETRIES,
+            unixsocket: None,
+            dir: DEFAULT_DIR.to_string(),
+            dbfilename: DEFAULT_DBFILENAME.to_string(),
+            requirepass: None,
+            loglevel: DEFAULT_LOGLEVEL,
+            slow_command_log_threshold: DEFAULT_SLOW_COMMAND_LOG_THRESHOLD,
+            slow_command_log_rate: DEFAULT_SLOW_COMMAND_LOG_RATE,
+            client_output_buffer_limit_normal: DEFAULT_NORMAL_OUTPUT_BUFFER_LIMIT,
+            client_output_buffer_limit_pubsub: DEFAULT_PUBSUB_OUTPUT_BUFFER_LIMIT,
+        }
+    }
+}
+
+// Every setting, by its name on the command line, its key in the config file and what it is
+// for, as listed by --help
+const SETTINGS: [(&str, &str, &str); 31] = [
+    ("bind", "server.host", "addresses to listen on, separated by spaces"),
+    ("port", "server.port", "TCP port to listen on; 0 lets the OS pick one"),
+    ("unixsocket", "unixsocket", "Unix socket to listen on as well"),
+    ("thread-pool-size", "thread.pool.size", "threads serving clients"),
+    ("thread-pool-queue-size", "thread.pool.queue.size", "most jobs waiting for a thread"),
+    ("blocking-thread-pool-size", "blocking.thread.pool.size", "threads for long-running commands"),
+    ("keyspace-shards", "keyspace.shards", "parts the keyspace is split into, each locked on its own"),
+    ("maxclients", "maxclients", "most clients connected at once"),
+    ("timeout", "timeout", "seconds a client may be idle before it is closed; 0 is never"),
+    ("tcp-keepalive", "tcp-keepalive", "seconds before a silent connection is probed; 0 is off"),
+    ("bind-retries", "bind-retries", "times to retry binding an address that is in use"),
+    ("proto-max-bulk-len", "proto-max-bulk-len", "largest bulk string a client may send"),
+    ("proto-max-multibulk-len", "proto-max-multibulk-len", "most arguments in a request"),
+    ("client-query-buffer-limit", "client-query-buffer-limit", "most bytes in a request"),
+    ("max-key-length", "max.key.length", "longest key name"),
+    ("max-value-length", "max.value.length", "longest value"),
+    ("max-container-elements", "max.container.elements", "most elements a command may add to a list"),
+    ("maxmemory", "maxmemory", "most memory the keys may take; 0 is no limit"),
+    ("maxmemory-policy", "maxmemory-policy", "noeviction, allkeys-lru, allkeys-lfu, allkeys-random, volatile-lru or volatile-lfu"),
+    ("maxmemory-samples", "maxmemory-samples", "keys looked at to choose each one to evict"),
+    ("lfu-log-factor", "lfu-log-factor", "how slowly a key's LFU counter rises as it is used"),
+    ("lfu-decay-time", "lfu-decay-time", "minutes for an unused key's LFU counter to fall by one; 0 is never"),
+    ("notify-keyspace-events", "notify-keyspace-events", "keyspace events to publish, e.g. \"Ee\""),
+    ("dir", "dir", "directory the database is saved in"),
+    ("dbfilename", "dbfilename", "file name the database is saved as"),
+    ("requirepass", "requirepass", "password clients must AUTH with"),
+    ("loglevel", "loglevel", "debug, verbose, notice, warning or nothing"),
+    ("slow-command-log-threshold", "slow-command-log-threshold", "milliseconds before a command is logged as slow; negative is never"),
+    ("slow-command-log-rate", "slow-command-log-rate", "most slow commands logged a second"),
+    ("client-output-buffer-limit-normal", "client-output-buffer-limit-normal", "output a client may have waiting, as \"hard soft seconds\""),
+    ("client-output-buffer-limit-pubsub", "client-output-buffer-limit-pubsub", "output a subscriber may have waiting, as \"hard soft seconds\""),
+];
+
+impl Config {
+    // Reads the config file named on the command line, or app.properties when none is (and it
+    // exists), then applies the settings given on the command line over it
+    pub fn load(command_line: &CommandLine) -> io::Result<Config> {
+        let properties = match &command_line.config_file {
+            Some(path) => Properties::load(path)?,
+            None => match Properties::load(DEFAULT_CONFIG_FILE) {
+                Err(error) if error.kind() == ErrorKind::NotFound => Properties::default(),
+                loaded => loaded?,
+            },
+        };
+        Config::merge(&properties, command_line)
+    }
+
+    fn merge(properties: &Properties, command_line: &CommandLine) -> io::Result<Config> {
+        let mut config = Config::default();
+        for (name, key, _) in SETTINGS {
+            if let Some(value) = properties.get(key) {
+                config
+                    .set(name, value)
+                    .map_err(|reason| invalid(format!("Invalid {} '{}' in {}: {}", name, value, properties.source, reason)))?;
+            }
+        }
+        for (name, value) in &command_line.settings {
+            config
+                .set(name, value)
+                .map_err(|reason| invalid(format!("Invalid {} '{}' on the command line: {}", name, value, reason)))?;
+        }
+        Ok(config)
+    }
+
+    // Applies a single setting, by its command line name, or says why the value won't do
+    fn set(&mut self, name: &str, value: &str) -> Result<(), &'static str> {
+        match name {
+            "bind" => self.host = value.to_string(),
+            "port" => self.port = parse(value)?,
+            "unixsocket" => self.unixsocket = non_empty(value),
+            "thread-pool-size" => self.thread_pool_size = parse(value)?,
+            "thread-pool-queue-size" => self.thread_pool_queue_size = parse(value)?,
+            "blocking-thread-pool-size" => self.blocking_thread_pool_size = positive(value)?,
+            "keyspace-shards" => self.keyspace_shards = positive(value)?,
+            "maxclients" => self.maxclients = parse(value)?,
+            "timeout" => self.timeout = parse(value)?,
+            "tcp-keepalive" => self.tcp_keepalive = parse(value)?,
+            "bind-retries" => self.bind_retries = parse(value)?,
+            "proto-max-bulk-len" => self.proto_max_bulk_len = memory(value)?,
+            "proto-max-multibulk-len" => self.proto_max_multibulk_len = parse(value)?,
+            "client-query-buffer-limit" => self.client_query_buffer_limit = memory(value)?,
+            "max-key-length" => self.max_key_length = memory(value)?,
+            "max-value-length" => self.max_value_length = memory(value)?,
+            "max-container-elements" => self.max_container_elements = positive(value)?,
+            "maxmemory" => self.maxmemory = memory(value)?,
+            "maxmemory-policy" => {
+                self.maxmemory_policy = value
+                    .parse()
+                    .map_err(|_| "expected noeviction, allkeys-lru, allkeys-lfu, allkeys-random, volatile-lru or volatile-lfu")?
+            }
+            "maxmemory-samples" => self.maxmemory_samples = positive(value)?,
+            "lfu-log-factor" => self.lfu_log_factor = parse(value)?,
+            "lfu-decay-time" => self.lfu_decay_time = parse(value)?,
+            "notify-keyspace-events" => self.notify_keyspace_events = keyspace_events(value)?,
+            "dir" => self.dir = value.to_string(),
+            "dbfilename" => self.dbfilename = value.to_string(),
+            "requirepass" => self.requirepass = non_empty(value),
+            "loglevel" => self.loglevel = parse_loglevel(value)?,
+            "slow-command-log-threshold" => self.slow_command_log_threshold = parse(value)?,
+            "slow-command-log-rate" => self.slow_command_log_rate = parse(value)?,
+            "client-output-buffer-limit-normal" => self.client_output_buffer_limit_normal = output_limit(value)?,
+            "client-output-buffer-limit-pubsub" => self.client_output_buffer_limit_pubsub = output_limit(value)?,
+            _ => return Err("there is no such setting"),
+        }
+        Ok(())
+    }
+
+    // The addresses in `host`, with the brackets an IPv6 address may be written in removed
+    pub fn bind_addresses(&self) -> Vec<&str> {
+        self.host
+            .split_whitespace()
+            .map(|address| address.trim_start_matches('[').trim_end_matches(']'))
+            .collect()
+    }
+}
+
+// The limits on what a command may store, which CONFIG SET can change while the server runs
+#[derive(Debug)]
+pub struct SizeLimits {
+    max_key_length: AtomicUsize,
+    max_value_length: AtomicUsize,
+    max_container_elements: AtomicUsize,
+}
+
+// The settings CONFIG GET and CONFIG SET know about
+const RUNTIME_SETTINGS: [&str; 3] = ["max-key-length", "max-value-length", "max-container-elements"];
+
+impl SizeLimits {
+    pub fn new(config: &Config) -> SizeLimits {
+        SizeLimits {
+            max_key_length: AtomicUsize::new(config.max_key_length),
+            max_value_length: AtomicUsize::new(config.max_value_length),
+            max_container_elements: AtomicUsize::new(config.max_container_elements),
+        }
+    }
+
+    pub fn max_key_length(&self) -> usize {
+        self.max_key_length.load(Ordering::Relaxed)
+    }
+
+    pub fn max_value_length(&self) -> usize {
+        self.max_value_length.load(Ordering::Relaxed)
+    }
+
+    pub fn max_container_elements(&self) -> usize {
+        self.max_container_elements.load(Ordering::Relaxed)
+    }
+
+    fn get(&self, name: &str) -> Option<usize> {
+        match name {
+            "max-key-length" => Some(self.max_key_length()),
+            "max-value-length" => Some(self.max_value_length()),
+            "max-container-elements" => Some(self.max_container_elements()),
+            _ => None,
+        }
+    }
+
+    // Parsed just as it would be at startup
+    fn set(&self, name: &str, value: &str) -> Result<(), &'static str> {
+        let mut parsed = Config::default();
+        parsed.set(name, value)?;
+        match name {
+            "max-key-length" => self.max_key_length.store(parsed.max_key_length, Ordering::Relaxed),
+            "max-value-length" => self.max_value_length.store(parsed.max_value_length, Ordering::Relaxed),
+            "max-container-elements" => self.max_container_elements.store(parsed.max_container_elements, Ordering::Relaxed),
+            _ => return Err("it can't be changed while the server is running"),
+        }
+        Ok(())
+    }
+}
+
+pub fn is_command_supported(command: &[u8]) -> bool {
+    command.eq_ignore_ascii_case(b"CONFIG")
+}
+
+pub fn execute_command(request: &[Bytes], limits: &SizeLimits) -> Result<Value, ExecutionError> {
+    // support syntax: CONFIG GET parameter, CONFIG SET parameter value
+    // GET's parameter may be * for every setting that can be changed
+    let subcommand = String::from_utf8_lossy(&request[1]).to_uppercase();
+    let name = request.get(2).map(|name| String::from_utf8_lossy(name).to_lowercase());
+    match (subcommand.as_str(), request.len()) {
+        ("GET", 3) => {
+            let name = name.unwrap();
+            let settings = RUNTIME_SETTINGS
+                .iter()
+                .filter(|setting| name == "*" || name == **setting)
+                .flat_map(|setting| {
+                    let value = limits.get(setting).unwrap().to_string();
+                    [Value::BulkString(Bytes::from_static(setting.as_bytes())), Value::BulkString(Bytes::from(value))]
+                })
+                .collect();
+            Ok(Value::Array(settings))
+        }
+        ("SET", 4) => {
+            let name = name.unwrap();
+            if !RUNTIME_SETTINGS.contains(&name.as_str()) {
+                return Err(ExecutionError::new(&format!(
+                    "Unknown option or number of arguments for CONFIG SET - '{}'",
+                    name
+                )));
+            }
+            let value = String::from_utf8_lossy(&request[3]);
+            limits.set(&name, &value).map_err(|reason| {
+                ExecutionError::new(&format!("CONFIG SET failed (possibly related to argument '{}') - {}", name, reason))
+            })?;
+            Ok(Value::Ok)
+        }
+        ("GET", _) | ("SET", _) => {
+            Err(ParserError::WrongNumberOfArguments(format!("config|{}", subcommand.to_lowercase())).into())
+        }
+        _ => Err(ExecutionError::new(&format!(
+            "unknown subcommand '{}'. Try CONFIG HELP.",
+            String::from_utf8_lossy(&request[1])
+        ))),
+    }
+}
+
+// What was given on the command line: a config file to read, optionally, then any number of
+// `--setting value` (or `--setting=value`) pairs
+#[derive(Debug, Default, PartialEq)]
+pub struct CommandLine {
+    pub config_file: Option<String>,
+    // (setting, value), in the order given, so a later one wins
+    pub settings: Vec<(String, String)>,
+    pub help: bool,
+}
+
+impl CommandLine {
+    pub fn parse<I: IntoIterator<Item = String>>(args: I) -> io::Result<CommandLine> {
+        let mut command_line = CommandLine::default();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            if arg == "--help" || arg == "-h" {
+                command_line.help = true;
+            } else if let Some(option) = arg.strip_prefix("--") {
+                let (name, value) = match option.split_once('=') {
+                    Some((name, value)) => (name.to_string(), value.to_string()),
+                    None => {
+                        let value = args
+                            .next()
+                            .ok_or_else(|| invalid(format!("No value given for --{}", option)))?;
+                        (option.to_string(), value)
+                    }
+                };
+                if !SETTINGS.iter().any(|(setting, _, _)| *setting == name) {
+                    return Err(invalid(format!("Unknown option --{}", name)));
+                }
+                command_line.settings.push((name, value));
+            } else if command_line.config_file.is_none() && command_line.settings.is_empty() {
+                command_line.config_file = Some(arg);
+            } else {
+                return Err(invalid(format!("Unexpected argument '{}', the config file comes first", arg)));
+            }
+        }
+        Ok(command_line)
+    }
+
+    // For --help
+    pub fn usage(program: &str) -> String {
+        let mut usage = format!(
+            "Usage: {} [config file] [--setting value]...\n\n\
+             The config file is {} if none is named. Settings given here take precedence over it.\n\n",
+            program, DEFAULT_CONFIG_FILE
+        );
+        for (name, key, help) in SETTINGS {
+            let option = format!("--{} <value>", name);
+            usage.push_str(&format!("  {:<44} {} (\"{}\" in the config file)\n", option, help, key));
+        }
+        usage
+    }
+}
+
+// A config file: one `key: value` per line, with blank lines and lines starting with '#'
+// ignored. An empty value is the same as leaving the key out.
+#[derive(Debug, Default)]
+struct Properties {
+    values: HashMap<String, String>,
+    // the file they came from, for errors
+    source: String,
+}
+
+impl Properties {
+    fn load(path: &str) -> io::Result<Properties> {
+        let text = fs::read_to_string(path)
+            .map_err(|error| io::Error::new(error.kind(), format!("Unable to read {}: {}", path, error)))?;
+        Properties::parse(&text, path)
+    }
+
+    fn parse(text: &str, source: &str) -> io::Result<Properties> {
+        let mut values = HashMap::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let Some((key, value)) = line.split_once(':') else {
+                return Err(invalid(format!("Expected 'key: value' at line {} of {}", number + 1, source)));
+            };
+            let value = value.trim();
+            let value = value
+                .strip_prefix('"')
+                .and_then(|quoted| quoted.strip_suffix('"'))
+                .unwrap_or(value);
+            values.insert(key.trim().to_string(), value.to_string());
+        }
+        Ok(Properties { values, source: source.to_string() })
+    }
+
+    fn get(&self, key: &str) -> Option<&str> {
+        self.values.get(key).map(String::as_str).filter(|value| !value.is_empty())
+    }
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(ErrorKind::InvalidInput, message)
+}
+
+fn parse<T: FromStr>(value: &str) -> Result<T, &'static str> {
+    value.trim().parse::<T>().map_err(|_| "expected a number in range")
+}
+
+fn positive(value: &str) -> Result<usize, &'static str> {
+    parse(value).ok().filter(|&value| value > 0).ok_or("expected a number greater than 0")
+}
+
+fn memory(value: &str) -> Result<usize, &'static str> {
+    parse_memory(value).ok_or("expected a size such as 512mb")
+}
+
+fn output_limit(value: &str) -> Result<OutputBufferLimit, &'static str> {
+    value.parse().map_err(|_| "expected the hard limit, soft limit and seconds, e.g. \"32mb 8mb 60\"")
+}
+
+fn keyspace_events(value: &str) -> Result<String, &'static str> {
+    if value.chars().all(|flag| KEYSPACE_EVENT_FLAGS.contains(flag)) {
+        Ok(value.to_string())
+    } else {
+        Err("expected event classes from KEg$lshzxetmdnA")
+    }
+}
+
+fn non_empty(value: &str) -> Option<String> {
+    (!value.is_empty()).then(|| value.to_string())
+}
+
+fn parse_loglevel(value: &str) -> Result<LevelFilter, &'static str> {
+    match value.to_lowercase().as_str() {
+        "debug" => Ok(LevelFilter::Debug),
+        "verbose" | "notice" => Ok(LevelFilter::Info),
+        "warning" => Ok(LevelFilter::Warn),
+        "nothing" => Ok(LevelFilter::Off),
+        _ => Err("expected debug, verbose, notice, warning or nothing"),
+    }
+}
+
+// A byte count in Redis's notation: a plain number, or one with a k/kb/m/mb/g/gb suffix
+// where k, m and g are powers of 1000 and kb, mb and gb powers of 1024
+fn parse_memory(value: &str) -> Option<usize> {
+    let value = value.trim().to_lowercase();
+    let digits_end = value.find(|c: char| !c.is_ascii_digit()).unwrap_or(value.len());
+    let (digits, unit) = value.split_at(digits_end);
+    let multiplier: usize = match unit {
+        "" | "b" => 1,
+        "k" => 1000,
+        "kb" => 1024,
+        "m" => 1000 * 1000,
+        "mb" => 1024 * 1024,
+        "g" => 1000 * 1000 * 1000,
+        "gb" => 1024 * 1024 * 1024,
+        _ => return None,
+    };
+    digits.parse::<usize>().ok()?.checked_mul(multiplier)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn given_memory_units_when_parsed_then_scaled() {
+        assert_eq!(parse_memory("1024"), Some(1024));
+        assert_eq!(parse_memory("1k"), Some(1000));
+        assert_eq!(parse_memory("1kb"), Some(1024));
+        assert_eq!(parse_memory("512MB"), Some(512 * 1024 * 1024));
+        assert_eq!(parse_memory("2g"), Some(2_000_000_000));
+    }
+
+    #[test]
+    fn given_several_hosts_when_bind_addresses_then_each_returned_without_brackets() {
+        let config = Config { host: "127.0.0.1  [::1] ::".to_string(), ..Config::default() };
+        assert_eq!(config.bind_addresses(), vec!["127.0.0.1", "::1", "::"]);
+    }
+
+    #[test]
+    fn given_invalid_memory_value_when_parsed_then_none() {
+        assert_eq!(parse_memory(""), None);
+        assert_eq!(parse_memory("lots"), None);
+        assert_eq!(parse_memory("12tb"), None);
+    }
+
+    fn args(words: &[&str]) -> Vec<String> {
+        words.iter().map(|word| word.to_string()).collect()
+    }
+
+    #[test]
+    fn given_nothing_set_when_merged_then_defaults() {
+        let config = Config::merge(&Properties::default(), &CommandLine::default()).unwrap();
+        assert_eq!(config.port, DEFAULT_PORT);
+        assert_eq!(config.host, HOME);
+        assert_eq!(config.dbfilename, DEFAULT_DBFILENAME);
+        assert_eq!(config.requirepass, None);
+        assert_eq!(config.loglevel, LevelFilter::Info);
+    }
+
+    #[test]
+    fn given_file_and_command_line_when_merged_then_command_line_wins_over_file_over_defaults() {
+        let properties = Properties::parse(
+            "# from the file\nserver.port: 7000\nmaxclients: 50\ndir: /var/lib/redis\nthread.pool.queue.size: 64\nkeyspace.shards: 32\nmaxmemory: 100mb\nmaxmemory-policy: allkeys-lru\n",
+            "test.properties",
+        )
+        .unwrap();
+        let command_line = CommandLine::parse(args(&["--port", "7001", "--requirepass=secret"])).unwrap();
+
+        let config = Config::merge(&properties, &command_line).unwrap();
+        assert_eq!(config.port, 7001);
+        assert_eq!(config.maxclients, 50);
+        assert_eq!(config.thread_pool_queue_size, 64);
+        assert_eq!(config.keyspace_shards, 32);
+        assert_eq!(config.maxmemory, 100 * 1024 * 1024);
+        assert_eq!(config.maxmemory_policy, EvictionPolicy::AllKeysLru);
+        assert_eq!(config.dir, "/var/lib/redis");
+        assert_eq!(config.requirepass.as_deref(), Some("secret"));
+        assert_eq!(config.timeout, DEFAULT_TIMEOUT);
+    }
+
+    #[test]
+    fn given_same_option_twice_when_merged_then_last_one_wins() {
+        let command_line = CommandLine::parse(args(&["--bind", "::1", "--bind", "127.0.0.1 ::1"])).unwrap();
+        let config = Config::merge(&Properties::default(), &command_line).unwrap();
+        assert_eq!(config.bind_addresses(), vec!["127.0.0.1", "::1"]);
+    }
+
+    #[test]
+    fn given_non_numeric_port_when_merged_then_error_names_setting_and_source() {
+        let command_line = CommandLine::parse(args(&["--port", "redis"])).unwrap();
+        let error = Config::merge(&Properties::default(), &command_line).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidInput);
+        assert!(error.to_string().contains("Invalid port 'redis'"), "{}", error);
+        assert!(error.to_string().contains("on the command line"), "{}", error);
+
+        let properties = Properties::parse("server.port: 70000", "test.properties").unwrap();
+        let error = Config::merge(&properties, &CommandLine::default()).unwrap_err();
+        assert!(error.to_string().contains("Invalid port '70000'"), "{}", error);
+        assert!(error.to_string().contains("in test.properties"), "{}", error);
+    }
+
+    #[test]
+    fn given_invalid_typed_values_when_merged_then_errors() {
+        for (name, value) in [
+            ("maxclients", "-1"),
+            ("proto-max-bulk-len", "lots"),
+            ("loglevel", "loud"),
+            ("blocking-thread-pool-size", "0"),
+            ("keyspace-shards", "0"),
+            ("maxmemory-policy", "volatile-ttl"),
+            ("lfu-log-factor", "-1"),
+            ("notify-keyspace-events", "Eq"),
+        ] {
+            let command_line = CommandLine::parse(args(&[&format!("--{}", name), value])).unwrap();
+            let error = Config::merge(&Properties::default(), &command_line).unwrap_err();
+            assert!(error.to_string().contains(&format!("Invalid {} '{}'", name, value)), "{}", error);
+        }
+    }
+
+    #[test]
+    fn given_config_file_and_settings_when_parsed_then_file_comes_first() {
+        let command_line = CommandLine::parse(args(&["replica.properties", "--loglevel", "debug"])).unwrap();
+        assert_eq!(command_line.config_file.as_deref(), Some("replica.properties"));
+        assert_eq!(command_line.settings, vec![("loglevel".to_string(), "debug".to_string())]);
+
+        assert!(CommandLine::parse(args(&["--port", "1", "late.properties"])).is_err());
+        assert!(CommandLine::parse(args(&["one.properties", "two.properties"])).is_err());
+    }
+
+    #[test]
+    fn given_bad_options_when_parsed_then_errors() {
+        let error = CommandLine::parse(args(&["--colour", "blue"])).unwrap_err();
+        assert_eq!(error.to_string(), "Unknown option --colour");
+        let error = CommandLine::parse(args(&["--port"])).unwrap_err();
+        assert_eq!(error.to_string(), "No value given for --port");
+    }
+
+    #[test]
+    fn given_help_when_parsed_then_usage_lists_every_setting() {
+        assert!(CommandLine::parse(args(&["--help"])).unwrap().help);
+        let usage = CommandLine::usage("redis_in_rust");
+        for (name, key, _) in SETTINGS {
+            assert!(usage.contains(&format!("--{} <value>", name)), "{} missing from\n{}", name, usage);
+            assert!(usage.contains(key));
+        }
+    }
+
+    #[test]
+    fn given_properties_text_when_parsed_then_comments_skipped_and_quotes_removed() {
+        let properties = Properties::parse(
+            "\n# a comment\nserver.host: localhost\nrequirepass: \"p: w\"\nunixsocket:\n",
+            "test.properties",
+        )
+        .unwrap();
+        assert_eq!(properties.get("server.host"), Some("localhost"));
+        assert_eq!(properties.get("requirepass"), Some("p: w"));
+        assert_eq!(properties.get("unixsocket"), None);
+        assert!(Properties::parse("port 6379", "redis.conf").is_err());
+    }
+
+    #[test]
+    fn given_output_buffer_limit_when_set_then_parsed_as_hard_soft_seconds() {
+        let command_line = CommandLine::parse(args(&["--client-output-buffer-limit-pubsub", "1mb 256kb 10"])).unwrap();
+        let config = Config::merge(&Properties::default(), &command_line).unwrap();
+        assert_eq!(
+            config.client_output_buffer_limit_pubsub,
+            OutputBufferLimit { hard: 1024 * 1024, soft: 256 * 1024, soft_seconds: 10 }
+        );
+        assert_eq!(config.client_output_buffer_limit_normal, DEFAULT_NORMAL_OUTPUT_BUFFER_LIMIT);
+
+        let command_line = CommandLine::parse(args(&["--client-output-buffer-limit-normal", "1mb 60"])).unwrap();
+        assert!(Config::merge(&Properties::default(), &command_line).is_err());
+    }
+
+    #[test]
+    fn given_config_set_when_valid_then_limit_changed_and_reported_by_config_get() {
+        let limits = SizeLimits::new(&Config::default());
+        let reply = execute_command(&crate::commands::request(&["CONFIG", "SET", "max-key-length", "1kb"]), &limits).unwrap();
+        assert_eq!(reply, Value::Ok);
+        assert_eq!(limits.max_key_length(), 1024);
+
+        let reply = execute_command(&crate::commands::request(&["config", "get", "MAX-KEY-LENGTH"]), &limits).unwrap();
+        let setting = |text: &'static str| Value::BulkString(Bytes::from_static(text.as_bytes()));
+        assert_eq!(reply, Value::Array(vec![setting("max-key-length"), setting("1024")]));
+        let reply = execute_command(&crate::commands::request(&["CONFIG", "GET", "*"]), &limits).unwrap();
+        assert!(matches!(reply, Value::Array(settings) if settings.len() == 6));
+        let reply = execute_command(&crate::commands::request(&["CONFIG", "GET", "port"]), &limits).unwrap();
+        assert_eq!(reply, Value::Array(Vec::new()));
+    }
+
+    #[test]
+    fn given_config_set_when_invalid_then_error_and_limit_kept() {
+        let limits = SizeLimits::new(&Config::default());
+        let error = execute_command(&crate::commands::request(&["CONFIG", "SET", "max-container-elements", "0"]), &limits).unwrap_err();
+        assert_eq!(
+            error.get_message(),
+            "CONFIG SET failed (possibly related to argument 'max-container-elements') - expected a number greater than 0"
+        );
+        assert_eq!(limits.max_container_elements(), DEFAULT_MAX_CONTAINER_ELEMENTS);
+
+        let error = execute_command(&crate::commands::request(&["CONFIG", "SET", "port", "7000"]), &limits).unwrap_err();
+        assert_eq!(error.get_message(), "Unknown option or number of arguments for CONFIG SET - 'port'");
+        let error = execute_command(&crate::commands::request(&["CONFIG", "SET", "max-key-length"]), &limits).unwrap_err();
+        assert_eq!(error, ExecutionError::Request(ParserError::WrongNumberOfArguments("config|set".to_string())));
+    }
+}
@@ -0,0 +1,288 @@
+// The front end used with the `async` feature: each client is a tokio task rather than a socket
+// on one of the event loops. Requests still run on the synchronous executors, called directly
+// since they only hold their locks briefly.
+
+use crate::commands::ExecutionError;
+use crate::config::Config;
+use crate::controller::connection::{Client, ClientOutput, ConnectionContext};
+use crate::controller::session::Session;
+use crate::controller::shutdown::{OpenConnections, Registration, ShutdownSignal};
+use crate::controller::{
+    accept_failed, configure_socket, format_error, panic_message, run_long_running, AcceptFailure, Databases, SpareDescriptor, ACCEPT_RETRY_DELAY,
+};
+use crate::index::Index;
+use crate::info;
+use bytes::Bytes;
+use std::io::{self, ErrorKind, Write};
+use std::net;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::runtime::Builder;
+use tokio::sync::mpsc::{self, UnboundedSender};
+use tokio::task::{self, JoinError, JoinSet};
+use tokio::time;
+
+// Accepts clients on every listener until shutdown is requested, then closes them all and
+// waits for their tasks to finish before returning
+pub(crate) fn run(
+    listeners: Vec<net::TcpListener>,
+    connections: &Arc<OpenConnections>,
+    index_db: &Arc<Index>,
+    databases: &Arc<Databases>,
+    config: &Arc<Config>,
+    shutdown: &ShutdownSignal,
+) {
+    let runtime = match Builder::new_multi_thread()
+        .worker_threads(config.thread_pool_size)
+        // long-running commands are run on tokio's threads for blocking work, capped here
+        .max_blocking_threads(config.blocking_thread_pool_size)
+        .enable_all()
+        .build()
+    {
+        Ok(runtime) => runtime,
+        Err(error) => {
+            log::error!("Unable to start the async runtime: {:?}", error);
+            return;
+        }
+    };
+    runtime.block_on(async {
+        let mut accept_loops = Vec::with_capacity(listeners.len());
+        for listener in listeners {
+            let listener = match listener.set_nonblocking(true).and_then(|_| TcpListener::from_std(listener)) {
+                Ok(listener) => listener,
+                Err(error) => {
+                    log::error!("Unable to listen for clients: {:?}", error);
+                    continue;
+                }
+            };
+            accept_loops.push(tokio::spawn(accept_clients(
+                listener,
+                shutdown.clone(),
+                Arc::clone(connections),
+                Arc::clone(index_db),
+                Arc::clone(databases),
+                Arc::clone(config),
+            )));
+        }
+        let mut clients = Vec::with_capacity(accept_loops.len());
+        for accept_loop in accept_loops {
+            match accept_loop.await {
+                Ok(tasks) => clients.push(tasks),
+                Err(error) => log::error!("An accept loop failed: {:?}", error),
+            }
+        }
+
+        // there is no persistence yet, so nothing needs saving before the clients are closed
+        connections.close_all();
+        for mut tasks in clients {
+            while let Some(finished) = tasks.join_next().await {
+                client_finished(finished);
+            }
+        }
+    });
+}
+
+// Returning drops the listener, so its port is released straight away rather than once every
+// client has gone. The tasks serving its clients are handed back to be waited for. Nothing but
+// shutdown ends the loop: a failed accept is logged and the next client is waited for.
+async fn accept_clients(
+    listener: TcpListener,
+    shutdown: ShutdownSignal,
+    connections: Arc<OpenConnections>,
+    index_db: Arc<Index>,
+    databases: Arc<Databases>,
+    config: Arc<Config>,
+) -> JoinSet<()> {
+    let mut clients = JoinSet::new();
+    let mut spare = SpareDescriptor::new();
+    // checked before accepting too, in case a client turned away was the one made to wake the loop
+    while !shutdown.is_requested() {
+        let accepted = listener.accept().await;
+        if shutdown.is_requested() {
+            break; // this is the connection made to wake the loop
+        }
+        let stream = match accepted.and_then(|(stream, _)| stream.into_std()) {
+            Ok(stream) => stream,
+            Err(error) => {
+                match accept_failed(&error) {
+                    AcceptFailure::Retry => {}
+                    AcceptFailure::RetryAfterDelay => time::sleep(ACCEPT_RETRY_DELAY).await,
+                    AcceptFailure::OutOfDescriptors => {
+                        if spare.release() {
+                            drop(listener.accept().await);
+                        }
+                        spare.restore();
+                        time::sleep(ACCEPT_RETRY_DELAY).await;
+                    }
+                }
+                continue;
+            }
+        };
+        let Some(registration) = connections.register(&stream, config.maxclients) else {
+            // turned away here rather than given a task, so it costs nothing to refuse
+            log::warn!("Rejecting a client, {} are already connected", connections.count());
+            let _ = (&stream).write_all(&format_error("max number of clients reached"));
+            continue;
+        };
+        if let Err(error) = configure_socket(&stream, &config) {
+            log::warn!("Unable to set the client's socket options: {:?}", error);
+        }
+        // a handle on the socket kept aside, so it can be closed from other threads
+        let (stream, socket) = match stream.try_clone().and_then(|socket| Ok((TcpStream::from_std(stream)?, socket))) {
+            Ok(stream) => stream,
+            Err(error) => {
+                log::error!("Unable to set up the connection: {:?}", error);
+                continue;
+            }
+        };
+        let (index_db, databases, config) = (Arc::clone(&index_db), Arc::clone(&databases), Arc::clone(&config));
+        clients.spawn(handle_client(stream, socket, registration, index_db, databases, config));
+        // forget the clients that have already gone
+        while let Some(finished) = clients.try_join_next() {
+            client_finished(finished);
+        }
+    }
+    clients
+}
+
+// A client whose task panicked lost only its own connection, but the panic is still counted
+fn client_finished(finished: Result<(), JoinError>) {
+    if let Err(error) = finished
+        && error.is_panic()
+    {
+        log::error!("A client's task panicked: {}", panic_message(error.into_panic().as_ref()));
+        info::record_recovered_panic();
+    }
+}
+
+async fn handle_client(
+    stream: TcpStream,
+    socket: net::TcpStream,
+    registration: Registration,
+    index: Arc<Index>,
+    databases: Arc<Databases>,
+    config: Arc<Config>,
+) {
+    let peer = stream.peer_addr().ok();
+    let (reader, mut writer) = stream.into_split();
+    // Replies and pub/sub messages are queued for a task of their own, so neither a command
+    // nor a publisher on another thread ever waits for the client to read
+    let (sender, mut queued) = mpsc::unbounded_channel::<Bytes>();
+    let unsent = Arc::new(AtomicUsize::new(0));
+    let output = QueuedOutput {
+        sender,
+        unsent: Arc::clone(&unsent),
+        socket,
+    };
+    let client = Client::new(registration.id(), Box::new(output), &config);
+    let connection = ConnectionContext::new(client, Arc::clone(&databases.pubsub), peer);
+    let log_name = connection.log_name().to_string();
+    log::info!("{}: connected", log_name);
+    let writer_log_name = log_name.clone();
+    let writes = tokio::spawn(async move {
+        while let Some(bytes) = queued.recv().await {
+            if let Err(error) = writer.write_all(&bytes).await {
+                log::debug!("{}: unable to write: {:?}", writer_log_name, error);
+                break;
+            }
+            unsent.fetch_sub(bytes.len(), Ordering::Relaxed);
+        }
+    });
+    let mut session = Session::new(connection);
+    loop {
+        let readable = match session.idle_deadline(&config) {
+            Some(deadline) => match time::timeout_at(deadline.into(), reader.readable()).await {
+                Ok(readable) => readable,
+                Err(_) => {
+                    log::info!("{}: closing after being idle for {:?}", log_name, session.idle_time());
+                    break;
+                }
+            },
+            None => reader.readable().await,
+        };
+        if let Err(error) = readable {
+            log::error!("{}: system error: {:?}", log_name, error);
+            break;
+        }
+        match session.read_from(|buffer| reader.try_read(buffer)) {
+            Ok(0) => break, // the connection was closed
+            Ok(_) => {
+                if !session.execute_requests(&index, &databases, &config)
+                    || !run_long_running_commands(&mut session, &index, &databases, &config).await
+                {
+                    break;
+                }
+            }
+            Err(error) if matches!(error.kind(), ErrorKind::WouldBlock | ErrorKind::Interrupted) => {}
+            Err(error) => {
+                log::error!("{}: system error: {:?}", log_name, error);
+                break;
+            }
+        }
+    }
+    // the writer finishes once everything queued for the client has been sent, then closes
+    // the connection
+    drop(session);
+    let _ = writes.await;
+    log::debug!("{}: closed", log_name);
+    drop(registration);
+}
+
+// Runs the long-running command the client's requests stopped at, off the runtime's threads,
+// then carries on with the requests after it. Returns false once nothing more should be read
+// from the client, as execute_requests does.
+async fn run_long_running_commands(
+    session: &mut Session,
+    index: &Arc<Index>,
+    databases: &Arc<Databases>,
+    config: &Config,
+) -> bool {
+    while let Some(request) = session.take_long_running() {
+        let job_request = request.clone();
+        let result = task::spawn_blocking(move || run_long_running(&job_request))
+            .await
+            .unwrap_or_else(|_| Err(ExecutionError::new("the command failed unexpectedly")));
+        session.finish_long_running(&request, result, databases);
+        if !session.execute_requests(index, databases, config) {
+            return false;
+        }
+    }
+    true
+}
+
+// Hands whatever is written to the client's writer task, counting what it hasn't sent yet
+// towards the client's output buffer limit
+struct QueuedOutput {
+    sender: UnboundedSender<Bytes>,
+    unsent: Arc<AtomicUsize>,
+    socket: net::TcpStream,
+}
+
+impl Write for QueuedOutput {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        // counted first, so the writer task can never take away more than has been added
+        self.unsent.fetch_add(bytes.len(), Ordering::Relaxed);
+        if self.sender.send(Bytes::copy_from_slice(bytes)).is_err() {
+            self.unsent.fetch_sub(bytes.len(), Ordering::Relaxed);
+            return Err(ErrorKind::BrokenPipe.into());
+        }
+        Ok(bytes.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl ClientOutput for QueuedOutput {
+    fn unsent(&self) -> usize {
+        self.unsent.load(Ordering::Relaxed)
+    }
+
+    // the reader sees the connection end, and the writer task stops once its next write fails
+    fn disconnect(&self) {
+        self.socket.disconnect();
+    }
+}
@@ -0,0 +1,634 @@
+// State belonging to a single client connection, and the commands that act on it
+// rather than on the data (HELLO, pub/sub)
+
+use crate::commands::{syntax_error, text_argument, wrong_number_of_arguments, CommandName, ExecutionError};
+use crate::config::{Config, OutputBufferLimit};
+use crate::pubsub::PubSub;
+use crate::resp::{self, Protocol, Value};
+use bytes::{Buf, Bytes, BytesMut};
+use std::collections::HashSet;
+use std::io;
+use std::io::{ErrorKind, Write};
+use std::net::{self, Shutdown, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+const REDIS_CONNECTION_COMMANDS: [&str; 8] =
+    ["HELLO", "PING", "QUIT", "SUBSCRIBE", "UNSUBSCRIBE", "PSUBSCRIBE", "PUNSUBSCRIBE", "PUBLISH"];
+
+// The only commands a RESP2 client may send while it has subscriptions
+const SUBSCRIBED_CONTEXT_COMMANDS: [&str; 7] =
+    ["SUBSCRIBE", "UNSUBSCRIBE", "PSUBSCRIBE", "PUNSUBSCRIBE", "PING", "QUIT", "RESET"];
+
+// Long keys are cut short in the command log
+const MAX_LOGGED_KEY_LEN: usize = 64;
+
+static NEXT_CLIENT_ID: AtomicU64 = AtomicU64::new(1);
+
+// Every connection gets the next id as it is accepted, so a later client always has a higher one
+pub(crate) fn next_client_id() -> u64 {
+    NEXT_CLIENT_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+// The command name and its key, if it has one, for the log. Values are left out, they can be
+// large and may be sensitive.
+pub(crate) fn command_summary(request: &[Bytes]) -> String {
+    let mut summary = String::from_utf8_lossy(&request[0]).to_uppercase();
+    if let Some(key) = request.get(1) {
+        summary.push(' ');
+        summary.push_str(&String::from_utf8_lossy(&key[..key.len().min(MAX_LOGGED_KEY_LEN)]));
+    }
+    summary
+}
+
+// The parts of a connection other connections can reach, e.g. to deliver pub/sub messages.
+// All writes to the client go through `output` so replies and messages never interleave.
+pub(crate) struct Client {
+    id: u64,
+    protocol: Mutex<Protocol>,
+    output: Mutex<Outbox>,
+}
+
+// Where a client's output goes: its socket, or whatever stands in for it
+pub(crate) trait ClientOutput: Write + Send {
+    // Bytes taken but not sent yet, by an output that queues them itself
+    fn unsent(&self) -> usize {
+        0
+    }
+
+    // Closes the connection, so its front end sees it end and lets it go
+    fn disconnect(&self) {}
+}
+
+impl ClientOutput for net::TcpStream {
+    fn disconnect(&self) {
+        let _ = self.shutdown(Shutdown::Both);
+    }
+}
+
+impl ClientOutput for io::Sink {}
+
+// Writing to a client never blocks: whatever its socket won't take yet is kept here, in order,
+// and sent by its event loop once the socket has room for it. A client that doesn't read what
+// it is sent can't make it grow without bound though, it is disconnected once over its limit.
+struct Outbox {
+    stream: Box<dyn ClientOutput>,
+    pending: BytesMut,
+    normal_limit: OutputBufferLimit,
+    pubsub_limit: OutputBufferLimit,
+    // subscribers have a limit of their own, as they are sent whatever is published
+    subscribed: bool,
+    // when the output went over the soft limit, if it hasn't been under it since
+    over_soft_limit_since: Option<Instant>,
+    // set once a limit is broken: nothing more is kept for the client, it is being closed
+    disconnected: bool,
+}
+
+impl Outbox {
+    fn append(&mut self, bytes: &[u8]) -> io::Result<()> {
+        if self.disconnected {
+            return Err(ErrorKind::ConnectionAborted.into());
+        }
+        self.pending.extend_from_slice(bytes);
+        Ok(())
+    }
+
+    // Checked whenever output is added, once as much as possible has been sent
+    fn check_limit(&mut self, client_id: u64) -> io::Result<()> {
+        if self.disconnected {
+            return Err(ErrorKind::ConnectionAborted.into());
+        }
+        let limit = if self.subscribed { self.pubsub_limit } else { self.normal_limit };
+        let waiting = self.pending.len() + self.stream.unsent();
+        let broken = if limit.hard > 0 && waiting > limit.hard {
+            Some("hard")
+        } else if limit.soft > 0 && waiting > limit.soft {
+            let since = *self.over_soft_limit_since.get_or_insert_with(Instant::now);
+            (since.elapsed() >= Duration::from_secs(limit.soft_seconds)).then_some("soft")
+        } else {
+            self.over_soft_limit_since = None;
+            None
+        };
+        let Some(broken) = broken else {
+            return Ok(());
+        };
+        log::warn!(
+            "client {}: disconnecting, {} bytes of output waiting is over its {} limit",
+            client_id,
+            waiting,
+            broken
+        );
+        self.disconnected = true;
+        self.pending = BytesMut::new();
+        self.stream.disconnect();
+        Err(ErrorKind::ConnectionAborted.into())
+    }
+
+    fn send_pending(&mut self) -> io::Result<()> {
+        while !self.pending.is_empty() {
+            match self.stream.write(&self.pending) {
+                Ok(0) => return Err(ErrorKind::WriteZero.into()),
+                Ok(size) => self.pending.advance(size),
+                Err(error) if error.kind() == ErrorKind::WouldBlock => return Ok(()),
+                Err(error) if error.kind() == ErrorKind::Interrupted => {}
+                Err(error) => return Err(error),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Write for Outbox {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        self.append(bytes)?;
+        self.send_pending()?;
+        Ok(bytes.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.send_pending()
+    }
+}
+
+impl Client {
+    pub fn new(id: u64, output: Box<dyn ClientOutput>, config: &Config) -> Client {
+        Client {
+            id,
+            protocol: Mutex::new(Protocol::default()),
+            output: Mutex::new(Outbox {
+                stream: output,
+                pending: BytesMut::new(),
+                normal_limit: config.client_output_buffer_limit_normal,
+                pubsub_limit: config.client_output_buffer_limit_pubsub,
+                subscribed: false,
+                over_soft_limit_since: None,
+                disconnected: false,
+            }),
+        }
+    }
+
+    pub fn get_id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn get_protocol(&self) -> Protocol {
+        *self.protocol.lock().unwrap()
+    }
+
+    pub fn set_protocol(&self, protocol: Protocol) {
+        *self.protocol.lock().unwrap() = protocol;
+    }
+
+    pub fn write(&self, bytes: &[u8]) -> io::Result<()> {
+        let mut output = self.output.lock().unwrap();
+        output.write_all(bytes)?;
+        output.flush()?;
+        output.check_limit(self.id)
+    }
+
+    // Adds to the output without sending it yet, so the replies to a batch of pipelined
+    // commands can all go in a single write. Anything the client can't be sent is dropped, and
+    // the error comes from the flush.
+    pub fn queue(&self, bytes: &[u8]) {
+        let _ = self.output.lock().unwrap().append(bytes);
+    }
+
+    // Sends whatever is queued, or what an earlier write couldn't send before the socket had room
+    pub fn flush(&self) -> io::Result<()> {
+        let mut output = self.output.lock().unwrap();
+        output.flush()?;
+        output.check_limit(self.id)
+    }
+
+    fn set_subscribed(&self, subscribed: bool) {
+        self.output.lock().unwrap().subscribed = subscribed;
+    }
+
+    pub fn has_pending_output(&self) -> bool {
+        !self.output.lock().unwrap().pending.is_empty()
+    }
+
+    fn lock_output(&self) -> MutexGuard<'_, Outbox> {
+        self.output.lock().unwrap()
+    }
+}
+
+pub(crate) struct ConnectionContext {
+    client: Arc<Client>,
+    pubsub: Arc<PubSub>,
+    channels: HashSet<String>,
+    patterns: HashSet<String>,
+    // set by QUIT: nothing more is read, and the connection closes once its replies are sent
+    closing: bool,
+    // starts every log line about this connection, so the lines for one client can be picked
+    // out from among the others, e.g. "client 7 127.0.0.1:52210"
+    log_name: String,
+}
+
+impl ConnectionContext {
+    pub fn new(client: Client, pubsub: Arc<PubSub>, peer: Option<SocketAddr>) -> ConnectionContext {
+        let log_name = match peer {
+            Some(peer) => format!("client {} {}", client.get_id(), peer),
+            None => format!("client {}", client.get_id()),
+        };
+        ConnectionContext {
+            client: Arc::new(client),
+            pubsub,
+            channels: HashSet::new(),
+            patterns: HashSet::new(),
+            closing: false,
+            log_name,
+        }
+    }
+
+    pub fn log_name(&self) -> &str {
+        &self.log_name
+    }
+
+    // One line per command at debug level: what ran, on which key, how long it took and whether
+    // it worked. Values are left out, they can be large and may be sensitive.
+    pub fn log_command(&self, request: &[Bytes], elapsed: Duration, result: &Result<Bytes, ExecutionError>) {
+        if !log::log_enabled!(log::Level::Debug) {
+            return;
+        }
+        let outcome = match result {
+            Ok(_) => "ok".to_string(),
+            Err(error) => format!("error: {}", error.get_message()),
+        };
+        log::debug!(
+            "{}: {} took {}us, {}",
+            self.log_name,
+            command_summary(request),
+            elapsed.as_micros(),
+            outcome
+        );
+    }
+
+    pub fn get_protocol(&self) -> Protocol {
+        self.client.get_protocol()
+    }
+
+    pub fn queue(&self, bytes: &[u8]) {
+        self.client.queue(bytes)
+    }
+
+    pub fn flush(&self) -> io::Result<()> {
+        self.client.flush()
+    }
+
+    // The async front end never leaves output behind, its writes are queued for a task instead
+
+    #[cfg_attr(feature = "async", allow(dead_code))]
+    pub fn has_pending_output(&self) -> bool {
+        self.client.has_pending_output()
+    }
+
+    pub fn is_command_supported(command: &[u8]) -> bool {
+        REDIS_CONNECTION_COMMANDS
+            .iter()
+            .any(|&cmd| cmd.as_bytes().eq_ignore_ascii_case(command))
+    }
+
+    // RESP2 connections with subscriptions can only manage those subscriptions,
+    // since their replies would be indistinguishable from published messages
+    pub fn check_command_allowed(&self, command: &[u8]) -> Result<(), ExecutionError> {
+        if self.get_protocol() == Protocol::Resp2
+            && self.subscription_count() > 0
+            && !SUBSCRIBED_CONTEXT_COMMANDS.iter().any(|&cmd| cmd.as_bytes().eq_ignore_ascii_case(command))
+        {
+            return Err(ExecutionError::new(&format!(
+                "Can't execute '{}': only (P)SUBSCRIBE / (P)UNSUBSCRIBE / PING / QUIT / RESET are allowed in this context",
+                String::from_utf8_lossy(command).to_lowercase()
+            )));
+        }
+        Ok(())
+    }
+
+    pub fn execute_command(&mut self, request: &[Bytes]) -> Result<Bytes, ExecutionError> {
+        match CommandName::new(&request[0]).as_str() {
+            "HELLO" => self.hello(request),
+            "PING" => self.ping(request),
+            "QUIT" => {
+                self.closing = true;
+                Ok(resp::ok())
+            }
+            "SUBSCRIBE" => self.subscribe(request, false),
+            "PSUBSCRIBE" => self.subscribe(request, true),
+            "UNSUBSCRIBE" => self.unsubscribe(request, false),
+            "PUNSUBSCRIBE" => self.unsubscribe(request, true),
+            "PUBLISH" => {
+                let receivers = self.pubsub.publish(text_argument(&request[1])?, &request[2]);
+                Ok(Value::Integer(receivers as i64).encode(self.get_protocol()))
+            }
+            _ => Err(ExecutionError::new("Unsupported connection command")),
+        }
+    }
+
+    fn hello(&mut self, request: &[Bytes]) -> Result<Bytes, ExecutionError> {
+        // support syntax: HELLO [protover]
+        if request.len() > 2 {
+            return Err(syntax_error().into());
+        }
+        if let Some(version) = request.get(1) {
+            let protocol = match String::from_utf8_lossy(version).parse::<i64>() {
+                Ok(2) => Protocol::Resp2,
+                Ok(3) => Protocol::Resp3,
+                Ok(_) => return Err(ExecutionError::NoProto),
+                Err(_) => {
+                    return Err(ExecutionError::new(
+                        "Protocol version is not an integer or out of range",
+                    ));
+                }
+            };
+            self.client.set_protocol(protocol);
+        }
+        let version = match self.get_protocol() {
+            Protocol::Resp2 => 2,
+            Protocol::Resp3 => 3,
+        };
+        let reply = Value::Map(vec![
+            (bulk("server"), bulk("redis")),
+            (bulk("version"), bulk(env!("CARGO_PKG_VERSION"))),
+            (bulk("proto"), Value::Integer(version)),
+            (bulk("mode"), bulk("standalone")),
+            (bulk("role"), bulk("master")),
+            (bulk("modules"), Value::Array(Vec::new())),
+        ]);
+        Ok(reply.encode(self.get_protocol()))
+    }
+
+    fn ping(&self, request: &[Bytes]) -> Result<Bytes, ExecutionError> {
+        // support syntax: PING [message]
+        if request.len() > 2 {
+            return Err(wrong_number_of_arguments(&request[0]).into());
+        }
+        // a RESP2 subscriber can only be sent arrays, as its replies share the stream with messages
+        if self.get_protocol() == Protocol::Resp2 && self.subscription_count() > 0 {
+            let message = request.get(1).cloned().unwrap_or_default();
+            return Ok(Value::Array(vec![bulk("pong"), Value::BulkString(message)]).encode(self.get_protocol()));
+        }
+        let reply = match request.get(1) {
+            Some(message) => Value::BulkString(message.clone()),
+            None => Value::SimpleString(Bytes::from_static(b"PONG")),
+        };
+        Ok(reply.encode(self.get_protocol()))
+    }
+
+    fn subscribe(&mut self, request: &[Bytes], pattern: bool) -> Result<Bytes, ExecutionError> {
+        // support syntax: SUBSCRIBE channel [channel ...]
+        //                 PSUBSCRIBE pattern [pattern ...]
+        let kind = if pattern { "psubscribe" } else { "subscribe" };
+        // Each confirmation is written while holding the client's output, so a message
+        // published the moment the subscription is registered can't overtake it
+        let client = Arc::clone(&self.client);
+        let mut output = client.lock_output();
+        output.subscribed = true;
+        for name in &request[1..] {
+            let name = text_argument(name)?;
+            if pattern {
+                self.pubsub.psubscribe(&self.client, name);
+                self.patterns.insert(name.to_string());
+            } else {
+                self.pubsub.subscribe(&self.client, name);
+                self.channels.insert(name.to_string());
+            }
+            let confirmation = self.subscription_frame(kind, Some(name));
+            output
+                .write_all(&confirmation)
+                .map_err(|_| ExecutionError::new("Unable to write to the client"))?;
+        }
+        output
+            .flush()
+            .and_then(|_| output.check_limit(client.get_id()))
+            .map_err(|_| ExecutionError::new("Unable to write to the client"))?;
+        Ok(Bytes::new())
+    }
+
+    fn unsubscribe(&mut self, request: &[Bytes], pattern: bool) -> Result<Bytes, ExecutionError> {
+        // support syntax: UNSUBSCRIBE [channel ...]
+        //                 PUNSUBSCRIBE [pattern ...]
+        // With no names, every subscription of that kind is removed
+        let kind = if pattern { "punsubscribe" } else { "unsubscribe" };
+        let names: Vec<String> = if request.len() > 1 {
+            request[1..]
+                .iter()
+                .map(|name| text_argument(name).map(str::to_string))
+                .collect::<Result<_, _>>()?
+        } else if pattern {
+            self.patterns.iter().cloned().collect()
+        } else {
+            self.channels.iter().cloned().collect()
+        };
+        if names.is_empty() {
+            return Ok(self.subscription_frame(kind, None));
+        }
+
+        let mut reply = Vec::new();
+        for name in names {
+            if pattern {
+                self.pubsub.punsubscribe(self.client.get_id(), &name);
+                self.patterns.remove(&name);
+            } else {
+                self.pubsub.unsubscribe(self.client.get_id(), &name);
+                self.channels.remove(&name);
+            }
+            reply.extend_from_slice(&self.subscription_frame(kind, Some(&name)));
+        }
+        self.client.set_subscribed(self.subscription_count() > 0);
+        Ok(Bytes::from(reply))
+    }
+
+    fn subscription_frame(&self, kind: &'static str, name: Option<&str>) -> Bytes {
+        let name = match name {
+            Some(name) => Value::BulkString(Bytes::copy_from_slice(name.as_bytes())),
+            None => Value::Null,
+        };
+        Value::Push(vec![bulk(kind), name, Value::Integer(self.subscription_count() as i64)])
+            .encode(self.get_protocol())
+    }
+
+    pub fn is_closing(&self) -> bool {
+        self.closing
+    }
+
+    // Like Redis, a subscribed client is waiting on other clients rather than idle
+    pub fn is_exempt_from_timeout(&self) -> bool {
+        self.subscription_count() > 0
+    }
+
+    fn subscription_count(&self) -> usize {
+        self.channels.len() + self.patterns.len()
+    }
+}
+
+impl Drop for ConnectionContext {
+    fn drop(&mut self) {
+        // a closed connection must not be left behind in the pub/sub registry
+        for channel in self.channels.iter() {
+            self.pubsub.unsubscribe(self.client.get_id(), channel);
+        }
+        for pattern in self.patterns.iter() {
+            self.pubsub.punsubscribe(self.client.get_id(), pattern);
+        }
+    }
+}
+
+fn bulk(value: &'static str) -> Value {
+    Value::BulkString(Bytes::from_static(value.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::request;
+
+    #[test]
+    fn given_hello_3_when_executed_then_protocol_switches_to_resp3() {
+        let mut connection = test_connection();
+        let reply = connection
+            .execute_command(&request(&["HELLO", "3"]))
+            .unwrap();
+        assert!(reply.starts_with(b"%6\r\n"));
+        assert_eq!(connection.get_protocol(), Protocol::Resp3);
+    }
+
+    #[test]
+    fn given_unsupported_version_when_hello_then_error_and_protocol_unchanged() {
+        let mut connection = test_connection();
+        let result = connection.execute_command(&request(&["HELLO", "4"]));
+        assert_eq!(result.unwrap_err(), ExecutionError::NoProto);
+        assert_eq!(connection.get_protocol(), Protocol::Resp2);
+    }
+
+    #[test]
+    fn given_resp2_subscriber_when_other_command_then_rejected() {
+        let mut connection = test_connection();
+        connection
+            .execute_command(&request(&["SUBSCRIBE", "news"]))
+            .unwrap();
+        assert!(connection.check_command_allowed(b"GET").is_err());
+        assert!(connection.check_command_allowed(b"unsubscribe").is_ok());
+
+        let reply = connection
+            .execute_command(&request(&["UNSUBSCRIBE"]))
+            .unwrap();
+        assert_eq!(reply, "*3\r\n$11\r\nunsubscribe\r\n$4\r\nnews\r\n:0\r\n");
+        assert!(connection.check_command_allowed(b"GET").is_ok());
+    }
+
+    #[test]
+    fn given_ping_when_executed_then_pong_or_message_echoed() {
+        let mut connection = test_connection();
+        assert_eq!(connection.execute_command(&request(&["PING"])).unwrap(), "+PONG\r\n");
+        assert_eq!(connection.execute_command(&request(&["ping", "hi"])).unwrap(), "$2\r\nhi\r\n");
+        assert!(connection.execute_command(&request(&["PING", "a", "b"])).is_err());
+
+        connection.execute_command(&request(&["SUBSCRIBE", "news"])).unwrap();
+        assert_eq!(
+            connection.execute_command(&request(&["PING"])).unwrap(),
+            "*2\r\n$4\r\npong\r\n$0\r\n\r\n"
+        );
+    }
+
+    // Takes at most `room` bytes, then would block until given more room
+    #[derive(Default)]
+    struct Throttled {
+        received: Arc<Mutex<Vec<u8>>>,
+        room: Arc<Mutex<usize>>,
+        disconnected: Arc<Mutex<bool>>,
+    }
+
+    impl Write for Throttled {
+        fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+            let mut room = self.room.lock().unwrap();
+            if *room == 0 {
+                return Err(ErrorKind::WouldBlock.into());
+            }
+            let size = bytes.len().min(*room);
+            *room -= size;
+            self.received.lock().unwrap().extend_from_slice(&bytes[..size]);
+            Ok(size)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl ClientOutput for Throttled {
+        fn disconnect(&self) {
+            *self.disconnected.lock().unwrap() = true;
+        }
+    }
+
+    fn limited(hard: usize, soft: usize, soft_seconds: u64) -> Config {
+        let limit = OutputBufferLimit { hard, soft, soft_seconds };
+        Config { client_output_buffer_limit_normal: limit, ..Config::default() }
+    }
+
+    #[test]
+    fn given_full_socket_when_written_then_rest_kept_until_flushed() {
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let room = Arc::new(Mutex::new(3));
+        let output = Throttled {
+            received: Arc::clone(&received),
+            room: Arc::clone(&room),
+            ..Throttled::default()
+        };
+        let client = Client::new(next_client_id(), Box::new(output), &Config::default());
+
+        client.write(b"+OK\r\n").unwrap();
+        client.write(b":1\r\n").unwrap();
+        assert_eq!(*received.lock().unwrap(), b"+OK");
+        assert!(client.has_pending_output());
+
+        *room.lock().unwrap() = 100;
+        client.flush().unwrap();
+        assert_eq!(*received.lock().unwrap(), b"+OK\r\n:1\r\n");
+        assert!(!client.has_pending_output());
+
+        // queued replies wait for the flush
+        client.queue(b"+a\r\n");
+        client.queue(b"+b\r\n");
+        assert_eq!(received.lock().unwrap().len(), 9);
+        client.flush().unwrap();
+        assert_eq!(*received.lock().unwrap(), b"+OK\r\n:1\r\n+a\r\n+b\r\n");
+    }
+
+    #[test]
+    fn given_client_not_reading_when_output_over_hard_limit_then_disconnected_and_output_dropped() {
+        let output = Throttled::default();
+        let disconnected = Arc::clone(&output.disconnected);
+        let client = Client::new(next_client_id(), Box::new(output), &limited(10, 0, 0));
+
+        client.write(b"+12345\r\n").unwrap();
+        assert!(!*disconnected.lock().unwrap());
+        client.queue(b"+67\r\n");
+        assert!(client.flush().is_err());
+        assert!(*disconnected.lock().unwrap());
+        assert!(!client.has_pending_output());
+        // nothing more is kept for it
+        assert!(client.write(b"+8\r\n").is_err());
+        assert!(!client.has_pending_output());
+    }
+
+    #[test]
+    fn given_output_over_soft_limit_when_not_for_long_enough_then_kept() {
+        let output = Throttled::default();
+        let disconnected = Arc::clone(&output.disconnected);
+        let client = Client::new(next_client_id(), Box::new(output), &limited(0, 4, 60));
+        client.write(b"+12345\r\n").unwrap();
+        assert!(!*disconnected.lock().unwrap());
+
+        let output = Throttled::default();
+        let disconnected = Arc::clone(&output.disconnected);
+        let client = Client::new(next_client_id(), Box::new(output), &limited(0, 4, 0));
+        assert!(client.write(b"+12345\r\n").is_err());
+        assert!(*disconnected.lock().unwrap());
+    }
+
+    fn test_connection() -> ConnectionContext {
+        let client = Client::new(next_client_id(), Box::new(io::sink()), &Config::default());
+        ConnectionContext::new(client, Arc::new(PubSub::new()), None)
+    }
+}
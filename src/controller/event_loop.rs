@@ -0,0 +1,539 @@
+// Readiness-based connection handling: each event loop owns a share of the client sockets and
+// only touches one when it has data to read or room for a reply, so an idle client costs
+// nothing but its socket. Commands run inline on the loop as soon as they are complete.
+
+use crate::commands::ExecutionError;
+use crate::config::Config;
+use crate::controller::connection::{Client, ConnectionContext};
+use crate::controller::session::Session;
+use crate::controller::shutdown::{OpenConnections, Registration, ShutdownSignal};
+use crate::controller::{
+    accept_failed, configure_socket, format_error, panic_message, run_long_running, AcceptFailure, Databases, SpareDescriptor, ACCEPT_RETRY_DELAY,
+};
+use crate::index::Index;
+use crate::info;
+use crate::resp::Value;
+use crate::thread_pool::{ThreadPool, ThreadPoolOptions};
+use bytes::Bytes;
+use mio::event::Event;
+use mio::net::TcpStream;
+use mio::{Events, Interest, Poll, Token, Waker};
+use std::collections::HashMap;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{self, Shutdown, TcpListener};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Arc;
+use std::thread;
+use std::time::Instant;
+
+// Wakes the loop when a client is handed to it, when a long-running command has finished, or
+// when it is asked to stop
+const WAKER: Token = Token(0);
+
+// A long-running command run for a client, with its result
+type FinishedCommand = (Token, Vec<Bytes>, Result<Value, ExecutionError>);
+
+// Accepts clients on every listener until shutdown is requested, then closes them all and
+// waits for the event loops to finish with them before returning
+pub(crate) fn run(
+    listeners: Vec<TcpListener>,
+    connections: &Arc<OpenConnections>,
+    index_db: &Arc<Index>,
+    databases: &Arc<Databases>,
+    config: &Arc<Config>,
+    shutdown: &ShutdownSignal,
+) {
+    let options = ThreadPoolOptions { queue_capacity: config.thread_pool_queue_size, ..ThreadPoolOptions::default() };
+    let pool = ThreadPool::with_options(config.thread_pool_size, options);
+    let _ = databases.thread_pool.set(pool.monitor());
+    // kept apart, so a client waiting on a long-running command doesn't hold up the rest
+    let options = ThreadPoolOptions { thread_name: "redis-blocking", ..ThreadPoolOptions::default() };
+    let long_running = Arc::new(ThreadPool::with_options(config.blocking_thread_pool_size, options));
+
+    // An event loop for each worker in the pool, sharing the clients between them
+    let event_loops = match (0..config.thread_pool_size)
+        .map(|_| {
+            EventLoop::new(Arc::clone(index_db), Arc::clone(databases), Arc::clone(config), Arc::clone(&long_running))
+        })
+        .collect::<io::Result<Vec<_>>>()
+    {
+        Ok(event_loops) => event_loops,
+        Err(error) => {
+            log::error!("Unable to start the event loops: {:?}", error);
+            return;
+        }
+    };
+    let mut handles = Vec::with_capacity(event_loops.len());
+    for (event_loop, handle) in event_loops {
+        pool.execute(move || event_loop.run());
+        handles.push(handle);
+    }
+
+    // One accept loop per address, all handing their clients to the same event loops
+    thread::scope(|scope| {
+        for listener in listeners {
+            let handles = &handles;
+            scope.spawn(move || accept_clients(listener, shutdown, connections, handles, config));
+        }
+    });
+
+    // there is no persistence yet, so nothing needs saving before the clients are closed
+    connections.close_all();
+    for handle in &handles {
+        handle.stop();
+    }
+    // waits for each event loop to finish with its clients, then for any long-running
+    // commands still going
+    drop(pool);
+    drop(long_running);
+}
+
+// Where an accept loop gets its clients from: a listener, or in tests one that fails on cue
+trait Acceptor {
+    fn accept(&self) -> io::Result<net::TcpStream>;
+}
+
+impl Acceptor for TcpListener {
+    fn accept(&self) -> io::Result<net::TcpStream> {
+        TcpListener::accept(self).map(|(stream, _)| stream)
+    }
+}
+
+// Returning drops the listener, so its port is released straight away rather than once every
+// client has gone. Nothing but shutdown ends the loop: a failed accept is logged and the next
+// client is waited for.
+fn accept_clients(
+    listener: impl Acceptor,
+    shutdown: &ShutdownSignal,
+    connections: &Arc<OpenConnections>,
+    event_loops: &[EventLoopHandle],
+    config: &Config,
+) {
+    let mut spare = SpareDescriptor::new();
+    // checked before accepting too, in case a client turned away was the one made to wake the loop
+    while !shutdown.is_requested() {
+        let accepted = listener.accept();
+        if shutdown.is_requested() {
+            break; // this is the connection made to wake the loop
+        }
+        let mut stream = match accepted {
+            Ok(stream) => stream,
+            Err(error) => {
+                match accept_failed(&error) {
+                    AcceptFailure::Retry => {}
+                    AcceptFailure::RetryAfterDelay => thread::sleep(ACCEPT_RETRY_DELAY),
+                    AcceptFailure::OutOfDescriptors => {
+                        if spare.release() {
+                            drop(listener.accept());
+                        }
+                        spare.restore();
+                        thread::sleep(ACCEPT_RETRY_DELAY);
+                    }
+                }
+                continue;
+            }
+        };
+        let Some(registration) = connections.register(&stream, config.maxclients) else {
+            // turned away here rather than handed to an event loop, so it costs nothing to refuse
+            log::warn!("Rejecting a client, {} are already connected", connections.count());
+            let _ = stream.write_all(&format_error("max number of clients reached"));
+            continue;
+        };
+        if let Err(error) = configure_socket(&stream, config) {
+            log::warn!("Unable to set the client's socket options: {:?}", error);
+        }
+        // connection ids are handed out in turn, so the clients are spread evenly
+        let event_loop = &event_loops[registration.id() as usize % event_loops.len()];
+        event_loop.add_client(stream, registration);
+    }
+}
+
+// How the accept loops reach an event loop running on another thread
+#[derive(Clone)]
+pub(crate) struct EventLoopHandle {
+    new_clients: Sender<(net::TcpStream, Registration)>,
+    waker: Arc<Waker>,
+    stopping: Arc<AtomicBool>,
+}
+
+impl EventLoopHandle {
+    pub fn add_client(&self, stream: net::TcpStream, registration: Registration) {
+        if self.new_clients.send((stream, registration)).is_ok()
+            && let Err(error) = self.waker.wake()
+        {
+            log::error!("Unable to wake an event loop: {:?}", error);
+        }
+    }
+
+    // The loop finishes once every client it has is gone, so their read sides should be
+    // closed first
+    pub fn stop(&self) {
+        self.stopping.store(true, Ordering::SeqCst);
+        if let Err(error) = self.waker.wake() {
+            log::error!("Unable to wake an event loop: {:?}", error);
+        }
+    }
+}
+
+pub(crate) struct EventLoop {
+    poll: Poll,
+    new_clients: Receiver<(net::TcpStream, Registration)>,
+    stopping: Arc<AtomicBool>,
+    waker: Arc<Waker>,
+    // long-running commands run here, their results coming back through `finished`
+    long_running: Arc<ThreadPool>,
+    finished_sender: Sender<FinishedCommand>,
+    finished: Receiver<FinishedCommand>,
+    clients: HashMap<Token, ClientConnection>,
+    next_token: usize,
+    index: Arc<Index>,
+    databases: Arc<Databases>,
+    config: Arc<Config>,
+}
+
+impl EventLoop {
+    pub fn new(
+        index: Arc<Index>,
+        databases: Arc<Databases>,
+        config: Arc<Config>,
+        long_running: Arc<ThreadPool>,
+    ) -> io::Result<(EventLoop, EventLoopHandle)> {
+        let poll = Poll::new()?;
+        let waker = Arc::new(Waker::new(poll.registry(), WAKER)?);
+        let (sender, receiver) = mpsc::channel();
+        let (finished_sender, finished) = mpsc::channel();
+        let stopping = Arc::new(AtomicBool::new(false));
+        let handle = EventLoopHandle {
+            new_clients: sender,
+            waker: Arc::clone(&waker),
+            stopping: Arc::clone(&stopping),
+        };
+        let event_loop = EventLoop {
+            poll,
+            new_clients: receiver,
+            stopping,
+            waker,
+            long_running,
+            finished_sender,
+            finished,
+            clients: HashMap::new(),
+            next_token: WAKER.0 + 1,
+            index,
+            databases,
+            config,
+        };
+        Ok((event_loop, handle))
+    }
+
+    pub fn run(mut self) {
+        let mut events = Events::with_capacity(1024);
+        loop {
+            if self.stopping.load(Ordering::SeqCst) {
+                self.add_new_clients();
+                if self.clients.is_empty() {
+                    return;
+                }
+            }
+            // wake for the first client to reach the idle timeout, even if nothing happens
+            let timeout = self
+                .next_idle_deadline()
+                .map(|deadline| deadline.saturating_duration_since(Instant::now()));
+            if let Err(error) = self.poll.poll(&mut events, timeout) {
+                if error.kind() == ErrorKind::Interrupted {
+                    continue;
+                }
+                log::error!("Event loop failed, closing its clients: {:?}", error);
+                return;
+            }
+            for event in events.iter() {
+                match event.token() {
+                    WAKER => {
+                        self.add_new_clients();
+                        self.finish_long_running();
+                    }
+                    token => self.on_ready(token, event),
+                }
+            }
+            self.close_idle_clients();
+        }
+    }
+
+    fn add_new_clients(&mut self) {
+        while let Ok((stream, registration)) = self.new_clients.try_recv() {
+            if let Err(error) = self.add_client(stream, registration) {
+                log::error!("Unable to set up the connection: {:?}", error);
+            }
+        }
+    }
+
+    fn add_client(&mut self, stream: net::TcpStream, registration: Registration) -> io::Result<()> {
+        stream.set_nonblocking(true)?;
+        let peer = stream.peer_addr().ok();
+        let output = stream.try_clone()?;
+        let mut stream = TcpStream::from_std(stream);
+        let token = Token(self.next_token);
+        self.next_token += 1;
+        // edge-triggered: a socket that is already readable is reported straight away
+        self.poll
+            .registry()
+            .register(&mut stream, token, Interest::READABLE | Interest::WRITABLE)?;
+        let client = Client::new(registration.id(), Box::new(output), &self.config);
+        let connection = ConnectionContext::new(client, Arc::clone(&self.databases.pubsub), peer);
+        log::info!("{}: connected", connection.log_name());
+        self.clients.insert(
+            token,
+            ClientConnection {
+                stream,
+                session: Session::new(connection),
+                finished_reading: false,
+                _registration: registration,
+            },
+        );
+        Ok(())
+    }
+
+    fn on_ready(&mut self, token: Token, event: &Event) {
+        let Some(client) = self.clients.get_mut(&token) else {
+            return; // already closed
+        };
+        let mut open = true;
+        if event.is_readable() || event.is_read_closed() || event.is_error() {
+            let (index, databases, config) = (&self.index, &self.databases, &self.config);
+            open = run_guarded(client, |client| client.read_requests(index, databases, config));
+        }
+        if open && (event.is_writable() || event.is_write_closed() || event.is_error()) {
+            // pub/sub messages are written from other threads, so this flushes theirs too
+            if let Err(error) = client.session.connection().flush() {
+                log::debug!("{}: unable to write: {:?}", client.session.connection().log_name(), error);
+                open = false;
+            }
+        }
+        self.settle(token, open);
+    }
+
+    // Hands over the long-running command the client's requests stopped at, if any, then
+    // closes the client if it is done with
+    fn settle(&mut self, token: Token, mut open: bool) {
+        while open {
+            let Some(client) = self.clients.get_mut(&token) else {
+                return;
+            };
+            let Some(request) = client.session.take_long_running() else {
+                break;
+            };
+            let (sender, waker) = (self.finished_sender.clone(), Arc::clone(&self.waker));
+            let job_request = request.clone();
+            let submitted = self.long_running.try_execute(move || {
+                let result = run_long_running(&job_request);
+                if sender.send((token, job_request, result)).is_ok()
+                    && let Err(error) = waker.wake()
+                {
+                    log::error!("Unable to wake an event loop: {:?}", error);
+                }
+            });
+            if submitted.is_ok() {
+                break;
+            }
+            // turned away rather than left waiting behind everything queued
+            let busy = Err(ExecutionError::Busy);
+            let (index, databases, config) = (&self.index, &self.databases, &self.config);
+            open = run_guarded(client, |client| client.resume(&request, busy, index, databases, config));
+        }
+        let Some(client) = self.clients.get(&token) else {
+            return;
+        };
+        // once the client has stopped sending, wait only for what it is still owed
+        let session = &client.session;
+        let owed = (session.connection().has_pending_output() || session.is_waiting_on_long_running())
+            && !self.stopping.load(Ordering::SeqCst);
+        if !open || (client.finished_reading && !owed) {
+            self.close(token);
+        }
+    }
+
+    // Replies to the long-running commands that have finished, and runs what their clients
+    // sent after them
+    fn finish_long_running(&mut self) {
+        while let Ok((token, request, result)) = self.finished.try_recv() {
+            let Some(client) = self.clients.get_mut(&token) else {
+                continue; // it went while waiting
+            };
+            let (index, databases, config) = (&self.index, &self.databases, &self.config);
+            let open = run_guarded(client, |client| client.resume(&request, result, index, databases, config));
+            self.settle(token, open);
+        }
+    }
+
+    fn next_idle_deadline(&self) -> Option<Instant> {
+        self.clients
+            .values()
+            .filter_map(|client| client.session.idle_deadline(&self.config))
+            .min()
+    }
+
+    fn close_idle_clients(&mut self) {
+        let now = Instant::now();
+        let idle: Vec<Token> = self
+            .clients
+            .iter()
+            .filter(|(_, client)| client.session.idle_deadline(&self.config).is_some_and(|deadline| deadline <= now))
+            .map(|(token, _)| *token)
+            .collect();
+        for token in idle {
+            let session = &self.clients[&token].session;
+            log::info!("{}: closing after being idle for {:?}", session.connection().log_name(), session.idle_time());
+            self.close(token);
+        }
+    }
+
+    fn close(&mut self, token: Token) {
+        if let Some(mut client) = self.clients.remove(&token) {
+            let _ = self.poll.registry().deregister(&mut client.stream);
+            // the client's output holds another handle on the socket, so dropping this one
+            // alone wouldn't close it
+            let _ = client.stream.shutdown(Shutdown::Both);
+            log::debug!("{}: closed", client.session.connection().log_name());
+        }
+    }
+}
+
+struct ClientConnection {
+    stream: TcpStream,
+    session: Session,
+    // the client closed its end, sent QUIT or broke the protocol: nothing more is read from it
+    finished_reading: bool,
+    // keeps the client counted until it is gone
+    _registration: Registration,
+}
+
+impl ClientConnection {
+    // Reads until the socket has nothing more, running each request as soon as it is complete.
+    // Returns false if the connection should be closed straight away.
+    fn read_requests(&mut self, index: &Arc<Index>, databases: &Arc<Databases>, config: &Config) -> bool {
+        while !self.finished_reading {
+            match self.session.read_from(|buffer| self.stream.read(buffer)) {
+                Ok(0) => self.finished_reading = true,
+                Ok(_) => {
+                    // it is still owed whatever couldn't be sent yet, so it is closed once that
+                    // has gone rather than straight away
+                    if !self.session.execute_requests(index, databases, config) {
+                        self.finished_reading = true;
+                    }
+                }
+                Err(error) if error.kind() == ErrorKind::WouldBlock => return true,
+                Err(error) if error.kind() == ErrorKind::Interrupted => {}
+                Err(error) => {
+                    log::error!("{}: system error: {:?}", self.session.connection().log_name(), error);
+                    return false;
+                }
+            }
+        }
+        true
+    }
+
+    // Replies to the long-running command the client was waiting on, then carries on with the
+    // requests it has sent since
+    fn resume(
+        &mut self,
+        request: &[Bytes],
+        result: Result<Value, ExecutionError>,
+        index: &Arc<Index>,
+        databases: &Arc<Databases>,
+        config: &Config,
+    ) -> bool {
+        self.session.finish_long_running(request, result, databases);
+        if !self.session.execute_requests(index, databases, config) {
+            self.finished_reading = true;
+        }
+        true
+    }
+}
+
+// A command that panics closes its own client, rather than the loop and every client on it
+fn run_guarded(client: &mut ClientConnection, run: impl FnOnce(&mut ClientConnection) -> bool) -> bool {
+    match panic::catch_unwind(AssertUnwindSafe(|| run(&mut *client))) {
+        Ok(open) => open,
+        Err(payload) => {
+            log::error!(
+                "{}: panicked running a command, closing: {}",
+                client.session.connection().log_name(),
+                panic_message(payload.as_ref())
+            );
+            info::record_recovered_panic();
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::controller::{Server, EMFILE};
+    use std::collections::VecDeque;
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    // Fails with each of its errors in turn, then accepts for real
+    struct FlakyAcceptor {
+        listener: TcpListener,
+        failures: Mutex<VecDeque<io::Error>>,
+    }
+
+    impl Acceptor for FlakyAcceptor {
+        fn accept(&self) -> io::Result<net::TcpStream> {
+            match self.failures.lock().unwrap().pop_front() {
+                Some(error) => Err(error),
+                None => Acceptor::accept(&self.listener),
+            }
+        }
+    }
+
+    #[test]
+    fn given_accept_errors_when_accepting_then_later_clients_still_served() {
+        let mut server = Server::bind(Config { host: "127.0.0.1".to_string(), port: 0, ..Config::default() }).unwrap();
+        let address = server.local_addr();
+        let acceptor = FlakyAcceptor {
+            listener: server.listeners.pop().unwrap(),
+            failures: Mutex::new(VecDeque::from([
+                io::Error::from(ErrorKind::ConnectionAborted),
+                io::Error::other("unexpected"),
+                io::Error::from_raw_os_error(EMFILE),
+            ])),
+        };
+        let long_running = Arc::new(ThreadPool::with_options(1, ThreadPoolOptions::default()));
+        let (event_loop, handle) =
+            EventLoop::new(Arc::clone(&server.index), Arc::clone(&server.databases), Arc::clone(&server.config), long_running)
+                .unwrap();
+        let event_loop = thread::spawn(move || event_loop.run());
+        let connections = Arc::new(OpenConnections::new());
+
+        thread::scope(|scope| {
+            let accepting = scope.spawn(|| {
+                accept_clients(acceptor, &server.shutdown, &connections, std::slice::from_ref(&handle), &server.config)
+            });
+
+            // the client waiting when the descriptors ran out is closed, rather than left hanging
+            let mut turned_away = net::TcpStream::connect(address).unwrap();
+            turned_away.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+            assert_eq!(turned_away.read(&mut [0u8; 16]).unwrap(), 0);
+
+            for _ in 0..3 {
+                let mut client = net::TcpStream::connect(address).unwrap();
+                client.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+                client.write_all(b"*1\r\n$4\r\nPING\r\n").unwrap();
+                let mut reply = [0u8; 7];
+                client.read_exact(&mut reply).unwrap();
+                assert_eq!(&reply, b"+PONG\r\n");
+            }
+
+            // only shutdown ends the loop
+            server.shutdown.request();
+            accepting.join().unwrap();
+        });
+        connections.close_all();
+        handle.stop();
+        event_loop.join().unwrap();
+    }
+}
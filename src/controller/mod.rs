@@ -1,127 +1,1285 @@
+#[cfg(feature = "async")]
+pub(crate) mod async_server;
+pub(crate) mod connection;
+#[cfg(not(feature = "async"))]
+pub(crate) mod event_loop;
+pub(crate) mod session;
+pub(crate) mod shutdown;
+pub(crate) mod slow_commands;
+
+use crate::commands::table;
 use crate::commands::{ExecutionError, ParserError};
+use crate::config::{self, Config, SizeLimits};
+use crate::controller::connection::ConnectionContext;
+use crate::controller::shutdown::{OpenConnections, ShutdownSignal};
+use crate::controller::slow_commands::SlowCommands;
+use crate::pubsub::PubSub;
 use crate::index::Index;
+use crate::debug;
+use crate::executor::Executors;
+use crate::expiration::Sweeper;
+use crate::hash_executor::HashExecutor;
+use crate::info;
+use crate::list_executor::ListExecutor;
+use crate::memory::{self, Eviction, Usage};
+use crate::resp::{self, Value};
 use crate::string_executor::StringExecutor;
-use crate::thread_pool::ThreadPool;
-use crate::tokenizer;
-use app_properties::AppProperties;
+use crate::thread_pool::PoolMonitor;
+use crate::tokenizer::ParsedRequest;
+use bytes::Bytes;
+use socket2::{Domain, Protocol, SockRef, Socket, TcpKeepalive, Type};
 use std::{
-    io,
-    io::prelude::*,
-    net::{TcpListener, TcpStream},
-    sync::Arc,
+    fs::File,
+    io::{self, ErrorKind},
+    net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    sync::{Arc, OnceLock},
+    thread,
+    time::Duration,
 };
-use crate::list_executor::ListExecutor;
 
-const HOME: &'static str = "127.0.0.1";
-const DEFAULT_PORT: u16 = 6379;
-const DEFAULT_THREAD_POOL_SIZE: usize = 4;
+// The same as Redis's default tcp-backlog
+const LISTEN_BACKLOG: i32 = 511;
+const BIND_RETRY_DELAY: Duration = Duration::from_millis(100);
+const MAX_BIND_RETRY_DELAY: Duration = Duration::from_secs(5);
+// How long an accept loop waits after an error that could simply happen again straight away
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(10);
+// errno for running out of file descriptors, in the process and in the whole system; the same
+// on Linux, macOS and the BSDs
+const EMFILE: i32 = 24;
+const ENFILE: i32 = 23;
 
 pub struct Databases {
+    // the executor of each type of key, which the index passes their commands to
+    pub(crate) executors: Executors,
     pub string: Arc<StringExecutor>,
-    pub list: Arc<ListExecutor>
+    pub list: Arc<ListExecutor>,
+    pub hash: Arc<HashExecutor>,
+    pub pubsub: Arc<PubSub>,
+    pub slow_commands: SlowCommands,
+    pub limits: SizeLimits,
+    pub eviction: Eviction,
+    // set once the threads serving clients have started, if they are a pool of our own
+    pub thread_pool: OnceLock<PoolMonitor>,
 }
 
-pub fn initialize_controller() {
-    let properties = AppProperties::new();
-    let mut server_address = properties.get("server.host");
-    let server_port = properties
-        .get("server.port")
-        .parse::<u16>()
-        .unwrap_or(DEFAULT_PORT);
-    let thread_pool_size = properties
-        .get("thread.pool.size")
-        .parse::<usize>()
-        .unwrap_or(DEFAULT_THREAD_POOL_SIZE);
-    if server_address.is_empty() {
-        server_address = HOME;
+impl Databases {
+    pub fn new(config: &Config) -> Databases {
+        let string = Arc::new(StringExecutor::new());
+        let list = Arc::new(ListExecutor::new());
+        let hash = Arc::new(HashExecutor::new());
+        Databases {
+            executors: Executors::new(vec![string.clone(), list.clone(), hash.clone()]),
+            string,
+            list,
+            hash,
+            pubsub: Arc::new(PubSub::new()),
+            slow_commands: SlowCommands::new(config),
+            limits: SizeLimits::new(config),
+            eviction: Eviction::new(config),
+            thread_pool: OnceLock::new(),
+        }
+    }
+
+    // What the keys take, as far as maxmemory is concerned
+    pub fn used_memory(&self) -> usize {
+        self.memory_usage().total()
     }
-    log::info!("Starting server at {}:{}", server_address, server_port);
 
-    let listener = TcpListener::bind((server_address, server_port)).unwrap();
-    let pool = ThreadPool::new(thread_pool_size);
+    pub fn memory_usage(&self) -> Usage {
+        Usage {
+            strings: self.string.used_memory(),
+            lists: self.list.used_memory(),
+            hashes: self.hash.used_memory(),
+            maxmemory: self.eviction.maxmemory,
+            policy: self.eviction.policy,
+        }
+    }
 
+    // For tests: the counts kept as values come and go match a count of everything held
+    #[cfg(test)]
+    pub fn assert_memory_accounted(&self) {
+        assert_eq!(self.string.used_memory(), self.string.recount_memory(), "strings");
+        assert_eq!(self.list.used_memory(), self.list.recount_memory(), "lists");
+        assert_eq!(self.hash.used_memory(), self.hash.recount_memory(), "hashes");
+    }
+}
+
+// A server with its listeners bound, so the addresses it can be reached at (including the port
+// the OS picked, when configured with port 0) are known before it starts accepting clients.
+// It owns the data too, which starts out empty.
+pub struct Server {
+    listeners: Vec<TcpListener>,
+    addresses: Vec<SocketAddr>,
+    config: Arc<Config>,
+    shutdown: ShutdownSignal,
     // The set of all the keys in the database, with the data type
-    let index_db = Arc::new(Index::new());
+    index: Arc<Index>,
+    databases: Arc<Databases>,
+}
 
-    let databases = Arc::new(Databases {
-        string: Arc::new(StringExecutor::new()),
-        list: Arc::new(ListExecutor::new()),
-    });
+impl Server {
+    pub fn bind(config: Config) -> io::Result<Server> {
+        let listeners = bind_listeners(&config)?;
+        let addresses = listeners
+            .iter()
+            .map(|listener| listener.local_addr())
+            .collect::<io::Result<Vec<SocketAddr>>>()?;
+        let shutdown = ShutdownSignal::new(&listeners)?;
+        let index = Index::with_shards(config.keyspace_shards);
+        let databases = Databases::new(&config);
+        Ok(Server {
+            listeners,
+            addresses,
+            config: Arc::new(config),
+            shutdown,
+            index: Arc::new(index),
+            databases: Arc::new(databases),
+        })
+    }
 
-    for stream in listener.incoming() {
-        let stream = stream.unwrap();
-        let databases = Arc::clone(&databases);
-        let index_db = Arc::clone(&index_db);
+    // The first address listened on, which is the only one unless several hosts were configured
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addresses[0]
+    }
 
-        pool.execute(move || {
-            handle_connection(stream, &index_db, &databases);
-        });
+    pub fn local_addrs(&self) -> &[SocketAddr] {
+        &self.addresses
+    }
+
+    // For stopping the server once it is running, from another thread
+    pub fn shutdown_signal(&self) -> ShutdownSignal {
+        self.shutdown.clone()
+    }
+
+    // Runs a request straight against the server's data, without a client, returning the reply
+    // a client would have been sent. Only the keyspace commands can be run this way, since the
+    // others need a connection, and the reply is in RESP2, as a client that hasn't sent HELLO gets.
+    pub fn execute(&self, request: &[Bytes]) -> Bytes {
+        match self.index.execute_command(&self.databases, request) {
+            Ok(reply) => reply.encode(resp::Protocol::Resp2),
+            Err(error) => format_execution_error(&error),
+        }
+    }
+
+    // Serves clients until shutdown is requested
+    pub fn run(self) {
+        serve(self.listeners, &self.config, &self.index, &self.databases, &self.shutdown);
+    }
+
+    // Serves clients on a thread of its own, until the returned handle is used to stop it
+    pub fn spawn(self) -> io::Result<ServerHandle> {
+        let addresses = self.addresses.clone();
+        let shutdown = self.shutdown.clone();
+        let thread = thread::Builder::new()
+            .name("redis-server".to_string())
+            .spawn(move || self.run())?;
+        Ok(ServerHandle { addresses, shutdown, thread })
+    }
+}
+
+// A server running on its own thread. Dropping the handle leaves it running, like dropping a
+// thread's JoinHandle.
+pub struct ServerHandle {
+    addresses: Vec<SocketAddr>,
+    shutdown: ShutdownSignal,
+    thread: thread::JoinHandle<()>,
+}
+
+impl ServerHandle {
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addresses[0]
+    }
+
+    pub fn local_addrs(&self) -> &[SocketAddr] {
+        &self.addresses
+    }
+
+    // Stops accepting clients, closes the ones connected once they have their replies, and
+    // waits until every thread the server started has finished
+    pub fn shutdown(self) {
+        self.shutdown.request();
+        if self.thread.join().is_err() {
+            log::error!("The server stopped with a panic");
+        }
     }
+}
 
-    log::info!("Shutting down.");
+// A listener for every address in the config. Failing to bind any of them is fatal, since the
+// server would otherwise be silently unreachable at that address.
+fn bind_listeners(config: &Config) -> io::Result<Vec<TcpListener>> {
+    if config.bind_addresses().is_empty() {
+        return Err(io::Error::new(ErrorKind::InvalidInput, "No address to listen on"));
+    }
+    let mut listeners = Vec::new();
+    for address in config.bind_addresses() {
+        let listener = bind_with_retries(address, config).map_err(|error| {
+            io::Error::new(
+                error.kind(),
+                format!("Unable to bind to {} port {}: {}", address, config.port, error),
+            )
+        })?;
+        listeners.push(listener);
+    }
+    Ok(listeners)
 }
 
-fn handle_connection(mut stream: TcpStream, index: &Arc<Index>, databases: &Arc<Databases>) {
+// An address still in use is often only held by the connections of a server that was just
+// stopped, so it is worth waiting a little for it, backing off between attempts
+fn bind_with_retries(address: &str, config: &Config) -> io::Result<TcpListener> {
+    let mut retries = config.bind_retries;
+    let mut delay = BIND_RETRY_DELAY;
     loop {
-        // Wrap the stream in a BufReader, so we can use the BufRead methods
-        let mut reader = io::BufReader::new(&mut stream);
-
-        // Read current data in the TcpStream
-        let received = reader.fill_buf();
-        match received {
-            Ok(received) => {
-                log::debug!("Raw bytes: {:?}", received);
-                let size = received.len();
-                if size == 0 {
-                    return;
-                } // the connection was closed, so exit this thread
-
-                // Identify the command
-                let command = tokenizer::identify_command(received);
-                reader.consume(size);
-
-                match command {
-                    Ok(request) => {
-                        log::info!("Received Request: {:?}", request);
-
-                        match index.execute_command(&databases, &request) {
-                            Ok(result) => {
-                                log::debug!("Result: {:?}", result);
-                                stream.write_all(result.iter().as_slice()).unwrap()
-                            }
-                            Err(error) => {
-                                log::error!("Error: {:?}", error);
-                                stream
-                                    .write_all(format_execution_error(&error).as_slice())
-                                    .unwrap();
-                            }
-                        }
-                    }
-                    Err(error) => {
-                        log::error!("Parse Error: {:?}", error);
-                        stream
-                            .write_all(format_parse_error(&error).as_slice())
-                            .unwrap();
-                    }
-                }
-            }
-            Err(msg) => {
-                log::error!("System Error: {:?}", msg);
-                return; // issue with the TCP stream so close it and exit this thread
+        match bind(address, config.port) {
+            Err(error) if error.kind() == ErrorKind::AddrInUse && retries > 0 => {
+                log::warn!("{} port {} is in use, trying again in {:?}", address, config.port, delay);
+                thread::sleep(delay);
+                delay = (delay * 2).min(MAX_BIND_RETRY_DELAY);
+                retries -= 1;
             }
-        };
+            result => return result,
+        }
+    }
+}
+
+// Binds the first address the host resolves to that can be bound. SO_REUSEADDR is set so the
+// port can be taken again while connections from before a restart are still in TIME_WAIT.
+fn bind(host: &str, port: u16) -> io::Result<TcpListener> {
+    let mut last_error = io::Error::new(ErrorKind::InvalidInput, "no address found");
+    for address in (host, port).to_socket_addrs()? {
+        let socket = Socket::new(Domain::for_address(address), Type::STREAM, Some(Protocol::TCP))?;
+        socket.set_reuse_address(true)?;
+        match socket.bind(&address.into()).and_then(|_| socket.listen(LISTEN_BACKLOG)) {
+            Ok(()) => return Ok(socket.into()),
+            Err(error) => last_error = error,
+        }
+    }
+    Err(last_error)
+}
+
+// Serves clients until shutdown is requested, from the event loops or, with the `async`
+// feature, from tokio tasks
+fn serve(
+    listeners: Vec<TcpListener>,
+    config: &Arc<Config>,
+    index_db: &Arc<Index>,
+    databases: &Arc<Databases>,
+    shutdown: &ShutdownSignal,
+) {
+    let connections = Arc::new(OpenConnections::new());
+    info::record_start_time();
+    // stopped once the clients have all gone
+    let _sweeper = Sweeper::start(index_db, databases);
+
+    #[cfg(not(feature = "async"))]
+    event_loop::run(listeners, &connections, index_db, databases, config, shutdown);
+    #[cfg(feature = "async")]
+    async_server::run(listeners, &connections, index_db, databases, config, shutdown);
+}
+
+// Replies are small and sent whole, so there is nothing to gain from Nagle's algorithm
+// delaying them. Keepalive lets the OS notice clients that vanished without closing
+// the connection, so they don't stay open forever.
+fn configure_socket(stream: &TcpStream, config: &Config) -> io::Result<()> {
+    stream.set_nodelay(true)?;
+    let socket = SockRef::from(stream);
+    if config.tcp_keepalive > 0 {
+        let idle = Duration::from_secs(config.tcp_keepalive);
+        socket.set_tcp_keepalive(&TcpKeepalive::new().with_time(idle))?;
+    } else {
+        socket.set_keepalive(false)?;
+    }
+    Ok(())
+}
+
+// What to do about a failed accept, once it has been logged. A client that gave up before it
+// was accepted is no reason to wait, anything else may well fail again on the next try.
+fn accept_failed(error: &io::Error) -> AcceptFailure {
+    if matches!(error.raw_os_error(), Some(EMFILE | ENFILE)) {
+        log::warn!("Unable to accept a client, out of file descriptors: {}", error);
+        AcceptFailure::OutOfDescriptors
+    } else if matches!(
+        error.kind(),
+        ErrorKind::ConnectionAborted | ErrorKind::ConnectionReset | ErrorKind::Interrupted
+    ) {
+        log::debug!("A client went before it was accepted: {}", error);
+        AcceptFailure::Retry
+    } else {
+        log::error!("Unable to accept a client: {:?}", error);
+        AcceptFailure::RetryAfterDelay
     }
 }
 
-fn format_parse_error(error: &ParserError) -> Vec<u8> {
-    format_error(error.get_message())
+#[cfg_attr(test, derive(Debug, PartialEq))]
+enum AcceptFailure {
+    Retry,
+    RetryAfterDelay,
+    // the client waiting should be turned away using the spare descriptor, so the listener
+    // doesn't keep reporting it, then retry after a delay
+    OutOfDescriptors,
 }
 
-fn format_execution_error(error: &ExecutionError) -> Vec<u8> {
-    format_error(error.get_message())
+// The trick Redis uses for running out of file descriptors: one is held in reserve, so it can be
+// given up long enough to accept the client waiting and close it straight away. Otherwise the
+// client would hang until some other connection closed.
+struct SpareDescriptor(Option<File>);
+
+impl SpareDescriptor {
+    fn new() -> SpareDescriptor {
+        SpareDescriptor(Self::open())
+    }
+
+    fn open() -> Option<File> {
+        File::open(if cfg!(windows) { "NUL" } else { "/dev/null" }).ok()
+    }
+
+    // Closes the spare, returning false if there wasn't one to close
+    fn release(&mut self) -> bool {
+        self.0.take().is_some()
+    }
+
+    fn restore(&mut self) {
+        if self.0.is_none() {
+            self.0 = Self::open();
+        }
+    }
 }
 
-fn format_error(error: &str) -> Vec<u8> {
-    log::info!("Error {:?}", error);
-    format!("-ERR {} \r\n", error).as_bytes().to_vec()
+// What a panic was raised with, which is almost always a message
+pub(crate) fn panic_message(payload: &(dyn std::any::Any + Send)) -> &str {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message
+    } else {
+        "(not a message)"
+    }
+}
+
+// An empty or null array has nothing to run
+fn command_words(request: ParsedRequest) -> Option<Vec<Option<Bytes>>> {
+    match request {
+        ParsedRequest::Command(arguments) => Some(arguments),
+        ParsedRequest::NoOp => None,
+    }
+}
+
+fn execute_request(
+    request: &[Bytes],
+    connection: &mut ConnectionContext,
+    index: &Index,
+    databases: &Arc<Databases>,
+) -> Result<Bytes, ExecutionError> {
+    table::validate(request)?;
+    connection.check_command_allowed(&request[0])?;
+    if ConnectionContext::is_command_supported(&request[0]) {
+        // the connection's commands write their own frames: HELLO replies in the protocol it
+        // has just chosen, and subscribers' confirmations are written as they are made
+        return connection.execute_command(request);
+    }
+    let reply = if info::is_command_supported(&request[0]) {
+        let usage = databases.memory_usage();
+        info::execute_command(request, index, databases.thread_pool.get(), &usage)
+    } else if debug::is_command_supported(&request[0]) {
+        debug::execute_command(request, index, databases)
+    } else if memory::is_command_supported(&request[0]) {
+        memory::execute_command(request, index, &databases.memory_usage())
+    } else if config::is_command_supported(&request[0]) {
+        config::execute_command(request, &databases.limits)
+    } else if table::is_command_supported(&request[0]) {
+        table::execute_command(request)
+    } else {
+        index.execute_command(databases, request)
+    }?;
+    // every other reply is encoded here, in the protocol the client has negotiated
+    Ok(reply.encode(connection.get_protocol()))
+}
+
+// Long-running commands are handed back by the session rather than run where the client is
+// served, so its front end can run them on threads set aside for them and reply when they finish
+fn is_long_running(request: &[Bytes]) -> bool {
+    debug::is_long_running(request)
+}
+
+// A panic is reported to the client, which is waiting for a reply, rather than left to the
+// thread the command ran on
+fn run_long_running(request: &[Bytes]) -> Result<Value, ExecutionError> {
+    std::panic::catch_unwind(|| debug::sleep(request)).unwrap_or_else(|payload| {
+        log::error!("Long-running command panicked: {}", panic_message(payload.as_ref()));
+        info::record_recovered_panic();
+        Err(ExecutionError::new("the command failed unexpectedly"))
+    })
+}
+
+fn format_parse_error(error: &ParserError) -> Bytes {
+    if error.is_protocol_error() {
+        format_error(&format!("Protocol error: {}", error))
+    } else {
+        format_error(&error.get_message())
+    }
+}
+
+// The error's class, then its message, e.g. "-WRONGTYPE Operation against a key ..."
+fn format_execution_error(error: &ExecutionError) -> Bytes {
+    match error {
+        ExecutionError::Request(error) => format_parse_error(error),
+        error => resp::error(&format!("{} {}", error.error_class(), error)),
+    }
+}
+
+// For the errors that don't come from a command, all of which are a generic ERR
+fn format_error(error: &str) -> Bytes {
+    resp::error(&format!("ERR {}", error))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::OutputBufferLimit;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use crate::commands::request;
+    use std::io::prelude::*;
+    use std::sync::{Mutex, Once};
+    use std::time::Instant;
+    use std::net::SocketAddr;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn given_request_split_across_writes_when_received_then_executes_once_complete() {
+        let mut client = TcpStream::connect(start_server()).unwrap();
+        client.write_all(b"*3\r\n$3\r\nSET\r\n$3\r\nkey\r\n").unwrap();
+        thread::sleep(Duration::from_millis(50));
+        client.write_all(b"$5\r\nva").unwrap();
+        thread::sleep(Duration::from_millis(50));
+        client.write_all(b"lue\r\n").unwrap();
+        assert_eq!(read_reply(&mut client), "+OK\r\n");
+
+        client.write_all(b"*2\r\n$3\r\nGET\r\n$3\r\nkey\r\n").unwrap();
+        assert_eq!(read_replies(&mut client, 2), "$5\r\nvalue\r\n");
+    }
+
+    #[test]
+    fn given_malformed_prefix_when_received_then_errors_without_waiting() {
+        let mut client = TcpStream::connect(start_server()).unwrap();
+        client.write_all(b"GET key").unwrap();
+        assert_eq!(
+            read_reply(&mut client),
+            "-ERR Protocol error: expected '*', got 'G' at byte 0\r\n"
+        );
+    }
+
+    #[test]
+    fn given_garbage_bytes_when_received_then_connection_closed_after_one_error() {
+        let mut client = TcpStream::connect(start_server()).unwrap();
+        client
+            .write_all(b"*2\r\n$3\r\nGET\r\n#junk\r\n*1\r\n$4\r\nPING\r\n")
+            .unwrap();
+        let reply = read_reply(&mut client);
+        assert!(reply.starts_with("-ERR Protocol error: "), "{}", reply);
+        assert_eq!(reply.matches("\r\n").count(), 1);
+        let mut buffer = [0u8; 16];
+        assert_eq!(client.read(&mut buffer).unwrap(), 0);
+    }
+
+    #[test]
+    fn given_absurd_bulk_length_when_received_then_rejected_and_closed() {
+        let mut client = TcpStream::connect(start_server()).unwrap();
+        client
+            .write_all(b"*3\r\n$3\r\nSET\r\n$3\r\nkey\r\n$9999999999\r\nabc")
+            .unwrap();
+        assert_eq!(read_reply(&mut client), "-ERR Protocol error: invalid bulk length\r\n");
+        let mut buffer = [0u8; 16];
+        assert_eq!(client.read(&mut buffer).unwrap(), 0);
+    }
+
+    #[test]
+    fn given_null_bulk_argument_when_received_then_error_reply_and_connection_kept() {
+        let mut client = TcpStream::connect(start_server()).unwrap();
+        client.write_all(b"*4\r\n$3\r\nSET\r\n$1\r\nk\r\n$-1\r\n$3\r\nGET\r\n").unwrap();
+        assert_eq!(read_reply(&mut client), "-ERR null argument 2 given to 'set' command\r\n");
+        client.write_all(b"*2\r\n$-1\r\n$1\r\nk\r\n").unwrap();
+        assert_eq!(read_reply(&mut client), "-ERR null command name\r\n");
+
+        // nothing was set, and the connection still serves commands
+        client.write_all(b"*2\r\n$3\r\nGET\r\n$1\r\nk\r\n").unwrap();
+        assert_eq!(read_reply(&mut client), "$-1\r\n");
+    }
+
+    #[test]
+    fn given_too_many_arguments_declared_when_received_then_rejected_and_closed() {
+        let mut client = TcpStream::connect(start_server()).unwrap();
+        client.write_all(b"*1048577\r\n$3\r\nSET\r\n").unwrap();
+        assert_eq!(read_reply(&mut client), "-ERR Protocol error: invalid multibulk length\r\n");
+        let mut buffer = [0u8; 16];
+        assert_eq!(client.read(&mut buffer).unwrap(), 0);
+    }
+
+    #[test]
+    fn given_empty_value_when_set_then_get_round_trips() {
+        let mut client = TcpStream::connect(start_server()).unwrap();
+        client.write_all(b"*3\r\n$3\r\nSET\r\n$3\r\nkey\r\n$0\r\n\r\n").unwrap();
+        assert_eq!(read_reply(&mut client), "+OK\r\n");
+        client.write_all(b"*2\r\n$3\r\nGET\r\n$3\r\nkey\r\n").unwrap();
+        assert_eq!(read_replies(&mut client, 2), "$0\r\n\r\n");
+    }
+
+    #[test]
+    fn given_null_array_when_received_then_ignored_and_connection_stays_open() {
+        let mut client = TcpStream::connect(start_server()).unwrap();
+        client
+            .write_all(b"*-1\r\n*0\r\n*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$1\r\nv\r\n")
+            .unwrap();
+        assert_eq!(read_reply(&mut client), "+OK\r\n");
+    }
+
+    #[test]
+    fn given_wrong_arity_when_received_then_connection_stays_open() {
+        let mut client = TcpStream::connect(start_server()).unwrap();
+        client.write_all(b"*1\r\n$3\r\nGET\r\n").unwrap();
+        assert_eq!(read_reply(&mut client), "-ERR wrong number of arguments for 'get' command\r\n");
+        client.write_all(b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$1\r\nv\r\n").unwrap();
+        assert_eq!(read_reply(&mut client), "+OK\r\n");
+    }
+
+    #[test]
+    fn given_rename_of_missing_key_when_received_then_single_error_prefix() {
+        let mut client = TcpStream::connect(start_server()).unwrap();
+        client.write_all(b"*3\r\n$6\r\nRENAME\r\n$3\r\nold\r\n$3\r\nnew\r\n").unwrap();
+        assert_eq!(read_reply(&mut client), "-ERR no such key\r\n");
+    }
+
+    #[test]
+    fn given_typed_errors_when_received_then_rendered_with_their_class() {
+        let mut client = TcpStream::connect(start_server()).unwrap();
+        client.write_all(b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$1\r\nv\r\n").unwrap();
+        assert_eq!(read_reply(&mut client), "+OK\r\n");
+        client.write_all(b"*2\r\n$4\r\nLLEN\r\n$1\r\nk\r\n").unwrap();
+        assert_eq!(read_reply(&mut client), "-WRONGTYPE Operation against a key holding the wrong kind of value\r\n");
+        client.write_all(b"*2\r\n$4\r\nINCR\r\n$1\r\nk\r\n").unwrap();
+        assert_eq!(read_reply(&mut client), "-ERR value is not an integer or out of range\r\n");
+        client.write_all(b"*2\r\n$5\r\nHELLO\r\n$1\r\n4\r\n").unwrap();
+        assert_eq!(read_reply(&mut client), "-NOPROTO unsupported protocol version\r\n");
+    }
+
+    #[test]
+    fn given_error_of_each_class_when_replied_then_class_first_and_counted() {
+        // room for one key, after which writes are refused
+        let (address, _server) = start_serving(Config { maxmemory: 1, ..Config::default() });
+        let mut client = TcpStream::connect(address).unwrap();
+        let before = errorstats(&mut client);
+
+        client.write_all(b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$1\r\nv\r\n").unwrap();
+        assert_eq!(read_reply(&mut client), "+OK\r\n");
+        let errors: [(&[u8], &str); 4] = [
+            (b"*2\r\n$4\r\nLLEN\r\n$1\r\nk\r\n", "WRONGTYPE"),
+            (b"*3\r\n$3\r\nSET\r\n$2\r\nk2\r\n$1\r\nv\r\n", "OOM"),
+            (b"*2\r\n$5\r\nHELLO\r\n$1\r\n4\r\n", "NOPROTO"),
+            (b"*1\r\n$3\r\nGET\r\n", "ERR"),
+        ];
+        for (request, class) in errors {
+            client.write_all(request).unwrap();
+            let reply = read_reply(&mut client);
+            assert!(reply.starts_with(&format!("-{} ", class)), "{}", reply);
+        }
+        // a protocol error is an ERR too, after which the connection is closed
+        let mut broken = TcpStream::connect(address).unwrap();
+        broken.write_all(b"GET k\r\n").unwrap();
+        assert!(read_reply(&mut broken).starts_with("-ERR Protocol error: "));
+        assert_eq!(broken.read(&mut [0u8; 16]).unwrap(), 0);
+
+        // other tests may be counting errors meanwhile, so these are at least what was sent
+        let after = errorstats(&mut client);
+        for (class, sent) in [("WRONGTYPE", 1), ("OOM", 1), ("NOPROTO", 1), ("ERR", 2)] {
+            assert!(after(class) >= before(class) + sent, "{}: {} then {}", class, before(class), after(class));
+        }
+    }
+
+    // INFO errorstats, as a lookup of the count for each class
+    fn errorstats(client: &mut TcpStream) -> impl Fn(&str) -> u64 + use<> {
+        client.write_all(b"*2\r\n$4\r\nINFO\r\n$10\r\nerrorstats\r\n").unwrap();
+        let mut reply = Vec::new();
+        let mut buffer = [0u8; 4096];
+        loop {
+            let size = client.read(&mut buffer).unwrap();
+            reply.extend_from_slice(&buffer[..size]);
+            let text = String::from_utf8_lossy(&reply).to_string();
+            if let Some((header, body)) = text.split_once("\r\n")
+                && body.len() == header[1..].parse::<usize>().unwrap() + 2
+            {
+                let body = body.to_string();
+                return move |class: &str| {
+                    let prefix = format!("errorstat_{}:count=", class);
+                    body.lines()
+                        .find_map(|line| line.strip_prefix(&prefix))
+                        .map_or(0, |count| count.parse().unwrap())
+                };
+            }
+        }
+    }
+
+    #[test]
+    fn given_wrong_number_of_arguments_when_received_then_redis_wording() {
+        let mut client = TcpStream::connect(start_server()).unwrap();
+        client.write_all(b"*1\r\n$4\r\nLLEN\r\n").unwrap();
+        assert_eq!(read_reply(&mut client), "-ERR wrong number of arguments for 'llen' command\r\n");
+        client.write_all(b"*2\r\n$6\r\nRENAME\r\n$3\r\nold\r\n").unwrap();
+        assert_eq!(read_reply(&mut client), "-ERR wrong number of arguments for 'rename' command\r\n");
+        client.write_all(b"*1\r\n$7\r\nPUBLISH\r\n").unwrap();
+        assert_eq!(read_reply(&mut client), "-ERR wrong number of arguments for 'publish' command\r\n");
+        client.write_all(b"*4\r\n$3\r\nSET\r\n$1\r\nk\r\n$1\r\nv\r\n$4\r\nKEEP\r\n").unwrap();
+        assert_eq!(read_reply(&mut client), "-ERR syntax error\r\n");
+        client.write_all(b"*1\r\n$6\r\nMEMORY\r\n").unwrap();
+        assert_eq!(read_reply(&mut client), "-ERR wrong number of arguments for 'memory' command\r\n");
+    }
+
+    #[test]
+    fn given_command_table_when_command_count_sent_then_variadic_push_admitted() {
+        let mut client = TcpStream::connect(start_server()).unwrap();
+        client.write_all(b"*5\r\n$5\r\nRPUSH\r\n$4\r\nlist\r\n$1\r\na\r\n$1\r\nb\r\n$1\r\nc\r\n").unwrap();
+        assert_eq!(read_reply(&mut client), ":3\r\n");
+        client.write_all(b"*2\r\n$7\r\nCOMMAND\r\n$5\r\nCOUNT\r\n").unwrap();
+        assert!(read_reply(&mut client).starts_with(':'));
+    }
+
+    #[test]
+    fn given_classified_error_when_formatted_then_sent_with_its_class() {
+        assert_eq!(
+            format_execution_error(&ExecutionError::WrongType),
+            "-WRONGTYPE Operation against a key holding the wrong kind of value\r\n"
+        );
+        assert_eq!(format_execution_error(&ExecutionError::NoSuchKey), "-ERR no such key\r\n");
+        assert_eq!(
+            format_execution_error(&ExecutionError::OutOfMemory),
+            "-OOM command not allowed when used memory > 'maxmemory'.\r\n"
+        );
+        let error = ExecutionError::from(ParserError::InvalidLength { offset: 3 });
+        assert_eq!(format_execution_error(&error), "-ERR Protocol error: invalid length at byte 3\r\n");
+    }
+
+    #[test]
+    fn given_message_with_line_breaks_when_formatted_then_cannot_inject_frames() {
+        let error = ExecutionError::new("no such key 'a\r\n+OK'");
+        assert_eq!(format_execution_error(&error), "-ERR no such key 'a  +OK'\r\n");
+    }
+
+    #[test]
+    fn given_value_larger_than_read_buffer_when_set_then_get_round_trips() {
+        let value = "abcdefgh".repeat(1024 * 1024); // 8MB
+        let mut client = TcpStream::connect(start_server()).unwrap();
+        let request = format!(
+            "*3\r\n$3\r\nSET\r\n$3\r\nbig\r\n${}\r\n{}\r\n",
+            value.len(),
+            value
+        );
+        client.write_all(request.as_bytes()).unwrap();
+        assert_eq!(read_reply(&mut client), "+OK\r\n");
+
+        client.write_all(b"*2\r\n$3\r\nGET\r\n$3\r\nbig\r\n").unwrap();
+        let reply = read_replies(&mut client, 2);
+        assert_eq!(reply.len(), value.len() + "$8388608\r\n\r\n".len());
+        assert!(reply == format!("${}\r\n{}\r\n", value.len(), value), "Value was not returned intact");
+    }
+
+    #[test]
+    fn given_pipelined_commands_in_one_write_when_received_then_replies_in_order() {
+        let mut client = TcpStream::connect(start_server()).unwrap();
+        client
+            .write_all(
+                b"*3\r\n$3\r\nSET\r\n$1\r\na\r\n$1\r\n1\r\n\
+                  *3\r\n$3\r\nSET\r\n$1\r\nb\r\n$1\r\n2\r\n\
+                  *2\r\n$3\r\nGET\r\n$1\r\na\r\n",
+            )
+            .unwrap();
+        assert_eq!(read_replies(&mut client, 4), "+OK\r\n+OK\r\n$1\r\n1\r\n");
+    }
+
+    #[test]
+    fn given_hundreds_of_pipelined_commands_when_received_then_every_reply_arrives_in_order() {
+        let mut client = TcpStream::connect(start_server()).unwrap();
+        let mut requests = Vec::new();
+        let mut expected = String::new();
+        for i in 0..300 {
+            let (key, value) = (format!("key{}", i), i.to_string());
+            requests.extend_from_slice(
+                format!("*3\r\n$3\r\nSET\r\n${}\r\n{}\r\n${}\r\n{}\r\n", key.len(), key, value.len(), value).as_bytes(),
+            );
+            requests.extend_from_slice(format!("*2\r\n$3\r\nGET\r\n${}\r\n{}\r\n", key.len(), key).as_bytes());
+            expected.push_str(&format!("+OK\r\n${}\r\n{}\r\n", value.len(), value));
+        }
+
+        let started = Instant::now();
+        client.write_all(&requests).unwrap();
+        assert_eq!(read_replies(&mut client, 900), expected);
+        log::info!("600 pipelined commands answered in {:?}", started.elapsed());
+    }
+
+    #[test]
+    fn given_quit_when_pipelined_with_more_commands_then_ok_sent_and_rest_ignored() {
+        let mut client = TcpStream::connect(start_server()).unwrap();
+        client
+            .write_all(
+                b"*3\r\n$3\r\nSET\r\n$1\r\nq\r\n$1\r\n1\r\n\
+                  *1\r\n$4\r\nQUIT\r\n\
+                  *2\r\n$3\r\nGET\r\n$1\r\nq\r\n",
+            )
+            .unwrap();
+        let mut replies = Vec::new();
+        client.read_to_end(&mut replies).unwrap();
+        assert_eq!(replies, b"+OK\r\n+OK\r\n");
+    }
+
+    #[test]
+    fn given_two_connections_when_commands_logged_then_each_has_its_own_stable_id() {
+        capture_logs();
+        let address = start_server();
+        let mut first = TcpStream::connect(address).unwrap();
+        let mut second = TcpStream::connect(address).unwrap();
+        for client in [&mut first, &mut second] {
+            client.write_all(b"*3\r\n$3\r\nSET\r\n$6\r\nlogged\r\n$6\r\nsecret\r\n").unwrap();
+            assert_eq!(read_reply(client), "+OK\r\n");
+            client.write_all(b"*2\r\n$3\r\nGET\r\n$6\r\nlogged\r\n").unwrap();
+            assert_eq!(read_replies(client, 2), "$6\r\nsecret\r\n");
+        }
+
+        // the server names each client by the address it connected from
+        let ids: Vec<String> = [&first, &second]
+            .iter()
+            .map(|client| {
+                let peer = client.local_addr().unwrap().to_string();
+                let lines: Vec<String> = CAPTURED_LOGS
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .filter(|line| line.contains(&format!(" {}: ", peer)))
+                    .cloned()
+                    .collect();
+                assert!(lines.iter().any(|line| line.contains(": SET logged took ") && line.ends_with(", ok")), "{:?}", lines);
+                assert!(lines.iter().any(|line| line.contains(": GET logged took ")), "{:?}", lines);
+                assert!(lines.iter().all(|line| !line.contains("secret")), "{:?}", lines);
+
+                let ids: Vec<&str> = lines.iter().map(|line| line.split(' ').nth(2).unwrap()).collect();
+                assert!(ids.iter().all(|id| *id == ids[0]), "{:?}", lines);
+                ids[0].to_string()
+            })
+            .collect();
+        assert_ne!(ids[0], ids[1]);
+    }
+
+    #[test]
+    fn given_threshold_of_zero_when_commands_run_then_slow_warnings_logged_at_limited_rate() {
+        capture_logs();
+        let (address, server) =
+            start_serving(Config { slow_command_log_threshold: 0, slow_command_log_rate: 3, ..Config::default() });
+        let mut client = TcpStream::connect(address).unwrap();
+        client.write_all(&b"*3\r\n$3\r\nSET\r\n$4\r\nslow\r\n$6\r\nhidden\r\n".repeat(10)).unwrap();
+        assert_eq!(read_replies(&mut client, 10), "+OK\r\n".repeat(10));
+
+        let peer = client.local_addr().unwrap().to_string();
+        let lines: Vec<String> = CAPTURED_LOGS
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|line| line.contains(&format!(" {}: ", peer)))
+            .cloned()
+            .collect();
+        let warnings: Vec<&String> = lines.iter().filter(|line| line.contains(": slow command ")).collect();
+        // only the first three in the second were logged
+        assert_eq!(warnings.len(), 3, "{:?}", lines);
+        let id = lines[0].split(' ').nth(2).unwrap();
+        for warning in warnings {
+            assert!(warning.starts_with(&format!("WARN client {} {}: slow command SET slow took ", id, peer)), "{}", warning);
+            assert!(warning.ends_with("us"), "{}", warning);
+            assert!(!warning.contains("hidden"), "{}", warning);
+        }
+
+        // the ones left out are counted once the next second starts
+        thread::sleep(Duration::from_millis(1100));
+        client.write_all(b"*1\r\n$4\r\nPING\r\n").unwrap();
+        assert_eq!(read_reply(&mut client), "+PONG\r\n");
+        assert!(CAPTURED_LOGS.lock().unwrap().iter().any(|line| line == "WARN 7 more slow commands were not logged"));
+        server.shutdown();
+    }
+
+    #[test]
+    fn given_subscriber_not_reading_when_published_past_hard_limit_then_only_it_is_disconnected() {
+        let limit = OutputBufferLimit { hard: 1024 * 1024, soft: 0, soft_seconds: 0 };
+        let (address, server) = start_serving(Config { client_output_buffer_limit_pubsub: limit, ..Config::default() });
+        let subscribe = b"*2\r\n$9\r\nSUBSCRIBE\r\n$4\r\nfeed\r\n";
+        let message = "m".repeat(32 * 1024);
+        let frame = format!("*3\r\n$7\r\nmessage\r\n$4\r\nfeed\r\n${}\r\n{}\r\n", message.len(), message);
+        let messages = 1000; // 32MB, more than the socket buffers of the one not reading can hold
+
+        let mut stalled = TcpStream::connect(address).unwrap();
+        stalled.write_all(subscribe).unwrap();
+        // peeking leaves the confirmation unread, it only shows the subscription is in place
+        stalled.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        stalled.peek(&mut [0u8; 1]).unwrap();
+        let mut reading = TcpStream::connect(address).unwrap();
+        reading.write_all(subscribe).unwrap();
+        assert_eq!(read_replies(&mut reading, 6), "*3\r\n$9\r\nsubscribe\r\n$4\r\nfeed\r\n:1\r\n");
+
+        let expected = frame.repeat(messages);
+        let progress = Arc::new(AtomicUsize::new(0));
+        let read_so_far = Arc::clone(&progress);
+        let reader = thread::spawn(move || {
+            let mut received = Vec::new();
+            let mut buffer = [0u8; 64 * 1024];
+            while received.len() < expected.len() {
+                let size = reading.read(&mut buffer).unwrap();
+                assert_ne!(size, 0, "disconnected after {} bytes", received.len());
+                received.extend_from_slice(&buffer[..size]);
+                read_so_far.store(received.len(), Ordering::SeqCst);
+            }
+            assert!(received == expected.as_bytes(), "messages were not delivered intact");
+            reading
+        });
+
+        let mut publisher = TcpStream::connect(address).unwrap();
+        let publish = format!("*3\r\n$7\r\nPUBLISH\r\n$4\r\nfeed\r\n${}\r\n{}\r\n", message.len(), message);
+        let mut receivers = Vec::new();
+        for published in 0..messages {
+            // kept only a few messages ahead of the reader, so however busy the machine it is
+            // never far enough behind to reach the limit itself
+            while progress.load(Ordering::SeqCst) + 8 * frame.len() < published * frame.len() {
+                thread::sleep(Duration::from_millis(1));
+            }
+            publisher.write_all(publish.as_bytes()).unwrap();
+            receivers.push(read_reply(&mut publisher));
+        }
+        let _reading = reader.join().unwrap();
+        assert_eq!(receivers[0], ":2\r\n");
+
+        // the one not reading gets what its socket held, then the connection ends
+        let mut rest = Vec::new();
+        let closed = match stalled.read_to_end(&mut rest) {
+            Ok(_) => true,
+            Err(error) => error.kind() == ErrorKind::ConnectionReset,
+        };
+        assert!(closed);
+        assert!(rest.len() < messages * frame.len());
+        // and is no longer subscribed, once its connection has been let go
+        let deadline = Instant::now() + Duration::from_secs(2);
+        while subscribers(&mut publisher) != 1 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(subscribers(&mut publisher), 1);
+        server.shutdown();
+    }
+
+    // How many are subscribed to "feed", found by publishing an empty message to it
+    fn subscribers(publisher: &mut TcpStream) -> usize {
+        publisher.write_all(b"*3\r\n$7\r\nPUBLISH\r\n$4\r\nfeed\r\n$0\r\n\r\n").unwrap();
+        read_reply(publisher).trim_start_matches(':').trim_end().parse().unwrap()
+    }
+
+    #[test]
+    fn given_hello_3_on_connection_then_replies_switch_to_resp3_shapes() {
+        let mut client = TcpStream::connect(start_server()).unwrap();
+        client.write_all(b"*2\r\n$5\r\nHELLO\r\n$1\r\n2\r\n").unwrap();
+        assert!(read_replies(&mut client, 13).starts_with("*12\r\n$6\r\nserver\r\n"));
+
+        client.write_all(b"*2\r\n$5\r\nHELLO\r\n$1\r\n3\r\n").unwrap();
+        let reply = read_replies(&mut client, 13);
+        assert!(reply.starts_with("%6\r\n$6\r\nserver\r\n"));
+        assert!(reply.contains("$5\r\nproto\r\n:3\r\n"));
+    }
+
+    #[test]
+    fn given_subscriber_when_publish_then_message_frame_matches_protocol() {
+        let address = start_server();
+        let mut subscriber = TcpStream::connect(address).unwrap();
+        let mut publisher = TcpStream::connect(address).unwrap();
+
+        subscriber.write_all(b"*2\r\n$9\r\nSUBSCRIBE\r\n$4\r\nnews\r\n").unwrap();
+        assert_eq!(read_replies(&mut subscriber, 6), "*3\r\n$9\r\nsubscribe\r\n$4\r\nnews\r\n:1\r\n");
+        publisher.write_all(b"*3\r\n$7\r\nPUBLISH\r\n$4\r\nnews\r\n$2\r\nhi\r\n").unwrap();
+        assert_eq!(read_reply(&mut publisher), ":1\r\n");
+        assert_eq!(read_replies(&mut subscriber, 7), "*3\r\n$7\r\nmessage\r\n$4\r\nnews\r\n$2\r\nhi\r\n");
+
+        // HELLO is not allowed in a RESP2 subscribed context, so negotiate on a fresh connection
+        let mut subscriber = TcpStream::connect(address).unwrap();
+        subscriber.write_all(b"*2\r\n$5\r\nHELLO\r\n$1\r\n3\r\n").unwrap();
+        read_replies(&mut subscriber, 13);
+        subscriber.write_all(b"*2\r\n$9\r\nSUBSCRIBE\r\n$4\r\nnews\r\n").unwrap();
+        assert_eq!(read_replies(&mut subscriber, 6), ">3\r\n$9\r\nsubscribe\r\n$4\r\nnews\r\n:1\r\n");
+        publisher.write_all(b"*3\r\n$7\r\nPUBLISH\r\n$4\r\nnews\r\n$2\r\nhi\r\n").unwrap();
+        assert_eq!(read_reply(&mut publisher), ":2\r\n");
+        assert_eq!(read_replies(&mut subscriber, 7), ">3\r\n$7\r\nmessage\r\n$4\r\nnews\r\n$2\r\nhi\r\n");
+    }
+
+    #[test]
+    fn given_keyevent_subscriber_when_key_evicted_then_notified_and_counted() {
+        let config = Config {
+            maxmemory: 1,
+            maxmemory_policy: crate::memory::EvictionPolicy::AllKeysLru,
+            notify_keyspace_events: "Ee".to_string(),
+            ..Config::default()
+        };
+        let (address, _server) = start_serving(config);
+        let mut subscriber = TcpStream::connect(address).unwrap();
+        let mut client = TcpStream::connect(address).unwrap();
+        subscriber.write_all(b"*2\r\n$9\r\nSUBSCRIBE\r\n$22\r\n__keyevent@0__:evicted\r\n").unwrap();
+        read_replies(&mut subscriber, 6);
+
+        // the first key fits, as nothing is stored yet; storing the second evicts it
+        client.write_all(b"*3\r\n$3\r\nSET\r\n$2\r\nk1\r\n$1\r\nv\r\n*3\r\n$3\r\nSET\r\n$2\r\nk2\r\n$1\r\nv\r\n").unwrap();
+        assert_eq!(read_replies(&mut client, 2), "+OK\r\n+OK\r\n");
+        assert_eq!(
+            read_replies(&mut subscriber, 7),
+            "*3\r\n$7\r\nmessage\r\n$22\r\n__keyevent@0__:evicted\r\n$2\r\nk1\r\n"
+        );
+        client.write_all(b"*2\r\n$4\r\nINFO\r\n$5\r\nstats\r\n").unwrap();
+        let info = read_replies(&mut client, 4);
+        assert!(info.contains("evicted_keys:"), "{}", info);
+    }
+
+    #[test]
+    fn given_info_when_protocol_negotiated_then_verbatim_only_on_resp3() {
+        let mut client = TcpStream::connect(start_server()).unwrap();
+        client.write_all(b"*2\r\n$4\r\nINFO\r\n$8\r\nkeyspace\r\n").unwrap();
+        assert_eq!(read_replies(&mut client, 3), "$12\r\n# Keyspace\r\n\r\n");
+
+        client.write_all(b"*2\r\n$5\r\nHELLO\r\n$1\r\n3\r\n").unwrap();
+        read_replies(&mut client, 13);
+        client.write_all(b"*2\r\n$4\r\nINFO\r\n$8\r\nkeyspace\r\n").unwrap();
+        assert_eq!(read_replies(&mut client, 3), "=16\r\ntxt:# Keyspace\r\n\r\n");
+    }
+
+    #[test]
+    fn given_missing_key_when_read_then_null_sent_in_the_negotiated_protocol() {
+        let mut client = TcpStream::connect(start_server()).unwrap();
+        let reads = b"*2\r\n$3\r\nGET\r\n$7\r\nmissing\r\n*2\r\n$4\r\nLPOP\r\n$7\r\nmissing\r\n";
+        client.write_all(reads).unwrap();
+        assert_eq!(read_replies(&mut client, 2), "$-1\r\n$-1\r\n");
+
+        client.write_all(b"*2\r\n$5\r\nHELLO\r\n$1\r\n3\r\n").unwrap();
+        read_replies(&mut client, 13);
+        client.write_all(reads).unwrap();
+        assert_eq!(read_replies(&mut client, 2), "_\r\n_\r\n");
+    }
+
+    #[test]
+    fn given_shutdown_requested_when_serving_then_clients_finish_and_port_released() {
+        let (address, server) = start_serving(Config { thread_pool_size: 1, ..Config::default() });
+
+        let mut first = TcpStream::connect(address).unwrap();
+        first.write_all(b"*3\r\n$3\r\nSET\r\n$1\r\na\r\n$1\r\n1\r\n").unwrap();
+        assert_eq!(read_reply(&mut first), "+OK\r\n");
+        // the only worker's event loop serves both clients, neither has to wait for the other
+        let mut second = TcpStream::connect(address).unwrap();
+        second.write_all(b"*3\r\n$3\r\nSET\r\n$1\r\nb\r\n$1\r\n2\r\n").unwrap();
+        assert_eq!(read_reply(&mut second), "+OK\r\n");
+
+        server.shutdown();
+
+        let mut rest = Vec::new();
+        first.read_to_end(&mut rest).unwrap();
+        second.read_to_end(&mut rest).unwrap();
+        assert!(rest.is_empty());
+        assert!(TcpListener::bind(address).is_ok(), "the port should have been released");
+    }
+
+    #[test]
+    fn given_clients_that_vanish_before_their_replies_when_serving_then_workers_survive() {
+        let (address, server) = start_serving(Config { thread_pool_size: 2, ..Config::default() });
+        let value = "v".repeat(1024 * 1024);
+        let mut setup = TcpStream::connect(address).unwrap();
+        setup
+            .write_all(format!("*3\r\n$3\r\nSET\r\n$3\r\nbig\r\n${}\r\n{}\r\n", value.len(), value).as_bytes())
+            .unwrap();
+        assert_eq!(read_reply(&mut setup), "+OK\r\n");
+
+        // each asks for far more than the socket can buffer, then resets the connection
+        // without reading any of it, so writing the replies fails part way
+        for _ in 0..10 {
+            let mut client = TcpStream::connect(address).unwrap();
+            client.write_all(&b"*2\r\n$3\r\nGET\r\n$3\r\nbig\r\n".repeat(10)).unwrap();
+            SockRef::from(&client).set_linger(Some(Duration::ZERO)).unwrap();
+        }
+        thread::sleep(Duration::from_millis(100));
+
+        // every worker is still there to serve clients at the same time
+        let clients: Vec<_> = (0..8)
+            .map(|_| {
+                thread::spawn(move || {
+                    let mut client = TcpStream::connect(address).unwrap();
+                    client.write_all(b"*1\r\n$4\r\nPING\r\n").unwrap();
+                    read_reply(&mut client)
+                })
+            })
+            .collect();
+        for client in clients {
+            assert_eq!(client.join().unwrap(), "+PONG\r\n");
+        }
+
+        server.shutdown();
+    }
+
+    #[test]
+    fn given_many_idle_clients_when_pool_is_small_then_active_clients_still_served() {
+        let (address, server) = start_serving(Config { thread_pool_size: 4, ..Config::default() });
+        let mut idle: Vec<TcpStream> = (0..100).map(|_| TcpStream::connect(address).unwrap()).collect();
+
+        let mut active = TcpStream::connect(address).unwrap();
+        for _ in 0..10 {
+            active.write_all(b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$1\r\nv\r\n").unwrap();
+            assert_eq!(read_reply(&mut active), "+OK\r\n");
+            active.write_all(b"*2\r\n$3\r\nGET\r\n$1\r\nk\r\n").unwrap();
+            assert_eq!(read_replies(&mut active, 2), "$1\r\nv\r\n");
+        }
+        // none of the idle ones was left waiting for a thread either
+        for client in &mut idle {
+            client.write_all(b"*1\r\n$4\r\nPING\r\n").unwrap();
+            assert_eq!(read_reply(client), "+PONG\r\n");
+        }
+
+        server.shutdown();
+    }
+
+    #[test]
+    fn given_maxclients_reached_when_client_connects_then_rejected_until_one_leaves() {
+        let (address, server) = start_serving(Config { maxclients: 2, ..Config::default() });
+        let set = b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$1\r\nv\r\n";
+
+        let mut first = TcpStream::connect(address).unwrap();
+        first.write_all(set).unwrap();
+        assert_eq!(read_reply(&mut first), "+OK\r\n");
+        let mut second = TcpStream::connect(address).unwrap();
+        second.write_all(set).unwrap();
+        assert_eq!(read_reply(&mut second), "+OK\r\n");
+
+        let mut third = TcpStream::connect(address).unwrap();
+        let mut reply = Vec::new();
+        third.read_to_end(&mut reply).unwrap();
+        assert_eq!(reply, b"-ERR max number of clients reached\r\n");
+
+        drop(first);
+        thread::sleep(Duration::from_millis(100));
+        let mut fourth = TcpStream::connect(address).unwrap();
+        fourth.write_all(set).unwrap();
+        assert_eq!(read_reply(&mut fourth), "+OK\r\n");
+
+        server.shutdown();
+    }
+
+    #[test]
+    fn given_timeout_when_client_idle_then_closed_unless_active_or_subscribed() {
+        let config = Config { timeout: 1, ..Config::default() };
+        let (address, server) = start_serving(config);
+        let mut idle = TcpStream::connect(address).unwrap();
+        // a request that never completes doesn't count as activity
+        let mut partial = TcpStream::connect(address).unwrap();
+        partial.write_all(b"*2\r\n$3\r\nGET\r\n").unwrap();
+        let mut subscriber = TcpStream::connect(address).unwrap();
+        subscriber.write_all(b"*2\r\n$9\r\nSUBSCRIBE\r\n$4\r\nnews\r\n").unwrap();
+        read_replies(&mut subscriber, 6);
+        let mut active = TcpStream::connect(address).unwrap();
+
+        for _ in 0..4 {
+            thread::sleep(Duration::from_millis(400));
+            active.write_all(b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$1\r\nv\r\n").unwrap();
+            assert_eq!(read_reply(&mut active), "+OK\r\n");
+        }
+
+        let mut rest = Vec::new();
+        idle.read_to_end(&mut rest).unwrap();
+        partial.read_to_end(&mut rest).unwrap();
+        assert!(rest.is_empty());
+        active.write_all(b"*3\r\n$7\r\nPUBLISH\r\n$4\r\nnews\r\n$2\r\nhi\r\n").unwrap();
+        assert_eq!(read_reply(&mut active), ":1\r\n");
+        assert_eq!(read_replies(&mut subscriber, 7), "*3\r\n$7\r\nmessage\r\n$4\r\nnews\r\n$2\r\nhi\r\n");
+
+        server.shutdown();
+    }
+
+    #[test]
+    fn given_accepted_socket_when_configured_then_nodelay_and_keepalive_set() {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let _client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+
+        configure_socket(&stream, &Config::default()).unwrap();
+        assert!(stream.nodelay().unwrap());
+        assert!(SockRef::from(&stream).keepalive().unwrap());
+
+        configure_socket(&stream, &Config { tcp_keepalive: 0, ..Config::default() }).unwrap();
+        assert!(!SockRef::from(&stream).keepalive().unwrap());
+    }
+
+    #[test]
+    fn given_ipv4_and_ipv6_hosts_when_serving_then_reachable_over_both() {
+        let config = Config { host: "127.0.0.1 [::1]".to_string(), port: 0, ..Config::default() };
+        let server = Server::bind(config).unwrap();
+        let addresses = server.local_addrs().to_vec();
+        assert!(addresses[0].is_ipv4() && addresses[1].is_ipv6());
+        assert!(addresses[1].to_string().starts_with("[::1]:"));
+        let server = server.spawn().unwrap();
+
+        for address in &addresses {
+            let mut client = TcpStream::connect(address).unwrap();
+            client.write_all(b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$1\r\nv\r\n").unwrap();
+            assert_eq!(read_reply(&mut client), "+OK\r\n");
+        }
+
+        server.shutdown();
+    }
+
+    #[test]
+    fn given_unbindable_address_when_binding_then_error_names_it() {
+        let config = Config { host: "127.0.0.1 256.0.0.1".to_string(), port: 0, ..Config::default() };
+        let error = bind_listeners(&config).err().unwrap();
+        assert!(error.to_string().starts_with("Unable to bind to 256.0.0.1 port 0"), "{}", error);
+    }
+
+    #[test]
+    fn given_port_in_use_when_binding_then_error_until_it_is_free() {
+        let taken = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let port = taken.local_addr().unwrap().port();
+        let config = Config { port, ..Config::default() };
+
+        let error = Server::bind(config.clone()).err().unwrap();
+        assert_eq!(error.kind(), ErrorKind::AddrInUse);
+        assert!(error.to_string().starts_with(&format!("Unable to bind to 127.0.0.1 port {}: ", port)), "{}", error);
+
+        drop(taken);
+        let server = Server::bind(config).unwrap();
+        assert_eq!(server.local_addrs()[0].port(), port);
+    }
+
+    #[test]
+    fn given_bind_retries_when_port_freed_while_waiting_then_bound() {
+        let taken = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let port = taken.local_addr().unwrap().port();
+        let releaser = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(150));
+            drop(taken);
+        });
+
+        let server = Server::bind(Config { port, bind_retries: 5, ..Config::default() }).unwrap();
+        assert_eq!(server.local_addrs()[0].port(), port);
+        releaser.join().unwrap();
+    }
+
+    #[test]
+    fn given_port_0_when_bound_then_assigned_port_reported() {
+        let server = Server::bind(Config { port: 0, ..Config::default() }).unwrap();
+        let address = server.local_addrs()[0];
+        assert_ne!(address.port(), 0);
+        let server = server.spawn().unwrap();
+
+        let mut client = TcpStream::connect(address).unwrap();
+        client.write_all(b"*1\r\n$4\r\nPING\r\n").unwrap();
+        assert_eq!(read_reply(&mut client), "+PONG\r\n");
+
+        server.shutdown();
+    }
+
+    #[test]
+    fn given_unstarted_server_when_request_executed_directly_then_reply_returned() {
+        let server = Server::bind(Config { host: "127.0.0.1".to_string(), port: 0, ..Config::default() }).unwrap();
+        assert_eq!(server.execute(&request(&["SET", "direct", "1"])), "+OK\r\n");
+        assert_eq!(server.execute(&request(&["INCR", "direct"])), ":2\r\n");
+        assert_eq!(server.execute(&request(&["LPUSH", "direct", "x"])).slice(..11), "-WRONGTYPE ");
+
+        // the data is the same the clients see once it is serving
+        let address = server.local_addr();
+        let server = server.spawn().unwrap();
+        let mut client = TcpStream::connect(address).unwrap();
+        client.write_all(b"*2\r\n$3\r\nGET\r\n$6\r\ndirect\r\n").unwrap();
+        assert_eq!(read_replies(&mut client, 2), "$1\r\n2\r\n");
+        server.shutdown();
+    }
+
+    // Runs the real server, as main does, on a port picked by the OS, until the returned handle
+    // is used to stop it. That is the event loops, or the async server when built with `--features async`,
+    // so these tests cover whichever front end is enabled.
+    fn start_serving(config: Config) -> (SocketAddr, ServerHandle) {
+        let server = Server::bind(Config { host: "127.0.0.1".to_string(), port: 0, ..config }).unwrap();
+        (server.local_addr(), server.spawn().unwrap())
+    }
+
+    fn start_server() -> SocketAddr {
+        start_serving(Config::default()).0
+    }
+
+    // Keeps the controller's log lines, each starting with its level, for tests to check what was logged about their clients
+    static CAPTURED_LOGS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+    struct CapturingLogger;
+
+    impl log::Log for CapturingLogger {
+        fn enabled(&self, metadata: &log::Metadata) -> bool {
+            metadata.target().starts_with("redis_in_rust::controller")
+        }
+
+        fn log(&self, record: &log::Record) {
+            // other modules are skipped before anything is formatted, so tests counting their
+            // allocations aren't thrown off
+            if self.enabled(record.metadata()) {
+                CAPTURED_LOGS.lock().unwrap().push(format!("{} {}", record.level(), record.args()));
+            }
+        }
+
+        fn flush(&self) {}
+    }
+
+    fn capture_logs() {
+        static INSTALL: Once = Once::new();
+        INSTALL.call_once(|| {
+            log::set_logger(&CapturingLogger).unwrap();
+            log::set_max_level(log::LevelFilter::Debug);
+        });
+    }
+
+    fn read_reply(client: &mut TcpStream) -> String {
+        read_replies(client, 1)
+    }
+
+    // Reads until the given number of reply lines have arrived
+    fn read_replies(client: &mut TcpStream, count: usize) -> String {
+        client.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        let mut reply = Vec::new();
+        let mut buffer = [0u8; 64 * 1024];
+        while !reply.ends_with(b"\r\n") || reply.windows(2).filter(|w| w == b"\r\n").count() < count {
+            let size = client.read(&mut buffer).unwrap();
+            if size == 0 {
+                break;
+            }
+            reply.extend_from_slice(&buffer[..size]);
+        }
+        String::from_utf8(reply).unwrap()
+    }
+
+    #[test]
+    fn given_panic_payloads_when_described_then_messages_returned() {
+        let payload = std::panic::catch_unwind(|| panic!("static message")).unwrap_err();
+        assert_eq!(panic_message(payload.as_ref()), "static message");
+        let payload = std::panic::catch_unwind(|| panic!("formatted {}", 1)).unwrap_err();
+        assert_eq!(panic_message(payload.as_ref()), "formatted 1");
+        let payload = std::panic::catch_unwind(|| std::panic::panic_any(7)).unwrap_err();
+        assert_eq!(panic_message(payload.as_ref()), "(not a message)");
+    }
+
+    #[test]
+    fn given_one_thread_when_debug_sleep_running_then_other_clients_still_served() {
+        let (address, server) = start_serving(Config { thread_pool_size: 1, ..Config::default() });
+        let mut sleeper = TcpStream::connect(address).unwrap();
+        let mut other = TcpStream::connect(address).unwrap();
+        // the command after the sleep waits for it, so the replies stay in order
+        let started = Instant::now();
+        sleeper
+            .write_all(b"*3\r\n$5\r\nDEBUG\r\n$5\r\nSLEEP\r\n$3\r\n0.5\r\n*1\r\n$4\r\nPING\r\n")
+            .unwrap();
+        thread::sleep(Duration::from_millis(50));
+
+        other
+            .write_all(b"*3\r\n$3\r\nSET\r\n$3\r\nkey\r\n$5\r\nvalue\r\n*2\r\n$3\r\nGET\r\n$3\r\nkey\r\n")
+            .unwrap();
+        assert_eq!(read_replies(&mut other, 3), "+OK\r\n$5\r\nvalue\r\n");
+        assert!(started.elapsed() < Duration::from_millis(400), "{:?}", started.elapsed());
+
+        assert_eq!(read_replies(&mut sleeper, 2), "+OK\r\n+PONG\r\n");
+        assert!(started.elapsed() >= Duration::from_millis(500));
+        server.shutdown();
+    }
 }
@@ -0,0 +1,194 @@
+// A client's requests, from the bytes received to the replies written. Both front ends (the
+// event loops and the async server) read into a session and let it run whatever is complete.
+
+use crate::commands::table;
+use crate::commands::{ExecutionError, ParserError};
+use crate::config::Config;
+use crate::controller::connection::ConnectionContext;
+use crate::controller::{
+    command_words, execute_request, format_execution_error, format_parse_error, is_long_running, Databases,
+};
+use crate::index::Index;
+use crate::info;
+use crate::resp::Value;
+use crate::tokenizer;
+use bytes::{Bytes, BytesMut};
+use std::io;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const READ_BUFFER_SIZE: usize = 4096;
+const MAX_READ_SIZE: usize = 64 * 1024;
+
+pub(crate) struct Session {
+    connection: ConnectionContext,
+    // Bytes received so far that don't yet make up a complete request
+    pending: BytesMut,
+    // How many more bytes the partial request in `pending` has declared it needs
+    missing_bytes: usize,
+    // Only a complete command counts as activity, so a client can't stay connected by
+    // trickling in a request that never finishes
+    last_command: Instant,
+    // A long-running command waiting to be handed to its front end, which runs it elsewhere.
+    // Nothing more is run until its reply is in, so the replies still go back in order.
+    long_running: Option<Vec<Bytes>>,
+    waiting_on_long_running: bool,
+}
+
+impl Session {
+    pub fn new(connection: ConnectionContext) -> Session {
+        Session {
+            connection,
+            pending: BytesMut::with_capacity(READ_BUFFER_SIZE),
+            missing_bytes: 0,
+            last_command: Instant::now(),
+            long_running: None,
+            waiting_on_long_running: false,
+        }
+    }
+
+    #[cfg_attr(feature = "async", allow(dead_code))]
+    pub fn connection(&self) -> &ConnectionContext {
+        &self.connection
+    }
+
+    // When the client will have been idle for too long, unless it is exempt
+    // A client waiting on a long-running command isn't idle.
+    pub fn idle_deadline(&self, config: &Config) -> Option<Instant> {
+        (config.timeout > 0 && !self.connection.is_exempt_from_timeout() && !self.waiting_on_long_running)
+            .then(|| self.last_command + Duration::from_secs(config.timeout))
+    }
+
+    pub fn idle_time(&self) -> Duration {
+        self.last_command.elapsed()
+    }
+
+    // Reads straight into the spare room at the end of the pending buffer. When a large bulk
+    // string is on its way the whole of it is reserved at once, so the buffer is allocated a
+    // single time rather than growing with every read.
+    pub fn read_from(&mut self, read: impl FnOnce(&mut [u8]) -> io::Result<usize>) -> io::Result<usize> {
+        self.pending.reserve(self.missing_bytes);
+        let start = self.pending.len();
+        self.pending
+            .resize(start + self.missing_bytes.clamp(READ_BUFFER_SIZE, MAX_READ_SIZE), 0);
+        let received = read(&mut self.pending[start..]);
+        let size = *received.as_ref().unwrap_or(&0);
+        self.pending.truncate(start + size);
+        if size > 0 {
+            log::trace!("{}: raw bytes {:?}", self.connection.log_name(), &self.pending[start..]);
+        }
+        received
+    }
+
+    // Executes every complete command in the buffer, queueing the replies so they go back to
+    // the client in order and in a single write. Returns false once nothing more should be read
+    // from the client: it asked to QUIT, its framing is broken or it can't be written to. Any
+    // replies it is owed have been flushed by then, as far as the socket would take them.
+    pub fn execute_requests(&mut self, index: &Arc<Index>, databases: &Arc<Databases>, config: &Config) -> bool {
+        while !self.pending.is_empty() && !self.connection.is_closing() && !self.waiting_on_long_running {
+            match tokenizer::identify_command(&mut self.pending, config) {
+                Ok(request) => {
+                    self.last_command = Instant::now();
+                    let Some(arguments) = command_words(request) else {
+                        continue; // nothing to run, and nothing to reply
+                    };
+                    let not_null = table::check_not_null(&arguments);
+                    let request: Vec<Bytes> = arguments.into_iter().map(Option::unwrap_or_default).collect();
+                    if let Err(error) = not_null {
+                        self.reply(&request, Err(error.into()), databases);
+                        continue;
+                    }
+
+                    // one the client isn't allowed to run is turned down here, like any other
+                    if is_long_running(&request) && self.connection.check_command_allowed(&request[0]).is_ok() {
+                        self.long_running = Some(request);
+                        self.waiting_on_long_running = true;
+                        break;
+                    }
+
+                    let result = execute_request(&request, &mut self.connection, index, databases);
+                    self.reply(&request, result, databases);
+                }
+                Err(ParserError::Incomplete { missing_bytes }) => {
+                    self.missing_bytes = missing_bytes;
+                    log::debug!(
+                        "{}: partial request of {} bytes, waiting for {} more",
+                        self.connection.log_name(),
+                        self.pending.len(),
+                        missing_bytes
+                    );
+                    break;
+                }
+                Err(error) => {
+                    // the framing is broken, so neither the rest of the buffer nor anything
+                    // still on its way can be trusted: reply, then close the connection
+                    log::error!(
+                        "{}: protocol error at byte {:?}: {}",
+                        self.connection.log_name(),
+                        error.get_offset(),
+                        error
+                    );
+                    info::record_error_reply(error.error_class());
+                    self.connection.queue(&format_parse_error(&error));
+                    self.flush();
+                    return false;
+                }
+            }
+        }
+        if !self.flush() || self.connection.is_closing() {
+            return false;
+        }
+
+        if self.pending.is_empty() {
+            self.missing_bytes = 0;
+            if self.pending.capacity() > MAX_READ_SIZE {
+                // don't hold on to the memory from an unusually large request
+                self.pending = BytesMut::with_capacity(READ_BUFFER_SIZE);
+            }
+        }
+        true
+    }
+
+    // The long-running command the front end should now run, if there is one
+    pub fn take_long_running(&mut self) -> Option<Vec<Bytes>> {
+        self.long_running.take()
+    }
+
+    #[cfg_attr(feature = "async", allow(dead_code))]
+    pub fn is_waiting_on_long_running(&self) -> bool {
+        self.waiting_on_long_running
+    }
+
+    // Queues the reply to the long-running command, after which execute_requests carries on
+    // with whatever the client has sent since
+    pub fn finish_long_running(&mut self, request: &[Bytes], result: Result<Value, ExecutionError>, databases: &Databases) {
+        self.waiting_on_long_running = false;
+        let result = result.map(|reply| reply.encode(self.connection.get_protocol()));
+        self.reply(request, result, databases);
+        self.last_command = Instant::now();
+    }
+
+    fn reply(&mut self, request: &[Bytes], result: Result<Bytes, ExecutionError>, databases: &Databases) {
+        // measured once, for every log that wants it
+        let elapsed = self.last_command.elapsed();
+        self.connection.log_command(request, elapsed, &result);
+        databases.slow_commands.record(self.connection.log_name(), request, elapsed);
+        match result {
+            Ok(result) => self.connection.queue(&result),
+            Err(error) => {
+                info::record_error_reply(error.error_class());
+                self.connection.queue(&format_execution_error(&error));
+            }
+        }
+    }
+
+    fn flush(&self) -> bool {
+        match self.connection.flush() {
+            Ok(()) => true,
+            Err(error) => {
+                log::debug!("{}: unable to write: {:?}", self.connection.log_name(), error);
+                false
+            }
+        }
+    }
+}
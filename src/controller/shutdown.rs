@@ -0,0 +1,121 @@
+// Stopping the server cleanly: the accept loop is woken and told to stop, then every
+// client is closed once the command it is running has been answered
+
+use crate::controller::connection::next_client_id;
+use std::collections::HashMap;
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+// Asks the accept loops to stop. Each is blocked in accept(), so once the flag is set they are
+// woken by connecting to their listener, and see the flag before serving that connection.
+#[derive(Clone)]
+pub struct ShutdownSignal {
+    requested: Arc<AtomicBool>,
+    wake_addresses: Vec<SocketAddr>,
+}
+
+impl ShutdownSignal {
+    pub fn new(listeners: &[TcpListener]) -> io::Result<ShutdownSignal> {
+        let mut wake_addresses = Vec::with_capacity(listeners.len());
+        for listener in listeners {
+            let mut address = listener.local_addr()?;
+            // a listener on every interface can't be connected to at the unspecified address everywhere
+            if address.ip().is_unspecified() {
+                address.set_ip(match address {
+                    SocketAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+                    SocketAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+                });
+            }
+            wake_addresses.push(address);
+        }
+        Ok(ShutdownSignal {
+            requested: Arc::new(AtomicBool::new(false)),
+            wake_addresses,
+        })
+    }
+
+    pub fn request(&self) {
+        // only the first request needs to wake them
+        if self.requested.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        for address in &self.wake_addresses {
+            if let Err(error) = TcpStream::connect(address) {
+                log::error!("Unable to wake the accept loop on {}: {:?}", address, error);
+            }
+        }
+    }
+
+    pub fn is_requested(&self) -> bool {
+        self.requested.load(Ordering::SeqCst)
+    }
+}
+
+// Every client connection, accepted or still on its way to an event loop, so they can be
+// counted against maxclients and all closed at shutdown. Only the read side is closed: a
+// command already read still gets its reply, then the event loop sees the end of the stream
+// and lets the connection go.
+pub(crate) struct OpenConnections {
+    live: AtomicUsize,
+    streams: Mutex<HashMap<u64, TcpStream>>,
+}
+
+impl OpenConnections {
+    pub fn new() -> OpenConnections {
+        OpenConnections {
+            live: AtomicUsize::new(0),
+            streams: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Counts the connection, unless there are already `limit`. It stays counted until the
+    // returned registration is dropped, which happens however its event loop finishes
+    // with it, even by panicking.
+    pub fn register(self: &Arc<Self>, stream: &TcpStream, limit: usize) -> Option<Registration> {
+        self.live
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |live| (live < limit).then_some(live + 1))
+            .ok()?;
+        let id = next_client_id();
+        match stream.try_clone() {
+            Ok(stream) => {
+                self.streams.lock().unwrap().insert(id, stream);
+            }
+            Err(error) => log::error!("Unable to track client {}: {:?}", id, error),
+        }
+        Some(Registration {
+            id,
+            connections: Arc::clone(self),
+        })
+    }
+
+    pub fn count(&self) -> usize {
+        self.live.load(Ordering::SeqCst)
+    }
+
+    pub fn close_all(&self) {
+        for stream in self.streams.lock().unwrap().values() {
+            let _ = stream.shutdown(Shutdown::Read);
+        }
+    }
+}
+
+pub(crate) struct Registration {
+    id: u64,
+    connections: Arc<OpenConnections>,
+}
+
+impl Registration {
+    // The client's id, as used in the log and by CLIENT ID
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.connections.streams.lock().unwrap().remove(&self.id);
+        self.connections.live.fetch_sub(1, Ordering::SeqCst);
+    }
+}
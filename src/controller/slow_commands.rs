@@ -0,0 +1,67 @@
+// Commands that take longer than slow-command-log-threshold are logged as warnings, so they show
+// up in the normal log, and whatever alerts on it, without anyone having to ask for them. A
+// workload where everything is slow would flood the log, so only so many are logged a second
+// and the rest are counted.
+
+use crate::config::Config;
+use crate::controller::connection::command_summary;
+use bytes::Bytes;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+const WINDOW: Duration = Duration::from_secs(1);
+
+pub(crate) struct SlowCommands {
+    // None when slow commands aren't logged
+    threshold: Option<Duration>,
+    per_second: u32,
+    window: Mutex<Window>,
+}
+
+struct Window {
+    started: Instant,
+    logged: u32,
+    suppressed: u64,
+}
+
+impl SlowCommands {
+    pub fn new(config: &Config) -> SlowCommands {
+        SlowCommands {
+            threshold: u64::try_from(config.slow_command_log_threshold).ok().map(Duration::from_millis),
+            per_second: config.slow_command_log_rate,
+            window: Mutex::new(Window {
+                started: Instant::now(),
+                logged: 0,
+                suppressed: 0,
+            }),
+        }
+    }
+
+    // Takes the time the command was measured to take when it ran, rather than timing it again
+    pub fn record(&self, log_name: &str, request: &[Bytes], elapsed: Duration) {
+        match self.threshold {
+            Some(threshold) if elapsed >= threshold => {}
+            _ => return,
+        }
+        {
+            let mut window = self.window.lock().unwrap();
+            let now = Instant::now();
+            if now.duration_since(window.started) >= WINDOW {
+                if window.suppressed > 0 {
+                    log::warn!("{} more slow commands were not logged", window.suppressed);
+                }
+                *window = Window {
+                    started: now,
+                    logged: 0,
+                    suppressed: 0,
+                };
+            }
+            if window.logged >= self.per_second {
+                window.suppressed += 1;
+                return;
+            }
+            window.logged += 1;
+        }
+        log::warn!("{}: slow command {} took {}us", log_name, command_summary(request), elapsed.as_micros());
+    }
+}
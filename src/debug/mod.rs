@@ -0,0 +1,405 @@
+// The DEBUG command, for testing and troubleshooting the server: SLEEP, and EXPORT and IMPORT,
+// which copy keys out of the server and back in as JSON.
+
+use crate::commands::{check_arity, syntax_error, text_argument, ExecutionError, ParserError};
+use crate::controller::Databases;
+use crate::executor::EntryView;
+use crate::expiration;
+use crate::glob;
+use crate::index::Index;
+use crate::resp::Value;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use bytes::Bytes;
+use serde_json::json;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const REDIS_DEBUG_COMMANDS: [&str; 1] = ["DEBUG"];
+
+// The shape of the document EXPORT writes, which IMPORT checks before loading anything. Version 2
+// added the times to live of hashes' fields; IMPORT still takes documents of version 1.
+const EXPORT_VERSION: u64 = 2;
+
+pub fn is_command_supported(command: &[u8]) -> bool {
+    REDIS_DEBUG_COMMANDS
+        .iter()
+        .any(|&cmd| cmd.as_bytes().eq_ignore_ascii_case(command))
+}
+
+// Commands that keep their thread busy for as long as they run, so they are given threads of
+// their own rather than holding up the other clients on an event loop
+pub fn is_long_running(request: &[Bytes]) -> bool {
+    is_command_supported(&request[0]) && request.get(1).is_some_and(|subcommand| subcommand.eq_ignore_ascii_case(b"SLEEP"))
+}
+
+pub fn execute_command(request: &[Bytes], index: &Index, databases: &Arc<Databases>) -> Result<Value, ExecutionError> {
+    // support syntax: DEBUG SLEEP seconds
+    //                 DEBUG EXPORT [pattern]
+    //                 DEBUG IMPORT document
+    //                 DEBUG IMPORT FILE path
+    let subcommand = String::from_utf8_lossy(&request[1]).to_uppercase();
+    match subcommand.as_str() {
+        "SLEEP" => sleep(request),
+        "EXPORT" => {
+            if request.len() > 3 {
+                return Err(syntax_error().into());
+            }
+            Ok(Value::BulkString(Bytes::from(export(index, databases, request.get(2)))))
+        }
+        "IMPORT" => match request.len() {
+            3 => import(&request[2], index, databases),
+            4 if request[2].eq_ignore_ascii_case(b"FILE") => {
+                let path = text_argument(&request[3])?;
+                let document = std::fs::read(path)
+                    .map_err(|error| ExecutionError::new(&format!("unable to read '{}': {}", path, error)))?;
+                import(&document, index, databases)
+            }
+            4.. => Err(syntax_error().into()),
+            _ => Err(ParserError::WrongNumberOfArguments("debug|import".to_string()).into()),
+        },
+        _ => Err(ExecutionError::new(&format!(
+            "unknown subcommand '{}'. Try DEBUG HELP.",
+            String::from_utf8_lossy(&request[1])
+        ))),
+    }
+}
+
+// DEBUG SLEEP, which needs nothing from the keyspace so can run on a thread of its own
+pub fn sleep(request: &[Bytes]) -> Result<Value, ExecutionError> {
+    check_arity(request, 3)?;
+    // fractions of a second are allowed, e.g. DEBUG SLEEP 0.25
+    let seconds = text_argument(&request[2])?
+        .parse::<f64>()
+        .ok()
+        .and_then(|seconds| Duration::try_from_secs_f64(seconds).ok())
+        .ok_or(ParserError::NotAFloat)?;
+    thread::sleep(seconds);
+    Ok(Value::Ok)
+}
+
+// The matching keys as {"version": 2, "keys": [{"key", "type", "ttl", and "value", "elements" or "fields"}]},
+// in order of their names. Each executor is looked at in turn, so keys changed while this runs
+// may be seen before or after the change.
+fn export(index: &Index, databases: &Databases, pattern: Option<&Bytes>) -> String {
+    let mut keys = Vec::new();
+    for executor in databases.executors.iter() {
+        executor.for_each_entry(&mut |key, entry| {
+            let deadline = index.deadline(key);
+            if deadline.is_some_and(|deadline| deadline <= Instant::now()) {
+                return;
+            }
+            if pattern.is_none_or(|pattern| glob::matches(pattern, key.as_bytes())) {
+                let ttl = deadline.map_or(-1, expiration::seconds_left);
+                keys.push((key.to_string(), exported_key(key, ttl, entry)));
+            }
+        });
+    }
+    keys.sort_by(|(first, _), (second, _)| first.cmp(second));
+    let keys: Vec<_> = keys.into_iter().map(|(_, key)| key).collect();
+    json!({ "version": EXPORT_VERSION, "keys": keys }).to_string()
+}
+
+// The ttl is in seconds as TTL reports it, so -1 for a key that never expires
+fn exported_key(key: &str, ttl: i64, entry: EntryView) -> serde_json::Value {
+    match entry {
+        EntryView::String(value) => json!({ "key": key, "type": "string", "ttl": ttl, "value": exported_bytes(value) }),
+        EntryView::List(elements) => {
+            let elements: Vec<_> = elements.iter().map(exported_bytes).collect();
+            json!({ "key": key, "type": "list", "ttl": ttl, "elements": elements })
+        }
+        // each field followed by its value, as HSET takes them, in the order of the fields, then
+        // the milliseconds left to those given a time to live, as HPEXPIRE takes them
+        EntryView::Hash(hash) => {
+            let mut fields: Vec<_> = hash.iter().collect();
+            fields.sort_by_key(|(field, ..)| *field);
+            let field_ttls: Vec<_> = fields
+                .iter()
+                .filter_map(|(field, _, left)| left.map(|left| json!({ "field": exported_bytes(field), "pttl": left.as_millis().max(1) as u64 })))
+                .collect();
+            let fields: Vec<_> = fields.into_iter().flat_map(|(field, value, _)| [exported_bytes(field), exported_bytes(value)]).collect();
+            json!({ "key": key, "type": "hash", "ttl": ttl, "fields": fields, "field_ttls": field_ttls })
+        }
+    }
+}
+
+// Text as a string, and anything that isn't UTF-8 as {"base64": "..."}
+fn exported_bytes(bytes: &Bytes) -> serde_json::Value {
+    match std::str::from_utf8(bytes) {
+        Ok(text) => json!(text),
+        Err(_) => json!({ "base64": BASE64.encode(bytes) }),
+    }
+}
+
+// Loads the keys of a document EXPORT wrote, with their times to live, and their fields', counted from now, leaving any that already exist as they are, and
+// replies with how many were created, skipped and failed
+fn import(document: &[u8], index: &Index, databases: &Arc<Databases>) -> Result<Value, ExecutionError> {
+    let document: serde_json::Value = serde_json::from_slice(document)
+        .map_err(|error| ExecutionError::new(&format!("invalid export document: {}", error)))?;
+    if !matches!(document["version"].as_u64(), Some(1 | EXPORT_VERSION)) {
+        return Err(ExecutionError::new(&format!("unsupported export version {}", document["version"])));
+    }
+    let keys = document["keys"]
+        .as_array()
+        .ok_or_else(|| ExecutionError::new("invalid export document: no keys"))?;
+
+    let (mut created, mut skipped, mut failed) = (0, 0, 0);
+    for key in keys {
+        match import_key(key, index, databases) {
+            Ok(true) => created += 1,
+            Ok(false) => skipped += 1,
+            Err(error) => {
+                log::warn!("DEBUG IMPORT: {} not imported: {}", key["key"], error);
+                failed += 1;
+            }
+        }
+    }
+    let count = |name: &'static str, count: i64| (Value::BulkString(Bytes::from_static(name.as_bytes())), Value::Integer(count));
+    Ok(Value::Map(vec![count("created", created), count("skipped", skipped), count("failed", failed)]))
+}
+
+// Restores the key with the command a client would use, so it is indexed, counted and limited
+// like any other. Returns whether it was created, or left because it already exists.
+fn import_key(key: &serde_json::Value, index: &Index, databases: &Arc<Databases>) -> Result<bool, ExecutionError> {
+    let name = key["key"].as_str().ok_or_else(|| ExecutionError::new("no key name"))?;
+    let name = Bytes::copy_from_slice(name.as_bytes());
+    let mut field_ttls = Vec::new();
+    let request = match key["type"].as_str() {
+        Some("string") => vec![Bytes::from_static(b"SET"), name.clone(), imported_bytes(&key["value"])?],
+        Some("list") => {
+            let elements = key["elements"].as_array().filter(|elements| !elements.is_empty());
+            let elements = elements.ok_or_else(|| ExecutionError::new("a list needs elements"))?;
+            let mut request = vec![Bytes::from_static(b"RPUSH"), name.clone()];
+            for element in elements {
+                request.push(imported_bytes(element)?);
+            }
+            request
+        }
+        Some("hash") => {
+            let fields = key["fields"].as_array().filter(|fields| !fields.is_empty() && fields.len().is_multiple_of(2));
+            let fields = fields.ok_or_else(|| ExecutionError::new("a hash needs fields, each with a value"))?;
+            let mut request = vec![Bytes::from_static(b"HSET"), name.clone()];
+            for field in fields {
+                request.push(imported_bytes(field)?);
+            }
+            // checked before the hash is created, so a bad one doesn't leave it half restored
+            for field_ttl in key["field_ttls"].as_array().into_iter().flatten() {
+                let pttl = field_ttl["pttl"].as_u64().filter(|pttl| *pttl > 0);
+                let pttl = pttl.ok_or_else(|| ExecutionError::new("a field's pttl must be a positive number of milliseconds"))?;
+                let field = imported_bytes(&field_ttl["field"])?;
+                field_ttls.push([
+                    Bytes::from_static(b"HPEXPIRE"),
+                    name.clone(),
+                    Bytes::from(pttl.to_string()),
+                    Bytes::from_static(b"FIELDS"),
+                    Bytes::from_static(b"1"),
+                    field,
+                ]);
+            }
+            request
+        }
+        _ => return Err(ExecutionError::new(&format!("unsupported type {}", key["type"]))),
+    };
+    if index.execute_command(databases, &[Bytes::from_static(b"EXISTS"), name.clone()])? != Value::Integer(0) {
+        return Ok(false);
+    }
+    index.execute_command(databases, &request)?;
+    for field_ttl in field_ttls {
+        index.execute_command(databases, &field_ttl)?;
+    }
+    if let Some(ttl) = key["ttl"].as_i64().filter(|ttl| *ttl > 0) {
+        let ttl = Bytes::from(ttl.to_string());
+        index.execute_command(databases, &[Bytes::from_static(b"EXPIRE"), name, ttl])?;
+    }
+    Ok(true)
+}
+
+fn imported_bytes(value: &serde_json::Value) -> Result<Bytes, ExecutionError> {
+    if let Some(text) = value.as_str() {
+        return Ok(Bytes::copy_from_slice(text.as_bytes()));
+    }
+    value["base64"]
+        .as_str()
+        .and_then(|encoded| BASE64.decode(encoded).ok())
+        .map(Bytes::from)
+        .ok_or_else(|| ExecutionError::new(&format!("{} is neither a string nor base64", value)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::request;
+    use crate::config::Config;
+    use crate::hash_executor::fake_clock;
+    use std::time::Instant;
+
+    fn server() -> (Index, Arc<Databases>) {
+        (Index::new(), Arc::new(Databases::new(&Config::default())))
+    }
+
+    #[test]
+    fn given_sleep_when_executed_then_waits_and_replies_ok() {
+        assert!(is_long_running(&request(&["debug", "sleep", "0.05"])));
+        let started = Instant::now();
+        let reply = sleep(&request(&["DEBUG", "SLEEP", "0.05"])).unwrap();
+        assert_eq!(reply, Value::Ok);
+        assert!(started.elapsed() >= Duration::from_millis(50));
+    }
+
+    #[test]
+    fn given_bad_arguments_when_executed_then_errors() {
+        let (index, databases) = server();
+        let run = |words: &[&str]| execute_command(&request(words), &index, &databases);
+        assert!(!is_long_running(&request(&["DEBUG", "OBJECT", "key"])));
+        assert_eq!(run(&["DEBUG", "SLEEP", "soon"]).unwrap_err(), ExecutionError::NotAFloat);
+        assert_eq!(run(&["DEBUG", "SLEEP", "-1"]).unwrap_err(), ExecutionError::NotAFloat);
+        let error = run(&["DEBUG", "SLEEP"]).unwrap_err();
+        assert_eq!(error, ExecutionError::Request(ParserError::WrongNumberOfArguments("debug".to_string())));
+        let error = run(&["DEBUG", "OBJECT", "key"]).unwrap_err();
+        assert_eq!(error.get_message(), "unknown subcommand 'OBJECT'. Try DEBUG HELP.");
+        assert_eq!(run(&["DEBUG", "EXPORT", "a*", "b*"]).unwrap_err(), ExecutionError::Syntax);
+        assert_eq!(run(&["DEBUG", "IMPORT", "{}", "{}"]).unwrap_err(), ExecutionError::Syntax);
+        let error = run(&["DEBUG", "IMPORT"]).unwrap_err();
+        assert_eq!(error, ExecutionError::Request(ParserError::WrongNumberOfArguments("debug|import".to_string())));
+    }
+
+    #[test]
+    fn given_mixed_keyspace_when_exported_flushed_and_imported_then_keys_restored() {
+        let (index, databases) = server();
+        let run = |words: Vec<Bytes>| index.execute_command(&databases, &words).unwrap();
+        let binary = Bytes::from_static(b"\xff\x00bytes");
+        run(request(&["SET", "text", "value"]));
+        run(vec![Bytes::from("SET"), Bytes::from("binary"), binary.clone()]);
+        run(vec![Bytes::from("RPUSH"), Bytes::from("list"), Bytes::from("a"), binary.clone(), Bytes::from("c")]);
+        run(vec![Bytes::from("HSET"), Bytes::from("hash"), Bytes::from("z"), Bytes::from("last"), Bytes::from("a"), binary.clone()]);
+
+        let Value::BulkString(document) = execute_command(&request(&["DEBUG", "EXPORT"]), &index, &databases).unwrap() else {
+            panic!("EXPORT didn't reply with a bulk string");
+        };
+        let exported: serde_json::Value = serde_json::from_slice(&document).unwrap();
+        assert_eq!(exported["version"], 2);
+        assert_eq!(exported["keys"][0], json!({ "key": "binary", "type": "string", "ttl": -1, "value": { "base64": "/wBieXRlcw==" } }));
+        assert_eq!(exported["keys"][1]["fields"], json!(["a", { "base64": "/wBieXRlcw==" }, "z", "last"]));
+        assert_eq!(exported["keys"][2]["elements"], json!(["a", { "base64": "/wBieXRlcw==" }, "c"]));
+        assert_eq!(exported["keys"][3]["value"], "value");
+
+        run(request(&["FLUSHDB"]));
+        assert_eq!(run(request(&["DBSIZE"])), Value::Integer(0));
+        let import = vec![Bytes::from("DEBUG"), Bytes::from("IMPORT"), document];
+        let counts = |created, skipped| {
+            Value::Map(vec![
+                (Value::BulkString(Bytes::from("created")), Value::Integer(created)),
+                (Value::BulkString(Bytes::from("skipped")), Value::Integer(skipped)),
+                (Value::BulkString(Bytes::from("failed")), Value::Integer(0)),
+            ])
+        };
+        assert_eq!(execute_command(&import, &index, &databases).unwrap(), counts(4, 0));
+
+        assert_eq!(run(request(&["GET", "text"])), Value::BulkString(Bytes::from("value")));
+        assert_eq!(run(request(&["GET", "binary"])), Value::BulkString(binary.clone()));
+        assert_eq!(run(request(&["LLEN", "list"])), Value::Integer(3));
+        assert_eq!(run(request(&["LINDEX", "list", "1"])), Value::BulkString(binary.clone()));
+        assert_eq!(run(request(&["LINDEX", "list", "2"])), Value::BulkString(Bytes::from("c")));
+        assert_eq!(run(request(&["HGET", "hash", "a"])), Value::BulkString(binary));
+        assert_eq!(run(request(&["HGET", "hash", "z"])), Value::BulkString(Bytes::from("last")));
+
+        // keys that already exist are left alone
+        run(request(&["SET", "text", "changed"]));
+        assert_eq!(execute_command(&import, &index, &databases).unwrap(), counts(0, 4));
+        assert_eq!(run(request(&["GET", "text"])), Value::BulkString(Bytes::from("changed")));
+    }
+
+    #[test]
+    fn given_pattern_when_exported_then_only_matching_keys_written() {
+        let (index, databases) = server();
+        for key in ["user:1", "user:2", "session:1"] {
+            index.execute_command(&databases, &request(&["SET", key, "v"])).unwrap();
+        }
+        let reply = execute_command(&request(&["DEBUG", "EXPORT", "user:*"]), &index, &databases).unwrap();
+        let Value::BulkString(document) = reply else { panic!("EXPORT replied {:?}", reply) };
+        let exported: serde_json::Value = serde_json::from_slice(&document).unwrap();
+        let keys: Vec<_> = exported["keys"].as_array().unwrap().iter().map(|key| key["key"].as_str().unwrap()).collect();
+        assert_eq!(keys, ["user:1", "user:2"]);
+    }
+
+    #[test]
+    fn given_keys_with_ttls_when_exported_and_imported_then_ttls_kept_and_expired_left_out() {
+        let (index, databases) = server();
+        let run = |words: &[&str]| index.execute_command(&databases, &request(words)).unwrap();
+        run(&["SETEX", "string", "100", "v"]);
+        run(&["RPUSH", "list", "a"]);
+        run(&["EXPIRE", "list", "50"]);
+        run(&["SET", "expired", "v"]);
+        index.set_deadline("expired", Instant::now());
+
+        let Value::BulkString(document) = execute_command(&request(&["DEBUG", "EXPORT"]), &index, &databases).unwrap() else {
+            panic!("EXPORT didn't reply with a bulk string");
+        };
+        let exported: serde_json::Value = serde_json::from_slice(&document).unwrap();
+        let ttls: Vec<_> = exported["keys"].as_array().unwrap().iter().map(|key| (key["key"].clone(), key["ttl"].clone())).collect();
+        assert_eq!(ttls, [(json!("list"), json!(50)), (json!("string"), json!(100))]);
+
+        run(&["FLUSHDB"]);
+        execute_command(&[Bytes::from("DEBUG"), Bytes::from("IMPORT"), document], &index, &databases).unwrap();
+        assert_eq!(run(&["TTL", "string"]), Value::Integer(100));
+        assert_eq!(run(&["TTL", "list"]), Value::Integer(50));
+    }
+
+    #[test]
+    fn given_hash_fields_with_ttls_when_exported_and_imported_then_ttls_kept_and_expired_fields_left_out() {
+        let (_, databases) = fake_clock::databases();
+        let index = Index::new();
+        let run = |words: &[&str]| index.execute_command(&databases, &request(words)).unwrap();
+        run(&["HSET", "session", "a", "1", "b", "2", "c", "3"]);
+        run(&["HPEXPIRE", "session", "5000", "FIELDS", "1", "a"]);
+        run(&["HPEXPIRE", "session", "100", "FIELDS", "1", "b"]);
+        run(&["HSET", "gone", "x", "1"]);
+        run(&["HPEXPIRE", "gone", "100", "FIELDS", "1", "x"]);
+
+        // expired, but not yet taken out by a command or the sweeper
+        fake_clock::advance(100);
+        assert_eq!(run(&["KEYS", "*"]), Value::Array(vec![Value::BulkString(Bytes::from("session"))]));
+        let Value::BulkString(document) = execute_command(&request(&["DEBUG", "EXPORT"]), &index, &databases).unwrap() else {
+            panic!("EXPORT didn't reply with a bulk string");
+        };
+        let exported: serde_json::Value = serde_json::from_slice(&document).unwrap();
+        assert_eq!(
+            exported["keys"],
+            json!([{ "key": "session", "type": "hash", "ttl": -1, "fields": ["a", "1", "c", "3"], "field_ttls": [{ "field": "a", "pttl": 4900 }] }])
+        );
+
+        run(&["FLUSHDB"]);
+        execute_command(&[Bytes::from("DEBUG"), Bytes::from("IMPORT"), document], &index, &databases).unwrap();
+        assert_eq!(run(&["EXISTS", "gone"]), Value::Integer(0));
+        let pttls = run(&["HPTTL", "session", "FIELDS", "3", "a", "b", "c"]);
+        assert_eq!(pttls, Value::Array(vec![Value::Integer(4900), Value::Integer(-2), Value::Integer(-1)]));
+    }
+
+    #[test]
+    fn given_file_when_imported_then_bad_keys_counted_as_failed() {
+        let (index, databases) = server();
+        let document = json!({ "version": 1, "keys": [
+            { "key": "good", "type": "string", "ttl": -1, "value": "v" },
+            { "key": "hash", "type": "hash", "ttl": -1, "fields": {} },
+            { "key": "empty", "type": "list", "ttl": -1, "elements": [] },
+            { "key": "broken", "type": "string", "ttl": -1, "value": { "base64": "not base64!" } },
+            { "key": "expiring", "type": "hash", "ttl": -1, "fields": ["a", "1"], "field_ttls": [{ "field": "a", "pttl": 0 }] },
+        ]});
+        let path = std::env::temp_dir().join(format!("debug-import-{}.json", std::process::id()));
+        std::fs::write(&path, document.to_string()).unwrap();
+        let reply = execute_command(&request(&["DEBUG", "IMPORT", "file", path.to_str().unwrap()]), &index, &databases);
+        std::fs::remove_file(&path).unwrap();
+        let Value::Map(counts) = reply.unwrap() else { panic!("IMPORT didn't reply with a map") };
+        let counts: Vec<_> = counts.into_iter().map(|(_, count)| count).collect();
+        assert_eq!(counts, [Value::Integer(1), Value::Integer(0), Value::Integer(4)]);
+        assert_eq!(index.key_count(), 1);
+
+        let run = |document: &str| execute_command(&request(&["DEBUG", "IMPORT", document]), &index, &databases).unwrap_err();
+        assert!(run("{not json").get_message().starts_with("invalid export document: "));
+        assert_eq!(run(r#"{"version": 3, "keys": []}"#).get_message(), "unsupported export version 3");
+        assert_eq!(run(r#"{"version": 1}"#).get_message(), "invalid export document: no keys");
+        let error = execute_command(&request(&["DEBUG", "IMPORT", "FILE", "/no/such/file"]), &index, &databases).unwrap_err();
+        assert!(error.get_message().starts_with("unable to read '/no/such/file': "), "{}", error);
+    }
+}
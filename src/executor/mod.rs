@@ -0,0 +1,197 @@
+// What the index needs from each type of value, so a new type is added by writing an executor
+// for it and registering it in Databases, without the index having to know it exists.
+
+use crate::commands::{ExecutionError, ParserError};
+use crate::index::{CommandCompleted, CommandIdentifier, IndexImpactOnCompletion, Key, KeyType};
+use bytes::Bytes;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+pub(crate) trait CommandExecutor: Send + Sync {
+    // The commands it runs, in upper case
+    fn supported_commands(&self) -> &'static [&'static str];
+
+    // The type of the keys its commands create and expect
+    fn key_type(&self) -> KeyType;
+
+    // Checks the request is well formed and works out which keys it uses, before anything is locked
+    fn build_command(&self, command: &[Bytes]) -> Result<CommandIdentifier, ParserError>;
+
+    // Runs the command, with its keys locked and their types already checked
+    fn execute(&self, command: &CommandIdentifier) -> Result<CommandCompleted, ExecutionError>;
+
+    // Removes the key and its value, returning how many keys went
+    fn delete(&self, key: &str) -> u16;
+
+    // Moves the value to the new key, returning whether there was one
+    fn rename(&self, old_key: &str, new_key: &Key) -> bool;
+
+    // Every key it holds
+    fn keys(&self) -> Vec<Key>;
+
+    // Calls `visit` with every key and a view of its value, without copying either. The
+    // executor's storage stays locked while `visit` runs, so it must not use the executor itself.
+    fn for_each_entry(&self, visit: &mut dyn FnMut(&str, EntryView));
+
+    // Up to a sweep's batch of the keys holding parts that expire on their own, such as hash
+    // fields given a time to live, whose time has come
+    fn due_subkeys(&self) -> Vec<Key> {
+        Vec::new()
+    }
+
+    // Takes out the parts of the key's value whose time has come, with the key locked, returning
+    // Delete when that left nothing of it
+    fn expire_subkeys(&self, _key: &Key) -> IndexImpactOnCompletion {
+        IndexImpactOnCompletion::NoImpact
+    }
+}
+
+// A value as it is held, for looking at without copying it
+pub(crate) enum EntryView<'a> {
+    String(&'a Bytes),
+    List(&'a VecDeque<Bytes>),
+    Hash(HashView<'a>),
+}
+
+// A hash's fields as they are held, leaving out any whose time has come that no command or sweep
+// has taken out yet, so they are as invisible here as they are to commands
+pub(crate) struct HashView<'a> {
+    fields: &'a HashMap<Bytes, Bytes>,
+    deadlines: &'a HashMap<Bytes, Instant>,
+    now: Instant,
+}
+
+impl<'a> HashView<'a> {
+    pub fn new(fields: &'a HashMap<Bytes, Bytes>, deadlines: &'a HashMap<Bytes, Instant>, now: Instant) -> HashView<'a> {
+        HashView { fields, deadlines, now }
+    }
+
+    // Each field with its value, and the time it has left if it was given a time to live
+    pub fn iter(&self) -> impl Iterator<Item = (&'a Bytes, &'a Bytes, Option<Duration>)> + '_ {
+        self.fields.iter().filter_map(|(field, value)| match self.deadlines.get(field) {
+            Some(deadline) if *deadline <= self.now => None,
+            Some(deadline) => Some((field, value, Some(*deadline - self.now))),
+            None => Some((field, value, None)),
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.iter().count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.iter().next().is_none()
+    }
+}
+
+impl EntryView<'_> {
+    // The bytes in a string, the elements in a list, or the fields in a hash
+    #[allow(dead_code)] // for SCAN's TYPE and saving the database, neither of which exist yet
+    pub fn len(&self) -> usize {
+        match self {
+            EntryView::String(value) => value.len(),
+            EntryView::List(elements) => elements.len(),
+            EntryView::Hash(fields) => fields.len(),
+        }
+    }
+}
+
+// The executors, looked up by the commands they run or the type of the keys they hold
+pub(crate) struct Executors {
+    executors: Vec<Arc<dyn CommandExecutor>>,
+}
+
+impl Executors {
+    pub fn new(executors: Vec<Arc<dyn CommandExecutor>>) -> Executors {
+        Executors { executors }
+    }
+
+    pub fn for_command(&self, command: &[u8]) -> Option<&dyn CommandExecutor> {
+        self.executors
+            .iter()
+            .find(|executor| executor.supported_commands().iter().any(|name| name.as_bytes().eq_ignore_ascii_case(command)))
+            .map(|executor| executor.as_ref())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &dyn CommandExecutor> {
+        self.executors.iter().map(|executor| executor.as_ref())
+    }
+
+    pub fn for_type(&self, key_type: &KeyType) -> Option<&dyn CommandExecutor> {
+        self.executors
+            .iter()
+            .find(|executor| executor.key_type() == *key_type)
+            .map(|executor| executor.as_ref())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::{check_arity, request, text_argument};
+    use crate::config::Config;
+    use crate::controller::Databases;
+    use crate::index::IndexImpactOnCompletion::Add;
+    use crate::index::{Index, LockType};
+    use crate::resp::Value;
+    use std::sync::Mutex;
+
+    // Remembers the keys it was given, and replies with how many it has
+    #[derive(Default)]
+    struct FakeExecutor {
+        keys: Mutex<Vec<Key>>,
+    }
+
+    impl CommandExecutor for FakeExecutor {
+        fn supported_commands(&self) -> &'static [&'static str] {
+            &["FAKEADD"]
+        }
+        fn key_type(&self) -> KeyType {
+            KeyType::List
+        }
+        fn build_command(&self, command: &[Bytes]) -> Result<CommandIdentifier, ParserError> {
+            check_arity(command, 2)?;
+            let key = text_argument(&command[1])?.to_string();
+            Ok(CommandIdentifier::new(key, "FAKEADD", Vec::new(), KeyType::List, LockType::Write))
+        }
+        fn execute(&self, command: &CommandIdentifier) -> Result<CommandCompleted, ExecutionError> {
+            let mut keys = self.keys.lock().unwrap();
+            keys.push(Key::clone(command.get_target()));
+            Ok(CommandCompleted::new(command.get_target(), KeyType::List, Add, Value::Integer(keys.len() as i64)))
+        }
+        fn delete(&self, key: &str) -> u16 {
+            let mut keys = self.keys.lock().unwrap();
+            let before = keys.len();
+            keys.retain(|existing| &**existing != key);
+            (before - keys.len()) as u16
+        }
+        fn rename(&self, _old_key: &str, _new_key: &Key) -> bool {
+            false
+        }
+        fn keys(&self) -> Vec<Key> {
+            self.keys.lock().unwrap().clone()
+        }
+        fn for_each_entry(&self, _visit: &mut dyn FnMut(&str, EntryView)) {}
+    }
+
+    #[test]
+    fn given_fake_executor_registered_when_its_command_sent_then_run_and_key_indexed() {
+        let fake = Arc::new(FakeExecutor::default());
+        let databases = Databases::new(&Config::default());
+        let executors = Executors::new(vec![databases.string.clone(), fake.clone()]);
+        let databases = Arc::new(Databases { executors, ..databases });
+        let index = Index::new();
+
+        assert_eq!(index.execute_command(&databases, &request(&["fakeadd", "first"])).unwrap(), Value::Integer(1));
+        assert_eq!(index.execute_command(&databases, &request(&["FAKEADD", "second"])).unwrap(), Value::Integer(2));
+
+        assert_eq!(fake.keys(), [Key::from("first"), Key::from("second")]);
+        assert_eq!(index.execute_command(&databases, &request(&["EXISTS", "first"])).unwrap(), Value::Integer(1));
+        let error = index.execute_command(&databases, &request(&["GET", "first"])).unwrap_err();
+        assert_eq!(error, ExecutionError::WrongType);
+        // the list executor wasn't registered
+        let error = index.execute_command(&databases, &request(&["LLEN", "first"])).unwrap_err();
+        assert!(error.get_message().starts_with("unknown command 'LLEN'"), "{}", error.get_message());
+    }
+}
@@ -0,0 +1,176 @@
+// When keys expire, whatever their type. The index keeps each key's deadline with the rest of what
+// it knows of the key, removing the key before a command uses it if its time has come, and a
+// sweeper thread takes out the keys that have expired every so often, so the ones no command asks
+// for again don't keep their memory for ever.
+
+use crate::controller::Databases;
+use crate::index::{Index, Key};
+use std::collections::BTreeSet;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// How often the sweeper looks for expired keys, as Redis's default hz of 10
+const SWEEP_INTERVAL: Duration = Duration::from_millis(100);
+
+// How long a sweep may go on for, so a great many keys expiring at once are taken out over a few
+// sweeps; Redis's active expiry likewise takes no more than a quarter of each interval
+pub(crate) const SWEEP_TIME_LIMIT: Duration = Duration::from_millis(25);
+
+// How many expired keys are taken from a shard's store at a time, so it isn't locked for long
+pub(crate) const SWEEP_BATCH: usize = 20;
+
+// What a time to live is given in
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TimeUnit {
+    Seconds,
+    Milliseconds,
+}
+
+impl TimeUnit {
+    // The amount in milliseconds, or None when that is too many to hold
+    pub fn millis(self, amount: i64) -> Option<i64> {
+        match self {
+            TimeUnit::Seconds => amount.checked_mul(1000),
+            TimeUnit::Milliseconds => Some(amount),
+        }
+    }
+}
+
+// The milliseconds from now until a Unix time in milliseconds, negative when it has passed
+pub fn millis_until(unix_millis: i64) -> i64 {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since| since.as_millis() as i64);
+    unix_millis.saturating_sub(now)
+}
+
+// The deadline that many milliseconds from now, or None when it is too far off for the clock
+pub fn deadline_after(millis: u64) -> Option<Instant> {
+    Instant::now().checked_add(Duration::from_millis(millis))
+}
+
+// The milliseconds left before the deadline
+pub fn millis_left(deadline: Instant) -> i64 {
+    deadline.saturating_duration_since(Instant::now()).as_millis() as i64
+}
+
+// The whole seconds left, rounded as Redis's TTL does
+pub fn seconds_left(deadline: Instant) -> i64 {
+    (millis_left(deadline) + 500) / 1000
+}
+
+// The deadlines of one shard's keys, soonest first, so the ones due are found without looking at
+// the rest. The index entry of each key holds the same deadline, which is what commands look at;
+// this is only for the sweeper, and is kept in step with the entries under the shard's lock. The
+// hash executor keeps one as well, of the soonest deadline of the fields of each hash.
+#[derive(Debug, Default)]
+pub struct ExpirationStore {
+    in_order: BTreeSet<(Instant, Key)>,
+}
+
+impl ExpirationStore {
+    pub fn insert(&mut self, key: &Key, deadline: Instant) {
+        self.in_order.insert((deadline, Key::clone(key)));
+    }
+
+    // The deadline must be the one the key was given
+    pub fn remove(&mut self, key: &Key, deadline: Instant) {
+        self.in_order.remove(&(deadline, Key::clone(key)));
+    }
+
+    pub fn clear(&mut self) {
+        self.in_order.clear();
+    }
+
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.in_order.len()
+    }
+
+    // Up to SWEEP_BATCH of the keys whose time has come by `now`, the longest expired first. They
+    // keep their deadlines until they are removed.
+    pub fn due(&self, now: Instant) -> Vec<Key> {
+        self.in_order
+            .iter()
+            .take_while(|(deadline, _)| *deadline <= now)
+            .take(SWEEP_BATCH)
+            .map(|(_, key)| Key::clone(key))
+            .collect()
+    }
+}
+
+// Takes out the expired keys every SWEEP_INTERVAL on a thread of its own, so they go whether or
+// not any client is sending commands. Dropping it stops the thread and waits for it to finish.
+pub struct Sweeper {
+    stop: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Sweeper {
+    pub fn start(index: &Arc<Index>, databases: &Arc<Databases>) -> Sweeper {
+        let (stop, stopped) = mpsc::channel::<()>();
+        let (index, databases) = (Arc::clone(index), Arc::clone(databases));
+        let thread = thread::Builder::new()
+            .name("expiry-sweeper".to_string())
+            .spawn(move || {
+                while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(SWEEP_INTERVAL) {
+                    index.expire_due(&databases);
+                }
+            })
+            .expect("Unable to start the thread removing expired keys");
+        Sweeper { stop: Some(stop), thread: Some(thread) }
+    }
+}
+
+impl Drop for Sweeper {
+    fn drop(&mut self) {
+        // the thread stops as soon as its end of the channel is closed
+        self.stop.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn given_deadlines_when_inserted_and_removed_then_expired_ones_due() {
+        let mut store = ExpirationStore::default();
+        let (past, future) = (Key::from("past"), Key::from("future"));
+        let now = Instant::now();
+        assert!(store.due(now).is_empty());
+
+        store.insert(&past, now);
+        store.insert(&future, now + Duration::from_secs(60));
+        assert_eq!(store.len(), 2);
+        assert_eq!(store.due(now), [Key::clone(&past)]);
+
+        store.remove(&past, now);
+        assert!(store.due(now).is_empty());
+        store.clear();
+        assert_eq!(store.len(), 0);
+    }
+
+    #[test]
+    fn given_more_keys_due_than_a_batch_when_due_then_longest_expired_first() {
+        let mut store = ExpirationStore::default();
+        let start = Instant::now() - Duration::from_secs(1);
+        for offset in (0..SWEEP_BATCH as u64 + 5).rev() {
+            store.insert(&Key::from(offset.to_string()), start + Duration::from_millis(offset));
+        }
+        let expected: Vec<Key> = (0..SWEEP_BATCH).map(|offset| Key::from(offset.to_string())).collect();
+        assert_eq!(store.due(Instant::now()), expected);
+    }
+
+    #[test]
+    fn given_amount_when_converted_then_overflow_is_none() {
+        assert_eq!(TimeUnit::Seconds.millis(3), Some(3000));
+        assert_eq!(TimeUnit::Milliseconds.millis(3), Some(3));
+        assert_eq!(TimeUnit::Seconds.millis(i64::MAX), None);
+        assert!(millis_until(0) < 0);
+        assert_eq!(millis_until(i64::MIN), i64::MIN);
+    }
+}
@@ -0,0 +1,126 @@
+// Redis style glob matching, used by pattern subscriptions
+// Supports * (any run of bytes), ? (a single byte), [abc] / [^abc] / [a-z] classes and \ escapes
+
+pub fn matches(pattern: &[u8], text: &[u8]) -> bool {
+    let mut pattern_position = 0;
+    let mut text_position = 0;
+    // where to resume if the bytes after the most recent '*' stop matching
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while text_position < text.len() {
+        if pattern_position < pattern.len() {
+            match pattern[pattern_position] {
+                b'*' => {
+                    pattern_position += 1;
+                    backtrack = Some((pattern_position, text_position));
+                    continue;
+                }
+                b'?' => {
+                    pattern_position += 1;
+                    text_position += 1;
+                    continue;
+                }
+                b'[' => {
+                    if let Some((matched, next)) = match_class(pattern, pattern_position, text[text_position]) {
+                        if matched {
+                            pattern_position = next;
+                            text_position += 1;
+                            continue;
+                        }
+                    } else if text[text_position] == b'[' {
+                        // an unterminated class is treated as a literal '['
+                        pattern_position += 1;
+                        text_position += 1;
+                        continue;
+                    }
+                }
+                b'\\' if pattern_position + 1 < pattern.len() => {
+                    if pattern[pattern_position + 1] == text[text_position] {
+                        pattern_position += 2;
+                        text_position += 1;
+                        continue;
+                    }
+                }
+                literal => {
+                    if literal == text[text_position] {
+                        pattern_position += 1;
+                        text_position += 1;
+                        continue;
+                    }
+                }
+            }
+        }
+        // mismatch, so let the last '*' swallow one more byte and try again
+        match backtrack {
+            Some((star_pattern, star_text)) => {
+                pattern_position = star_pattern;
+                text_position = star_text + 1;
+                backtrack = Some((star_pattern, star_text + 1));
+            }
+            None => return false,
+        }
+    }
+    // the text is used up, so only trailing '*'s may remain in the pattern
+    pattern[pattern_position..].iter().all(|&byte| byte == b'*')
+}
+
+// Matches a byte against the [...] class starting at `start`, returning whether it matched
+// and the position after the closing ']', or None if the class is never closed
+fn match_class(pattern: &[u8], start: usize, byte: u8) -> Option<(bool, usize)> {
+    let mut position = start + 1;
+    let negated = pattern.get(position) == Some(&b'^');
+    if negated {
+        position += 1;
+    }
+    let mut matched = false;
+    while position < pattern.len() && pattern[position] != b']' {
+        if pattern[position] == b'\\' && position + 1 < pattern.len() {
+            matched |= pattern[position + 1] == byte;
+            position += 2;
+        } else if position + 2 < pattern.len() && pattern[position + 1] == b'-' && pattern[position + 2] != b']' {
+            let (low, high) = (pattern[position].min(pattern[position + 2]), pattern[position].max(pattern[position + 2]));
+            matched |= low <= byte && byte <= high;
+            position += 3;
+        } else {
+            matched |= pattern[position] == byte;
+            position += 1;
+        }
+    }
+    if position >= pattern.len() {
+        return None;
+    }
+    Some((matched != negated, position + 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn given_wildcards_when_matching_then_star_and_question_mark_apply() {
+        assert!(matches(b"*", b""));
+        assert!(matches(b"news.*", b"news.sports"));
+        assert!(matches(b"h?llo", b"hello"));
+        assert!(matches(b"*o*o*", b"foobar"));
+        assert!(!matches(b"h?llo", b"hllo"));
+        assert!(!matches(b"news.*", b"weather.today"));
+    }
+
+    #[test]
+    fn given_character_classes_when_matching_then_ranges_and_negation_apply() {
+        assert!(matches(b"h[ae]llo", b"hallo"));
+        assert!(!matches(b"h[ae]llo", b"hillo"));
+        assert!(matches(b"h[^e]llo", b"hallo"));
+        assert!(!matches(b"h[^e]llo", b"hello"));
+        assert!(matches(b"key[0-9]", b"key7"));
+        assert!(!matches(b"key[0-9]", b"keyx"));
+    }
+
+    #[test]
+    fn given_escaped_characters_when_matching_then_treated_literally() {
+        assert!(matches(b"what\\?", b"what?"));
+        assert!(!matches(b"what\\?", b"whatx"));
+        assert!(matches(b"a\\*b", b"a*b"));
+        assert!(matches(b"[abc", b"[abc"));
+    }
+}
@@ -0,0 +1,1005 @@
+use crate::executor::{CommandExecutor, EntryView, HashView};
+use crate::commands::options::{self, flag, OptionSpec};
+use crate::commands::table;
+use crate::commands::{integer_argument, stored_value, syntax_error, wrong_number_of_arguments, wrong_type, ExecutionError, ParserError};
+use crate::expiration::{ExpirationStore, TimeUnit};
+use crate::index::IndexImpactOnCompletion::{self, Add, Delete, NoImpact};
+use crate::index::{CommandCompleted, CommandIdentifier, Key, KeyType};
+use crate::memory;
+use crate::resp::Value;
+use bytes::Bytes;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, RandomState};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+const REDIS_HASH_COMMANDS: [&str; 13] = [
+    "HSET", "HGET", "HDEL", "HEXISTS", "HGETALL", "HKEYS", "HVALS", "HLEN", "HEXPIRE", "HPEXPIRE", "HTTL", "HPTTL", "HPERSIST",
+];
+
+// HEXPIRE's conditions, of which only one may be given
+const HEXPIRE_OPTIONS: [OptionSpec; 4] =
+    [flag("NX", &["XX", "GT", "LT"]), flag("XX", &["NX", "GT", "LT"]), flag("GT", &["NX", "XX", "LT"]), flag("LT", &["NX", "XX", "GT"])];
+
+const SHARDS: usize = 16;
+
+// The hashes, laid out as the lists are: split into shards by a hash of their keys, and each
+// behind a lock of its own, so a command on one large hash holds up only the commands on that hash.
+// A shard is only locked long enough to find, add or take out a hash, and a hash taken out is
+// marked removed, so a command that found it just before then looks for the key again.
+//  - a field given a time to live is taken out by the first command on its hash once its time has
+//    come, so no command sees it, and by the sweeper if none comes
+//  - the store of the soonest deadline of each hash is only locked while a hash is, or alone
+pub(crate) struct HashExecutor {
+    shards: [Mutex<HashMap<Key, Arc<Mutex<Hash>>>>; SHARDS],
+    hasher: RandomState,
+    // the bytes held, counted as fields are set and deleted
+    used_memory: AtomicUsize,
+    // each hash with fields that expire, by the soonest of their deadlines, for the sweeper
+    expiring: Mutex<ExpirationStore>,
+    // what the fields' deadlines are measured against, which tests can move on
+    clock: fn() -> Instant,
+}
+
+#[derive(Default)]
+struct Hash {
+    fields: HashMap<Bytes, Bytes>,
+    // the deadlines of the fields given one
+    deadlines: HashMap<Bytes, Instant>,
+    // the soonest of them, as it is in the executor's store
+    soonest: Option<Instant>,
+    removed: bool,
+}
+
+impl Hash {
+    // Takes out the field, and its deadline if it had one, returning the bytes it held
+    fn remove(&mut self, field: &[u8]) -> Option<usize> {
+        let (field, value) = self.fields.remove_entry(field)?;
+        self.deadlines.remove(&field);
+        Some(memory::element_size(&field) + memory::element_size(&value))
+    }
+
+    // Takes out the fields whose time has come, returning the bytes they held
+    fn expire_fields(&mut self, now: Instant) -> usize {
+        if self.deadlines.is_empty() {
+            return 0;
+        }
+        let due: Vec<Bytes> = self.deadlines.iter().filter(|(_, deadline)| **deadline <= now).map(|(field, _)| Bytes::clone(field)).collect();
+        due.iter().filter_map(|field| self.remove(field)).sum()
+    }
+}
+
+impl HashExecutor {
+    pub(crate) fn new() -> HashExecutor {
+        HashExecutor {
+            shards: std::array::from_fn(|_| Mutex::new(HashMap::new())),
+            hasher: RandomState::new(),
+            used_memory: AtomicUsize::new(0),
+            expiring: Mutex::new(ExpirationStore::default()),
+            clock: Instant::now,
+        }
+    }
+
+    pub fn build_command(command: &[Bytes]) -> Result<CommandIdentifier, ParserError> {
+        // support syntax: HSET name field value [field value ...]
+        //                 HGET name field
+        //                 HDEL name field [field ...]
+        //                 HEXISTS name field
+        //                 HGETALL name
+        //                 HKEYS name
+        //                 HVALS name
+        //                 HLEN name
+        //                 HEXPIRE name seconds [NX | XX | GT | LT] FIELDS numfields field [field ...]
+        //                 HPEXPIRE name milliseconds [NX | XX | GT | LT] FIELDS numfields field [field ...]
+        //                 HTTL name FIELDS numfields field [field ...]
+        //                 HPTTL name FIELDS numfields field [field ...]
+        //                 HPERSIST name FIELDS numfields field [field ...]
+
+        // The table has checked the arity, and says where the key is and how it is locked
+        let spec = table::validate(command)?.ok_or_else(|| ParserError::new("Unsupported Hash command type"))?;
+        let params: Vec<Bytes> = match spec.name {
+            "HSET" => {
+                // a value for every field
+                if !(command.len() - 2).is_multiple_of(2) {
+                    return Err(wrong_number_of_arguments(&command[0]));
+                }
+                command[2..].to_vec()
+            }
+            "HGET" | "HEXISTS" => vec![command[2].clone()],
+            "HDEL" => command[2..].to_vec(),
+            "HGETALL" | "HKEYS" | "HVALS" | "HLEN" => Vec::new(),
+            "HEXPIRE" | "HPEXPIRE" => {
+                let unit = if spec.name == "HEXPIRE" { TimeUnit::Seconds } else { TimeUnit::Milliseconds };
+                let time = std::str::from_utf8(&command[2]).ok().and_then(|time| time.parse::<i64>().ok()).ok_or(ParserError::NotAnInteger)?;
+                if time < 0 {
+                    return Err(ParserError::new("invalid expire time, must be >= 0"));
+                }
+                let millis = unit
+                    .millis(time)
+                    .ok_or_else(|| ParserError::new(&format!("invalid expire time in '{}' command", spec.name.to_lowercase())))?;
+                let (conditions, rest) = options::parse(&HEXPIRE_OPTIONS, &command[3..])?;
+                // the time in milliseconds, the condition or nothing, then the fields
+                let condition = conditions.names().next().unwrap_or_default();
+                let mut params = vec![Bytes::from(millis.to_string()), Bytes::from_static(condition.as_bytes())];
+                params.extend_from_slice(fields_argument(rest)?);
+                params
+            }
+            "HTTL" | "HPTTL" | "HPERSIST" => fields_argument(&command[2..])?.to_vec(),
+            _ => return Err(ParserError::new("Unsupported Hash command type")),
+        };
+
+        CommandIdentifier::from_spec(spec, command, params)
+    }
+
+    pub fn execute_command(
+        &self,
+        command: &CommandIdentifier,
+    ) -> Result<CommandCompleted, ExecutionError> {
+        let target = command.get_target();
+        match command.get_action() {
+            "HSET" => {
+                // only the fields that weren't there before are counted
+                let (added, index_impact) = self
+                    .update(target, true, |hash| {
+                        let mut added = 0;
+                        for pair in command.get_params().chunks(2) {
+                            let (field, value) = (stored_value(&pair[0]), stored_value(&pair[1]));
+                            self.used_memory.fetch_add(memory::element_size(&value), Ordering::Relaxed);
+                            match hash.fields.get_mut(&field) {
+                                Some(replaced) => {
+                                    self.used_memory.fetch_sub(memory::element_size(replaced), Ordering::Relaxed);
+                                    *replaced = value;
+                                    // a field given a new value loses its time to live, as in Redis
+                                    hash.deadlines.remove(&field);
+                                }
+                                None => {
+                                    self.used_memory.fetch_add(memory::element_size(&field), Ordering::Relaxed);
+                                    hash.fields.insert(field, value);
+                                    added += 1;
+                                }
+                            }
+                        }
+                        added
+                    })
+                    .expect("a hash is added when there is none");
+
+                Ok(CommandCompleted::new(
+                    target,
+                    KeyType::Hash,
+                    index_impact,
+                    Value::Integer(added),
+                ))
+            }
+            "HGET" => {
+                let (value, index_impact) = self.read(target, |hash| hash.fields.get(&command.get_params()[0]).cloned());
+
+                Ok(CommandCompleted::new(
+                    target,
+                    KeyType::Hash,
+                    index_impact,
+                    Value::bulk_or_null(value.flatten()),
+                ))
+            }
+            "HDEL" => {
+                let deleted = self.update(target, false, |hash| {
+                    let mut removed = 0;
+                    for field in command.get_params() {
+                        if let Some(size) = hash.remove(field) {
+                            self.used_memory.fetch_sub(size, Ordering::Relaxed);
+                            removed += 1;
+                        }
+                    }
+                    removed
+                });
+                let (removed, index_impact) = deleted.unwrap_or((0, NoImpact));
+
+                Ok(CommandCompleted::new(
+                    target,
+                    KeyType::Hash,
+                    index_impact,
+                    Value::Integer(removed),
+                ))
+            }
+            "HEXISTS" => {
+                let (exists, index_impact) = self.read(target, |hash| hash.fields.contains_key(&command.get_params()[0]));
+
+                Ok(CommandCompleted::new(
+                    target,
+                    KeyType::Hash,
+                    index_impact,
+                    Value::Integer(exists.unwrap_or(false) as i64),
+                ))
+            }
+            "HGETALL" | "HKEYS" | "HVALS" => {
+                // in the order the fields are held, which is the same for each of them until the
+                // hash is changed
+                let bulk = |bytes: &Bytes| Value::BulkString(bytes.clone());
+                let (response, index_impact) = self.read(target, |hash| match command.get_action() {
+                    // an array of each field followed by its value, or a map in RESP3
+                    "HGETALL" => Value::Map(hash.fields.iter().map(|(field, value)| (bulk(field), bulk(value))).collect()),
+                    "HKEYS" => Value::Array(hash.fields.keys().map(bulk).collect()),
+                    _ => Value::Array(hash.fields.values().map(bulk).collect()),
+                });
+                let empty = || if command.get_action() == "HGETALL" { Value::Map(Vec::new()) } else { Value::Array(Vec::new()) };
+
+                Ok(CommandCompleted::new(
+                    target,
+                    KeyType::Hash,
+                    index_impact,
+                    response.unwrap_or_else(empty),
+                ))
+            }
+            "HLEN" => {
+                let (length, index_impact) = self.read(target, |hash| hash.fields.len());
+
+                Ok(CommandCompleted::new(
+                    target,
+                    KeyType::Hash,
+                    index_impact,
+                    Value::Integer(length.unwrap_or(0) as i64),
+                ))
+            }
+            "HEXPIRE" | "HPEXPIRE" => {
+                // for each field, -2 when there is no such field, 0 when the condition isn't met,
+                // 1 when its deadline is set, and 2 when a time of 0 deleted it there and then
+                let params = command.get_params();
+                let millis = integer_argument(&params[0])? as u64;
+                let name = if command.get_action() == "HEXPIRE" { "HEXPIRE" } else { "HPEXPIRE" };
+                let deadline = (self.clock)().checked_add(Duration::from_millis(millis)).ok_or(ExecutionError::InvalidExpireTime(name))?;
+                let (condition, fields) = (&params[1][..], &params[2..]);
+                let set = self.update(target, false, |hash| {
+                    let mut set_one = |field: &Bytes| {
+                        let Some((field, _)) = hash.fields.get_key_value(field) else {
+                            return -2;
+                        };
+                        // a field without a deadline counts as never expiring, as for EXPIRE
+                        let current = hash.deadlines.get(field).copied();
+                        let met = match condition {
+                            b"NX" => current.is_none(),
+                            b"XX" => current.is_some(),
+                            b"GT" => current.is_some_and(|current| deadline > current),
+                            b"LT" => current.is_none_or(|current| deadline < current),
+                            _ => true,
+                        };
+                        if !met {
+                            0
+                        } else if millis == 0 {
+                            let size = hash.remove(&Bytes::clone(field)).unwrap_or_default();
+                            self.used_memory.fetch_sub(size, Ordering::Relaxed);
+                            2
+                        } else {
+                            hash.deadlines.insert(Bytes::clone(field), deadline);
+                            1
+                        }
+                    };
+                    fields.iter().map(|field| Value::Integer(set_one(field))).collect()
+                });
+                let (replies, index_impact) = set.unwrap_or_else(|| (no_such_fields(fields), NoImpact));
+
+                Ok(CommandCompleted::new(
+                    target,
+                    KeyType::Hash,
+                    index_impact,
+                    Value::Array(replies),
+                ))
+            }
+            "HTTL" | "HPTTL" => {
+                // for each field, -2 when there is no such field, -1 when it never expires, else
+                // the seconds left, rounded as TTL's are, or for HPTTL the milliseconds
+                let now = (self.clock)();
+                let left = |deadline: Instant| {
+                    let millis = deadline.saturating_duration_since(now).as_millis() as i64;
+                    if command.get_action() == "HTTL" { (millis + 500) / 1000 } else { millis }
+                };
+                let fields = command.get_params();
+                let (ttls, index_impact) = self.read(target, |hash| {
+                    let ttl = |field: &Bytes| {
+                        if !hash.fields.contains_key(field) {
+                            return -2;
+                        }
+                        hash.deadlines.get(field).map_or(-1, |deadline| left(*deadline))
+                    };
+                    fields.iter().map(|field| Value::Integer(ttl(field))).collect()
+                });
+
+                Ok(CommandCompleted::new(
+                    target,
+                    KeyType::Hash,
+                    index_impact,
+                    Value::Array(ttls.unwrap_or_else(|| no_such_fields(fields))),
+                ))
+            }
+            "HPERSIST" => {
+                // for each field, -2 when there is no such field, -1 when it never expired, and 1
+                // when its deadline was taken away
+                let fields = command.get_params();
+                let persisted = self.update(target, false, |hash| {
+                    let mut persist = |field: &Bytes| {
+                        if !hash.fields.contains_key(field) {
+                            return -2;
+                        }
+                        if hash.deadlines.remove(field).is_some() { 1 } else { -1 }
+                    };
+                    fields.iter().map(|field| Value::Integer(persist(field))).collect()
+                });
+                let (replies, index_impact) = persisted.unwrap_or_else(|| (no_such_fields(fields), NoImpact));
+
+                Ok(CommandCompleted::new(
+                    target,
+                    KeyType::Hash,
+                    index_impact,
+                    Value::Array(replies),
+                ))
+            }
+            _ => Err(wrong_type()),
+        }
+    }
+
+    fn shard(&self, key: &str) -> MutexGuard<'_, HashMap<Key, Arc<Mutex<Hash>>>> {
+        let shard = self.hasher.hash_one(key) as usize % SHARDS;
+        self.shards[shard].lock().unwrap()
+    }
+
+    // Calls `read` with the key's hash, if it has one, holding only that hash's lock. Fields whose
+    // time has come are taken out first, so they aren't seen; if that leaves none, the key is
+    // deleted, and returned with the Delete for the index.
+    fn read<R>(&self, key: &Key, read: impl FnOnce(&Hash) -> R) -> (Option<R>, IndexImpactOnCompletion) {
+        match self.update(key, false, |hash| (!hash.fields.is_empty()).then(|| read(hash))) {
+            Some((result, impact)) => (result, impact),
+            None => (None, NoImpact),
+        }
+    }
+
+    // Calls `change` with the key's hash, adding an empty one first if there is none and `create`
+    // is set, and removes the hash if `change` leaves it empty. Fields whose time has come are
+    // taken out before `change` sees the hash. Returns what `change` did and whether the key was
+    // added or deleted, or None when there was no hash to change.
+    fn update<R>(&self, key: &Key, create: bool, change: impl FnOnce(&mut Hash) -> R) -> Option<(R, IndexImpactOnCompletion)> {
+        let mut change = Some(change);
+        loop {
+            let (entry, created) = {
+                let mut shard = self.shard(key);
+                match shard.get(key) {
+                    Some(entry) => (Arc::clone(entry), false),
+                    None if create => {
+                        let entry = Arc::new(Mutex::new(Hash::default()));
+                        shard.insert(Key::clone(key), Arc::clone(&entry));
+                        self.used_memory.fetch_add(memory::key_size(key, 0), Ordering::Relaxed);
+                        (entry, true)
+                    }
+                    None => return None,
+                }
+            };
+            let mut hash = entry.lock().unwrap();
+            if hash.removed {
+                continue;
+            }
+            if hash.fields.is_empty() && !create {
+                // just added by an HSET that hasn't set anything yet
+                return None;
+            }
+            let expired = hash.expire_fields((self.clock)());
+            self.used_memory.fetch_sub(expired, Ordering::Relaxed);
+            let result = change.take().expect("a hash is changed only once")(&mut hash);
+            self.reschedule(key, &mut hash);
+            let impact = match (created, hash.fields.is_empty()) {
+                (false, false) => NoImpact,
+                (true, false) => Add,
+                (created, true) => {
+                    hash.removed = true;
+                    let mut shard = self.shard(key);
+                    if shard.get(key).is_some_and(|found| Arc::ptr_eq(found, &entry)) {
+                        shard.remove(key);
+                    }
+                    self.used_memory.fetch_sub(memory::key_size(key, 0), Ordering::Relaxed);
+                    if created { NoImpact } else { Delete }
+                }
+            };
+            return Some((result, impact));
+        }
+    }
+
+    // Keeps the soonest of the hash's field deadlines in the store the sweeper looks at
+    fn reschedule(&self, key: &Key, hash: &mut Hash) {
+        let soonest = hash.deadlines.values().min().copied();
+        if soonest == hash.soonest {
+            return;
+        }
+        let mut expiring = self.expiring.lock().unwrap();
+        if let Some(replaced) = std::mem::replace(&mut hash.soonest, soonest) {
+            expiring.remove(key, replaced);
+        }
+        if let Some(soonest) = soonest {
+            expiring.insert(key, soonest);
+        }
+    }
+
+    // Marks a hash taken out of its shard as removed, and stops counting it, unless a command
+    // emptied and removed it first. Returns whether this removed it.
+    fn discard(&self, key: &str, entry: &Mutex<Hash>) -> bool {
+        let mut hash = entry.lock().unwrap();
+        if hash.removed {
+            return false;
+        }
+        hash.removed = true;
+        if let Some(soonest) = hash.soonest.take() {
+            self.expiring.lock().unwrap().remove(&Key::from(key), soonest);
+        }
+        self.used_memory.fetch_sub(memory::key_size(key, 0) + Self::fields_size(&hash.fields), Ordering::Relaxed);
+        true
+    }
+
+    // Every hash with its key, a shard at a time, with each shard let go before any hash is looked at
+    fn entries(&self) -> Vec<(Key, Arc<Mutex<Hash>>)> {
+        let mut entries = Vec::new();
+        for shard in &self.shards {
+            entries.extend(shard.lock().unwrap().iter().map(|(key, entry)| (Key::clone(key), Arc::clone(entry))));
+        }
+        entries
+    }
+
+    // Up to a sweep's batch of the hashes with fields whose time has come, the longest expired first
+    pub fn due_subkeys(&self) -> Vec<Key> {
+        self.expiring.lock().unwrap().due((self.clock)())
+    }
+
+    // Takes out the hash's fields whose time has come, deleting the key if that leaves none
+    pub fn expire_subkeys(&self, key: &Key) -> IndexImpactOnCompletion {
+        self.update(key, false, |_| ()).map_or(NoImpact, |(_, impact)| impact)
+    }
+
+    pub fn used_memory(&self) -> usize {
+        self.used_memory.load(Ordering::Relaxed)
+    }
+
+    // Counts every field held, including any whose time has come that haven't been taken out yet,
+    // as used_memory does
+    #[cfg(test)]
+    pub fn recount_memory(&self) -> usize {
+        let mut total = 0;
+        for (key, entry) in self.entries() {
+            let hash = entry.lock().unwrap();
+            if !hash.removed && !hash.fields.is_empty() {
+                total += memory::key_size(&key, 0) + Self::fields_size(&hash.fields);
+            }
+        }
+        total
+    }
+
+    fn fields_size(fields: &HashMap<Bytes, Bytes>) -> usize {
+        fields.iter().map(|(field, value)| memory::element_size(field) + memory::element_size(value)).sum()
+    }
+
+    // Removes the whole hash, returning how many keys went
+    pub fn delete(&self, key: &str) -> u16 {
+        let Some(entry) = self.shard(key).remove(key) else {
+            return 0;
+        };
+        self.discard(key, &entry) as u16
+    }
+
+    // Moves the hash's fields, and their deadlines, to the new key, replacing any hash there
+    pub fn rename(&self, old_key: &str, new_key: &Key) -> bool {
+        let Some(entry) = self.shard(old_key).remove(old_key) else {
+            return false;
+        };
+        let mut moved = {
+            let mut hash = entry.lock().unwrap();
+            if hash.removed {
+                return false;
+            }
+            hash.removed = true;
+            if let Some(soonest) = hash.soonest.take() {
+                self.expiring.lock().unwrap().remove(&Key::from(old_key), soonest);
+            }
+            Hash { fields: std::mem::take(&mut hash.fields), deadlines: std::mem::take(&mut hash.deadlines), ..Hash::default() }
+        };
+        self.used_memory.fetch_sub(memory::key_size(old_key, 0), Ordering::Relaxed);
+        self.used_memory.fetch_add(memory::key_size(new_key, 0), Ordering::Relaxed);
+        self.reschedule(new_key, &mut moved);
+        if let Some(replaced) = self.shard(new_key).insert(Key::clone(new_key), Arc::new(Mutex::new(moved))) {
+            self.discard(new_key, &replaced);
+        }
+        true
+    }
+
+    pub fn keys(&self) -> Vec<Key> {
+        let mut keys = Vec::new();
+        for shard in &self.shards {
+            keys.extend(shard.lock().unwrap().keys().cloned());
+        }
+        keys
+    }
+
+    // A hash at a time, each locked while `visit` sees it, so `visit` must not use the executor.
+    // A hash added or removed meanwhile may or may not be seen. Fields whose time has come are
+    // left out, and a hash with no others isn't seen at all.
+    pub fn for_each_entry(&self, visit: &mut dyn FnMut(&str, EntryView)) {
+        let now = (self.clock)();
+        for (key, entry) in self.entries() {
+            let hash = entry.lock().unwrap();
+            let view = HashView::new(&hash.fields, &hash.deadlines, now);
+            if !hash.removed && !view.is_empty() {
+                visit(&key, EntryView::Hash(view));
+            }
+        }
+    }
+}
+
+// The fields that follow FIELDS and their number, which must be all the words left
+fn fields_argument(words: &[Bytes]) -> Result<&[Bytes], ParserError> {
+    if !words.first().is_some_and(|word| word.eq_ignore_ascii_case(b"FIELDS")) {
+        return Err(ParserError::new("Mandatory argument FIELDS is missing or not at the right position"));
+    }
+    let fields = &words[1..];
+    let Some(count) = fields.first() else {
+        return Err(syntax_error());
+    };
+    let count = std::str::from_utf8(count).ok().and_then(|count| count.parse::<i64>().ok()).ok_or(ParserError::NotAnInteger)?;
+    if count <= 0 {
+        return Err(ParserError::new("Parameter `numFields` should be greater than 0"));
+    }
+    if count as usize != fields.len() - 1 {
+        return Err(ParserError::new("The `numfields` parameter must match the number of arguments"));
+    }
+    Ok(&fields[1..])
+}
+
+// The reply for fields of a hash that doesn't exist: -2 for each
+fn no_such_fields(fields: &[Bytes]) -> Vec<Value> {
+    vec![Value::Integer(-2); fields.len()]
+}
+
+impl CommandExecutor for HashExecutor {
+    fn supported_commands(&self) -> &'static [&'static str] {
+        &REDIS_HASH_COMMANDS
+    }
+    fn key_type(&self) -> KeyType {
+        KeyType::Hash
+    }
+    fn build_command(&self, command: &[Bytes]) -> Result<CommandIdentifier, ParserError> {
+        HashExecutor::build_command(command)
+    }
+    fn execute(&self, command: &CommandIdentifier) -> Result<CommandCompleted, ExecutionError> {
+        self.execute_command(command)
+    }
+    fn delete(&self, key: &str) -> u16 {
+        HashExecutor::delete(self, key)
+    }
+    fn rename(&self, old_key: &str, new_key: &Key) -> bool {
+        HashExecutor::rename(self, old_key, new_key)
+    }
+    fn keys(&self) -> Vec<Key> {
+        HashExecutor::keys(self)
+    }
+    fn for_each_entry(&self, visit: &mut dyn FnMut(&str, EntryView)) {
+        HashExecutor::for_each_entry(self, visit)
+    }
+    fn due_subkeys(&self) -> Vec<Key> {
+        HashExecutor::due_subkeys(self)
+    }
+    fn expire_subkeys(&self, key: &Key) -> IndexImpactOnCompletion {
+        HashExecutor::expire_subkeys(self, key)
+    }
+}
+
+// A clock tests move on by hand, for the deadlines of hashes' fields. Each test runs on a thread of
+// its own, so has a clock of its own.
+#[cfg(test)]
+pub(crate) mod fake_clock {
+    use crate::config::Config;
+    use crate::controller::Databases;
+    use crate::executor::Executors;
+    use crate::hash_executor::HashExecutor;
+    use std::cell::Cell;
+    use std::sync::{Arc, LazyLock};
+    use std::time::{Duration, Instant};
+
+    static STARTED: LazyLock<Instant> = LazyLock::new(Instant::now);
+
+    thread_local! {
+        static ELAPSED: Cell<Duration> = const { Cell::new(Duration::ZERO) };
+    }
+
+    pub fn now() -> Instant {
+        *STARTED + ELAPSED.get()
+    }
+
+    pub fn advance(millis: u64) {
+        ELAPSED.set(ELAPSED.get() + Duration::from_millis(millis));
+    }
+
+    pub fn hash_executor() -> HashExecutor {
+        HashExecutor { clock: now, ..HashExecutor::new() }
+    }
+
+    // The server's executors, with the hash one on this clock
+    pub fn databases() -> (Arc<HashExecutor>, Arc<Databases>) {
+        let databases = Databases::new(&Config::default());
+        let hash = Arc::new(hash_executor());
+        let executors = Executors::new(vec![databases.string.clone(), databases.list.clone(), hash.clone()]);
+        (Arc::clone(&hash), Arc::new(Databases { executors, hash, ..databases }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::commands::{request, ExecutionError, ParserError};
+    use crate::config::Config;
+    use crate::controller::Databases;
+    use crate::hash_executor::fake_clock::{self, advance};
+    use crate::hash_executor::HashExecutor;
+    use crate::index::{Index, Key};
+    use crate::resp::{Protocol, Value};
+    use bytes::Bytes;
+    use std::sync::{mpsc, Arc};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    fn run(db: &HashExecutor, words: &[&str]) -> Value {
+        let command = HashExecutor::build_command(&request(words)).unwrap();
+        db.execute_command(&command).unwrap().get_response().clone()
+    }
+
+    fn integers(replies: &[i64]) -> Value {
+        Value::Array(replies.iter().map(|reply| Value::Integer(*reply)).collect())
+    }
+
+    #[test]
+    fn given_wrong_number_of_arguments_when_build_command_then_redis_message() {
+        let error = HashExecutor::build_command(&request(&["HSET", "key", "field"])).err().unwrap();
+        assert_eq!(error, ParserError::WrongNumberOfArguments("hset".to_string()));
+        let error = HashExecutor::build_command(&request(&["hset", "key", "field", "value", "field"])).err().unwrap();
+        assert_eq!(error, ParserError::WrongNumberOfArguments("hset".to_string()));
+        let error = HashExecutor::build_command(&request(&["HGET", "key"])).err().unwrap();
+        assert_eq!(error, ParserError::WrongNumberOfArguments("hget".to_string()));
+        let error = HashExecutor::build_command(&request(&["HDEL", "key"])).err().unwrap();
+        assert_eq!(error, ParserError::WrongNumberOfArguments("hdel".to_string()));
+        let error = HashExecutor::build_command(&request(&["HEXISTS", "key", "a", "b"])).err().unwrap();
+        assert_eq!(error, ParserError::WrongNumberOfArguments("hexists".to_string()));
+    }
+
+    #[test]
+    fn given_pairs_when_hset_then_only_new_fields_counted_and_values_replaced() {
+        let db = HashExecutor::new();
+        assert_eq!(run(&db, &["HSET", "key", "name", "redis", "version", "7"]), Value::Integer(2));
+        assert_eq!(run(&db, &["HSET", "key", "version", "8", "license", "bsd"]), Value::Integer(1));
+
+        assert_eq!(run(&db, &["HGET", "key", "name"]), Value::BulkString(Bytes::from_static(b"redis")));
+        assert_eq!(run(&db, &["HGET", "key", "version"]), Value::BulkString(Bytes::from_static(b"8")));
+        assert_eq!(db.keys(), [Key::from("key")]);
+        assert_eq!(db.used_memory(), db.recount_memory());
+    }
+
+    #[test]
+    fn given_missing_key_or_field_when_hget_then_null() {
+        let db = HashExecutor::new();
+        assert_eq!(run(&db, &["HGET", "key", "field"]), Value::Null);
+        run(&db, &["HSET", "key", "field", "value"]);
+        assert_eq!(run(&db, &["HGET", "key", "other"]), Value::Null);
+    }
+
+    #[test]
+    fn given_fields_when_hdel_then_existing_ones_counted_and_empty_hash_removed() {
+        let db = HashExecutor::new();
+        run(&db, &["HSET", "key", "a", "1", "b", "2", "c", "3"]);
+
+        assert_eq!(run(&db, &["HDEL", "key", "a", "missing", "a"]), Value::Integer(1));
+        assert_eq!(run(&db, &["HGET", "key", "a"]), Value::Null);
+        assert_eq!(db.used_memory(), db.recount_memory());
+
+        assert_eq!(run(&db, &["HDEL", "key", "b", "c"]), Value::Integer(2));
+        assert!(db.keys().is_empty());
+        assert_eq!(db.used_memory(), 0);
+        assert_eq!(run(&db, &["HDEL", "key", "b"]), Value::Integer(0));
+    }
+
+    #[test]
+    fn given_field_set_or_not_when_hexists_then_one_or_zero() {
+        let db = HashExecutor::new();
+        assert_eq!(run(&db, &["HEXISTS", "key", "field"]), Value::Integer(0));
+        run(&db, &["HSET", "key", "field", "value"]);
+        assert_eq!(run(&db, &["HEXISTS", "key", "field"]), Value::Integer(1));
+        assert_eq!(run(&db, &["HEXISTS", "key", "other"]), Value::Integer(0));
+        run(&db, &["HDEL", "key", "field"]);
+        assert_eq!(run(&db, &["HEXISTS", "key", "field"]), Value::Integer(0));
+    }
+
+    #[test]
+    fn given_hash_when_hgetall_hkeys_and_hvals_then_each_field_once_in_the_same_order() {
+        let db = HashExecutor::new();
+        run(&db, &["HSET", "key", "a", "1", "b", "2", "c", "3", "d", "4"]);
+
+        let Value::Map(pairs) = run(&db, &["HGETALL", "key"]) else { panic!("HGETALL didn't reply with a map") };
+        let Value::Array(fields) = run(&db, &["HKEYS", "key"]) else { panic!("HKEYS didn't reply with an array") };
+        let Value::Array(values) = run(&db, &["HVALS", "key"]) else { panic!("HVALS didn't reply with an array") };
+        assert_eq!(pairs.len(), 4);
+        assert_eq!(pairs, fields.clone().into_iter().zip(values).collect::<Vec<_>>());
+        let mut names: Vec<_> = fields.iter().map(|field| field.encode(Protocol::Resp2)).collect();
+        names.sort();
+        assert_eq!(names, ["$1\r\na\r\n", "$1\r\nb\r\n", "$1\r\nc\r\n", "$1\r\nd\r\n"]);
+        // and the same again while the hash is unchanged
+        assert_eq!(run(&db, &["HKEYS", "key"]), Value::Array(fields));
+        assert_eq!(run(&db, &["HLEN", "key"]), Value::Integer(4));
+    }
+
+    #[test]
+    fn given_one_field_when_hgetall_then_interleaved_array_or_map_by_protocol() {
+        let db = HashExecutor::new();
+        run(&db, &["HSET", "key", "field", "two\r\nlines"]);
+
+        let reply = run(&db, &["HGETALL", "key"]);
+        assert_eq!(reply.encode(Protocol::Resp2), "*2\r\n$5\r\nfield\r\n$10\r\ntwo\r\nlines\r\n");
+        assert_eq!(reply.encode(Protocol::Resp3), "%1\r\n$5\r\nfield\r\n$10\r\ntwo\r\nlines\r\n");
+        assert_eq!(run(&db, &["HVALS", "key"]).encode(Protocol::Resp2), "*1\r\n$10\r\ntwo\r\nlines\r\n");
+    }
+
+    #[test]
+    fn given_missing_key_when_hgetall_hkeys_hvals_or_hlen_then_empty() {
+        let db = HashExecutor::new();
+        assert_eq!(run(&db, &["HGETALL", "key"]).encode(Protocol::Resp2), "*0\r\n");
+        assert_eq!(run(&db, &["HKEYS", "key"]), Value::Array(Vec::new()));
+        assert_eq!(run(&db, &["HVALS", "key"]), Value::Array(Vec::new()));
+        assert_eq!(run(&db, &["HLEN", "key"]), Value::Integer(0));
+        let error = HashExecutor::build_command(&request(&["HGETALL", "key", "extra"])).err().unwrap();
+        assert_eq!(error, ParserError::WrongNumberOfArguments("hgetall".to_string()));
+    }
+
+    #[test]
+    fn given_two_hashes_when_one_renamed_over_the_other_then_it_replaces_it() {
+        let db = HashExecutor::new();
+        run(&db, &["HSET", "first", "a", "1"]);
+        run(&db, &["HSET", "second", "b", "2", "c", "3"]);
+
+        assert!(db.rename("first", &Key::from("second")));
+        assert_eq!(run(&db, &["HGET", "second", "a"]), Value::BulkString(Bytes::from_static(b"1")));
+        assert_eq!(run(&db, &["HEXISTS", "second", "b"]), Value::Integer(0));
+        assert!(!db.rename("first", &Key::from("third")));
+        assert_eq!(db.used_memory(), db.recount_memory());
+        assert_eq!(db.delete("second"), 1);
+        assert_eq!(db.used_memory(), 0);
+    }
+
+    #[test]
+    fn given_hash_commands_when_run_through_the_index_then_key_indexed_as_a_hash() {
+        let index = Arc::new(Index::new());
+        let databases = Arc::new(Databases::new(&Config::default()));
+        let run = |words: &[&str]| index.execute_command(&databases, &request(words));
+
+        assert_eq!(run(&["HSET", "key", "field", "value"]).unwrap(), Value::Integer(1));
+        assert_eq!(run(&["EXISTS", "key"]).unwrap(), Value::Integer(1));
+        assert_eq!(run(&["LLEN", "key"]).unwrap_err(), ExecutionError::WrongType);
+        assert_eq!(run(&["HGET", "key", "field"]).unwrap(), Value::BulkString(Bytes::from_static(b"value")));
+
+        assert_eq!(run(&["HDEL", "key", "field"]).unwrap(), Value::Integer(1));
+        assert_eq!(run(&["EXISTS", "key"]).unwrap(), Value::Integer(0));
+        assert_eq!(index.key_count(), 0);
+
+        run(&["HSET", "key", "field", "value"]).unwrap();
+        assert_eq!(run(&["DEL", "key"]).unwrap(), Value::Integer(1));
+        assert!(databases.hash.keys().is_empty());
+        databases.assert_memory_accounted();
+    }
+
+    #[test]
+    fn given_one_hash_held_when_another_hash_used_then_it_is_not_held_up() {
+        let db = Arc::new(HashExecutor::new());
+        run(&db, &["HSET", "busy", "field", "value"]);
+
+        // a long command on one hash, such as an HGETALL over a giant one, keeps it locked
+        let (held, release) = (mpsc::channel(), mpsc::channel::<()>());
+        let holder = {
+            let (db, held, release) = (Arc::clone(&db), held.0, release.1);
+            thread::spawn(move || {
+                db.update(&Key::from("busy"), false, |_| {
+                    held.send(()).unwrap();
+                    release.recv().unwrap();
+                })
+            })
+        };
+        held.1.recv().unwrap();
+
+        let started = Instant::now();
+        for key in ["other", "another", "third"] {
+            assert_eq!(run(&db, &["HSET", key, "a", "1", "b", "2"]), Value::Integer(2));
+            assert_eq!(run(&db, &["HDEL", key, "a"]), Value::Integer(1));
+            assert_eq!(run(&db, &["HLEN", key]), Value::Integer(1));
+        }
+        assert!(started.elapsed() < Duration::from_secs(1), "{:?}", started.elapsed());
+
+        release.0.send(()).unwrap();
+        holder.join().unwrap();
+        assert_eq!(run(&db, &["HLEN", "busy"]), Value::Integer(1));
+    }
+
+    #[test]
+    fn given_threads_setting_and_deleting_when_run_without_index_lock_then_each_progresses_and_memory_kept() {
+        let db = Arc::new(HashExecutor::new());
+        // two on keys of their own, and two taking turns emptying and recreating a shared one
+        let threads: Vec<_> = [("first", "a"), ("second", "a"), ("shared", "a"), ("shared", "b")]
+            .into_iter()
+            .map(|(key, field)| {
+                let db = Arc::clone(&db);
+                thread::spawn(move || {
+                    let mut deleted = 0;
+                    for _ in 0..2000 {
+                        run(&db, &["HSET", key, field, "value"]);
+                        if run(&db, &["HDEL", key, field]) == Value::Integer(1) {
+                            deleted += 1;
+                        }
+                    }
+                    run(&db, &["HSET", key, field, "last"]);
+                    deleted
+                })
+            })
+            .collect();
+        let deleted: usize = threads.into_iter().map(|thread| thread.join().unwrap()).sum();
+
+        assert_eq!(deleted, 4 * 2000);
+        assert_eq!(run(&db, &["HLEN", "first"]), Value::Integer(1));
+        assert_eq!(run(&db, &["HLEN", "second"]), Value::Integer(1));
+        assert_eq!(run(&db, &["HLEN", "shared"]), Value::Integer(2));
+        assert_eq!(db.keys().len(), 3);
+        assert_eq!(db.used_memory(), db.recount_memory());
+    }
+
+    #[test]
+    fn given_bad_fields_or_time_when_build_hexpire_family_then_rejected_as_redis_does() {
+        let error = |words: &[&str]| HashExecutor::build_command(&request(words)).err().unwrap();
+        assert_eq!(
+            error(&["HEXPIRE", "key", "10", "FIELD", "1", "a"]),
+            ParserError::new("Mandatory argument FIELDS is missing or not at the right position")
+        );
+        assert_eq!(error(&["HTTL", "key", "FIELDS", "0", "a"]), ParserError::new("Parameter `numFields` should be greater than 0"));
+        assert_eq!(
+            error(&["HPERSIST", "key", "FIELDS", "2", "a"]),
+            ParserError::new("The `numfields` parameter must match the number of arguments")
+        );
+        assert_eq!(error(&["HPTTL", "key", "FIELDS", "one", "a"]), ParserError::NotAnInteger);
+        assert_eq!(error(&["HEXPIRE", "key", "soon", "FIELDS", "1", "a"]), ParserError::NotAnInteger);
+        assert_eq!(error(&["HEXPIRE", "key", "-1", "FIELDS", "1", "a"]), ParserError::new("invalid expire time, must be >= 0"));
+        assert_eq!(
+            error(&["HEXPIRE", "key", &i64::MAX.to_string(), "FIELDS", "1", "a"]),
+            ParserError::new("invalid expire time in 'hexpire' command")
+        );
+        assert_eq!(error(&["HPEXPIRE", "key", "10", "NX", "XX", "FIELDS", "1", "a"]), ParserError::Syntax);
+        assert_eq!(error(&["HTTL", "key", "FIELDS", "1"]), ParserError::WrongNumberOfArguments("httl".to_string()));
+    }
+
+    #[test]
+    fn given_fields_when_hexpire_then_each_replies_whether_set_missing_or_deleted() {
+        let db = fake_clock::hash_executor();
+        assert_eq!(run(&db, &["HEXPIRE", "missing", "10", "FIELDS", "2", "a", "b"]), integers(&[-2, -2]));
+        run(&db, &["HSET", "key", "a", "1", "b", "2", "c", "3"]);
+
+        assert_eq!(run(&db, &["HEXPIRE", "key", "10", "FIELDS", "2", "a", "missing"]), integers(&[1, -2]));
+        assert_eq!(run(&db, &["HPEXPIRE", "key", "1500", "FIELDS", "1", "b"]), integers(&[1]));
+        assert_eq!(run(&db, &["HPTTL", "key", "FIELDS", "2", "a", "b"]), integers(&[10_000, 1500]));
+        // a time of 0 deletes the field there and then
+        assert_eq!(run(&db, &["HPEXPIRE", "key", "0", "FIELDS", "1", "c"]), integers(&[2]));
+        assert_eq!(run(&db, &["HEXISTS", "key", "c"]), Value::Integer(0));
+        assert_eq!(run(&db, &["HLEN", "key"]), Value::Integer(2));
+        assert_eq!(db.used_memory(), db.recount_memory());
+    }
+
+    #[test]
+    fn given_nx_xx_gt_or_lt_when_hexpire_then_zero_where_condition_not_met() {
+        let db = fake_clock::hash_executor();
+        run(&db, &["HSET", "key", "expiring", "1", "lasting", "2"]);
+        run(&db, &["HEXPIRE", "key", "100", "FIELDS", "1", "expiring"]);
+
+        let both = |condition: &str, seconds: &str| run(&db, &["HEXPIRE", "key", seconds, condition, "FIELDS", "2", "expiring", "lasting"]);
+        // one without a deadline counts as never expiring, so is later than any
+        assert_eq!(both("GT", "50"), integers(&[0, 0]));
+        assert_eq!(both("GT", "200"), integers(&[1, 0]));
+        assert_eq!(both("LT", "300"), integers(&[0, 1]));
+        assert_eq!(both("lt", "100"), integers(&[1, 1]));
+        assert_eq!(run(&db, &["HPERSIST", "key", "FIELDS", "1", "lasting"]), integers(&[1]));
+        assert_eq!(both("NX", "400"), integers(&[0, 1]));
+        run(&db, &["HPERSIST", "key", "FIELDS", "1", "lasting"]);
+        assert_eq!(both("XX", "500"), integers(&[1, 0]));
+        assert_eq!(run(&db, &["HTTL", "key", "FIELDS", "2", "expiring", "lasting"]), integers(&[500, -1]));
+    }
+
+    #[test]
+    fn given_fields_when_httl_or_hpttl_then_time_left_minus_one_or_minus_two() {
+        let db = fake_clock::hash_executor();
+        assert_eq!(run(&db, &["HTTL", "missing", "FIELDS", "1", "a"]), integers(&[-2]));
+        run(&db, &["HSET", "key", "a", "1", "b", "2"]);
+        run(&db, &["HPEXPIRE", "key", "10000", "FIELDS", "1", "a"]);
+
+        advance(2400);
+        assert_eq!(run(&db, &["HPTTL", "key", "FIELDS", "3", "a", "b", "c"]), integers(&[7600, -1, -2]));
+        // rounded to the nearest second, as TTL is
+        assert_eq!(run(&db, &["HTTL", "key", "FIELDS", "1", "a"]), integers(&[8]));
+    }
+
+    #[test]
+    fn given_fields_when_hpersist_then_deadline_taken_away_where_there_was_one() {
+        let db = fake_clock::hash_executor();
+        assert_eq!(run(&db, &["HPERSIST", "missing", "FIELDS", "1", "a"]), integers(&[-2]));
+        run(&db, &["HSET", "key", "a", "1", "b", "2"]);
+        run(&db, &["HEXPIRE", "key", "10", "FIELDS", "1", "a"]);
+
+        assert_eq!(run(&db, &["HPERSIST", "key", "FIELDS", "3", "a", "b", "c"]), integers(&[1, -1, -2]));
+        assert_eq!(run(&db, &["HTTL", "key", "FIELDS", "1", "a"]), integers(&[-1]));
+        advance(20_000);
+        assert_eq!(run(&db, &["HGET", "key", "a"]), Value::BulkString(Bytes::from_static(b"1")));
+    }
+
+    #[test]
+    fn given_field_expired_when_read_then_no_command_sees_it() {
+        let db = fake_clock::hash_executor();
+        run(&db, &["HSET", "key", "expiring", "1", "lasting", "2"]);
+        run(&db, &["HPEXPIRE", "key", "100", "FIELDS", "1", "expiring"]);
+
+        advance(99);
+        assert_eq!(run(&db, &["HEXISTS", "key", "expiring"]), Value::Integer(1));
+        advance(1);
+        assert_eq!(run(&db, &["HGET", "key", "expiring"]), Value::Null);
+        assert_eq!(run(&db, &["HEXISTS", "key", "expiring"]), Value::Integer(0));
+        assert_eq!(run(&db, &["HLEN", "key"]), Value::Integer(1));
+        assert_eq!(run(&db, &["HKEYS", "key"]), Value::Array(vec![Value::BulkString(Bytes::from_static(b"lasting"))]));
+        assert_eq!(run(&db, &["HPTTL", "key", "FIELDS", "1", "expiring"]), integers(&[-2]));
+        assert_eq!(db.used_memory(), db.recount_memory());
+    }
+
+    #[test]
+    fn given_field_expiring_when_hset_or_renamed_then_hset_clears_deadline_and_rename_keeps_it() {
+        let db = fake_clock::hash_executor();
+        run(&db, &["HSET", "key", "a", "1", "b", "2"]);
+        run(&db, &["HEXPIRE", "key", "10", "FIELDS", "2", "a", "b"]);
+
+        run(&db, &["HSET", "key", "a", "new"]);
+        assert!(db.rename("key", &Key::from("renamed")));
+        assert_eq!(run(&db, &["HTTL", "renamed", "FIELDS", "2", "a", "b"]), integers(&[-1, 10]));
+        advance(10_000);
+        assert_eq!(db.due_subkeys(), [Key::from("renamed")]);
+        let (a, new) = (Value::BulkString(Bytes::from_static(b"a")), Value::BulkString(Bytes::from_static(b"new")));
+        assert_eq!(run(&db, &["HGETALL", "renamed"]), Value::Map(vec![(a, new)]));
+        assert!(db.due_subkeys().is_empty());
+    }
+
+    #[test]
+    fn given_last_field_expired_when_read_through_the_index_then_key_deleted() {
+        let (hash, databases) = fake_clock::databases();
+        let index = Arc::new(Index::new());
+        let run = |words: &[&str]| index.execute_command(&databases, &request(words)).unwrap();
+        run(&["HSET", "key", "a", "1", "b", "2"]);
+        run(&["HPEXPIRE", "key", "100", "FIELDS", "2", "a", "b"]);
+
+        advance(100);
+        assert_eq!(run(&["EXISTS", "key"]), Value::Integer(1));
+        assert_eq!(run(&["HGET", "key", "a"]), Value::Null);
+        assert_eq!(run(&["EXISTS", "key"]), Value::Integer(0));
+        assert!(hash.keys().is_empty());
+        assert!(hash.due_subkeys().is_empty());
+        databases.assert_memory_accounted();
+
+        // and a time of 0 on the last one deletes the key there and then
+        run(&["HSET", "key", "a", "1"]);
+        assert_eq!(run(&["HEXPIRE", "key", "0", "FIELDS", "1", "a"]), integers(&[2]));
+        assert_eq!(run(&["EXISTS", "key"]), Value::Integer(0));
+    }
+
+    #[test]
+    fn given_last_field_expired_when_swept_then_key_deleted_without_a_command() {
+        let (hash, databases) = fake_clock::databases();
+        let index = Arc::new(Index::new());
+        let run = |words: &[&str]| index.execute_command(&databases, &request(words)).unwrap();
+        run(&["HSET", "gone", "a", "1"]);
+        run(&["HSET", "kept", "a", "1", "b", "2"]);
+        for key in ["gone", "kept"] {
+            run(&["HPEXPIRE", key, "100", "FIELDS", "1", "a"]);
+        }
+
+        advance(100);
+        index.expire_due(&databases);
+        assert_eq!(run(&["EXISTS", "gone"]), Value::Integer(0));
+        assert_eq!(run(&["EXISTS", "kept"]), Value::Integer(1));
+        assert_eq!(hash.keys(), [Key::from("kept")]);
+        assert_eq!(run(&["HLEN", "kept"]), Value::Integer(1));
+        databases.assert_memory_accounted();
+    }
+}
@@ -2,16 +2,37 @@
 
 use std::cmp::PartialEq;
 use std::collections::HashMap;
-use std::sync::{Arc, Mutex, MutexGuard};
-use bytes::{Bytes, BytesMut};
-use crate::commands::{ExecutionError, ParserError};
+use std::fmt;
+use std::hash::{BuildHasher, RandomState};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::Instant;
+use bytes::Bytes;
+use crate::commands::options::{self, flag, OptionSpec};
+use crate::commands::table::{self, CommandSpec};
+use crate::commands::{check_sizes, integer_argument, key_arguments, syntax_error, unknown_command, wrong_number_of_arguments, wrong_type, ExecutionError, ParserError};
 use crate::controller::Databases;
+use crate::expiration::{self, ExpirationStore, TimeUnit};
 use crate::index::IndexImpactOnCompletion::{Delete, NoImpact};
 use crate::index::KeyType::Undefined;
 use crate::index::LockType::{Read, Write};
-use crate::index::RedisCommandType::{UnknownCommand, StringCommand, ListCommand, IndexCommand};
-use crate::list_executor::ListExecutor;
-use crate::string_executor::StringExecutor;
+use crate::glob;
+use crate::info;
+use crate::memory::{self, EvictionPolicy};
+use crate::resp::Value;
+
+// FLUSHDB's and FLUSHALL's options, both of which empty the keyspace before replying
+const FLUSH_OPTIONS: [OptionSpec; 2] = [flag("ASYNC", &["SYNC"]), flag("SYNC", &[])];
+
+// Redis's reply to OBJECT FREQ when uses aren't being counted
+const LFU_NOT_SELECTED: &str = "An LFU maxmemory policy is not selected, access frequency not tracked. \
+    Please note that when switching between policies at runtime LRU and LFU data will take some time to adjust.";
+
+// Commands that look at keys without it counting as a use of them, as in Redis
+const NO_TOUCH_COMMANDS: [&str; 3] = ["OBJECT", "TTL", "PTTL"];
+
+// The index commands that set a key's deadline, and so take a time after the key
+const EXPIRE_COMMANDS: [&str; 4] = ["EXPIRE", "PEXPIRE", "EXPIREAT", "PEXPIREAT"];
 
 // What kind of lock do we need on the Index for this command?
 #[derive(Debug, PartialEq)]
@@ -26,51 +47,80 @@ pub enum IndexImpactOnCompletion {
     #[default]
     NoImpact,
     Add,
-    Delete,
-    Rename
+    Delete
 }
 
+// What a command needs of the types of the keys it uses that already exist
 #[derive(Debug, PartialEq)]
-pub enum RedisCommandType {
-    UnknownCommand,
-    StringCommand,
-    ListCommand,
-    IndexCommand
-    // Add other command types as needed
+pub enum TypeCheck {
+    // each must hold the command's own type, or the command fails with WRONGTYPE
+    SameType,
+    // any type will do, as for the commands on keys in general
+    AnyType,
+    // one of another type is deleted first, as SET replaces whatever was there
+    Overwrite,
+    // one of another type is left alone and the command replies with this without running, as
+    // a command that only stores a key that doesn't exist, such as SETNX, finds it there
+    UnlessExists(Value),
+    // each must hold one of these, for a command on keys in general that only applies to some types
+    #[allow(dead_code)] // for OBJECT and the like, none of which exist yet
+    OneOf(&'static [KeyType]),
 }
 
+// A key's name as the hot path passes it around: made once from the request, then shared by the
+// command, its impacts, the index and the executor's map, each clone only a count going up.
+// Looking one up borrows it as a &str, which allocates nothing.
+pub type Key = Arc<str>;
+
 pub struct CommandIdentifier {
-    command_type: RedisCommandType,
-    target: String,
-    action: String, // which action to perform on the target
+    keys: Vec<Key>, // every key the command uses, the target first
+    action: &'static str, // which action to perform on the target
     params: Vec<Bytes>,
     key_type: KeyType,
-    lock_type: LockType
+    lock_type: LockType,
+    type_check: TypeCheck,
 }
 
 impl CommandIdentifier {
     
-    pub fn new(command_type: RedisCommandType, target: String, action: String, params: Vec<Bytes>, key_type: KeyType, lock_type: LockType) -> CommandIdentifier {
+    // for the executors' tests, which build commands directly rather than from requests
+    #[cfg(test)]
+    pub fn new(target: impl Into<Key>, action: &'static str, params: Vec<Bytes>, key_type: KeyType, lock_type: LockType) -> CommandIdentifier {
+        CommandIdentifier::with_keys(vec![target.into()], action, params, key_type, lock_type)
+    }
+    // Everything but the parameters comes from the command table: the keys, from the positions
+    // it gives, and the type and lock from what it says of the command
+    pub fn from_spec(spec: &'static CommandSpec, command: &[Bytes], params: Vec<Bytes>) -> Result<CommandIdentifier, ParserError> {
+        let keys = key_arguments(command, spec.first_key, spec.last_key, spec.key_step)?;
+        Ok(CommandIdentifier::with_keys(keys, spec.name, params, spec.key_type.clone(), spec.lock_type()))
+    }
+    // For the commands using more than one key, each of which must be of the command's key type
+    pub fn with_keys(keys: Vec<Key>, action: &'static str, params: Vec<Bytes>, key_type: KeyType, lock_type: LockType) -> CommandIdentifier {
+        // the commands on keys in general don't mind their type unless they say otherwise
+        let type_check = if key_type == KeyType::Index { TypeCheck::AnyType } else { TypeCheck::SameType };
         CommandIdentifier {
-            command_type,
-            target,
+            keys,
             action,
             params,
             key_type,
-            lock_type
+            lock_type,
+            type_check,
         }
     }
-    pub fn get_command_type(&self) -> &RedisCommandType {
-        &self.command_type
+    pub fn with_type_check(self, type_check: TypeCheck) -> CommandIdentifier {
+        CommandIdentifier { type_check, ..self }
     }
     pub fn get_lock_type(&self) -> &LockType {
         &self.lock_type
     }
-    pub fn get_target(&self) -> &str {
-        &self.target
+    pub fn get_target(&self) -> &Key {
+        &self.keys[0]
+    }
+    pub fn get_keys(&self) -> &[Key] {
+        &self.keys
     }
     pub fn get_action(&self) -> &str {
-        &self.action   
+        self.action
     }
     pub fn get_params(&self) -> &[Bytes] {
         &self.params  
@@ -78,274 +128,740 @@ impl CommandIdentifier {
     pub fn get_key_type(&self) -> &KeyType {
         &self.key_type
     }
+    pub fn get_type_check(&self) -> &TypeCheck {
+        &self.type_check
+    }
 }
 
-#[derive(Default, Debug)]
-pub(crate) struct CommandCompleted {
-    key_name: String,
+// For logging: the command and its keys, but not the values it was given, which may be private
+// e.g. SET "user:1" (1 argument hidden)
+impl fmt::Display for CommandIdentifier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.action)?;
+        for key in &self.keys {
+            write!(f, " \"{}\"", key.escape_debug())?;
+        }
+        match self.params.len() {
+            0 => Ok(()),
+            1 => write!(f, " (1 argument hidden)"),
+            hidden => write!(f, " ({} arguments hidden)", hidden),
+        }
+    }
+}
+
+// One key a command changed, and what that does to the index
+#[derive(Debug)]
+pub(crate) struct KeyImpact {
+    key_name: Key,
     key_type: KeyType,
-    impact_on_index: IndexImpactOnCompletion,
-    response: Bytes
+    impact: IndexImpactOnCompletion,
+    // when a key given a value expires; with none, it lasts until it is removed
+    expires_at: Option<Instant>,
 }
 
-impl CommandCompleted {
-    pub fn new(key_name: &str, key_type: KeyType, impact_on_index: IndexImpactOnCompletion, response: Bytes) -> CommandCompleted {
-        CommandCompleted {
-            key_name: key_name.parse().unwrap(),
-            key_type,
-            impact_on_index,
-            response
-        }
+impl KeyImpact {
+    pub fn new(key_name: &Key, key_type: KeyType, impact: IndexImpactOnCompletion) -> KeyImpact {
+        KeyImpact { key_name: Key::clone(key_name), key_type, impact, expires_at: None }
     }
 
-    pub fn get_key_name(&self) -> &String {
-        &self.key_name
+    // For an Add, as SETEX's is
+    pub fn expiring_at(self, expires_at: Instant) -> KeyImpact {
+        KeyImpact { expires_at: Some(expires_at), ..self }
     }
-    pub fn get_key_type(&self) -> &KeyType {
-        &self.key_type
+
+    // The name moves into the index rather than being copied. A key given a new value loses its
+    // deadline, as it does to SET, unless the command gave it another; a key deleted takes its
+    // deadline with it. A value only changed, as INCR's or RPUSH's is, has no impact, so keeps its
+    // deadline.
+    fn apply(self, entries: &mut HashMap<Key, IndexEntry>, expiring: &mut ExpirationStore, now: u64) {
+        match self.impact {
+            NoImpact => {}
+            IndexImpactOnCompletion::Add => {
+                // a key given a new value, perhaps of a new type, is still the key it was, so
+                // keeps how often it has been used
+                let replaced = if let Some(entry) = entries.get_mut(&self.key_name) {
+                    entry.key_type = self.key_type;
+                    std::mem::replace(&mut entry.expires_at, self.expires_at)
+                } else {
+                    let entry = IndexEntry { expires_at: self.expires_at, ..IndexEntry::new(self.key_type, now) };
+                    entries.insert(Key::clone(&self.key_name), entry);
+                    None
+                };
+                if let Some(replaced) = replaced {
+                    expiring.remove(&self.key_name, replaced);
+                }
+                if let Some(deadline) = self.expires_at {
+                    expiring.insert(&self.key_name, deadline);
+                }
+            }
+            Delete => {
+                if let Some(IndexEntry { expires_at: Some(deadline), .. }) = entries.remove(&self.key_name) {
+                    expiring.remove(&self.key_name, deadline);
+                }
+            }
+        }
     }
-    pub fn get_impact_on_index(&self) -> &IndexImpactOnCompletion {
-        &self.impact_on_index
+}
+
+#[derive(Default, Debug)]
+pub(crate) struct CommandCompleted {
+    impacts: Vec<KeyImpact>,
+    response: Value
+}
+
+impl CommandCompleted {
+    // For the commands that change at most one key
+    pub fn new(key_name: &Key, key_type: KeyType, impact_on_index: IndexImpactOnCompletion, response: Value) -> CommandCompleted {
+        let impacts = if impact_on_index == NoImpact {
+            Vec::new() // nothing to allocate for the many commands that only read
+        } else {
+            vec![KeyImpact::new(key_name, key_type, impact_on_index)]
+        };
+        CommandCompleted::with_impacts(impacts, response)
     }
-    pub fn get_response(&self) -> &Bytes {
+
+    pub fn with_impacts(impacts: Vec<KeyImpact>, response: Value) -> CommandCompleted {
+        CommandCompleted { impacts, response }
+    }
+
+    // the index takes the response apart; the executors' tests look at it whole
+    #[cfg(test)]
+    pub fn get_response(&self) -> &Value {
         &self.response
     }
 }
 
 
-const REDIS_INDEX_COMMANDS: [&str; 3] = ["EXISTS", "DEL", "RENAME"];
 
 
 
 
 #[derive(Debug)]
 pub struct Index {
-    shared: InternalStorage
+    shared: InternalStorage,
+    // what keys' last access times are read from; tests set their own to be sure of the order
+    clock: fn() -> u64,
+    // called with the command's keys locked, so tests can hold them for as long as they like
+    #[cfg(test)]
+    while_locked: Option<fn(&LockType)>,
+}
+
+// What the index knows of a key: its type, when it expires, when a command last used it, for
+// evicting the least recently used keys, and how often, for evicting the least frequently used.
+// The last two are updated under the shard's read lock, so are atomic.
+#[derive(Debug)]
+struct IndexEntry {
+    key_type: KeyType,
+    // with none, the key lasts until it is removed
+    expires_at: Option<Instant>,
+    last_access: AtomicU64,
+    // the LFU counter, as memory::lfu_used keeps it
+    frequency: AtomicU64,
+}
+
+impl IndexEntry {
+    fn new(key_type: KeyType, now: u64) -> IndexEntry {
+        IndexEntry { key_type, expires_at: None, last_access: AtomicU64::new(now), frequency: AtomicU64::new(memory::lfu_new(now)) }
+    }
+
+    fn is_expired(&self) -> bool {
+        self.expires_at.is_some_and(|deadline| deadline <= Instant::now())
+    }
+}
+
+// The lock on the shard of a key a command uses, held until it has finished: shared between
+// commands that only read keys there, or held alone by one that may change one
+enum KeyLock<'a> {
+    Read(#[allow(dead_code)] RwLockReadGuard<'a, ()>),
+    Write(#[allow(dead_code)] RwLockWriteGuard<'a, ()>),
+}
+
+// Most commands' keys are all in the one shard, so need no list of locks
+enum KeyLocks<'a> {
+    One(#[allow(dead_code)] KeyLock<'a>),
+    Many(#[allow(dead_code)] Vec<KeyLock<'a>>),
 }
 
 impl Index {
+    #[cfg(test)]
     pub fn new() -> Index {
+        Index::with_shards(crate::config::Config::default().keyspace_shards)
+    }
+
+    pub fn with_shards(shards: usize) -> Index {
         Index {
-            shared: InternalStorage::new()
+            shared: InternalStorage::new(shards),
+            clock: memory::millis_since_start,
+            #[cfg(test)]
+            while_locked: None,
         }
     }
 
 
-    pub fn execute_command(&self, databases: &Arc<Databases>, request: &Vec<String>) -> Result<Bytes, ExecutionError> {
+    // The reply is left for the controller to encode in the protocol the client has negotiated
+    pub fn execute_command(&self, databases: &Arc<Databases>, request: &[Bytes]) -> Result<Value, ExecutionError> {
         let command = &request[0];
+        if let Some(spec) = table::validate(request)? && spec.is_whole_keyspace() {
+            return self.execute_keyspace_command(databases, spec, request);
+        }
         let execution_context =
-            if StringExecutor::is_command_supported(&command) {
-                StringExecutor::build_command(&request)?
-            } else if self.is_index_command(&command) {
-                self.build_index_command(&request)?
-            } else if ListExecutor::is_command_supported(&command) {
-                ListExecutor::build_command(&request)?
+            if self.is_index_command(command) {
+                self.build_index_command(request)?
+            } else if let Some(executor) = databases.executors.for_command(command) {
+                executor.build_command(request)?
             } else {
-                Err(ExecutionError::new("Unknown Command"))?
+                Err(unknown_command(request))?
             };
+        let keys = execution_context.get_keys().iter().map(|key| key.as_bytes());
+        check_sizes(&databases.limits, keys, execution_context.get_params())?;
+        if memory::may_use_more_memory(execution_context.get_action()) {
+            // before the command's own keys are locked, as evicting locks the keys it evicts
+            self.make_room(databases)?;
+        }
 
-        // lock the index
+        // Lock the shards of the command's keys for as long as it runs. Only a command holding a
+        // key's shard changes its type, so the type checked below stays true until the command is done, even
+        // though the shard's map is only locked long enough to look the key up and to update it
+        // afterwards. Commands on keys in other shards carry on meanwhile, however long the
+        // executor takes.
         {
-            let mut index = self.shared.entries.lock().unwrap();
-            let cmd = self.internal_execute_command(&databases, &execution_context, &mut index)?;
-            Ok(cmd.get_response().clone())
+            let _keys = self.lock_keys(&execution_context);
+            #[cfg(test)]
+            if let Some(while_locked) = self.while_locked {
+                while_locked(execution_context.get_lock_type());
+            }
+            self.internal_execute_command(&databases, &execution_context)
         } // we unlock when we leave the block
     }
 
-    fn internal_execute_command(&self, databases: &&Arc<Databases>, execution_context: &CommandIdentifier, index: &mut MutexGuard<HashMap<String, KeyType>>) -> Result<CommandCompleted, ExecutionError> {
-        // We need to be able to modify the index in the RENAME command by possibly deleting an old key, possibly of a different type.
-        // So we need to be able to manipulate the index while holding the lock for a second command.
-        // This method is then called recursively in that case
-
-        // See if the key exists in the index, then check that the types match
-        //
-        let key = execution_context.get_target();
-        let key_type: KeyType;
-        if index.contains_key(key) {
-            key_type = index.get_mut(key).unwrap().clone();
-            if execution_context.get_key_type() != &KeyType::Index && key_type != *execution_context.get_key_type() {
-                // Index commands apply to all key types
-                return Err(ExecutionError::new("Key already exists with different type"))
-            }
-        } else {
-            key_type = Undefined;
+    // The shards of all the command's keys are locked, always lowest first, so two commands on
+    // the same keys can't each hold a shard the other wants, whichever way round they name them.
+    fn lock_keys(&self, command: &CommandIdentifier) -> KeyLocks<'_> {
+        let lock = |shard: usize| match command.get_lock_type() {
+            Read => KeyLock::Read(self.shared.shards[shard].in_use.read().unwrap()),
+            Write => KeyLock::Write(self.shared.shards[shard].in_use.write().unwrap()),
+        };
+        let keys = command.get_keys();
+        let first = self.shared.shard_of(keys[0].as_bytes());
+        if keys[1..].iter().all(|key| self.shared.shard_of(key.as_bytes()) == first) {
+            return KeyLocks::One(lock(first));
         }
+        let shards = self.shared.shards_in_order(keys.iter().map(|key| &**key));
+        KeyLocks::Many(shards.into_iter().map(lock).collect())
+    }
 
-        let command_result: Result<CommandCompleted, ExecutionError> =
-            match execution_context.get_command_type() {
-                UnknownCommand => { Ok(CommandCompleted::default()) } // We should never get here, but we need the case to be certain all the RedisCommandTypes are covered
-                StringCommand => {
-                    StringExecutor::execute_command(&databases.string, &execution_context)
+    fn internal_execute_command(&self, databases: &&Arc<Databases>, execution_context: &CommandIdentifier) -> Result<Value, ExecutionError> {
+        // See if each key exists in the index, then check its type is one the command can use.
+        // Every command goes through this, whether it reads or writes. The executor is told the
+        // type of the target; it finds the other keys in the command.
+        let mut key_type = Undefined;
+        let touches = !NO_TOUCH_COMMANDS.contains(&execution_context.get_action());
+        let counts_uses = databases.eviction.policy.is_lfu();
+        for (position, key) in execution_context.get_keys().iter().enumerate() {
+            let existing = self.shared.entries(key).read().unwrap().get(key).map(|entry| {
+                // a key whose time has come is gone before the command sees it
+                if entry.is_expired() {
+                    return None;
                 }
-                ListCommand => {
-                    ListExecutor::execute_command(&databases.list, &execution_context)
+                if touches {
+                    let now = (self.clock)();
+                    entry.last_access.store(now, Ordering::Relaxed);
+                    if counts_uses {
+                        let frequency = entry.frequency.load(Ordering::Relaxed);
+                        entry.frequency.store(memory::lfu_used(frequency, now, &databases.eviction), Ordering::Relaxed);
+                    }
                 }
-                IndexCommand => {
-                    self.execute_index_command(index, &databases, &execution_context, &key_type)
+                Some(entry.key_type.clone())
+            });
+            let existing = match existing {
+                Some(Some(existing)) => existing,
+                Some(None) => {
+                    self.expire(databases, key);
+                    continue;
                 }
+                None => continue,
             };
+            let wanted = execution_context.get_key_type();
+            match execution_context.get_type_check() {
+                TypeCheck::SameType if existing != *wanted => return Err(wrong_type()),
+                TypeCheck::OneOf(types) if !types.contains(&existing) => return Err(wrong_type()),
+                TypeCheck::UnlessExists(reply) if existing != *wanted => return Ok(reply.clone()),
+                TypeCheck::Overwrite if existing != *wanted => {
+                    // the command's own Add replaces the key's entry in the index
+                    if let Some(executor) = databases.executors.for_type(&existing) {
+                        executor.delete(key);
+                    }
+                    continue;
+                }
+                _ => {}
+            }
+            if position == 0 {
+                key_type = existing;
+            }
+        }
 
-        let cmd = command_result?;
-        match cmd.get_impact_on_index() {
-            NoImpact => {}
-            IndexImpactOnCompletion::Add => {
-                index.insert(cmd.get_key_name().clone(), cmd.get_key_type().clone());
+        let command_result = if execution_context.get_key_type() == &KeyType::Index {
+            self.execute_index_command(databases, execution_context, &key_type)
+        } else if let Some(executor) = databases.executors.for_type(execution_context.get_key_type()) {
+            executor.execute(execution_context)
+        } else {
+            Ok(CommandCompleted::default()) // We should never get here, only a type's own executor builds commands for it
+        };
+
+        let CommandCompleted { impacts, response } = command_result?;
+        self.apply_impacts(impacts);
+        Ok(response)
+    }
+
+    // Removes the keys whose time has come, each under the lock on its shard, so ones no command
+    // asks for again don't keep their memory. The sweeper calls this; it stops after
+    // SWEEP_TIME_LIMIT, leaving any still due for the next sweep. The shards are swept in turn
+    // from a random one, so it isn't always the same ones that are left. The parts of values that
+    // expire on their own, such as hash fields, are swept after the keys, in the time left.
+    pub(crate) fn expire_due(&self, databases: &Databases) {
+        let started = Instant::now();
+        let shards = &self.shared.shards;
+        let first = memory::random() as usize % shards.len();
+        for shard in shards.iter().cycle().skip(first).take(shards.len()) {
+            loop {
+                let due = shard.expiring.lock().unwrap().due(Instant::now());
+                for key in &due {
+                    let _key = shard.in_use.write().unwrap();
+                    self.expire(databases, key);
+                }
+                if started.elapsed() >= expiration::SWEEP_TIME_LIMIT {
+                    return;
+                }
+                if due.len() < expiration::SWEEP_BATCH {
+                    break;
+                }
             }
-            Delete => {
-                index.remove(cmd.get_key_name());
+        }
+        for executor in databases.executors.iter() {
+            loop {
+                let due = executor.due_subkeys();
+                for key in &due {
+                    // locked as a command changing the key would have it, so the key is deleted
+                    // from the index along with its last part
+                    let _key = self.shared.shard(key).in_use.write().unwrap();
+                    let impact = executor.expire_subkeys(key);
+                    self.apply_impacts(vec![KeyImpact::new(key, executor.key_type(), impact)]);
+                }
+                if started.elapsed() >= expiration::SWEEP_TIME_LIMIT {
+                    return;
+                }
+                if due.len() < expiration::SWEEP_BATCH {
+                    break;
+                }
             }
-            IndexImpactOnCompletion::Rename => {
-                index.insert(cmd.get_key_name().clone(), cmd.get_key_type().clone());
-                index.remove(execution_context.get_target());
+        }
+    }
+
+    // Removes the key from the index and its executor if its time has come; a command may have
+    // given it another deadline, or taken it away, since it was found to have expired. The caller
+    // holds the lock on the key's shard, though perhaps only to read, so two commands may both
+    // get here for the key; only the one that removes it counts it.
+    fn expire(&self, databases: &Databases, key: &str) {
+        let shard = self.shared.shard(key);
+        let removed = {
+            let mut entries = shard.entries.write().unwrap();
+            if !entries.get(key).is_some_and(IndexEntry::is_expired) {
+                return;
+            }
+            let (key, entry) = entries.remove_entry(key).unwrap();
+            if let Some(deadline) = entry.expires_at {
+                shard.expiring.lock().unwrap().remove(&key, deadline);
             }
+            entry
+        };
+        if let Some(executor) = databases.executors.for_type(&removed.key_type) {
+            executor.delete(key);
         }
-        Ok(cmd)
+        info::record_expired_key();
     }
 
-    fn is_index_command(&self, command: &str) -> bool {
-        REDIS_INDEX_COMMANDS
-            .iter()
-            .any(|&cmd| cmd.eq_ignore_ascii_case(command))
+    // Updates the index for every key the command changed, all at once: the maps of the shards
+    // they are in are locked, lowest first, before any is changed, so nothing looking through
+    // the index sees some of the changes without the others
+    fn apply_impacts(&self, impacts: Vec<KeyImpact>) {
+        let Some(first) = impacts.first() else {
+            return;
+        };
+        let now = (self.clock)();
+        let shard_of = |impact: &KeyImpact| self.shared.shard_of(impact.key_name.as_bytes());
+        let first = shard_of(first);
+        if impacts.iter().all(|impact| shard_of(impact) == first) {
+            // most commands change the one key, so have the one map to lock
+            let shard = &self.shared.shards[first];
+            let mut entries = shard.entries.write().unwrap();
+            let mut expiring = shard.expiring.lock().unwrap();
+            impacts.into_iter().for_each(|impact| impact.apply(&mut entries, &mut expiring, now));
+            return;
+        }
+        let mut locked: Vec<_> = self
+            .shared
+            .shards_in_order(impacts.iter().map(|impact| &*impact.key_name))
+            .into_iter()
+            .map(|shard| {
+                let shard_locks = &self.shared.shards[shard];
+                (shard, shard_locks.entries.write().unwrap(), shard_locks.expiring.lock().unwrap())
+            })
+            .collect();
+        for impact in impacts {
+            let shard = shard_of(&impact);
+            let (_, entries, expiring) = locked.iter_mut().find(|(locked, _, _)| *locked == shard).unwrap();
+            impact.apply(entries, expiring, now);
+        }
     }
 
-    fn build_index_command(&self, command: &Vec<String>) -> Result<CommandIdentifier, ParserError> {
-        // support syntax: EXISTS name
-        //                 DEL name
-        //                 RENAME oldname newname
+    // While the keys take more than maxmemory, evicts one at a time as the policy says, or
+    // refuses the command when there is nothing the policy lets go
+    fn make_room(&self, databases: &Databases) -> Result<(), ExecutionError> {
+        let eviction = &databases.eviction;
+        let lru = |entry: &IndexEntry| entry.last_access.load(Ordering::Relaxed);
+        let now = (self.clock)();
+        let lfu = |entry: &IndexEntry| memory::lfu_count(entry.frequency.load(Ordering::Relaxed), now, eviction);
+        // the volatile policies only evict keys that would expire anyway
+        let has_deadline = |entry: &IndexEntry| entry.expires_at.is_some();
+        while eviction.maxmemory > 0 && databases.used_memory() > eviction.maxmemory {
+            let victim = match eviction.policy {
+                EvictionPolicy::NoEviction => None,
+                EvictionPolicy::AllKeysLru => self.coldest(eviction.samples, |_| true, lru),
+                EvictionPolicy::AllKeysLfu => self.coldest(eviction.samples, |_| true, lfu),
+                EvictionPolicy::AllKeysRandom => self.sample(1, |_| true, |_| 0).pop().map(|(key, _)| key),
+                EvictionPolicy::VolatileLru => self.coldest(eviction.samples, has_deadline, lru),
+                EvictionPolicy::VolatileLfu => self.coldest(eviction.samples, has_deadline, lfu),
+            };
+            match victim {
+                Some(key) => self.evict(databases, &key),
+                None => return Err(ExecutionError::OutOfMemory),
+            }
+        }
+        Ok(())
+    }
+
+    // The key with the lowest score of a sample of `count`
+    fn coldest(&self, count: usize, eligible: impl Fn(&IndexEntry) -> bool, score: impl Fn(&IndexEntry) -> u64) -> Option<Key> {
+        self.sample(count, eligible, score).into_iter().min_by_key(|(_, score)| *score).map(|(key, _)| key)
+    }
 
-        if command.len() < 2 {
-            return Err(ParserError::new(
-                "Not enough identifiers provided for index command",
-            ));
+    // Up to `count` of the eligible keys with their scores, as Redis samples: starting from a
+    // random place rather than looking at every key. Asking for as many as there are keys sees them all.
+    fn sample(&self, count: usize, eligible: impl Fn(&IndexEntry) -> bool, score: impl Fn(&IndexEntry) -> u64) -> Vec<(Key, u64)> {
+        let shards = &self.shared.shards;
+        let first = memory::random() as usize % shards.len();
+        let mut sample = Vec::with_capacity(count);
+        for shard in shards.iter().cycle().skip(first).take(shards.len()) {
+            let entries = shard.entries.read().unwrap();
+            if entries.is_empty() {
+                continue;
+            }
+            let start = memory::random() as usize % entries.len();
+            let wanted = count - sample.len();
+            sample.extend(
+                entries
+                    .iter()
+                    .skip(start)
+                    .chain(entries.iter().take(start))
+                    .filter(|(_, entry)| eligible(entry))
+                    .take(wanted)
+                    .map(|(key, entry)| (key.clone(), score(entry))),
+            );
+            if sample.len() == count {
+                break;
+            }
         }
+        sample
+    }
 
-        let command_type: RedisCommandType;
-        let target: String;
-        let action: String;
-        let lock_type: LockType;
-        let mut params: Vec<Bytes> = Vec::new();
+    // Removes the key, if it is still there, from the index and its executor
+    fn evict(&self, databases: &Databases, key: &str) {
+        let shard = self.shared.shard(key);
+        let _key = shard.in_use.write().unwrap();
+        let removed = shard.entries.write().unwrap().remove_entry(key);
+        let Some((key, entry)) = removed else {
+            return;
+        };
+        if let Some(deadline) = entry.expires_at {
+            shard.expiring.lock().unwrap().remove(&key, deadline);
+        }
+        if let Some(executor) = databases.executors.for_type(&entry.key_type) {
+            executor.delete(&key);
+        }
+        info::record_evicted_key();
+        if databases.eviction.notify_keyspace {
+            databases.pubsub.publish(&format!("__keyspace@0__:{}", key), &Bytes::from_static(b"evicted"));
+        }
+        if databases.eviction.notify_keyevent {
+            databases.pubsub.publish("__keyevent@0__:evicted", &Bytes::copy_from_slice(key.as_bytes()));
+        }
+    }
 
-        match command[0].to_uppercase().as_str() {
-            "EXISTS" => {
-                if command.len() != 2 {
-                    return Err(ParserError::new(
-                        "EXISTS command requires exactly one parameter",
-                    ));
+    // The commands on every key at once lock every shard, lowest first as always, so they see the
+    // keyspace as it was between one command and the next
+    fn execute_keyspace_command(&self, databases: &Arc<Databases>, spec: &CommandSpec, request: &[Bytes]) -> Result<Value, ExecutionError> {
+        // support syntax: KEYS pattern
+        //                 DBSIZE
+        //                 FLUSHDB [ASYNC | SYNC]
+        //                 FLUSHALL [ASYNC | SYNC]
+        let _shards: Vec<KeyLock> = (0..self.shared.shards.len())
+            .map(|shard| match spec.lock_type() {
+                Read => KeyLock::Read(self.shared.shards[shard].in_use.read().unwrap()),
+                Write => KeyLock::Write(self.shared.shards[shard].in_use.write().unwrap()),
+            })
+            .collect();
+        match spec.name {
+            "KEYS" => {
+                let mut keys = Vec::new();
+                for executor in databases.executors.iter() {
+                    executor.for_each_entry(&mut |key, _| {
+                        if glob::matches(&request[1], key.as_bytes()) && !self.has_expired(key) {
+                            keys.push(Value::BulkString(Bytes::copy_from_slice(key.as_bytes())));
+                        }
+                    });
                 }
-                command_type = IndexCommand;
-                action = "EXISTS".to_string();
-                target = command[1].clone();
-                // not no params for GET command
-                lock_type = Read
-            }
-            "DEL" => {
-                if command.len() != 2 {
-                    return Err(ParserError::new("DEL command requires one parameter"));
+                Ok(Value::Array(keys))
+            }
+            "DBSIZE" => Ok(Value::Integer(self.key_count() as i64)),
+            _ => {
+                let (_, rest) = options::parse(&FLUSH_OPTIONS, &request[1..])?;
+                if !rest.is_empty() {
+                    Err(syntax_error())?
+                }
+                // There is only the one database, emptied straight away whichever is asked for
+                for executor in databases.executors.iter() {
+                    for key in executor.keys() {
+                        executor.delete(&key);
+                    }
                 }
-                command_type = IndexCommand;
-                action = "DEL".to_string();
-                target = command[1].clone();
-                lock_type = Write
-            }
-            "RENAME" => {
-                if command.len() != 3 {
-                    return Err(ParserError::new("RENAME command requires two parameter"));
+                for shard in &self.shared.shards {
+                    shard.entries.write().unwrap().clear();
+                    shard.expiring.lock().unwrap().clear();
                 }
-                command_type = IndexCommand;
-                action = "RENAME".to_string();
-                target = command[1].clone();
-                params.push(command[2].as_bytes().to_vec().into());
-                lock_type = Write
+                Ok(Value::Ok)
             }
-            _ => return Err(ParserError::new("Unsupported Index command type")),
         }
+    }
 
-        Ok(CommandIdentifier::new(
-            command_type,
-            target,
-            action,
-            params,
-            KeyType::Index,
-            lock_type,
-        ))
+    fn is_index_command(&self, command: &[u8]) -> bool {
+        table::lookup(command).is_some_and(|spec| spec.key_type == KeyType::Index)
     }
 
-    pub fn execute_index_command(
+    fn build_index_command(&self, command: &[Bytes]) -> Result<CommandIdentifier, ParserError> {
+        // support syntax: EXISTS name
+        //                 DEL name
+        //                 RENAME oldname newname
+        //                 OBJECT FREQ name
+        //                 EXPIRE name seconds
+        //                 PEXPIRE name milliseconds
+        //                 EXPIREAT name unix-time-seconds
+        //                 PEXPIREAT name unix-time-milliseconds
+        //                 TTL name
+        //                 PTTL name
+        //                 PERSIST name
+        if command[0].eq_ignore_ascii_case(b"OBJECT") {
+            check_object_subcommand(command)?;
+        }
+        match table::validate(command)? {
+            Some(spec) if spec.key_type == KeyType::Index => {
+                // only the EXPIRE commands have a parameter, the time; the others have only keys
+                let params = if EXPIRE_COMMANDS.contains(&spec.name) { vec![command[2].clone()] } else { Vec::new() };
+                CommandIdentifier::from_spec(spec, command, params)
+            }
+            _ => Err(ParserError::new("Unsupported Index command type")),
+        }
+    }
+
+    fn execute_index_command(
         &self,
-        index: &mut MutexGuard<HashMap<String, KeyType>>,
         databases: &Arc<Databases>,
         command: &CommandIdentifier,
         original_key_type: &KeyType,
     ) -> Result<CommandCompleted, ExecutionError> {
 
         if command.get_action() ==  "EXISTS" {
-            let response = if *original_key_type == Undefined { ":0\r\n".as_bytes().to_vec() } else { ":1\r\n".as_bytes().to_vec() };
+            let response = Value::Integer(if *original_key_type == Undefined { 0 } else { 1 });
             Ok(CommandCompleted::new(
                 command.get_target(),
                 KeyType::Index,
                 NoImpact,
-                Bytes::from(response),
+                response,
             ))
         }
         else if command.get_action() == "DEL" {
-            let mut num_deleted: u16 = 0;
-            let impact: IndexImpactOnCompletion;
-            if *original_key_type == Undefined {
-                impact = NoImpact;
-            }
-            else { // TODO - is there a cleaner way to do this without the set of if statements for each type?
-                if original_key_type == &KeyType::String {
-                    // we know it has to be here
-                    num_deleted = StringExecutor::delete(&databases.string, command.get_target());
-                }
-                if num_deleted == 0 {
-                    impact = NoImpact;
-                }
-                else {
-                    impact = Delete;
-                }
-
-            }
-            let mut buf = BytesMut::new();
-            buf.extend_from_slice(b":");
-            buf.extend_from_slice(&num_deleted.to_string().as_bytes());
-            buf.extend_from_slice(b"\r\n");
+            // every type's executor deletes its own keys, so one added later needs nothing here
+            let num_deleted = match original_key_type {
+                Undefined => 0,
+                key_type => databases.executors.for_type(key_type).map_or(0, |executor| executor.delete(command.get_target())),
+            };
+            let impact = if num_deleted == 0 { NoImpact } else { Delete };
             Ok(CommandCompleted::new(
                 command.get_target(),
                 original_key_type.clone(),
                 impact,
-               buf.freeze(),
+                Value::Integer(num_deleted as i64),
             ))
-
         }
         else if command.get_action() == "RENAME" {
             if original_key_type == &KeyType::Undefined {
-                Err(ExecutionError::new("-no such key"))?
+                Err(ExecutionError::NoSuchKey)?
+            }
+            let destination_key = &command.get_keys()[1];
+            if destination_key == command.get_target() {
+                return Ok(CommandCompleted::new(destination_key, KeyType::Index, NoImpact, Value::Ok));
+            }
+            // Delete the destination key if it exists, its shard already locked with the source's
+            let destination_type = self.shared.entries(destination_key).read().unwrap().get(destination_key).map(|entry| entry.key_type.clone());
+            if let Some(executor) = destination_type.and_then(|key_type| databases.executors.for_type(&key_type)) {
+                executor.delete(destination_key);
             }
-            let destination_key = std::str::from_utf8(&command.get_params()[0]).unwrap();
-            // Delete the destination key if it exists
-            let delete_command = self.build_index_command(&vec!["DEL".to_string(), destination_key.to_string()])?;
-            self.internal_execute_command(&databases, &delete_command, index)?;
 
-            if original_key_type == &KeyType::String {
-                StringExecutor::rename(&databases.string, command.get_target(), destination_key);
+            if let Some(executor) = databases.executors.for_type(original_key_type) {
+                executor.rename(command.get_target(), destination_key);
             }
-            Ok(CommandCompleted::new(
-                destination_key,
-                original_key_type.clone(),
-                IndexImpactOnCompletion::Rename,
-                Bytes::from("+OK\r\n"),
+            // the value keeps its deadline under its new name
+            let mut renamed = KeyImpact::new(destination_key, original_key_type.clone(), IndexImpactOnCompletion::Add);
+            if let Some(expires_at) = self.deadline(command.get_target()) {
+                renamed = renamed.expiring_at(expires_at);
+            }
+            Ok(CommandCompleted::with_impacts(
+                vec![KeyImpact::new(command.get_target(), original_key_type.clone(), Delete), renamed],
+                Value::Ok,
             ))
         }
+        else if EXPIRE_COMMANDS.contains(&command.get_action()) {
+            // they differ only in what the time is given in, and whether it is a time to live or
+            // a Unix time
+            let (unit, name, at) = match command.get_action() {
+                "EXPIRE" => (TimeUnit::Seconds, "EXPIRE", false),
+                "PEXPIRE" => (TimeUnit::Milliseconds, "PEXPIRE", false),
+                "EXPIREAT" => (TimeUnit::Seconds, "EXPIREAT", true),
+                _ => (TimeUnit::Milliseconds, "PEXPIREAT", true),
+            };
+            let invalid = ExecutionError::InvalidExpireTime(name);
+            // the time is checked before the key, as Redis does
+            let millis = unit.millis(integer_argument(&command.get_params()[0])?).ok_or(invalid.clone())?;
+            let millis = if at { expiration::millis_until(millis) } else { millis };
+            let target = command.get_target();
+            if *original_key_type == Undefined || (at && millis <= 0) {
+                // a Unix time already passed leaves the key as it is
+                return Ok(CommandCompleted::new(target, KeyType::Index, NoImpact, Value::Integer(0)));
+            }
+            if millis <= 0 {
+                // a time to live already over deletes the key there and then, as in Redis
+                if let Some(executor) = databases.executors.for_type(original_key_type) {
+                    executor.delete(target);
+                }
+                return Ok(CommandCompleted::new(target, original_key_type.clone(), Delete, Value::Integer(1)));
+            }
+            let deadline = expiration::deadline_after(millis as u64).ok_or(invalid)?;
+            // the key keeps its value, and is indexed again with its new deadline
+            let impact = KeyImpact::new(target, original_key_type.clone(), IndexImpactOnCompletion::Add).expiring_at(deadline);
+            Ok(CommandCompleted::with_impacts(vec![impact], Value::Integer(1)))
+        }
+        else if matches!(command.get_action(), "TTL" | "PTTL") {
+            // -2 for a key that doesn't exist, -1 for one that never expires, else the seconds
+            // left, or for PTTL the milliseconds
+            let left = |deadline| match command.get_action() {
+                "TTL" => expiration::seconds_left(deadline),
+                _ => expiration::millis_left(deadline),
+            };
+            let ttl = match original_key_type {
+                Undefined => -2,
+                _ => self.deadline(command.get_target()).map_or(-1, left),
+            };
+            Ok(CommandCompleted::new(command.get_target(), KeyType::Index, NoImpact, Value::Integer(ttl)))
+        }
+        else if command.get_action() == "PERSIST" {
+            let target = command.get_target();
+            if *original_key_type == Undefined || self.deadline(target).is_none() {
+                return Ok(CommandCompleted::new(target, KeyType::Index, NoImpact, Value::Integer(0)));
+            }
+            // indexed again, this time without a deadline
+            Ok(CommandCompleted::new(target, original_key_type.clone(), IndexImpactOnCompletion::Add, Value::Integer(1)))
+        }
+        else if command.get_action() == "OBJECT" {
+            // only FREQ so far, which needs uses to be counted
+            if !databases.eviction.policy.is_lfu() {
+                Err(ExecutionError::new(LFU_NOT_SELECTED))?
+            }
+            let target = command.get_target();
+            let response = match self.shared.entries(target).read().unwrap().get(target) {
+                Some(entry) => {
+                    let count = memory::lfu_count(entry.frequency.load(Ordering::Relaxed), (self.clock)(), &databases.eviction);
+                    Value::Integer(count as i64)
+                }
+                None => Value::Null,
+            };
+            Ok(CommandCompleted::new(target, KeyType::Index, NoImpact, response))
+        }
         else {
-            Err(ExecutionError::new(
-                "-WRONGTYPE Operation against a key holding the wrong kind of value",
-            ))
+            Err(wrong_type())
         }
     }
 
+    // When the key expires, if it exists and has a deadline, whether or not that has passed
+    pub(crate) fn deadline(&self, key: &str) -> Option<Instant> {
+        self.shared.entries(key).read().unwrap().get(key).and_then(|entry| entry.expires_at)
+    }
+
+    fn has_expired(&self, key: &str) -> bool {
+        self.shared.entries(key).read().unwrap().get(key).is_some_and(IndexEntry::is_expired)
+    }
+
+    // So a test can give a key that exists a deadline already passed, without waiting for it
+    #[cfg(test)]
+    pub(crate) fn set_deadline(&self, key: &str, deadline: Instant) {
+        let shard = self.shared.shard(key);
+        let mut entries = shard.entries.write().unwrap();
+        let key_type = entries[key].key_type.clone();
+        let impact = KeyImpact::new(&Key::from(key), key_type, IndexImpactOnCompletion::Add).expiring_at(deadline);
+        impact.apply(&mut entries, &mut shard.expiring.lock().unwrap(), 0);
+    }
+
+    // How many keys have a deadline
+    #[cfg(test)]
+    fn expiring_count(&self) -> usize {
+        self.shared.shards.iter().map(|shard| shard.expiring.lock().unwrap().len()).sum()
+    }
+
+    // Counted a shard at a time, so keys added or removed meanwhile may or may not be included
+    pub fn key_count(&self) -> usize {
+        self.shared.shards.iter().map(|shard| shard.entries.read().unwrap().len()).sum()
+    }
+
+    #[cfg(test)]
     fn contains(&self, key: &str) -> bool {
-        self.shared.entries.lock().unwrap().contains_key(key)
+        self.shared.entries(key).read().unwrap().contains_key(key)
+    }
+
+    #[cfg(test)]
+    fn all_entries(&self) -> HashMap<Key, KeyType> {
+        self.shared
+            .shards
+            .iter()
+            .flat_map(|shard| {
+                let entries = shard.entries.read().unwrap();
+                entries.iter().map(|(key, entry)| (key.clone(), entry.key_type.clone())).collect::<Vec<_>>()
+            })
+            .collect()
+    }
+}
+
+// OBJECT's subcommands each have their own arity, and only FREQ is supported
+fn check_object_subcommand(command: &[Bytes]) -> Result<(), ParserError> {
+    match command.get(1) {
+        Some(subcommand) if subcommand.eq_ignore_ascii_case(b"FREQ") => {
+            if command.len() != 3 {
+                return Err(ParserError::WrongNumberOfArguments("object|freq".to_string()));
+            }
+            Ok(())
+        }
+        Some(subcommand) => Err(ParserError::new(&format!(
+            "unknown subcommand '{}'. Try OBJECT HELP.",
+            String::from_utf8_lossy(subcommand)
+        ))),
+        None => Err(wrong_number_of_arguments(&command[0])),
     }
 }
 
@@ -355,42 +871,87 @@ pub enum KeyType {
     Undefined,
     Index, // Not really a 'type' but, the command is executing against the index
     String,
-    List
+    List,
+    Hash
 }
 
+// The keys are split between shards by a hash of their names, each with its own locks
 #[derive(Debug)]
 struct InternalStorage {
-    entries: Mutex<HashMap<String, KeyType>>
+    shards: Vec<Shard>,
+    hasher: RandomState,
+}
+
+#[derive(Debug, Default)]
+struct Shard {
+    entries: RwLock<HashMap<Key, IndexEntry>>,
+    // the deadlines of the entries that have one, soonest first; only ever locked after entries
+    expiring: Mutex<ExpirationStore>,
+    // held by a command for as long as it uses a key in the shard
+    in_use: RwLock<()>,
 }
 
 impl InternalStorage {
-    fn new() -> InternalStorage {
+    fn new(shards: usize) -> InternalStorage {
         InternalStorage {
-            entries: Mutex::new(HashMap::new())
+            shards: (0..shards.max(1)).map(|_| Shard::default()).collect(),
+            hasher: RandomState::new(),
         }
     }
+
+    fn shard_of(&self, key: &[u8]) -> usize {
+        (self.hasher.hash_one(key) % self.shards.len() as u64) as usize
+    }
+
+    fn shard(&self, key: &str) -> &Shard {
+        &self.shards[self.shard_of(key.as_bytes())]
+    }
+
+    fn entries(&self, key: &str) -> &RwLock<HashMap<Key, IndexEntry>> {
+        &self.shard(key).entries
+    }
+
+    // The shards the keys are in, each once, lowest first: the order they are always locked in
+    fn shards_in_order<'a>(&self, keys: impl Iterator<Item = &'a str>) -> Vec<usize> {
+        let mut shards: Vec<usize> = keys.map(|key| self.shard_of(key.as_bytes())).collect();
+        shards.sort_unstable();
+        shards.dedup();
+        shards
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::sync::Arc;
-    use bytes::Bytes;
-    use crate::commands::ExecutionError;
+    use std::collections::HashMap;
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::cell::Cell;
+    use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+    use std::sync::{mpsc, Arc, Barrier};
+    use std::thread;
+    use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+    use bytes::{Bytes, BytesMut};
+    use crate::commands::{request, ExecutionError, ParserError};
+    use crate::config::{self, Config};
+    use crate::expiration;
+    use crate::memory::{self, Eviction, EvictionPolicy};
     use crate::controller::Databases;
-    use crate::index::{Index};
+    use crate::index::{CommandCompleted, CommandIdentifier, Index, IndexImpactOnCompletion, Key, KeyImpact, KeyType, LockType, LFU_NOT_SELECTED};
+    use crate::index::IndexImpactOnCompletion::{Delete, NoImpact};
+    use crate::index::LockType::{Read, Write};
     use crate::string_executor::StringExecutor;
-    use crate::list_executor::ListExecutor;
+    use crate::tokenizer::{self, ParsedRequest};
+    use crate::resp::{Protocol, Value};
 
     #[test]
     fn given_unknown_command_return_error() {
         let index = Arc::new(Index::new());
             let databases = Arc::new(setup_databases());
-            let request = vec!["UNKNOWN".to_string(), "key".to_string(), "value".to_string()];
+            let request = request(&["UNKNOWN", "key", "value"]);
             match Index::execute_command(&index, &databases, &request) {
                 Ok(response) => {
                     panic!("Expected error, but got response: {:?}", response)
                 },
-                Err(error) => assert_eq!(error.get_message(), "Unknown Command")
+                Err(error) => assert_eq!(error.get_message(), "unknown command 'UNKNOWN', with args beginning with: 'key', 'value'")
             }
     }
 
@@ -399,10 +960,10 @@ mod tests {
         // Given an empty index
         let index = Arc::new(Index::new());
         let databases = Arc::new(setup_databases());
-        let request = vec!["GET".to_string(), "key".to_string()]; // Note: GET does not change the index, nor fail if not found
+        let request = request(&["GET", "key"]); // Note: GET does not change the index, nor fail if not found
         match Index::execute_command(&index, &databases, &request) {
             Ok(_) => {
-                assert_eq!(index.contains("key"), false) // Note this test isn't interested in the return, only that the index isn't updated
+                assert!(!index.contains("key")) // Note this test isn't interested in the return, only that the index isn't updated
             },
             Err(error) => panic!("Error executing command: {:?}", error)
         }
@@ -417,7 +978,7 @@ mod tests {
         let response = set_a_string_value(&index, &databases, "key", "value");
         match response {
             Ok(_) => {
-                assert_eq!(index.contains("key"), true)
+                assert!(index.contains("key"))
             },
             Err(error) => panic!("Error executing command: {:?}", error)
         }
@@ -428,15 +989,31 @@ mod tests {
         let index = Arc::new(Index::new());
         let databases = Arc::new(setup_databases());
         set_a_string_value(&index, &databases, "key", "value").expect("Failed to setup Index for test");
-        let request = vec!["DEL".to_string(), "key".to_string()];
+        let request = request(&["DEL", "key"]);
         match Index::execute_command(&index, &databases, &request) {
             Ok(_) => {
-                assert_eq!(index.contains("key"), false)
+                assert!(!index.contains("key"))
             },
             Err(error) => panic!("Error executing command: {:?}", error)
         }
         // now confirm the key was removed from the string database
-        assert_eq!(databases.string.internal_exists("key"), false, "Key was not removed from the string database");
+        assert!(!databases.string.internal_exists("key"), "Key was not removed from the string database");
+    }
+
+    #[test]
+    fn given_list_when_delete_then_its_elements_removed_too() {
+        let index = Arc::new(Index::new());
+        let databases = Arc::new(setup_databases());
+        let run = |words: &[&str]| Index::execute_command(&index, &databases, &request(words)).unwrap();
+        run(&["RPUSH", "list", "a", "b"]);
+
+        assert_eq!(run(&["DEL", "list"]), Value::Integer(1));
+
+        assert_eq!(run(&["LLEN", "list"]), Value::Integer(0));
+        assert!(!index.contains("list"));
+        assert_eq!(databases.list.internal_get_length(), 0);
+        databases.assert_memory_accounted();
+        assert_eq!(run(&["DEL", "list"]), Value::Integer(0));
     }
 
     #[test]
@@ -444,10 +1021,10 @@ mod tests {
         let index = Arc::new(Index::new());
         let databases = Arc::new(setup_databases());
         set_a_string_value(&index, &databases, "key", "value").expect("Failed to setup Index for test");
-        let request = vec!["DEL".to_string(), "another_key".to_string()];
+        let request = request(&["DEL", "another_key"]);
         match Index::execute_command(&index, &databases, &request) {
             Ok(response) => {
-                assert_eq!(response, ":0\r\n")
+                assert_eq!(response, Value::Integer(0))
             },
             Err(error) => panic!("Error executing command: {:?}", error)
         }
@@ -455,74 +1032,370 @@ mod tests {
 
     #[test]
     fn given_key_when_rename_and_dest_not_exists_name_has_changed() {
-        const KEY_NAME: &'static str = "key";
-        const NEW_KEY_NAME: &'static str = "new_key";
+        const KEY_NAME: &str = "key";
+        const NEW_KEY_NAME: &str = "new_key";
 
         let index = Arc::new(Index::new());
         let databases = Arc::new(setup_databases());
         set_a_string_value(&index, &databases, KEY_NAME, "value").expect("Failed to setup Index for test");
-        let request = vec!["RENAME".to_string(), KEY_NAME.to_string(), NEW_KEY_NAME.to_string()];
+        let request = request(&["RENAME", KEY_NAME, NEW_KEY_NAME]);
 
         match Index::execute_command(&index, &databases, &request) {
             Ok(_) => {
-                assert_eq!(index.contains(NEW_KEY_NAME), true);
-                assert_eq!(index.contains(KEY_NAME), false)
+                assert!(index.contains(NEW_KEY_NAME));
+                assert!(!index.contains(KEY_NAME))
             },
             Err(error) => panic!("Error executing command: {:?}", error)
         }
         // now confirm the key was removed from the string database
-        assert_eq!(databases.string.internal_exists(KEY_NAME), false, "Key was not removed from the string database");
-        assert_eq!(databases.string.internal_exists(NEW_KEY_NAME), true, "Key was not renamed from the string database");
+        assert!(!databases.string.internal_exists(KEY_NAME), "Key was not removed from the string database");
+        assert!(databases.string.internal_exists(NEW_KEY_NAME), "Key was not renamed from the string database");
     }
 
     #[test]
     fn given_key_which_already_exists_when_rename_delete_old_and_rename() {
-        const KEY_NAME: &'static str = "key";
-        const KEY_VALUE: &'static str = "value";
-        const NEW_KEY_NAME: &'static str = "new_key";
-        const NEW_KEY_VALUE: &'static str = "new_value";
+        const KEY_NAME: &str = "key";
+        const KEY_VALUE: &str = "value";
+        const NEW_KEY_NAME: &str = "new_key";
+        const NEW_KEY_VALUE: &str = "new_value";
 
         let index = Arc::new(Index::new());
         let databases = Arc::new(setup_databases());
         set_a_string_value(&index, &databases, KEY_NAME, KEY_VALUE).expect("Failed to setup Index for test");
         set_a_string_value(&index, &databases, NEW_KEY_NAME, NEW_KEY_VALUE).expect("Failed to setup Index for test");
-        let request = vec!["RENAME".to_string(), KEY_NAME.to_string(), NEW_KEY_NAME.to_string()];
+        let rename_request = request(&["RENAME", KEY_NAME, NEW_KEY_NAME]);
 
-        match Index::execute_command(&index, &databases, &request) {
+        match Index::execute_command(&index, &databases, &rename_request) {
             Ok(_) => {
-                assert_eq!(index.contains(NEW_KEY_NAME), true);
-                assert_eq!(index.contains(KEY_NAME), false)
+                assert!(index.contains(NEW_KEY_NAME));
+                assert!(!index.contains(KEY_NAME))
             },
             Err(error) => panic!("Error executing command: {:?}", error)
         }
         // now confirm the key was removed from the string database
-        assert_eq!(databases.string.internal_exists(KEY_NAME), false, "Key was not removed from the string database");
-        assert_eq!(databases.string.internal_exists(NEW_KEY_NAME), true, "Key was not renamed from the string database");
+        assert!(!databases.string.internal_exists(KEY_NAME), "Key was not removed from the string database");
+        assert!(databases.string.internal_exists(NEW_KEY_NAME), "Key was not renamed from the string database");
 
         // Finally, confirm that the value is the one initiatlly set
-        let get_request = vec!["GET".to_string(), NEW_KEY_NAME.to_string()];
+        let get_request = request(&["GET", NEW_KEY_NAME]);
         match Index::execute_command(&index, &databases, &get_request) {
             Ok(get_value) => {
-                assert_eq!(get_value, format!("+{}\r\n",KEY_VALUE).as_bytes());
+                assert_eq!(get_value, Value::BulkString(Bytes::from_static(KEY_VALUE.as_bytes())));
             },
             Err(error) => panic!("Error executing command: {:?}", error)
         }
     }
 
+    #[test]
+    fn given_command_changing_two_keys_when_applied_then_index_has_both_changes() {
+        let index = Index::new();
+        let (removed, added) = keys_in_different_shards(&index);
+        let (removed, added) = (Key::from(removed), Key::from(added));
+        let same_shard = (1..)
+            .map(|n| Key::from(format!("other{}", n)))
+            .find(|key| index.shared.shard_of(key.as_bytes()) == index.shared.shard_of(added.as_bytes()))
+            .unwrap();
+        index.apply_impacts(vec![KeyImpact::new(&removed, KeyType::String, IndexImpactOnCompletion::Add)]);
+
+        let completed = CommandCompleted::with_impacts(
+            vec![
+                KeyImpact::new(&removed, KeyType::String, Delete),
+                KeyImpact::new(&added, KeyType::List, IndexImpactOnCompletion::Add),
+                KeyImpact::new(&Key::from("unchanged"), KeyType::String, NoImpact),
+            ],
+            Value::Ok,
+        );
+        index.apply_impacts(completed.impacts);
+
+        assert_eq!(index.all_entries(), HashMap::from([(added.clone(), KeyType::List)]));
+
+        // and both in the one shard
+        index.apply_impacts(vec![
+            KeyImpact::new(&same_shard, KeyType::String, IndexImpactOnCompletion::Add),
+            KeyImpact::new(&added, KeyType::List, Delete),
+        ]);
+        assert_eq!(index.all_entries(), HashMap::from([(same_shard, KeyType::String)]));
+    }
+
+    #[test]
+    fn given_command_on_two_keys_when_second_of_other_type_then_wrongtype() {
+        let index = Arc::new(Index::new());
+        let databases = Arc::new(setup_databases());
+        set_a_string_value(&index, &databases, "string", "value").unwrap();
+        Index::execute_command(&index, &databases, &request(&["LPUSH", "list", "element"])).unwrap();
+        let command = |keys: &[&str]| {
+            let keys = keys.iter().map(|key| Key::from(*key)).collect();
+            CommandIdentifier::with_keys(keys, "GET", Vec::new(), KeyType::String, Read)
+        };
+
+        let error = index.internal_execute_command(&&databases, &command(&["string", "list"])).unwrap_err();
+
+        assert_eq!(error, ExecutionError::WrongType);
+        // and a missing key is no conflict
+        assert!(index.internal_execute_command(&&databases, &command(&["string", "missing"])).is_ok());
+    }
+
+    #[test]
+    fn given_key_of_each_type_when_each_command_used_then_wrongtype_as_in_redis() {
+        // the command, and whether it works on a string, on a list and on a hash
+        let matrix: [(&[&str], bool, bool, bool); 47] = [
+            (&["GET"], true, false, false),
+            // a list or a hash reads as missing
+            (&["MGET", "string"], true, true, true),
+            (&["INCR"], true, false, false),
+            (&["INCRBY", "2"], true, false, false),
+            (&["DECR"], true, false, false),
+            (&["DECRBY", "2"], true, false, false),
+            (&["SET", "1"], true, true, true),
+            (&["SET", "1", "XX"], true, true, true),
+            (&["SET", "1", "GET"], true, false, false),
+            (&["GETSET", "1"], true, false, false),
+            (&["SETEX", "10", "1"], true, true, true),
+            (&["PSETEX", "10", "1"], true, true, true),
+            (&["LLEN"], false, true, false),
+            (&["LINDEX", "0"], false, true, false),
+            (&["LRANGE", "0", "-1"], false, true, false),
+            (&["LPOS", "x"], false, true, false),
+            (&["LSET", "0", "x"], false, true, false),
+            (&["LREM", "0", "x"], false, true, false),
+            (&["LTRIM", "0", "-1"], false, true, false),
+            (&["LINSERT", "BEFORE", "element", "x"], false, true, false),
+            (&["LMOVE", "other", "LEFT", "LEFT"], false, true, false),
+            (&["RPOPLPUSH", "other"], false, true, false),
+            (&["RPUSH", "x"], false, true, false),
+            (&["LPUSH", "x"], false, true, false),
+            (&["RPOP"], false, true, false),
+            (&["LPOP"], false, true, false),
+            (&["HSET", "field", "x"], false, false, true),
+            (&["HGET", "field"], false, false, true),
+            (&["HDEL", "field"], false, false, true),
+            (&["HEXISTS", "field"], false, false, true),
+            (&["HGETALL"], false, false, true),
+            (&["HKEYS"], false, false, true),
+            (&["HVALS"], false, false, true),
+            (&["HLEN"], false, false, true),
+            (&["HEXPIRE", "10", "FIELDS", "1", "field"], false, false, true),
+            (&["HPEXPIRE", "10", "FIELDS", "1", "field"], false, false, true),
+            (&["HTTL", "FIELDS", "1", "field"], false, false, true),
+            (&["HPTTL", "FIELDS", "1", "field"], false, false, true),
+            (&["HPERSIST", "FIELDS", "1", "field"], false, false, true),
+            (&["EXISTS"], true, true, true),
+            (&["DEL"], true, true, true),
+            (&["RENAME", "renamed"], true, true, true),
+            // NX only looks for a key, so SET replies nil, and SETNX and MSETNX 0, for one of any type
+            (&["SET", "1", "NX"], true, true, true),
+            (&["SET", "1", "NX", "GET"], true, false, false),
+            (&["SETNX", "1"], true, true, true),
+            (&["MSET", "1"], true, true, true),
+            (&["MSETNX", "1"], true, true, true),
+        ];
+        // so a command added to an executor can't go untried
+        for executor in setup_databases().executors.iter() {
+            for name in executor.supported_commands() {
+                assert!(matrix.iter().any(|(words, _, _, _)| words[0] == *name), "{} isn't tried", name);
+            }
+        }
+        for (words, on_string, on_list, on_hash) in matrix {
+            for (key, expected) in [("string", on_string), ("list", on_list), ("hash", on_hash)] {
+                let index = Arc::new(Index::new());
+                let databases = Arc::new(setup_databases());
+                set_a_string_value(&index, &databases, "string", "1").unwrap();
+                Index::execute_command(&index, &databases, &request(&["LPUSH", "list", "element"])).unwrap();
+                Index::execute_command(&index, &databases, &request(&["HSET", "hash", "field", "value"])).unwrap();
+                let mut command = vec![words[0], key];
+                command.extend(&words[1..]);
+
+                match Index::execute_command(&index, &databases, &request(&command)) {
+                    Ok(_) => assert!(expected, "{:?} worked", command),
+                    Err(error) => {
+                        assert!(!expected, "{:?} failed: {}", command, error.get_message());
+                        assert_eq!(error, ExecutionError::WrongType);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn given_list_when_set_nx_setnx_or_msetnx_then_nothing_set_and_list_kept() {
+        let index = Arc::new(Index::new());
+        let databases = Arc::new(setup_databases());
+        Index::execute_command(&index, &databases, &request(&["RPUSH", "key", "a", "b"])).unwrap();
+
+        assert_eq!(Index::execute_command(&index, &databases, &request(&["SETNX", "key", "value"])).unwrap(), Value::Integer(0));
+        assert_eq!(Index::execute_command(&index, &databases, &request(&["SET", "key", "value", "NX"])).unwrap(), Value::Null);
+        let msetnx = request(&["MSETNX", "other", "1", "key", "2"]);
+        assert_eq!(Index::execute_command(&index, &databases, &msetnx).unwrap(), Value::Integer(0));
+
+        assert_eq!(Index::execute_command(&index, &databases, &request(&["LLEN", "key"])).unwrap(), Value::Integer(2));
+        assert_eq!(Index::execute_command(&index, &databases, &request(&["EXISTS", "other"])).unwrap(), Value::Integer(0));
+        assert!(databases.string.keys().is_empty());
+        databases.assert_memory_accounted();
+    }
+
+    #[test]
+    fn given_list_when_set_then_replaced_by_string() {
+        let index = Arc::new(Index::new());
+        let databases = Arc::new(setup_databases());
+        Index::execute_command(&index, &databases, &request(&["RPUSH", "key", "a", "b"])).unwrap();
+
+        assert_eq!(Index::execute_command(&index, &databases, &request(&["SET", "key", "value"])).unwrap(), Value::Ok);
+
+        assert_eq!(Index::execute_command(&index, &databases, &request(&["GET", "key"])).unwrap(), Value::BulkString(Bytes::from_static(b"value")));
+        let error = Index::execute_command(&index, &databases, &request(&["LLEN", "key"])).unwrap_err();
+        assert_eq!(error, ExecutionError::WrongType);
+        assert!(databases.list.keys().is_empty());
+        databases.assert_memory_accounted();
+    }
+
+    #[test]
+    fn given_keys_in_different_shards_when_mset_then_each_indexed_and_list_replaced() {
+        let index = Arc::new(Index::new());
+        let databases = Arc::new(setup_databases());
+        let (first, second) = keys_in_different_shards(&index);
+        let run = |words: &[&str]| Index::execute_command(&index, &databases, &request(words));
+        run(&["RPUSH", &second, "element"]).unwrap();
+
+        assert_eq!(run(&["MSET", &first, "1", &second, "2"]).unwrap(), Value::Ok);
+
+        assert_eq!(index.all_entries(), HashMap::from([(Key::from(first.as_str()), KeyType::String), (Key::from(second.as_str()), KeyType::String)]));
+        assert!(databases.list.keys().is_empty());
+        assert_eq!(run(&["MSETNX", "new", "3", &first, "4"]).unwrap(), Value::Integer(0));
+        assert_eq!(run(&["EXISTS", "new"]).unwrap(), Value::Integer(0));
+        assert_eq!(run(&["MSETNX", "new", "3", "newer", "4"]).unwrap(), Value::Integer(1));
+        assert_eq!(run(&["EXISTS", "newer"]).unwrap(), Value::Integer(1));
+        databases.assert_memory_accounted();
+    }
+
+    #[test]
+    fn given_command_when_displayed_then_keys_shown_and_values_hidden() {
+        let command = |words: &[&str]| StringExecutor::build_command(&request(words)).unwrap().to_string();
+        assert_eq!(command(&["get", "key"]), "GET \"key\"");
+        assert_eq!(command(&["SET", "user:1", "secret"]), "SET \"user:1\" (1 argument hidden)");
+        assert_eq!(command(&["SET", "a\r\n\"b", "secret", "NX", "GET"]), "SET \"a\\r\\n\\\"b\" (3 arguments hidden)");
+        let rename = CommandIdentifier::with_keys(vec![Key::from("old"), Key::from("new")], "RENAME", Vec::new(), KeyType::Index, Write);
+        assert_eq!(rename.to_string(), "RENAME \"old\" \"new\"");
+    }
+
+    #[test]
+    fn given_keys_of_each_type_when_listed_counted_and_flushed_then_all_seen_then_none_left() {
+        let index = Arc::new(Index::new());
+        let databases = Arc::new(setup_databases());
+        set_a_string_value(&index, &databases, "user:1", "a").unwrap();
+        set_a_string_value(&index, &databases, "user:2", "b").unwrap();
+        Index::execute_command(&index, &databases, &request(&["RPUSH", "user:list", "x", "y"])).unwrap();
+        Index::execute_command(&index, &databases, &request(&["RPUSH", "other", "x"])).unwrap();
+        let keys = |pattern: &str| {
+            let reply = Index::execute_command(&index, &databases, &request(&["KEYS", pattern])).unwrap();
+            let mut keys = keys_in(&reply);
+            keys.sort();
+            keys
+        };
+
+        assert_eq!(keys("user:?"), ["user:1", "user:2"]);
+        assert_eq!(keys("user:*"), ["user:1", "user:2", "user:list"]);
+        assert_eq!(keys("*").len(), 4);
+        assert_eq!(Index::execute_command(&index, &databases, &request(&["DBSIZE"])).unwrap(), Value::Integer(4));
+
+        let error = Index::execute_command(&index, &databases, &request(&["FLUSHDB", "SYNC", "ASYNC"])).unwrap_err();
+        assert_eq!(error, ExecutionError::Syntax);
+        assert_eq!(Index::execute_command(&index, &databases, &request(&["flushall", "sync"])).unwrap(), Value::Ok);
+
+        assert!(keys("*").is_empty());
+        assert_eq!(Index::execute_command(&index, &databases, &request(&["DBSIZE"])).unwrap(), Value::Integer(0));
+        assert_eq!(databases.used_memory(), 0);
+        databases.assert_memory_accounted();
+    }
+
+    #[test]
+    fn given_writes_going_on_when_keys_listed_then_every_settled_key_seen_once() {
+        let index = Arc::new(Index::new());
+        let databases = Arc::new(setup_databases());
+        for key in 0..200 {
+            set_a_string_value(&index, &databases, &format!("settled:{}", key), "value").unwrap();
+            Index::execute_command(&index, &databases, &request(&["RPUSH", &format!("settled-list:{}", key), "x"])).unwrap();
+        }
+        let stop = Arc::new(AtomicBool::new(false));
+        let writers: Vec<_> = (0..2)
+            .map(|writer| {
+                let (index, databases, stop) = (Arc::clone(&index), Arc::clone(&databases), Arc::clone(&stop));
+                thread::spawn(move || {
+                    let mut round = 0;
+                    while !stop.load(Ordering::Relaxed) {
+                        set_a_string_value(&index, &databases, &format!("churn:{}:{}", writer, round % 50), "value").unwrap();
+                        Index::execute_command(&index, &databases, &request(&["DEL", &format!("churn:{}:{}", writer, (round + 25) % 50)])).unwrap();
+                        round += 1;
+                    }
+                })
+            })
+            .collect();
+
+        for _ in 0..20 {
+            let reply = Index::execute_command(&index, &databases, &request(&["KEYS", "*"])).unwrap();
+            let keys = keys_in(&reply);
+            let mut seen: HashMap<String, usize> = HashMap::new();
+            keys.into_iter().for_each(|key| *seen.entry(key).or_default() += 1);
+            assert!(seen.values().all(|&times| times == 1));
+            assert_eq!(seen.keys().filter(|key| key.starts_with("settled")).count(), 400);
+        }
+        stop.store(true, Ordering::Relaxed);
+        for writer in writers {
+            writer.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn given_list_when_renamed_over_a_string_then_string_deleted_and_list_moved() {
+        let index = Arc::new(Index::new());
+        let databases = Arc::new(setup_databases());
+        let run = |words: &[&str]| Index::execute_command(&index, &databases, &request(words)).unwrap();
+        run(&["RPUSH", "list", "a", "b", "c"]);
+        run(&["SET", "string", "value"]);
+
+        assert_eq!(run(&["RENAME", "list", "string"]), Value::Ok);
+
+        assert!(!databases.string.internal_exists("string"));
+        assert_eq!(databases.list.keys(), [Key::from("string")]);
+        assert_eq!(databases.list.internal_get_list_length("string"), 3);
+        assert_eq!(index.all_entries(), HashMap::from([(Key::from("string"), KeyType::List)]));
+        assert_eq!(run(&["LLEN", "string"]), Value::Integer(3));
+        databases.assert_memory_accounted();
+
+        // and a string over a list
+        run(&["SET", "other", "value"]);
+        assert_eq!(run(&["RENAME", "other", "string"]), Value::Ok);
+        assert!(databases.list.keys().is_empty());
+        assert_eq!(run(&["GET", "string"]), Value::BulkString(Bytes::from_static(b"value")));
+        databases.assert_memory_accounted();
+    }
+
+    #[test]
+    fn given_key_when_renamed_to_itself_then_key_kept() {
+        let index = Arc::new(Index::new());
+        let databases = Arc::new(setup_databases());
+        set_a_string_value(&index, &databases, "key", "value").unwrap();
+
+        let reply = Index::execute_command(&index, &databases, &request(&["RENAME", "key", "key"])).unwrap();
+
+        assert_eq!(reply, Value::Ok);
+        assert!(index.contains("key"));
+        assert_eq!(Index::execute_command(&index, &databases, &request(&["GET", "key"])).unwrap(), Value::BulkString(Bytes::from_static(b"value")));
+    }
+
     #[test]
     fn given_key_does_not_exist_when_rename_return_error() {
-        const KEY_NAME: &'static str = "key";
-        const NEW_KEY_NAME: &'static str = "new_key";
+        const KEY_NAME: &str = "key";
+        const NEW_KEY_NAME: &str = "new_key";
         let index = Arc::new(Index::new());
         let databases = Arc::new(setup_databases());
-        let request = vec!["RENAME".to_string(), KEY_NAME.to_string(), NEW_KEY_NAME.to_string()];
+        let request = request(&["RENAME", KEY_NAME, NEW_KEY_NAME]);
 
         match Index::execute_command(&index, &databases, &request) {
             Ok(_) => {
                 panic!("Expected error, but got response")
             },
             Err(error) => {
-                assert_eq!(error.get_message(), "-no such key")
+                assert_eq!(error, ExecutionError::NoSuchKey)
             }
         }
     }
@@ -532,10 +1405,10 @@ mod tests {
         let index = Arc::new(Index::new());
         let databases = Arc::new(setup_databases());
         set_a_string_value(&index, &databases, "key", "value").expect("Failed to setup Index for test");
-        let request = vec!["EXISTS".to_string(), "key".to_string()];
+        let request = request(&["EXISTS", "key"]);
         match Index::execute_command(&index, &databases, &request) {
             Ok(response) => {
-                assert_eq!(response, b":1\r\n".as_ref())
+                assert_eq!(response, Value::Integer(1))
             },
             Err(error) => panic!("Error executing command: {:?}", error)
         }
@@ -545,42 +1418,744 @@ mod tests {
     fn given_exists_command_for_nonexistent_key_return_0() {
         let index = Arc::new(Index::new());
         let databases = Arc::new(setup_databases());
-        let request = vec!["EXISTS".to_string(), "nonexistent".to_string()];
+        let request = request(&["EXISTS", "nonexistent"]);
         match Index::execute_command(&index, &databases, &request) {
             Ok(response) => {
-                assert_eq!(response, b":0\r\n".as_ref())
+                assert_eq!(response, Value::Integer(0))
             },
             Err(error) => panic!("Error executing command: {:?}", error)
         }
     }
 
+    #[test]
+    fn given_key_limit_changed_by_config_set_when_keys_around_it_set_then_only_the_longer_rejected() {
+        let index = Arc::new(Index::new());
+        let databases = Arc::new(setup_databases());
+        config::execute_command(&request(&["CONFIG", "SET", "max-key-length", "8"]), &databases.limits).unwrap();
+
+        let error = set_a_string_value(&index, &databases, "123456789", "value").unwrap_err();
+        assert_eq!(error.get_message(), "key is longer than max-key-length (8 bytes)");
+        assert!(!index.contains("123456789"));
+        assert_eq!(set_a_string_value(&index, &databases, "12345678", "value").unwrap(), Value::Ok);
+        // a RENAME's destination is a key too
+        let error = index.execute_command(&databases, &request(&["RENAME", "12345678", "123456789"])).unwrap_err();
+        assert_eq!(error.get_message(), "key is longer than max-key-length (8 bytes)");
+    }
+
     #[test]
     fn given_rpush_for_empty_index_when_execute_command_then_index_is_updated() {
         let index = Arc::new(Index::new());
         let databases = Arc::new(setup_databases());
-        let request = vec!["RPUSH".to_string(), "Key".to_string(), "FirstPush".to_string()];
+        let request = request(&["RPUSH", "Key", "FirstPush"]);
         match index.execute_command(&databases, &request) {
             Ok(response) => {
-                assert_eq!(response, b":1\r\n".as_ref())
+                assert_eq!(response, Value::Integer(1))
             },
             Err(error) => panic!("Error executing command: {:?}", error)
         }
     }
 
-    fn set_a_string_value(index: &Arc<Index>, databases: &Arc<Databases>, key: &str, value: &str) -> Result<Bytes, ExecutionError> {
+    // The keys in a reply to KEYS, which is framed just as a request is
+    fn keys_in(reply: &Value) -> Vec<String> {
+        let Value::Array(keys) = reply else { panic!("KEYS replied {:?}", reply) };
+        keys.iter()
+            .map(|key| match key {
+                Value::BulkString(key) => String::from_utf8(key.to_vec()).unwrap(),
+                other => panic!("KEYS replied {:?} as a key", other),
+            })
+            .collect()
+    }
+
+    fn set_a_string_value(index: &Arc<Index>, databases: &Arc<Databases>, key: &str, value: &str) -> Result<Value, ExecutionError> {
         // common setup for all tests
-        let request = vec!["SET".to_string(), key.to_string(), value.to_string()];
-         Index::execute_command(&index, &databases, &request)
+        let request = request(&["SET", key, value]);
+         Index::execute_command(index, databases, &request)
+    }
+
+
+    // Inside a key's lock, for the locking test: how many readers and writers are in there,
+    // the most readers seen at once, and whether a writer ever had company
+    static READERS_INSIDE: AtomicUsize = AtomicUsize::new(0);
+    static WRITERS_INSIDE: AtomicUsize = AtomicUsize::new(0);
+    static MOST_READERS: AtomicUsize = AtomicUsize::new(0);
+    static WRITER_OVERLAPPED: AtomicBool = AtomicBool::new(false);
+
+    fn hold_lock(lock_type: &LockType) {
+        let inside = if *lock_type == Read { &READERS_INSIDE } else { &WRITERS_INSIDE };
+        let count = inside.fetch_add(1, Ordering::SeqCst) + 1;
+        if *lock_type == Read {
+            MOST_READERS.fetch_max(count, Ordering::SeqCst);
+        }
+        let alone = READERS_INSIDE.load(Ordering::SeqCst) + WRITERS_INSIDE.load(Ordering::SeqCst) == 1;
+        if *lock_type == Write && !alone {
+            WRITER_OVERLAPPED.store(true, Ordering::SeqCst);
+        }
+        thread::sleep(Duration::from_millis(200));
+        inside.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    #[test]
+    fn given_slow_commands_when_run_together_then_readers_share_the_lock_and_writers_do_not() {
+        let mut index = Index::new();
+        index.while_locked = Some(hold_lock);
+        let (index, databases) = (Arc::new(index), Arc::new(setup_databases()));
+        set_a_string_value(&index, &databases, "key", "value").unwrap();
+
+        let started = Instant::now();
+        let readers: Vec<_> = (0..2)
+            .map(|_| {
+                let (index, databases) = (Arc::clone(&index), Arc::clone(&databases));
+                thread::spawn(move || {
+                    let reply = index.execute_command(&databases, &request(&["GET", "key"])).unwrap();
+                    (reply, started.elapsed())
+                })
+            })
+            .collect();
+        thread::sleep(Duration::from_millis(50));
+        // the same key, since writers to other keys are free to run alongside the readers
+        set_a_string_value(&index, &databases, "key", "value").unwrap();
+        for reader in readers {
+            let (reply, elapsed) = reader.join().unwrap();
+            assert_eq!(reply, Value::BulkString(Bytes::from_static(b"value")));
+            // the two GETs held the lock at the same time rather than one after the other
+            assert!(elapsed < Duration::from_millis(380), "{:?}", elapsed);
+        }
+        assert_eq!(MOST_READERS.load(Ordering::SeqCst), 2);
+        assert!(!WRITER_OVERLAPPED.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn given_many_threads_on_the_same_keys_when_done_then_index_matches_the_executors() {
+        let (index, databases) = (Arc::new(Index::new()), Arc::new(setup_databases()));
+        // a mismatch can be put right by a later command, so check after every short round
+        for _ in 0..200 {
+            let start = Arc::new(Barrier::new(8));
+            let threads: Vec<_> = (0..8)
+                .map(|thread| {
+                    let (index, databases, start) = (Arc::clone(&index), Arc::clone(&databases), Arc::clone(&start));
+                    thread::spawn(move || {
+                        start.wait();
+                        for i in 0..200 {
+                            let key = format!("key{}", (thread + i) % 2);
+                            let request = match (thread * 7 + i) % 6 {
+                                0 | 1 => request(&["SET", &key, "value"]),
+                                2 => request(&["GET", &key]),
+                                3 => request(&["DEL", &key]),
+                                4 => request(&["LPUSH", &key, "value"]),
+                                _ => request(&["LPOP", &key]),
+                            };
+                            // a key may well hold the other type by now, so WRONGTYPE is expected
+                            let _ = index.execute_command(&databases, &request);
+                        }
+                    })
+                })
+                .collect();
+            for thread in threads {
+                thread.join().unwrap();
+            }
+
+            let entries = index.all_entries();
+            let strings = databases.string.keys();
+            let lists = databases.list.keys();
+            for (key, key_type) in entries.iter() {
+                match key_type {
+                    KeyType::String => assert!(strings.contains(key) && !lists.contains(key), "{}", key),
+                    KeyType::List => assert!(lists.contains(key) && !strings.contains(key), "{}", key),
+                    other => panic!("{} indexed as {:?}", key, other),
+                }
+            }
+            for key in strings.iter().chain(lists.iter()) {
+                assert!(entries.contains_key(key), "{} missing from the index", key);
+            }
+            databases.assert_memory_accounted();
+        }
+    }
+
+    // Two keys in different shards, so a command on both has two shards to lock
+    fn keys_in_different_shards(index: &Index) -> (String, String) {
+        (1..)
+            .map(|n| format!("key{}", n))
+            .find(|key| index.shared.shard_of(key.as_bytes()) != index.shared.shard_of(b"key0"))
+            .map(|key| ("key0".to_string(), key))
+            .unwrap()
+    }
+
+    static WRITERS_AT_ONCE: AtomicUsize = AtomicUsize::new(0);
+    static MOST_WRITERS: AtomicUsize = AtomicUsize::new(0);
+
+    fn hold_write(_: &LockType) {
+        let count = WRITERS_AT_ONCE.fetch_add(1, Ordering::SeqCst) + 1;
+        MOST_WRITERS.fetch_max(count, Ordering::SeqCst);
+        thread::sleep(Duration::from_millis(200));
+        WRITERS_AT_ONCE.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    #[test]
+    fn given_writes_to_keys_in_different_shards_when_run_together_then_neither_waits() {
+        let mut index = Index::with_shards(16);
+        index.while_locked = Some(hold_write);
+        let (first, second) = keys_in_different_shards(&index);
+        let (index, databases) = (Arc::new(index), Arc::new(setup_databases()));
+
+        let started = Instant::now();
+        let writers: Vec<_> = [first, second]
+            .into_iter()
+            .map(|key| {
+                let (index, databases) = (Arc::clone(&index), Arc::clone(&databases));
+                thread::spawn(move || set_a_string_value(&index, &databases, &key, "value").unwrap())
+            })
+            .collect();
+        for writer in writers {
+            assert_eq!(writer.join().unwrap(), Value::Ok);
+        }
+        assert!(started.elapsed() < Duration::from_millis(380), "{:?}", started.elapsed());
+        assert_eq!(MOST_WRITERS.load(Ordering::SeqCst), 2);
+        assert_eq!(index.key_count(), 2);
+    }
+
+    #[test]
+    fn given_renames_both_ways_between_two_keys_when_run_together_then_no_deadlock() {
+        let index = Index::with_shards(16);
+        let (first, second) = keys_in_different_shards(&index);
+        let (index, databases) = (Arc::new(index), Arc::new(setup_databases()));
+        set_a_string_value(&index, &databases, &first, "value").unwrap();
+
+        let (done, finished) = mpsc::channel();
+        for (from, to) in [(first.clone(), second.clone()), (second.clone(), first.clone())] {
+            for _ in 0..2 {
+                let (index, databases, done) = (Arc::clone(&index), Arc::clone(&databases), done.clone());
+                let (from, to) = (from.clone(), to.clone());
+                thread::spawn(move || {
+                    for _ in 0..2000 {
+                        // fails with no such key whenever the other direction got there first
+                        let _ = index.execute_command(&databases, &request(&["RENAME", &from, &to]));
+                    }
+                    done.send(()).unwrap();
+                });
+            }
+        }
+        for _ in 0..4 {
+            finished.recv_timeout(Duration::from_secs(10)).expect("the renames deadlocked");
+        }
+        // the value ends up under exactly one of the names
+        assert_eq!(index.key_count(), 1);
+        assert!(index.contains(&first) != index.contains(&second));
+        assert_eq!(databases.string.keys().len(), 1);
+    }
+
+    #[test]
+    fn given_values_stored_and_removed_when_done_then_memory_back_to_baseline() {
+        let index = Arc::new(Index::new());
+        let databases = Arc::new(setup_databases());
+        let baseline = databases.used_memory();
+        let run = |words: &[&str]| index.execute_command(&databases, &request(words)).unwrap();
+
+        run(&["SET", "greeting", "hello"]);
+        assert_eq!(databases.used_memory(), baseline + memory::key_size("greeting", 5));
+        // replacing a value counts only the new one
+        run(&["SET", "greeting", "hello world"]);
+        assert_eq!(databases.used_memory(), baseline + memory::key_size("greeting", 11));
+        run(&["INCR", "counter"]);
+        run(&["INCRBY", "counter", "99"]);
+        assert_eq!(databases.memory_usage().strings, memory::key_size("greeting", 11) + memory::key_size("counter", 3));
+        run(&["RENAME", "counter", "count"]);
+        assert_eq!(databases.memory_usage().strings, memory::key_size("greeting", 11) + memory::key_size("count", 3));
+        run(&["RPUSH", "list", "a"]);
+        run(&["LPUSH", "list", "bcd"]);
+        let list = memory::key_size("list", 0) + memory::element_size(b"a") + memory::element_size(b"bcd");
+        assert_eq!(databases.memory_usage().lists, list);
+        databases.assert_memory_accounted();
+
+        run(&["DEL", "greeting"]);
+        run(&["DEL", "count"]);
+        run(&["LPOP", "list"]);
+        run(&["RPOP", "list"]);
+        assert!(!index.contains("list"));
+        assert_eq!(databases.used_memory(), baseline);
+        databases.assert_memory_accounted();
+    }
+
+    // Room for `keys` keys like "k1" holding "value", and no more
+    fn databases_with_room_for(keys: usize, policy: EvictionPolicy) -> Arc<Databases> {
+        let config = Config {
+            maxmemory: keys * memory::key_size("k1", "value".len()),
+            maxmemory_policy: policy,
+            // every key is looked at, so the choice doesn't depend on which were sampled
+            maxmemory_samples: 100,
+            ..Config::default()
+        };
+        Arc::new(Databases { eviction: Eviction::new(&config), ..setup_databases() })
+    }
+
+    #[test]
+    fn given_noeviction_when_over_maxmemory_then_writes_refused_and_reads_served() {
+        let index = Arc::new(Index::new());
+        let databases = databases_with_room_for(2, EvictionPolicy::NoEviction);
+        for key in ["k1", "k2", "k3"] {
+            // the limit is only checked before a write, so the third still goes in
+            set_a_string_value(&index, &databases, key, "value").unwrap();
+        }
+
+        let error = set_a_string_value(&index, &databases, "k4", "value").unwrap_err();
+        assert_eq!(error, ExecutionError::OutOfMemory);
+        let error = index.execute_command(&databases, &request(&["LPUSH", "list", "value"])).unwrap_err();
+        assert_eq!(error, ExecutionError::OutOfMemory);
+        assert_eq!(index.execute_command(&databases, &request(&["GET", "k1"])).unwrap(), Value::BulkString(Bytes::from_static(b"value")));
+        assert_eq!(index.key_count(), 3);
+
+        // freeing memory lets writes through again
+        index.execute_command(&databases, &request(&["DEL", "k1"])).unwrap();
+        set_a_string_value(&index, &databases, "k4", "value").unwrap();
     }
 
+    static NOW: AtomicU64 = AtomicU64::new(0);
+
+    fn fake_clock() -> u64 {
+        NOW.load(Ordering::SeqCst)
+    }
+
+    #[test]
+    fn given_allkeys_lru_when_over_maxmemory_then_least_recently_used_key_evicted() {
+        let mut index = Index::new();
+        index.clock = fake_clock;
+        let index = Arc::new(index);
+        let databases = databases_with_room_for(3, EvictionPolicy::AllKeysLru);
+        for (now, key) in ["k1", "k2", "k3", "k4"].into_iter().enumerate() {
+            NOW.store(now as u64 * 10, Ordering::SeqCst);
+            set_a_string_value(&index, &databases, key, "value").unwrap();
+        }
+        // k1 was set first, but read since, leaving k2 the least recently used
+        NOW.store(100, Ordering::SeqCst);
+        index.execute_command(&databases, &request(&["GET", "k1"])).unwrap();
+
+        NOW.store(110, Ordering::SeqCst);
+        set_a_string_value(&index, &databases, "k5", "value").unwrap();
+        assert!(!index.contains("k2"));
+        assert!(!databases.string.internal_exists("k2"));
+        for key in ["k1", "k3", "k4", "k5"] {
+            assert!(index.contains(key), "{} was evicted", key);
+        }
+        assert_eq!(databases.used_memory(), 4 * memory::key_size("k1", "value".len()));
+    }
+
+    // Under allkeys-lfu, counting every use rather than fewer as they grow
+    fn databases_counting_every_use(keys: usize) -> Arc<Databases> {
+        let config = Config {
+            maxmemory: keys * memory::key_size("k1", "value".len()),
+            maxmemory_policy: EvictionPolicy::AllKeysLfu,
+            maxmemory_samples: 100,
+            lfu_log_factor: 0,
+            ..Config::default()
+        };
+        Arc::new(Databases { eviction: Eviction::new(&config), ..setup_databases() })
+    }
+
+    static LFU_NOW: AtomicU64 = AtomicU64::new(0);
+
+    fn lfu_clock() -> u64 {
+        LFU_NOW.load(Ordering::SeqCst)
+    }
+
+    #[test]
+    fn given_allkeys_lfu_when_key_used_then_object_freq_counts_uses_and_decays() {
+        let mut index = Index::new();
+        index.clock = lfu_clock;
+        let index = Arc::new(index);
+        let databases = databases_counting_every_use(10);
+        let run = |words: &[&str]| index.execute_command(&databases, &request(words));
+
+        set_a_string_value(&index, &databases, "key", "value").unwrap();
+        assert_eq!(run(&["OBJECT", "FREQ", "key"]).unwrap(), Value::Integer(5));
+        // asking doesn't count as a use
+        assert_eq!(run(&["OBJECT", "freq", "key"]).unwrap(), Value::Integer(5));
+        for _ in 0..10 {
+            run(&["GET", "key"]).unwrap();
+        }
+        assert_eq!(run(&["OBJECT", "FREQ", "key"]).unwrap(), Value::Integer(15));
+
+        // a minute unused takes one off, with lfu-decay-time at 1
+        LFU_NOW.store(3 * 60 * 1000, Ordering::SeqCst);
+        assert_eq!(run(&["OBJECT", "FREQ", "key"]).unwrap(), Value::Integer(12));
+        run(&["GET", "key"]).unwrap();
+        assert_eq!(run(&["OBJECT", "FREQ", "key"]).unwrap(), Value::Integer(13));
+
+        // a new value for the key keeps its count
+        set_a_string_value(&index, &databases, "key", "other").unwrap();
+        assert_eq!(run(&["OBJECT", "FREQ", "key"]).unwrap(), Value::Integer(14));
+        assert_eq!(run(&["OBJECT", "FREQ", "missing"]).unwrap(), Value::Null);
+    }
+
+    #[test]
+    fn given_object_when_malformed_or_lfu_not_selected_then_errors_as_redis_does() {
+        let index = Arc::new(Index::new());
+        let databases = databases_with_room_for(10, EvictionPolicy::AllKeysLru);
+        set_a_string_value(&index, &databases, "key", "value").unwrap();
+        let error = index.execute_command(&databases, &request(&["OBJECT", "FREQ", "key"])).unwrap_err();
+        assert_eq!(error.get_message(), LFU_NOT_SELECTED);
+
+        let error = index.execute_command(&databases, &request(&["OBJECT", "FREQ"])).unwrap_err();
+        assert_eq!(error, ExecutionError::Request(ParserError::WrongNumberOfArguments("object|freq".to_string())));
+        let error = index.execute_command(&databases, &request(&["OBJECT", "ENCODING", "key"])).unwrap_err();
+        assert_eq!(error.get_message(), "unknown subcommand 'ENCODING'. Try OBJECT HELP.");
+    }
+
+    #[test]
+    fn given_allkeys_lfu_when_over_maxmemory_then_least_used_key_evicted() {
+        let index = Arc::new(Index::new());
+        let databases = databases_counting_every_use(3);
+        for key in ["k1", "k2", "k3", "k4"] {
+            set_a_string_value(&index, &databases, key, "value").unwrap();
+        }
+        // k3 is the only one never read
+        for (key, uses) in [("k1", 3), ("k2", 1), ("k4", 2)] {
+            for _ in 0..uses {
+                index.execute_command(&databases, &request(&["GET", key])).unwrap();
+            }
+        }
+
+        set_a_string_value(&index, &databases, "k5", "value").unwrap();
+        assert!(!index.contains("k3"));
+        assert!(!databases.string.internal_exists("k3"));
+        for key in ["k1", "k2", "k4", "k5"] {
+            assert!(index.contains(key), "{} was evicted", key);
+        }
+    }
+
+    #[test]
+    fn given_allkeys_random_when_over_maxmemory_then_keys_evicted_until_under() {
+        let index = Arc::new(Index::new());
+        let databases = databases_with_room_for(3, EvictionPolicy::AllKeysRandom);
+        for key in ["k1", "k2", "k3", "k4"] {
+            set_a_string_value(&index, &databases, key, "value").unwrap();
+        }
+        index.execute_command(&databases, &request(&["LPUSH", "k5", "value"])).unwrap();
+
+        // one went to make room, whichever it was, and the memory counts agree with what's left
+        assert_eq!(index.key_count(), 4);
+        let strings = databases.string.keys().len();
+        assert_eq!(strings + databases.list.keys().len(), 4);
+        let list = memory::key_size("k5", 0) + memory::element_size(b"value");
+        let expected = strings * memory::key_size("k1", "value".len()) + if index.contains("k5") { list } else { 0 };
+        assert_eq!(databases.used_memory(), expected);
+    }
+
+    #[test]
+    fn given_volatile_lru_when_over_maxmemory_then_refused_as_no_key_has_a_ttl() {
+        let index = Arc::new(Index::new());
+        let databases = databases_with_room_for(1, EvictionPolicy::VolatileLru);
+        set_a_string_value(&index, &databases, "k1", "value").unwrap();
+        set_a_string_value(&index, &databases, "k2", "value").unwrap();
+        assert!(set_a_string_value(&index, &databases, "k3", "value").is_err());
+        assert_eq!(index.key_count(), 2);
+    }
+
+    #[test]
+    fn given_volatile_lru_when_over_maxmemory_then_only_a_key_with_a_ttl_evicted() {
+        let index = Arc::new(Index::new());
+        let databases = databases_with_room_for(1, EvictionPolicy::VolatileLru);
+        index.execute_command(&databases, &request(&["SETEX", "k1", "100", "value"])).unwrap();
+        set_a_string_value(&index, &databases, "k2", "value").unwrap();
+
+        set_a_string_value(&index, &databases, "k3", "value").unwrap();
+
+        assert!(!index.contains("k1"));
+        assert!(index.contains("k2"));
+        assert_eq!(index.expiring_count(), 0);
+        // and with no key left that has a ttl, the next is refused
+        assert!(set_a_string_value(&index, &databases, "k4", "value").is_err());
+    }
+
+    #[test]
+    fn given_keys_when_expire_ttl_and_persist_then_replies_as_in_redis() {
+        let index = Arc::new(Index::new());
+        let databases = Arc::new(setup_databases());
+        let run = |words: &[&str]| index.execute_command(&databases, &request(words));
+        set_a_string_value(&index, &databases, "key", "value").unwrap();
+
+        assert_eq!(run(&["TTL", "missing"]).unwrap(), Value::Integer(-2));
+        assert_eq!(run(&["TTL", "key"]).unwrap(), Value::Integer(-1));
+        assert_eq!(run(&["EXPIRE", "missing", "100"]).unwrap(), Value::Integer(0));
+        assert_eq!(run(&["PERSIST", "key"]).unwrap(), Value::Integer(0));
+
+        assert_eq!(run(&["EXPIRE", "key", "100"]).unwrap(), Value::Integer(1));
+        assert_eq!(run(&["TTL", "key"]).unwrap(), Value::Integer(100));
+        assert_eq!(run(&["PERSIST", "key"]).unwrap(), Value::Integer(1));
+        assert_eq!(run(&["TTL", "key"]).unwrap(), Value::Integer(-1));
+
+        assert_eq!(run(&["EXPIRE", "key", "soon"]).err().unwrap(), ExecutionError::NotAnInteger);
+        let error = run(&["EXPIRE", "key", &i64::MAX.to_string()]).err().unwrap();
+        assert_eq!(error.get_message(), "invalid expire time in 'expire' command");
+        assert!(run(&["TTL", "key", "extra"]).is_err());
+
+        // a ttl already over deletes the key straight away
+        run(&["RPUSH", "list", "a", "b"]).unwrap();
+        assert_eq!(run(&["EXPIRE", "key", "0"]).unwrap(), Value::Integer(1));
+        assert_eq!(run(&["EXPIRE", "list", "-10"]).unwrap(), Value::Integer(1));
+        assert!(index.all_entries().is_empty());
+        assert!(databases.string.keys().is_empty());
+        assert!(databases.list.keys().is_empty());
+        databases.assert_memory_accounted();
+    }
+
+    #[test]
+    fn given_milliseconds_when_pexpire_then_pttl_counts_them_down_and_key_gone_once_they_are_up() {
+        let index = Arc::new(Index::new());
+        let databases = Arc::new(setup_databases());
+        let run = |words: &[&str]| index.execute_command(&databases, &request(words));
+        set_a_string_value(&index, &databases, "key", "value").unwrap();
+
+        assert_eq!(run(&["PTTL", "missing"]).unwrap(), Value::Integer(-2));
+        assert_eq!(run(&["PTTL", "key"]).unwrap(), Value::Integer(-1));
+        assert_eq!(run(&["PEXPIRE", "missing", "100"]).unwrap(), Value::Integer(0));
+
+        assert_eq!(run(&["PEXPIRE", "key", "5000"]).unwrap(), Value::Integer(1));
+        let Value::Integer(left) = run(&["PTTL", "key"]).unwrap() else { panic!("PTTL didn't reply with an integer") };
+        assert!((4900..=5000).contains(&left), "{} milliseconds left", left);
+        assert_eq!(run(&["TTL", "key"]).unwrap(), Value::Integer(5));
+
+        // a deadline passed, as PEXPIRE's would be once its milliseconds are up
+        index.set_deadline("key", Instant::now());
+        assert_eq!(run(&["GET", "key"]).unwrap(), Value::Null);
+        assert_eq!(run(&["PTTL", "key"]).unwrap(), Value::Integer(-2));
+
+        // the time is checked even when the key doesn't exist
+        assert_eq!(run(&["PEXPIRE", "key", "soon"]).err().unwrap(), ExecutionError::NotAnInteger);
+        set_a_string_value(&index, &databases, "key", "value").unwrap();
+        assert_eq!(run(&["pexpire", "key", "1.5"]).err().unwrap(), ExecutionError::NotAnInteger);
+        assert_eq!(run(&["PEXPIRE", "key", "0"]).unwrap(), Value::Integer(1));
+        assert!(index.all_entries().is_empty());
+        databases.assert_memory_accounted();
+    }
+
+    #[test]
+    fn given_unix_time_when_expireat_or_pexpireat_then_key_kept_until_then() {
+        let index = Arc::new(Index::new());
+        let databases = Arc::new(setup_databases());
+        let run = |words: &[&str]| index.execute_command(&databases, &request(words));
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        set_a_string_value(&index, &databases, "seconds", "value").unwrap();
+        run(&["RPUSH", "millis", "a"]).unwrap();
+
+        // whole seconds from now, which is at least one second away
+        let in_a_second = (now.as_secs() + 2).to_string();
+        assert_eq!(run(&["EXPIREAT", "seconds", &in_a_second]).unwrap(), Value::Integer(1));
+        let in_an_hour = (now.as_millis() + 3_600_000).to_string();
+        assert_eq!(run(&["PEXPIREAT", "millis", &in_an_hour]).unwrap(), Value::Integer(1));
+        assert_eq!(run(&["EXPIREAT", "missing", &in_a_second]).unwrap(), Value::Integer(0));
+
+        let Value::Integer(left) = run(&["PTTL", "seconds"]).unwrap() else { panic!("PTTL didn't reply with an integer") };
+        assert!((900..=2000).contains(&left), "{} milliseconds left", left);
+        assert_eq!(run(&["GET", "seconds"]).unwrap(), Value::BulkString(Bytes::from_static(b"value")));
+        assert_eq!(run(&["LLEN", "millis"]).unwrap(), Value::Integer(1));
+        let Value::Integer(left) = run(&["PTTL", "millis"]).unwrap() else { panic!("PTTL didn't reply with an integer") };
+        assert!((3_599_000..=3_600_000).contains(&left), "{} milliseconds left", left);
+        // once the time comes, the list is gone and the other key kept
+        index.set_deadline("millis", Instant::now());
+        assert_eq!(run(&["LLEN", "millis"]).unwrap(), Value::Integer(0));
+        assert_eq!(run(&["GET", "seconds"]).unwrap(), Value::BulkString(Bytes::from_static(b"value")));
+
+        // a time already passed leaves the key alone
+        set_a_string_value(&index, &databases, "kept", "value").unwrap();
+        let past = (now.as_secs() - 10).to_string();
+        assert_eq!(run(&["EXPIREAT", "kept", &past]).unwrap(), Value::Integer(0));
+        assert_eq!(run(&["PEXPIREAT", "kept", "0"]).unwrap(), Value::Integer(0));
+        assert_eq!(run(&["TTL", "kept"]).unwrap(), Value::Integer(-1));
+
+        assert_eq!(run(&["EXPIREAT", "kept", "tomorrow"]).err().unwrap(), ExecutionError::NotAnInteger);
+        let error = run(&["expireat", "kept", &i64::MAX.to_string()]).err().unwrap();
+        assert_eq!(error.get_message(), "invalid expire time in 'expireat' command");
+    }
+
+    #[test]
+    fn given_ttl_run_out_when_key_used_then_gone_whatever_its_type() {
+        let index = Arc::new(Index::new());
+        let databases = Arc::new(setup_databases());
+        let run = |words: &[&str]| index.execute_command(&databases, &request(words)).unwrap();
+        run(&["PSETEX", "string", "1", "value"]);
+        run(&["RPUSH", "list", "a", "b"]);
+        run(&["EXPIRE", "list", "100"]);
+        run(&["SET", "kept", "value"]);
+        index.set_deadline("list", Instant::now());
+        thread::sleep(Duration::from_millis(5));
+
+        assert_eq!(run(&["KEYS", "*"]), Value::Array(vec![Value::BulkString(Bytes::from_static(b"kept"))]));
+        assert_eq!(run(&["GET", "string"]), Value::Null);
+        assert_eq!(run(&["TTL", "list"]), Value::Integer(-2));
+        assert_eq!(run(&["LLEN", "list"]), Value::Integer(0));
+        assert_eq!(index.all_entries(), HashMap::from([(Key::from("kept"), KeyType::String)]));
+        assert!(databases.list.keys().is_empty());
+        assert_eq!(index.expiring_count(), 0);
+        databases.assert_memory_accounted();
+
+        // and a key made again under the same name starts without a ttl
+        run(&["RPUSH", "list", "c"]);
+        assert_eq!(run(&["TTL", "list"]), Value::Integer(-1));
+    }
+
+    #[test]
+    fn given_expired_keys_no_command_uses_when_sweep_due_then_removed() {
+        let index = Arc::new(Index::new());
+        let databases = Arc::new(setup_databases());
+        let run = |words: &[&str]| index.execute_command(&databases, &request(words)).unwrap();
+        for key in ["a", "b", "c"] {
+            run(&["SET", key, "value"]);
+            index.set_deadline(key, Instant::now());
+        }
+        run(&["SET", "kept", "value"]);
+        run(&["EXPIRE", "kept", "100"]);
+
+        index.expire_due(&databases);
+
+        assert_eq!(index.all_entries(), HashMap::from([(Key::from("kept"), KeyType::String)]));
+        assert_eq!(databases.string.keys(), [Key::from("kept")]);
+        assert_eq!(run(&["TTL", "kept"]), Value::Integer(100));
+        databases.assert_memory_accounted();
+    }
+
+    #[test]
+    fn given_more_expired_keys_than_a_batch_when_sweep_due_then_all_removed() {
+        let index = Arc::new(Index::new());
+        let databases = Arc::new(setup_databases());
+        for key in 0..expiration::SWEEP_BATCH * 3 {
+            let key = key.to_string();
+            set_a_string_value(&index, &databases, &key, "value").unwrap();
+            index.set_deadline(&key, Instant::now());
+        }
+
+        index.expire_due(&databases);
+
+        assert!(index.all_entries().is_empty());
+        assert_eq!(index.expiring_count(), 0);
+        databases.assert_memory_accounted();
+    }
+
+    #[test]
+    fn given_sweeper_running_when_no_command_sent_then_expired_key_removed() {
+        let index = Arc::new(Index::new());
+        let databases = Arc::new(setup_databases());
+        set_a_string_value(&index, &databases, "key", "value").unwrap();
+        index.set_deadline("key", Instant::now());
+
+        let sweeper = expiration::Sweeper::start(&index, &databases);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while index.contains("key") && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        drop(sweeper);
+
+        assert!(!index.contains("key"));
+        assert!(databases.string.keys().is_empty());
+    }
+
+    #[test]
+    fn given_key_with_ttl_when_written_then_set_clears_it_and_others_keep_it() {
+        let index = Arc::new(Index::new());
+        let databases = Arc::new(setup_databases());
+        let run = |words: &[&str]| index.execute_command(&databases, &request(words)).unwrap();
+        let ttl = |key: &str| index.execute_command(&databases, &request(&["TTL", key])).unwrap();
+        for key in ["set", "getset", "mset", "incr", "renamed"] {
+            run(&["SETEX", key, "100", "1"]);
+        }
+        run(&["RPUSH", "list", "a"]);
+        run(&["EXPIRE", "list", "100"]);
+
+        run(&["SET", "set", "value"]);
+        run(&["GETSET", "getset", "value"]);
+        run(&["MSET", "mset", "value"]);
+        run(&["INCR", "incr"]);
+        run(&["RPUSH", "list", "b"]);
+        run(&["RENAME", "renamed", "moved"]);
+
+        for key in ["set", "getset", "mset"] {
+            assert_eq!(ttl(key), Value::Integer(-1), "{}", key);
+        }
+        for key in ["incr", "list", "moved"] {
+            assert_eq!(ttl(key), Value::Integer(100), "{}", key);
+        }
+        assert_eq!(ttl("renamed"), Value::Integer(-2));
+        // and a key renamed over one with a ttl doesn't take it on
+        run(&["RENAME", "set", "moved"]);
+        assert_eq!(ttl("moved"), Value::Integer(-1));
+    }
 
     // TODO test - given a SET, followed by another command type, fail because the key exists as a string already
 
     fn setup_databases() -> Databases {
-        Databases {
-            string : Arc::new(StringExecutor::new()),
-            list: Arc::new(ListExecutor::new())
+        Databases::new(&Config::default())
+    }
+
+    // Counts the allocations made by each thread, so a test can see what a single command costs
+    struct CountingAllocator;
+
+    thread_local! {
+        static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+    }
+
+    fn count_allocation() {
+        let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+    }
+
+    unsafe impl GlobalAlloc for CountingAllocator {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            count_allocation();
+            unsafe { System.alloc(layout) }
         }
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            unsafe { System.dealloc(ptr, layout) }
+        }
+        unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+            count_allocation();
+            unsafe { System.realloc(ptr, layout, new_size) }
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+    // A rough benchmark of the whole path a command takes once its bytes have been read:
+    // tokenizing, building the command, and running it. Returns the allocations per command.
+    fn allocations_per_command(index: &Arc<Index>, databases: &Arc<Databases>, commands: &[Vec<u8>]) -> f64 {
+        let config = Config::default();
+        let mut buffer = BytesMut::new();
+        commands.iter().for_each(|command| buffer.extend_from_slice(command));
+
+        let before = ALLOCATIONS.with(Cell::get);
+        while !buffer.is_empty() {
+            let ParsedRequest::Command(arguments) = tokenizer::identify_command(&mut buffer, &config).unwrap() else {
+                panic!("expected a command");
+            };
+            let request: Vec<Bytes> = arguments.into_iter().flatten().collect();
+            // encoded as the controller would, so the reply's cost is counted too
+            index.execute_command(databases, &request).unwrap().encode(Protocol::Resp2);
+        }
+        (ALLOCATIONS.with(Cell::get) - before) as f64 / commands.len() as f64
+    }
+
+    fn set_of(key: &str) -> Vec<u8> {
+        format!("*3\r\n$3\r\nSET\r\n${}\r\n{}\r\n$16\r\n0123456789abcdef\r\n", key.len(), key).into_bytes()
+    }
+
+    // What remains is mostly the request's own Vecs, and the value copied out of the read buffer
+    #[test]
+    fn given_pipelined_sets_when_executed_then_few_allocations_per_set() {
+        let index = Arc::new(Index::new());
+        let databases = Arc::new(setup_databases());
+        let per_set = allocations_per_command(&index, &databases, &vec![set_of("key"); 1000]);
+        assert!(per_set < 9.0, "{:.1} allocations per SET", per_set);
+    }
+
+    // A new key's name is made once, from the request, and shared by the index and the executor
+    #[test]
+    fn given_pipelined_sets_of_new_keys_when_executed_then_key_name_made_once() {
+        let index = Arc::new(Index::new());
+        let databases = Arc::new(setup_databases());
+        let sets: Vec<_> = (0..1000).map(|key| set_of(&format!("key:{:04}", key))).collect();
+        let per_set = allocations_per_command(&index, &databases, &sets);
+        assert!(per_set < 9.0, "{:.1} allocations per SET of a new key", per_set);
+
+        let get = b"*2\r\n$3\r\nGET\r\n$8\r\nkey:0001\r\n".to_vec();
+        let per_get = allocations_per_command(&index, &databases, &vec![get; 1000]);
+        assert!(per_get < 8.0, "{:.1} allocations per GET", per_get);
     }
 
 
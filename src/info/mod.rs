@@ -0,0 +1,259 @@
+// The INFO command: human readable server statistics, grouped into sections
+
+use crate::commands::ExecutionError;
+use crate::index::Index;
+use crate::memory::{self, Usage};
+use crate::resp::Value;
+use crate::thread_pool::PoolMonitor;
+use bytes::Bytes;
+use std::fmt::Write;
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+
+const REDIS_INFO_COMMANDS: [&str; 1] = ["INFO"];
+
+// In the order they are reported
+const SECTIONS: [&str; 6] = ["server", "memory", "stats", "threads", "errorstats", "keyspace"];
+
+// Every class an error reply can start with, as ExecutionError::error_class gives them, in the
+// order INFO errorstats lists them
+const ERROR_CLASSES: [&str; 6] = ["BUSY", "BUSYKEY", "ERR", "NOPROTO", "OOM", "WRONGTYPE"];
+
+static STARTED: OnceLock<Instant> = OnceLock::new();
+// Jobs and commands that panicked, and were caught so the thread they ran on carried on
+static RECOVERED_PANICS: AtomicU64 = AtomicU64::new(0);
+// Keys removed to keep under maxmemory
+static EVICTED_KEYS: AtomicU64 = AtomicU64::new(0);
+// Keys removed because their time to live ran out
+static EXPIRED_KEYS: AtomicU64 = AtomicU64::new(0);
+// Error replies sent to clients, by class, in the order of ERROR_CLASSES
+static ERROR_REPLIES: [AtomicU64; ERROR_CLASSES.len()] = [const { AtomicU64::new(0) }; ERROR_CLASSES.len()];
+
+// Called once at startup so uptime is measured from when the server began accepting clients
+pub fn record_start_time() {
+    STARTED.get_or_init(Instant::now);
+}
+
+pub fn record_recovered_panic() {
+    RECOVERED_PANICS.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn record_evicted_key() {
+    EVICTED_KEYS.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn record_expired_key() {
+    EXPIRED_KEYS.fetch_add(1, Ordering::Relaxed);
+}
+
+// A class that isn't listed is counted as ERR, so every error reply is counted somewhere
+pub fn record_error_reply(class: &str) {
+    let position = ERROR_CLASSES.iter().position(|&listed| listed == class);
+    let position = position.unwrap_or_else(|| ERROR_CLASSES.iter().position(|&listed| listed == "ERR").unwrap());
+    ERROR_REPLIES[position].fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn is_command_supported(command: &[u8]) -> bool {
+    REDIS_INFO_COMMANDS
+        .iter()
+        .any(|&cmd| cmd.as_bytes().eq_ignore_ascii_case(command))
+}
+
+// The thread pool is reported on when the server has one serving its clients
+pub fn execute_command(
+    request: &[Bytes],
+    index: &Index,
+    thread_pool: Option<&PoolMonitor>,
+    memory: &Usage,
+) -> Result<Value, ExecutionError> {
+    // support syntax: INFO [section [section ...]]
+    // "all", "everything" and "default" (or no section) report every section
+    let requested: Vec<String> = request[1..]
+        .iter()
+        .map(|section| String::from_utf8_lossy(section).to_lowercase())
+        .collect();
+    let everything = requested.is_empty()
+        || requested
+            .iter()
+            .any(|section| matches!(section.as_str(), "all" | "everything" | "default"));
+
+    let mut text = String::new();
+    for section in SECTIONS {
+        if !everything && !requested.iter().any(|requested| requested == section) {
+            continue;
+        }
+        if !text.is_empty() {
+            text.push_str("\r\n");
+        }
+        match section {
+            "server" => server_section(&mut text),
+            "memory" => memory_section(&mut text, memory),
+            "stats" => stats_section(&mut text),
+            "threads" => threads_section(&mut text, thread_pool),
+            "errorstats" => errorstats_section(&mut text),
+            "keyspace" => keyspace_section(&mut text, index),
+            _ => {}
+        }
+    }
+
+    Ok(Value::Verbatim { format: "txt", text: Bytes::from(text) })
+}
+
+fn server_section(text: &mut String) {
+    let uptime = STARTED.get().map(|started| started.elapsed().as_secs()).unwrap_or(0);
+    text.push_str("# Server\r\n");
+    let _ = write!(text, "redis_version:{}\r\n", env!("CARGO_PKG_VERSION"));
+    text.push_str("redis_mode:standalone\r\n");
+    let _ = write!(text, "os:{} {}\r\n", std::env::consts::OS, std::env::consts::ARCH);
+    let _ = write!(text, "process_id:{}\r\n", std::process::id());
+    let _ = write!(text, "uptime_in_seconds:{}\r\n", uptime);
+    let _ = write!(text, "uptime_in_days:{}\r\n", uptime / (24 * 60 * 60));
+}
+
+fn memory_section(text: &mut String, usage: &Usage) {
+    text.push_str("# Memory\r\n");
+    let _ = write!(text, "used_memory:{}\r\n", usage.total());
+    let _ = write!(text, "used_memory_human:{}\r\n", memory::human_bytes(usage.total()));
+    let _ = write!(text, "used_memory_strings:{}\r\n", usage.strings);
+    let _ = write!(text, "used_memory_lists:{}\r\n", usage.lists);
+    let _ = write!(text, "used_memory_hashes:{}\r\n", usage.hashes);
+    let _ = write!(text, "maxmemory:{}\r\n", usage.maxmemory);
+    let _ = write!(text, "maxmemory_human:{}\r\n", memory::human_bytes(usage.maxmemory));
+    let _ = write!(text, "maxmemory_policy:{}\r\n", usage.policy.as_str());
+}
+
+fn stats_section(text: &mut String) {
+    text.push_str("# Stats\r\n");
+    let _ = write!(text, "recovered_panics:{}\r\n", RECOVERED_PANICS.load(Ordering::Relaxed));
+    let _ = write!(text, "expired_keys:{}\r\n", EXPIRED_KEYS.load(Ordering::Relaxed));
+    let _ = write!(text, "evicted_keys:{}\r\n", EVICTED_KEYS.load(Ordering::Relaxed));
+    let errors: u64 = ERROR_REPLIES.iter().map(|count| count.load(Ordering::Relaxed)).sum();
+    let _ = write!(text, "total_error_replies:{}\r\n", errors);
+}
+
+// As Redis does, only the classes that have been replied with are listed
+fn errorstats_section(text: &mut String) {
+    text.push_str("# Errorstats\r\n");
+    for (class, count) in ERROR_CLASSES.iter().zip(&ERROR_REPLIES) {
+        let count = count.load(Ordering::Relaxed);
+        if count > 0 {
+            let _ = write!(text, "errorstat_{}:count={}\r\n", class, count);
+        }
+    }
+}
+
+fn threads_section(text: &mut String, thread_pool: Option<&PoolMonitor>) {
+    text.push_str("# Threads\r\n");
+    let Some(thread_pool) = thread_pool else {
+        return;
+    };
+    let metrics = thread_pool.metrics();
+    let _ = write!(text, "thread_pool_size:{}\r\n", metrics.size);
+    let _ = write!(text, "thread_pool_busy:{}\r\n", metrics.busy);
+    let _ = write!(text, "thread_pool_queued:{}\r\n", metrics.queued);
+    let _ = write!(text, "thread_pool_jobs_executed:{}\r\n", metrics.jobs_executed);
+    let _ = write!(text, "thread_pool_panics_recovered:{}\r\n", metrics.panics_recovered);
+    for worker in metrics.workers {
+        let state = if worker.busy { "busy" } else { "idle" };
+        let _ = write!(text, "{}:state={},jobs={}\r\n", worker.name, state, worker.jobs_executed);
+    }
+}
+
+fn keyspace_section(text: &mut String, index: &Index) {
+    text.push_str("# Keyspace\r\n");
+    let keys = index.key_count();
+    if keys > 0 {
+        let _ = write!(text, "db0:keys={},expires=0,avg_ttl=0\r\n", keys);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::request;
+    use crate::resp::Protocol;
+    use crate::thread_pool::{ThreadPool, ThreadPoolOptions};
+
+    #[test]
+    fn given_resp3_when_info_then_verbatim_string_returned() {
+        let reply = execute_command(&request(&["INFO"]), &Index::new(), None, &Usage::default()).unwrap().encode(Protocol::Resp3);
+        let reply = String::from_utf8(reply.to_vec()).unwrap();
+        assert!(reply.starts_with('='));
+        assert!(reply.contains("\r\ntxt:# Server\r\n"));
+        assert!(reply.contains("# Keyspace\r\n"));
+    }
+
+    #[test]
+    fn given_resp2_when_info_then_bulk_string_returned() {
+        let reply = execute_command(&request(&["INFO"]), &Index::new(), None, &Usage::default()).unwrap().encode(Protocol::Resp2);
+        let reply = String::from_utf8(reply.to_vec()).unwrap();
+        let (header, body) = reply.split_once("\r\n").unwrap();
+        assert!(header.starts_with('$'));
+        assert!(body.starts_with("# Server\r\n"));
+        assert_eq!(header[1..].parse::<usize>().unwrap(), body.len() - 2);
+    }
+
+    #[test]
+    fn given_error_replies_when_recorded_then_listed_by_class_in_errorstats() {
+        for error in [ExecutionError::WrongType, ExecutionError::Busy, ExecutionError::BusyKey, ExecutionError::OutOfMemory,
+            ExecutionError::NoProto, ExecutionError::NoSuchKey, ExecutionError::new("other")]
+        {
+            assert!(ERROR_CLASSES.contains(&error.error_class()), "{:?}", error);
+        }
+        record_error_reply("BUSYKEY");
+        record_error_reply("NOSUCHCLASS");
+        let reply = execute_command(&request(&["INFO", "errorstats"]), &Index::new(), None, &Usage::default()).unwrap().encode(Protocol::Resp2);
+        let reply = String::from_utf8(reply.to_vec()).unwrap();
+        assert!(reply.contains("# Errorstats\r\n"), "{}", reply);
+        assert!(reply.contains("\r\nerrorstat_BUSYKEY:count="), "{}", reply);
+        assert!(reply.contains("\r\nerrorstat_ERR:count="), "{}", reply);
+        assert!(!reply.contains("NOSUCHCLASS"), "{}", reply);
+    }
+
+    #[test]
+    fn given_section_when_info_then_only_that_section_reported() {
+        let reply = execute_command(&request(&["INFO", "KEYSPACE"]), &Index::new(), None, &Usage::default()).unwrap().encode(Protocol::Resp2);
+        assert_eq!(reply, "$12\r\n# Keyspace\r\n\r\n");
+    }
+
+    #[test]
+    fn given_memory_usage_when_info_memory_then_totals_and_breakdown_reported() {
+        let usage = Usage { strings: 2048, lists: 384, hashes: 128, maxmemory: 1024 * 1024, policy: memory::EvictionPolicy::AllKeysLru };
+        let reply = execute_command(&request(&["INFO", "memory"]), &Index::new(), None, &usage).unwrap().encode(Protocol::Resp2);
+        let reply = String::from_utf8(reply.to_vec()).unwrap();
+        assert!(reply.contains(
+            "# Memory\r\nused_memory:2560\r\nused_memory_human:2.50K\r\nused_memory_strings:2048\r\nused_memory_lists:384\r\n\
+             used_memory_hashes:128\r\n"
+        ), "{}", reply);
+        assert!(reply.contains("maxmemory:1048576\r\nmaxmemory_human:1.00M\r\nmaxmemory_policy:allkeys-lru\r\n"), "{}", reply);
+    }
+
+    #[test]
+    fn given_recovered_panic_when_info_stats_then_counted() {
+        record_recovered_panic();
+        let reply = execute_command(&request(&["INFO", "stats"]), &Index::new(), None, &Usage::default()).unwrap().encode(Protocol::Resp2);
+        let reply = String::from_utf8(reply.to_vec()).unwrap();
+        let count = reply
+            .lines()
+            .find_map(|line| line.strip_prefix("recovered_panics:"))
+            .unwrap();
+        // other tests may have recorded some too
+        assert!(count.parse::<u64>().unwrap() >= 1);
+    }
+
+    #[test]
+    fn given_thread_pool_when_info_threads_then_pool_and_workers_reported() {
+        let pool = ThreadPool::with_options(2, ThreadPoolOptions::default());
+        let monitor = pool.monitor();
+        let reply = execute_command(&request(&["INFO", "threads"]), &Index::new(), Some(&monitor), &Usage::default());
+        let reply = String::from_utf8(reply.unwrap().encode(Protocol::Resp2).to_vec()).unwrap();
+        assert!(reply.contains("# Threads\r\nthread_pool_size:2\r\nthread_pool_busy:0\r\n"), "{}", reply);
+        assert!(reply.contains("\r\nredis-worker-0:state=idle,jobs=0\r\n"), "{}", reply);
+        assert!(reply.contains("\r\nredis-worker-1:state=idle,jobs=0\r\n"), "{}", reply);
+
+        // without a pool of its own, the section is empty
+        let reply = execute_command(&request(&["INFO", "threads"]), &Index::new(), None, &Usage::default()).unwrap().encode(Protocol::Resp2);
+        assert_eq!(reply, "$11\r\n# Threads\r\n\r\n");
+    }
+}
@@ -0,0 +1,30 @@
+// A Redis server that can be embedded in another program, or started in-process by tests.
+// main.rs is a thin wrapper that reads the config and runs it until it is told to stop.
+
+// The executors declare their per-command fields up front and assign them in each match arm
+#![allow(clippy::needless_late_init)]
+#![allow(clippy::enum_variant_names)]
+
+mod commands;
+mod tokenizer;
+mod string_executor;
+// the async front end runs on tokio's threads instead, so has no pool for INFO to report on
+#[cfg_attr(feature = "async", allow(dead_code))]
+mod thread_pool;
+mod controller;
+mod index;
+mod executor;
+mod expiration;
+mod list_executor;
+mod hash_executor;
+mod resp;
+mod pubsub;
+mod glob;
+mod info;
+mod debug;
+mod config;
+mod memory;
+
+pub use config::{CommandLine, Config, OutputBufferLimit};
+pub use controller::shutdown::ShutdownSignal;
+pub use controller::{Server, ServerHandle};
@@ -1,131 +1,133 @@
-// TODO add   LSET, LREM, LRANGE
-// TODO add support for multiple adds for LPUSH and RPUSH, RPOP and LPOP
+use crate::executor::{CommandExecutor, EntryView};
+use crate::commands::options::{self, with_value, OptionSpec};
+use crate::commands::table;
+use crate::commands::{stored_value, syntax_error, wrong_number_of_arguments, wrong_type, ExecutionError, ParserError};
+use crate::index::IndexImpactOnCompletion::{self, Add, Delete, NoImpact};
+use crate::index::{CommandCompleted, CommandIdentifier, Key, KeyImpact, KeyType};
+use crate::memory;
+use crate::resp::Value;
+use bytes::Bytes;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::hash::{BuildHasher, RandomState};
+use std::ops::RangeInclusive;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
 
-use crate::commands::{ExecutionError, ParserError};
-use crate::index::IndexImpactOnCompletion::{Add, Delete, NoImpact};
-use crate::index::LockType::{Read, Write};
-use crate::index::{CommandCompleted, CommandIdentifier, KeyType, LockType, RedisCommandType};
-use bytes::{Bytes, BytesMut};
-use std::collections::{HashMap, VecDeque};
-use std::sync::Mutex;
+const REDIS_LIST_COMMANDS: [&str; 14] =
+    ["LLEN", "LINDEX", "LRANGE", "LPOS", "LSET", "LREM", "LTRIM", "LINSERT", "LMOVE", "RPOPLPUSH", "RPUSH", "RPOP", "LPUSH", "LPOP"];
 
-const REDIS_LIST_COMMANDS: [&str; 6] = ["LLEN", "LINDEX", "RPUSH", "RPOP", "LPUSH", "LPOP"];
+const LPOS_OPTIONS: [OptionSpec; 3] = [with_value("RANK", &[]), with_value("COUNT", &[]), with_value("MAXLEN", &[])];
 
+// How many parts the lists are split into, each behind its own lock
+const SHARDS: usize = 16;
+
+// The lists, split into shards by a hash of their keys as the strings are, and each behind a lock
+// of its own as well. A shard is only locked long enough to find or add a list, so a long command
+// on one list, such as an LRANGE over millions of elements, holds up only the commands on that list.
+//  - a list is locked after its shard is let go; the one exception is a list a command has
+//    emptied, which is taken out of its shard while still locked. As nothing waits for a list
+//    while holding a shard, there is no order to get wrong
+//  - a list taken out of its shard is marked removed, and whoever marks it stops counting its
+//    memory; a command that found it just before then looks for the key again
 pub(crate) struct ListExecutor {
-    data: Mutex<HashMap<String, VecDeque<Bytes>>>,
+    shards: [Mutex<HashMap<Key, Arc<Mutex<List>>>>; SHARDS],
+    hasher: RandomState,
+    // the bytes held, counted as elements are pushed and popped
+    used_memory: AtomicUsize,
+}
+
+#[derive(Default)]
+struct List {
+    elements: VecDeque<Bytes>,
+    removed: bool,
 }
 
 impl ListExecutor {
     pub(crate) fn new() -> ListExecutor {
         ListExecutor {
-            data: Mutex::new(HashMap::new()),
+            shards: std::array::from_fn(|_| Mutex::new(HashMap::new())),
+            hasher: RandomState::new(),
+            used_memory: AtomicUsize::new(0),
         }
     }
 
-    pub fn is_command_supported(command: &str) -> bool {
-        REDIS_LIST_COMMANDS
-            .iter()
-            .any(|&cmd| cmd.eq_ignore_ascii_case(command))
-    }
-
-    pub fn build_command(command: &Vec<String>) -> Result<CommandIdentifier, ParserError> {
+    pub fn build_command(command: &[Bytes]) -> Result<CommandIdentifier, ParserError> {
         // support syntax: LLEN name
+        //                 LINDEX name index
+        //                 LRANGE name start stop
+        //                 LPOS name element [RANK rank] [COUNT num] [MAXLEN len]
+        //                 LSET name index element
+        //                 LREM name count element
+        //                 LTRIM name start stop
+        //                 LINSERT name BEFORE|AFTER pivot element
+        //                 LMOVE source destination LEFT|RIGHT LEFT|RIGHT
+        //                 RPOPLPUSH source destination
+        //                 RPUSH name element [element ...]
+        //                 LPUSH name element [element ...]
+        //                 RPOP name [count]
+        //                 LPOP name [count]
 
-        if command.len() < 2 {
-            return Err(ParserError::new(
-                "Not enough identifiers provided for List command",
-            ));
-        }
-
-        let command_type: RedisCommandType;
-        let target: String;
-        let action: String;
-        let lock_type: LockType;
-        let mut params: Vec<Bytes> = Vec::new();
-
-        match command[0].to_uppercase().as_str() {
-            "LLEN" => {
-                if command.len() != 2 {
-                    return Err(ParserError::new(
-                        "LLEN command requires exactly one parameter",
-                    ));
+        // The table has checked the arity, and says where the key is and how it is locked
+        let spec = table::validate(command)?.ok_or_else(|| ParserError::new("Unsupported List command type"))?;
+        let params: Vec<Bytes> = match spec.name {
+            "LLEN" => Vec::new(),
+            "RPOP" | "LPOP" => {
+                if command.len() > 3 {
+                    return Err(wrong_number_of_arguments(&command[0]));
                 }
-                command_type = RedisCommandType::ListCommand;
-                action = "LLEN".to_string();
-                target = command[1].clone();
-                //  no params for LLEN command
-                lock_type = Read
+                command[2..].to_vec()
             }
-            "LINDEX" => {
-                if command.len() != 3 {
-                    return Err(ParserError::new(
-                        "LINDEX command requires exactly two parameters",
-                    ));
+            "LINDEX" => vec![command[2].clone()],
+            "LRANGE" | "LSET" | "LREM" | "LTRIM" => command[2..4].to_vec(),
+            "LPOS" => {
+                let (lpos_options, rest) = options::parse(&LPOS_OPTIONS, &command[3..])?;
+                if !rest.is_empty() {
+                    return Err(syntax_error());
                 }
-                command_type = RedisCommandType::ListCommand;
-                action = "LINDEX".to_string();
-                target = command[1].clone();
-                params.push(command[2].as_bytes().to_vec().into());
-                lock_type = Read
-            }
-            "RPUSH" => {
-                if command.len() != 3 {
+                let rank = lpos_options.integer("RANK")?.unwrap_or(1);
+                if rank == 0 {
                     return Err(ParserError::new(
-                        "RPUSH command requires exactly two parameters",
+                        "RANK can't be zero: use 1 to start from the first match, 2 from the second ... or use negative to start from the end of the list",
                     ));
                 }
-                command_type = RedisCommandType::ListCommand;
-                action = "RPUSH".to_string();
-                target = command[1].clone();
-                params.push(command[2].as_bytes().to_vec().into());
-                lock_type = Write
-            }
-            "RPOP" => {
-                if command.len() != 2 {
-                    return Err(ParserError::new(
-                        "RPOP command requires exactly one parameters",
-                    ));
+                // counting back from the tail negates it, which i64::MIN can't be
+                if rank == i64::MIN {
+                    return Err(ParserError::new("value is out of range"));
+                }
+                let count = lpos_options.integer("COUNT")?;
+                if count.is_some_and(|count| count < 0) {
+                    return Err(ParserError::new("COUNT can't be negative"));
                 }
-                command_type = RedisCommandType::ListCommand;
-                action = "RPOP".to_string();
-                target = command[1].clone();
-                lock_type = Write
+                let maxlen = lpos_options.integer("MAXLEN")?.unwrap_or(0);
+                if maxlen < 0 {
+                    return Err(ParserError::new("MAXLEN can't be negative"));
+                }
+                // the element, the rank and the maxlen, then the count only if one was given, as
+                // that decides whether the reply is an array
+                let mut params = vec![command[2].clone(), Bytes::from(rank.to_string()), Bytes::from(maxlen.to_string())];
+                params.extend(count.map(|count| Bytes::from(count.to_string())));
+                params
             }
-            "LPUSH" => {
-                if command.len() != 3 {
-                    return Err(ParserError::new(
-                        "LPUSH command requires exactly two parameters",
-                    ));
+            "LINSERT" => {
+                if !command[2].eq_ignore_ascii_case(b"BEFORE") && !command[2].eq_ignore_ascii_case(b"AFTER") {
+                    return Err(syntax_error());
                 }
-                command_type = RedisCommandType::ListCommand;
-                action = "LPUSH".to_string();
-                target = command[1].clone();
-                params.push(command[2].as_bytes().to_vec().into());
-                lock_type = Write
+                command[2..5].to_vec()
             }
-            "LPOP" => {
-                if command.len() != 2 {
-                    return Err(ParserError::new(
-                        "LPOP command requires exactly one parameters",
-                    ));
+            "LMOVE" => {
+                let is_end = |word: &Bytes| word.eq_ignore_ascii_case(b"LEFT") || word.eq_ignore_ascii_case(b"RIGHT");
+                if !is_end(&command[3]) || !is_end(&command[4]) {
+                    return Err(syntax_error());
                 }
-                command_type = RedisCommandType::ListCommand;
-                action = "LPOP".to_string();
-                target = command[1].clone();
-                lock_type = Write
+                command[3..5].to_vec()
             }
-
+            // the older name for moving from the tail of one to the head of the other
+            "RPOPLPUSH" => vec![Bytes::from_static(b"RIGHT"), Bytes::from_static(b"LEFT")],
+            "RPUSH" | "LPUSH" => command[2..].to_vec(),
             _ => return Err(ParserError::new("Unsupported List command type")),
-        }
+        };
 
-        Ok(CommandIdentifier::new(
-            command_type,
-            target,
-            action,
-            params,
-            KeyType::List,
-            lock_type,
-        ))
+        CommandIdentifier::from_spec(spec, command, params)
     }
 
     pub fn execute_command(
@@ -134,37 +136,67 @@ impl ListExecutor {
     ) -> Result<CommandCompleted, ExecutionError> {
         match command.get_action() {
             "LLEN" => {
-                let index = self.data.lock().unwrap();
-                let values = index.get(command.get_target());
-                let length = match values {
-                    Some(entry) => entry.len(),
-                    None => 0,
-                };
+                let length = self.read(command.get_target(), |list| list.len()).unwrap_or(0);
 
                 Ok(CommandCompleted::new(
                     command.get_target(),
                     KeyType::List,
                     NoImpact,
-                    Self::format_size_response(length),
+                    Value::Integer(length as i64),
                 ))
             }
             "LINDEX" => {
-                let values = self.data.lock().unwrap();
-                let entries = values.get(command.get_target());
-                let response: Bytes;
-                match entries {
-                    Some(entry) => {
-                        let index = Self::index_from_bytes(&command.get_params()[0])?;
-                        response = entry
-                            .get(index as usize)
-                            .map_or(Self::format_null_response(), |value| {
-                                Self::format_string_response(value)
-                            })
-                    }
-                    None => {
-                        response = Self::format_null_response();
-                    }
-                }
+                let index = Self::signed_index_from_bytes(&command.get_params()[0])?;
+                let element = self.read(command.get_target(), |list| {
+                    Self::offset(list.len(), index).and_then(|index| list.get(index)).cloned()
+                });
+                Ok(CommandCompleted::new(
+                    command.get_target(),
+                    KeyType::List,
+                    NoImpact,
+                    Value::bulk_or_null(element.flatten()),
+                ))
+            }
+            "LRANGE" => {
+                let start = Self::signed_index_from_bytes(&command.get_params()[0])?;
+                let stop = Self::signed_index_from_bytes(&command.get_params()[1])?;
+                let elements = self.read(command.get_target(), |list| {
+                    Self::range(list.len(), start, stop)
+                        .map_or_else(Vec::new, |range| list.range(range).map(|value| Value::BulkString(value.clone())).collect())
+                });
+                Ok(CommandCompleted::new(
+                    command.get_target(),
+                    KeyType::List,
+                    NoImpact,
+                    Value::Array(elements.unwrap_or_default()),
+                ))
+            }
+            "LPOS" => {
+                // the rank'th match onwards, looking back from the tail when it is negative, at no
+                // more than maxlen elements (0 is all of them), and up to count matches (0 is all)
+                let params = command.get_params();
+                let rank = Self::signed_index_from_bytes(&params[1])?;
+                let maxlen = Self::signed_index_from_bytes(&params[2])?.unsigned_abs();
+                let count = params.get(3).map(Self::signed_index_from_bytes).transpose()?.map(isize::unsigned_abs);
+                let positions = self.read(command.get_target(), |list| {
+                    let scanned = if maxlen == 0 { list.len() } else { maxlen.min(list.len()) };
+                    let scan: Box<dyn Iterator<Item = usize>> =
+                        if rank > 0 { Box::new(0..scanned) } else { Box::new((list.len() - scanned..list.len()).rev()) };
+                    let wanted = match count {
+                        Some(0) => usize::MAX,
+                        Some(count) => count,
+                        None => 1,
+                    };
+                    scan.filter(|&position| list[position] == params[0])
+                        .skip(rank.unsigned_abs() - 1)
+                        .take(wanted)
+                        .collect::<Vec<usize>>()
+                });
+                let positions = positions.unwrap_or_default();
+                let response = match count {
+                    Some(_) => Value::Array(positions.into_iter().map(|position| Value::Integer(position as i64)).collect()),
+                    None => positions.first().map_or(Value::Null, |&position| Value::Integer(position as i64)),
+                };
 
                 Ok(CommandCompleted::new(
                     command.get_target(),
@@ -173,288 +205,581 @@ impl ListExecutor {
                     response,
                 ))
             }
-            "RPUSH" => {
-                let mut values = self.data.lock().unwrap();
-                let mut index_impact = NoImpact;
-                let entries = match values.get_mut(command.get_target()) {
-                    Some(entry) => entry,
-                    None => {
-                        let new_entry = VecDeque::new();
-                        values.insert(command.get_target().parse().unwrap(), new_entry);
-                        index_impact = Add;
-                        values.get_mut(command.get_target()).unwrap()
-                    }
-                };
-                entries.push_back(command.get_params()[0].clone());
-                let length = entries.len();
+            "LSET" => {
+                let index = Self::signed_index_from_bytes(&command.get_params()[0])?;
+                let replaced = self.update(command.get_target(), false, |list| -> Result<(), ExecutionError> {
+                    let element = Self::offset(list.len(), index)
+                        .and_then(|index| list.get_mut(index))
+                        .ok_or(ExecutionError::IndexOutOfRange)?;
+                    let value = stored_value(&command.get_params()[1]);
+                    self.used_memory.fetch_add(memory::element_size(&value), Ordering::Relaxed);
+                    self.used_memory.fetch_sub(memory::element_size(element), Ordering::Relaxed);
+                    *element = value;
+                    Ok(())
+                });
+                let (replaced, _) = replaced.ok_or(ExecutionError::NoSuchKey)?;
+                replaced?;
+
+                Ok(CommandCompleted::new(
+                    command.get_target(),
+                    KeyType::List,
+                    NoImpact,
+                    Value::Ok,
+                ))
+            }
+            "LREM" => {
+                // up to count matches from the head, from the tail when it is negative, or all of them when 0
+                let count = Self::signed_index_from_bytes(&command.get_params()[0])?;
+                let element = &command.get_params()[1];
+                let removed = self.update(command.get_target(), false, |list| {
+                    let limit = if count == 0 { usize::MAX } else { count.unsigned_abs() };
+                    let matches = list.iter().enumerate().filter(|(_, value)| *value == element).map(|(position, _)| position);
+                    let doomed: HashSet<usize> =
+                        if count < 0 { matches.rev().take(limit).collect() } else { matches.take(limit).collect() };
+                    let mut position = 0;
+                    list.retain(|_| {
+                        position += 1;
+                        !doomed.contains(&(position - 1))
+                    });
+                    self.used_memory.fetch_sub(doomed.len() * memory::element_size(element), Ordering::Relaxed);
+                    doomed.len()
+                });
+                let (removed, index_impact) = removed.unwrap_or((0, NoImpact));
 
                 Ok(CommandCompleted::new(
                     command.get_target(),
                     KeyType::List,
                     index_impact,
-                    Self::format_size_response(length),
+                    Value::Integer(removed as i64),
                 ))
             }
-            "RPOP" => {
-                let mut values = self.data.lock().unwrap();
-                let entries = values.get_mut(command.get_target());
-                let mut index_impact = NoImpact;
-                let response: Bytes;
-                match entries {
-                    Some(entry) => {
-                        match entry.pop_back() {
-                            Some(value) => {
-                                if entry.is_empty() {
-                                    values.remove(command.get_target());
-                                    index_impact = Delete;
-                                }
-                                response = Self::format_string_response(&value);
-                            }
-                            _ => {
-                                response = Self::format_null_response();
-                            }
+            "LTRIM" => {
+                let start = Self::signed_index_from_bytes(&command.get_params()[0])?;
+                let stop = Self::signed_index_from_bytes(&command.get_params()[1])?;
+                let trimmed = self.update(command.get_target(), false, |list| {
+                    let size = |element: Bytes| memory::element_size(&element);
+                    // everything goes when nothing is left of the range
+                    let removed: usize = match Self::range(list.len(), start, stop) {
+                        Some(kept) => {
+                            let after: usize = list.drain(kept.end() + 1..).map(size).sum();
+                            after + list.drain(..*kept.start()).map(size).sum::<usize>()
                         }
-                    }
-                    None => {
-                        response = Self::format_null_response();
-                    }
-                }
-
+                        None => list.drain(..).map(size).sum(),
+                    };
+                    self.used_memory.fetch_sub(removed, Ordering::Relaxed);
+                });
+                let index_impact = trimmed.map_or(NoImpact, |(_, impact)| impact);
 
                 Ok(CommandCompleted::new(
                     command.get_target(),
                     KeyType::List,
                     index_impact,
-                    response,
+                    Value::Ok,
                 ))
             }
-            "LPUSH" => {
-                let mut values = self.data.lock().unwrap();
-                let mut index_impact = NoImpact;
-                let entries = match values.get_mut(command.get_target()) {
-                    Some(entry) => entry,
-                    None => {
-                        let new_entry = VecDeque::new();
-                        values.insert(command.get_target().parse().unwrap(), new_entry);
-                        index_impact = Add;
-                        values.get_mut(command.get_target()).unwrap()
+            "LINSERT" => {
+                let params = command.get_params();
+                // 0 for a missing list, -1 for a missing pivot, otherwise the new length
+                let length = self.update(command.get_target(), false, |list| match list.iter().position(|value| *value == params[1]) {
+                    Some(pivot) => {
+                        let position = if params[0].eq_ignore_ascii_case(b"AFTER") { pivot + 1 } else { pivot };
+                        let value = stored_value(&params[2]);
+                        self.used_memory.fetch_add(memory::element_size(&value), Ordering::Relaxed);
+                        list.insert(position, value);
+                        list.len() as i64
                     }
-                };
-                entries.push_front(command.get_params()[0].clone());
-                let length = entries.len();
+                    None => -1,
+                });
 
                 Ok(CommandCompleted::new(
                     command.get_target(),
                     KeyType::List,
-                    index_impact,
-                    Self::format_size_response(length),
+                    NoImpact,
+                    Value::Integer(length.map_or(0, |(length, _)| length)),
                 ))
             }
-            "LPOP" => {
-                let mut values = self.data.lock().unwrap();
-                let entries = values.get_mut(command.get_target());
-                let mut index_impact = NoImpact;
-                let response: Bytes;
-                match entries {
-                    Some(entry) => {
-                        match entry.pop_front() {
-                            Some(value) => {
-                                if entry.is_empty() {
-                                    values.remove(command.get_target());
-                                    index_impact = Delete;
-                                }
-                                response = Self::format_string_response(&value);
-                            }
-                            _ => {
-                                response = Self::format_null_response();
-                            }
-                        }
+            "LMOVE" | "RPOPLPUSH" => {
+                // the Index holds both keys until this is done, so no other command sees the
+                // element in neither list or in both
+                let (source, destination) = (&command.get_keys()[0], &command.get_keys()[1]);
+                let from_left = command.get_params()[0].eq_ignore_ascii_case(b"LEFT");
+                let to_left = command.get_params()[1].eq_ignore_ascii_case(b"LEFT");
+                let pop = |list: &mut VecDeque<Bytes>| {
+                    let value = if from_left { list.pop_front() } else { list.pop_back() };
+                    value.expect("an empty list is removed")
+                };
+                let push = |list: &mut VecDeque<Bytes>, value: Bytes| if to_left { list.push_front(value) } else { list.push_back(value) };
+                if source == destination {
+                    // a list moved onto itself is rotated, so is never left empty
+                    let rotated = self.update(source, false, |list| {
+                        let value = pop(list);
+                        push(list, value.clone());
+                        value
+                    });
+                    return Ok(CommandCompleted::new(source, KeyType::List, NoImpact, Value::bulk_or_null(rotated.map(|(value, _)| value))));
+                }
+                let Some((value, popped)) = self.update(source, false, pop) else {
+                    return Ok(CommandCompleted::new(source, KeyType::List, NoImpact, Value::Null));
+                };
+                let (_, pushed) = self.update(destination, true, |list| push(list, value.clone())).expect("a list is created to push onto");
+                let impacts = [(source, popped), (destination, pushed)]
+                    .into_iter()
+                    .filter(|(_, impact)| *impact != NoImpact)
+                    .map(|(key, impact)| KeyImpact::new(key, KeyType::List, impact))
+                    .collect();
+
+                Ok(CommandCompleted::with_impacts(impacts, Value::BulkString(value)))
+            }
+            "RPUSH" => self.push(command, false),
+            "LPUSH" => self.push(command, true),
+            "RPOP" => self.pop(command, false),
+            "LPOP" => self.pop(command, true),
+            _ => Err(wrong_type()),
+        }
+    }
+
+    // Each element in turn, creating the list if there is none, replying with its new length
+    fn push(&self, command: &CommandIdentifier, onto_head: bool) -> Result<CommandCompleted, ExecutionError> {
+        let pushed = self.update(command.get_target(), true, |list| {
+            for element in command.get_params() {
+                let value = stored_value(element);
+                self.used_memory.fetch_add(memory::element_size(&value), Ordering::Relaxed);
+                if onto_head {
+                    list.push_front(value);
+                } else {
+                    list.push_back(value);
+                }
+            }
+            list.len()
+        });
+        let (length, index_impact) = pushed.expect("a list is created to push onto");
+
+        Ok(CommandCompleted::new(
+            command.get_target(),
+            KeyType::List,
+            index_impact,
+            Value::Integer(length as i64),
+        ))
+    }
+
+    // One element, or with a count, an array of up to that many
+    fn pop(&self, command: &CommandIdentifier, from_head: bool) -> Result<CommandCompleted, ExecutionError> {
+        let count = match command.get_params().first() {
+            Some(count) => Some(
+                Self::signed_index_from_bytes(count)
+                    .ok()
+                    .and_then(|count| usize::try_from(count).ok())
+                    .ok_or(ExecutionError::NotPositive)?,
+            ),
+            None => None,
+        };
+        let popped = self.update(command.get_target(), false, |list| {
+            let mut popped = Vec::new();
+            while popped.len() < count.unwrap_or(1) {
+                let Some(value) = (if from_head { list.pop_front() } else { list.pop_back() }) else {
+                    break;
+                };
+                self.used_memory.fetch_sub(memory::element_size(&value), Ordering::Relaxed);
+                popped.push(value);
+            }
+            popped
+        });
+        let Some((mut popped, index_impact)) = popped else {
+            let response = if count.is_some() { Value::NullArray } else { Value::Null };
+            return Ok(CommandCompleted::new(command.get_target(), KeyType::List, NoImpact, response));
+        };
+        let response = match count {
+            Some(_) => Value::Array(popped.into_iter().map(Value::BulkString).collect()),
+            None => Value::bulk_or_null(popped.pop()),
+        };
+
+        Ok(CommandCompleted::new(
+            command.get_target(),
+            KeyType::List,
+            index_impact,
+            response,
+        ))
+    }
+
+    fn shard(&self, key: &str) -> MutexGuard<'_, HashMap<Key, Arc<Mutex<List>>>> {
+        let shard = self.hasher.hash_one(key) as usize % SHARDS;
+        self.shards[shard].lock().unwrap()
+    }
+
+    // Calls `read` with the key's list, if it has one, holding only that list's lock
+    fn read<R>(&self, key: &str, read: impl FnOnce(&VecDeque<Bytes>) -> R) -> Option<R> {
+        let entry = Arc::clone(self.shard(key).get(key)?);
+        let list = entry.lock().unwrap();
+        // one just emptied, or just added and not pushed to yet, is as good as missing
+        (!list.removed && !list.elements.is_empty()).then(|| read(&list.elements))
+    }
+
+    // Calls `change` with the key's list, adding an empty one first if there is none and `create`
+    // is set, and removes the list if `change` leaves it empty. Returns what `change` did and
+    // whether the key was added or deleted, or None when there was no list to change.
+    fn update<R>(
+        &self,
+        key: &Key,
+        create: bool,
+        change: impl FnOnce(&mut VecDeque<Bytes>) -> R,
+    ) -> Option<(R, IndexImpactOnCompletion)> {
+        let mut change = Some(change);
+        loop {
+            let (entry, created) = {
+                let mut shard = self.shard(key);
+                match shard.get(key) {
+                    Some(entry) => (Arc::clone(entry), false),
+                    None if create => {
+                        let entry = Arc::new(Mutex::new(List::default()));
+                        shard.insert(Key::clone(key), Arc::clone(&entry));
+                        self.used_memory.fetch_add(memory::key_size(key, 0), Ordering::Relaxed);
+                        (entry, true)
                     }
-                    None => {
-                        response = Self::format_null_response();
+                    None => return None,
+                }
+            };
+            let mut list = entry.lock().unwrap();
+            if list.removed {
+                continue;
+            }
+            if list.elements.is_empty() && !create {
+                // just added by a push that hasn't pushed yet
+                return None;
+            }
+            let result = change.take().expect("a list is changed only once")(&mut list.elements);
+            let impact = match (created, list.elements.is_empty()) {
+                (false, false) => NoImpact,
+                (true, false) => Add,
+                (created, true) => {
+                    list.removed = true;
+                    let mut shard = self.shard(key);
+                    if shard.get(key).is_some_and(|found| Arc::ptr_eq(found, &entry)) {
+                        shard.remove(key);
                     }
+                    self.used_memory.fetch_sub(memory::key_size(key, 0), Ordering::Relaxed);
+                    if created { NoImpact } else { Delete }
                 }
+            };
+            return Some((result, impact));
+        }
+    }
 
+    // Marks a list taken out of its shard as removed, and stops counting it, unless a command
+    // emptied and removed it first. Returns whether this removed it.
+    fn discard(&self, key: &str, entry: &Mutex<List>) -> bool {
+        let mut list = entry.lock().unwrap();
+        if list.removed {
+            return false;
+        }
+        list.removed = true;
+        let elements: usize = list.elements.iter().map(|element| memory::element_size(element)).sum();
+        self.used_memory.fetch_sub(memory::key_size(key, 0) + elements, Ordering::Relaxed);
+        true
+    }
 
-                Ok(CommandCompleted::new(
-                    command.get_target(),
-                    KeyType::List,
-                    index_impact,
-                    response,
-                ))
+    // Every list with its key, a shard at a time, with each shard let go before any list is looked at
+    fn entries(&self) -> Vec<(Key, Arc<Mutex<List>>)> {
+        let mut entries = Vec::new();
+        for shard in &self.shards {
+            entries.extend(shard.lock().unwrap().iter().map(|(key, entry)| (Key::clone(key), Arc::clone(entry))));
+        }
+        entries
+    }
+
+    pub fn used_memory(&self) -> usize {
+        self.used_memory.load(Ordering::Relaxed)
+    }
+
+    #[cfg(test)]
+    pub fn recount_memory(&self) -> usize {
+        let mut total = 0;
+        self.for_each_entry(&mut |key, entry| {
+            if let EntryView::List(list) = entry {
+                total += memory::key_size(key, 0) + list.iter().map(|element| memory::element_size(element)).sum::<usize>();
+            }
+        });
+        total
+    }
+
+    // Removes the whole list, returning how many keys went
+    pub fn delete(&self, key: &str) -> u16 {
+        let Some(entry) = self.shard(key).remove(key) else {
+            return 0;
+        };
+        self.discard(key, &entry) as u16
+    }
+
+    // Moves the list's elements to the new key, replacing any list there
+    pub fn rename(&self, old_key: &str, new_key: &Key) -> bool {
+        let Some(entry) = self.shard(old_key).remove(old_key) else {
+            return false;
+        };
+        let elements = {
+            let mut list = entry.lock().unwrap();
+            if list.removed {
+                return false;
+            }
+            list.removed = true;
+            std::mem::take(&mut list.elements)
+        };
+        self.used_memory.fetch_sub(memory::key_size(old_key, 0), Ordering::Relaxed);
+        self.used_memory.fetch_add(memory::key_size(new_key, 0), Ordering::Relaxed);
+        let moved = Arc::new(Mutex::new(List { elements, removed: false }));
+        if let Some(replaced) = self.shard(new_key).insert(Key::clone(new_key), moved) {
+            self.discard(new_key, &replaced);
+        }
+        true
+    }
+
+    pub fn keys(&self) -> Vec<Key> {
+        let mut keys = Vec::new();
+        for shard in &self.shards {
+            keys.extend(shard.lock().unwrap().keys().cloned());
+        }
+        keys
+    }
+
+    // A list at a time, each locked while `visit` sees it, so `visit` must not use the executor.
+    // A list added or removed meanwhile may or may not be seen.
+    pub fn for_each_entry(&self, visit: &mut dyn FnMut(&str, EntryView)) {
+        for (key, entry) in self.entries() {
+            let list = entry.lock().unwrap();
+            if !list.removed && !list.elements.is_empty() {
+                visit(&key, EntryView::List(&list.elements));
             }
-            _ => Err(ExecutionError::new(
-                "-WRONGTYPE Operation against a key holding the wrong kind of value",
-            )),
         }
     }
 
-    fn format_size_response(size: usize) -> Bytes {
-        let mut buf = BytesMut::new();
-        buf.extend_from_slice(b":");
-        buf.extend_from_slice(size.to_string().as_bytes());
-        buf.extend_from_slice(b"\r\n");
-        buf.freeze()
+    // The elements from start to stop, both included, as Redis reads them: negative indexes count
+    // back from the end, and the range is clipped to the list. None when nothing is left of it.
+    fn range(length: usize, start: isize, stop: isize) -> Option<RangeInclusive<usize>> {
+        let start = Self::from_head(length, start).max(0);
+        let stop = Self::from_head(length, stop).min(length as isize - 1);
+        (start <= stop && start < length as isize).then_some(start as usize..=stop as usize)
     }
 
-    fn format_string_response(value: &Bytes) -> Bytes {
-        let mut buf = BytesMut::with_capacity(1 + value.len() + 2);
-        buf.extend_from_slice(b"+");
-        buf.extend_from_slice(&value);
-        buf.extend_from_slice(b"\r\n");
-        buf.freeze()
+    fn signed_index_from_bytes(bytes: &Bytes) -> Result<isize, ExecutionError> {
+        std::str::from_utf8(&bytes[..])
+            .ok()
+            .and_then(|text| text.parse::<isize>().ok())
+            .ok_or(ExecutionError::NotAnInteger)
     }
 
-    fn format_null_response() -> Bytes {
-        Bytes::from("_\r\n")
+    // Where an index falls in the list, with a negative one counting back from the tail. None
+    // when it is before the head; one past the tail is left for the caller's lookup to reject.
+    fn offset(length: usize, index: isize) -> Option<usize> {
+        usize::try_from(Self::from_head(length, index)).ok()
     }
 
-    fn index_from_bytes(bytes: &Bytes) -> Result<usize, ExecutionError> {
-        let index_str = std::str::from_utf8(&bytes[..])
-            .map_err(|_| ExecutionError::new("Invalid index format"))?;
-        let index = index_str
-            .parse::<isize>()
-            .map_err(|_| ExecutionError::new("Index is not an integer or out of range"))?;
-        Ok(index as usize)
+    // The index counted from the head, which is below zero for a negative one reaching back
+    // past the head
+    fn from_head(length: usize, index: isize) -> isize {
+        if index < 0 { length as isize + index } else { index }
     }
 
+    #[cfg(test)]
     pub(crate) fn internal_get_length(&self) -> usize {
-        let values = self.data.lock().unwrap();
-        values.len() as usize
+        self.shards.iter().map(|shard| shard.lock().unwrap().len()).sum()
     }
 
+    #[cfg(test)]
     pub(crate) fn internal_get_list_length(&self, key: &str) -> usize {
-        let values = self.data.lock().unwrap();
-        match values.get(key) {
-            Some(entry) => entry.len(),
-            None => 0,
-        }
+        self.read(key, |list| list.len()).unwrap_or(0)
     }
 
+    #[cfg(test)]
     pub (crate) fn internal_get_list_head(&self, key: &str) -> Option<Bytes> {
-        let values = self.data.lock().unwrap();
-        match values.get(key) {
-            Some(entry) => entry.front().cloned(),
-            None => None
-        }
+        self.read(key, |list| list.front().cloned()).flatten()
+    }
+}
+
+impl CommandExecutor for ListExecutor {
+    fn supported_commands(&self) -> &'static [&'static str] {
+        &REDIS_LIST_COMMANDS
+    }
+    fn key_type(&self) -> KeyType {
+        KeyType::List
+    }
+    fn build_command(&self, command: &[Bytes]) -> Result<CommandIdentifier, ParserError> {
+        ListExecutor::build_command(command)
+    }
+    fn execute(&self, command: &CommandIdentifier) -> Result<CommandCompleted, ExecutionError> {
+        self.execute_command(command)
+    }
+    fn delete(&self, key: &str) -> u16 {
+        ListExecutor::delete(self, key)
+    }
+    fn rename(&self, old_key: &str, new_key: &Key) -> bool {
+        ListExecutor::rename(self, old_key, new_key)
+    }
+    fn keys(&self) -> Vec<Key> {
+        ListExecutor::keys(self)
+    }
+    fn for_each_entry(&self, visit: &mut dyn FnMut(&str, EntryView)) {
+        ListExecutor::for_each_entry(self, visit)
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use crate::commands::{request, ExecutionError, ParserError};
     use crate::index::LockType::{Read, Write};
-    use crate::index::{CommandIdentifier, KeyType, RedisCommandType};
+    use crate::config::Config;
+    use crate::controller::Databases;
+    use crate::index::{CommandCompleted, CommandIdentifier, Index, Key, KeyType};
+    use crate::executor::EntryView;
     use crate::list_executor::ListExecutor;
+    use crate::resp::{Protocol, Value};
     use bytes::Bytes;
+    use std::sync::{mpsc, Arc};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    fn execute(db: &ListExecutor, words: &[&str]) -> Result<CommandCompleted, ExecutionError> {
+        let command = ListExecutor::build_command(&request(words)).unwrap();
+        db.execute_command(&command)
+    }
+
+    fn run(db: &ListExecutor, words: &[&str]) -> Value {
+        execute(db, words).unwrap().get_response().clone()
+    }
+
+    // The same through the index, for the commands that create, drop or move keys
+    fn run_indexed(index: &Index, databases: &Arc<Databases>, words: &[&str]) -> Result<Value, ExecutionError> {
+        index.execute_command(databases, &request(words))
+    }
+
+    #[test]
+    fn given_wrong_number_of_arguments_when_build_command_then_redis_message() {
+        let error = ListExecutor::build_command(&request(&["LLEN", "key", "extra"])).err().unwrap();
+        assert_eq!(error, ParserError::WrongNumberOfArguments("llen".to_string()));
+        let error = ListExecutor::build_command(&request(&["RPush", "key"])).err().unwrap();
+        assert_eq!(error, ParserError::WrongNumberOfArguments("rpush".to_string()));
+    }
 
     #[test]
     fn given_no_list_when_llen_return_zero() {
         let db = ListExecutor::new();
         let command = CommandIdentifier::new(
-            RedisCommandType::StringCommand,
             "key".to_string(),
-            "LLEN".to_string(),
+            "LLEN",
             Vec::new(),
             KeyType::List,
             Write,
         );
         let result = db.execute_command(&command);
-        assert_eq!(result.unwrap().get_response(), ":0\r\n");
+        assert_eq!(result.unwrap().get_response(), &Value::Integer(0));
     }
 
     #[test]
     fn given_list_with_one_element_when_llen_return_one() {
         let db = setup_list_with_multiple_elements("key", 1);
         let command = CommandIdentifier::new(
-            RedisCommandType::StringCommand,
             "key".to_string(),
-            "LLEN".to_string(),
+            "LLEN",
             Vec::new(),
             KeyType::List,
             Read,
         );
         let result = db.execute_command(&command);
-        assert_eq!(result.unwrap().get_response(), ":1\r\n");
+        assert_eq!(result.unwrap().get_response(), &Value::Integer(1));
     }
 
     #[test]
     fn given_missing_list_when_lindex_return_null() {
         let db = ListExecutor::new();
         let command = CommandIdentifier::new(
-            RedisCommandType::StringCommand,
             "key".to_string(),
-            "LINDEX".to_string(),
+            "LINDEX",
             vec![Bytes::from("0")],
             KeyType::List,
             Read,
         );
         let result = db.execute_command(&command);
-        assert_eq!(result.unwrap().get_response(), "_\r\n");
+        assert_eq!(result.unwrap().get_response(), &Value::Null);
     }
     #[test]
     fn given_list_when_lindex_0_return_value() {
         let db = setup_list_with_multiple_elements("key", 1);
         let command = CommandIdentifier::new(
-            RedisCommandType::StringCommand,
             "key".to_string(),
-            "LINDEX".to_string(),
+            "LINDEX",
             vec![Bytes::from("0")],
             KeyType::List,
             Read,
         );
         let result = db.execute_command(&command);
-        assert_eq!(result.unwrap().get_response(), "+Element0\r\n");
+        assert_eq!(result.unwrap().get_response(), &Value::BulkString(Bytes::from_static(b"Element0")));
     }
 
     #[test]
     fn given_single_list_when_lindex_1_return_null() {
         let db = setup_list_with_multiple_elements("key", 1);
         let command = CommandIdentifier::new(
-            RedisCommandType::StringCommand,
             "key".to_string(),
-            "LINDEX".to_string(),
+            "LINDEX",
             vec![Bytes::from("1")],
             KeyType::List,
             Read,
         );
         let result = db.execute_command(&command);
-        assert_eq!(result.unwrap().get_response(), "_\r\n");
+        assert_eq!(result.unwrap().get_response(), &Value::Null);
     }
 
     #[test]
     fn given_multiple_element_list_when_lindex_1_return_value() {
         let db = setup_list_with_multiple_elements("key", 2);
         let command = CommandIdentifier::new(
-            RedisCommandType::StringCommand,
             "key".to_string(),
-            "LINDEX".to_string(),
+            "LINDEX",
             vec![Bytes::from("1")],
             KeyType::List,
             Read,
         );
         let result = db.execute_command(&command);
-        assert_eq!(result.unwrap().get_response(), "+Element1\r\n");
+        assert_eq!(result.unwrap().get_response(), &Value::BulkString(Bytes::from_static(b"Element1")));
+    }
+
+    #[test]
+    fn given_negative_index_when_lindex_then_counted_back_from_the_tail() {
+        let db = setup_list_with_multiple_elements("key", 3);
+        assert_eq!(run(&db, &["LINDEX", "key", "-1"]), Value::BulkString(Bytes::from_static(b"Element2")));
+        assert_eq!(run(&db, &["LINDEX", "key", "-2"]), Value::BulkString(Bytes::from_static(b"Element1")));
+        assert_eq!(run(&db, &["LINDEX", "key", "-3"]), Value::BulkString(Bytes::from_static(b"Element0")));
+        assert_eq!(run(&db, &["LINDEX", "key", "-4"]), Value::Null);
+        assert_eq!(run(&db, &["LINDEX", "key", "-100"]), Value::Null);
+    }
+
+    #[test]
+    fn given_element_with_line_break_when_read_back_then_sent_whole_as_bulk_string() {
+        let db = ListExecutor::new();
+        run(&db, &["RPUSH", "key", "two\r\nlines", "last"]);
+
+        assert_eq!(run(&db, &["LINDEX", "key", "0"]).encode(Protocol::Resp2), "$10\r\ntwo\r\nlines\r\n");
+        assert_eq!(run(&db, &["LPOP", "key"]).encode(Protocol::Resp2), "$10\r\ntwo\r\nlines\r\n");
+        assert_eq!(run(&db, &["RPOP", "key"]).encode(Protocol::Resp2), "$4\r\nlast\r\n");
+        assert_eq!(run(&db, &["RPOP", "key"]).encode(Protocol::Resp2), "$-1\r\n");
+        assert_eq!(run(&db, &["LINDEX", "key", "0"]).encode(Protocol::Resp2), "$-1\r\n");
     }
 
     #[test]
     fn given_valid_list_when_lindex_with_non_numeric_error() {
         let db = setup_list_with_multiple_elements("key", 2);
-        let command = CommandIdentifier::new(
-            RedisCommandType::StringCommand,
-            "key".to_string(),
-            "LINDEX".to_string(),
-            vec![Bytes::from("a")],
-            KeyType::List,
-            Read,
-        );
-        let result = db.execute_command(&command);
-        match result {
-            Ok(_) => panic!("Should have returned an error"),
-            Err(error) => {
-                assert_eq!(
-                    error.get_message(),
-                    "Index is not an integer or out of range"
-                );
+        // a missing list is no different, as the index is checked before looking the key up
+        for key in ["key", "missing"] {
+            let command = CommandIdentifier::new(
+                key.to_string(),
+                "LINDEX",
+                vec![Bytes::from("a")],
+                KeyType::List,
+                Read,
+            );
+            let result = db.execute_command(&command);
+            match result {
+                Ok(_) => panic!("Should have returned an error"),
+                Err(error) => {
+                    assert_eq!(error, ExecutionError::NotAnInteger);
+                }
             }
         }
     }
@@ -462,18 +787,16 @@ mod tests {
     #[test]
     fn given_empty_list_when_rpush_then_add_to_list() {
         let db = ListExecutor::new();
-        let mut value = Vec::new();
-        value.push(Bytes::from("FirstPush"));
+        let value = vec![Bytes::from("FirstPush")];
         let command = CommandIdentifier::new(
-            RedisCommandType::StringCommand,
             "key".to_string(),
-            "RPUSH".to_string(),
+            "RPUSH",
             value,
             KeyType::List,
             Write,
         );
         let result = db.execute_command(&command);
-        assert_eq!(result.unwrap().get_response(), ":1\r\n");
+        assert_eq!(result.unwrap().get_response(), &Value::Integer(1));
         assert_eq!(db.internal_get_length(), 1);
     }
 
@@ -481,15 +804,14 @@ mod tests {
     fn given_empty_list_when_rpop_then_return_null() {
         let db = ListExecutor::new();
         let command = CommandIdentifier::new(
-            RedisCommandType::StringCommand,
             "key".to_string(),
-            "RPOP".to_string(),
+            "RPOP",
             Vec::new(),
             KeyType::List,
             Write,
         );
         let result = db.execute_command(&command);
-        assert_eq!(result.unwrap().get_response(), "_\r\n");
+        assert_eq!(result.unwrap().get_response(), &Value::Null);
         assert_eq!(db.internal_get_length(), 0);
     }
 
@@ -497,15 +819,14 @@ mod tests {
     fn given_list_with_one_element_when_rpop_then_return_element() {
         let db = setup_list_with_multiple_elements("key", 1);
         let command = CommandIdentifier::new(
-            RedisCommandType::StringCommand,
             "key".to_string(),
-            "RPOP".to_string(),
+            "RPOP",
             Vec::new(),
             KeyType::List,
             Write,
         );
         let result = db.execute_command(&command);
-        assert_eq!(result.unwrap().get_response(), "+Element0\r\n");
+        assert_eq!(result.unwrap().get_response(), &Value::BulkString(Bytes::from_static(b"Element0")));
         assert_eq!(db.internal_get_length(), 0);
     }
 
@@ -513,15 +834,14 @@ mod tests {
     fn given_list_with_multiple_elements_when_rpop_then_return_element() {
         let db = setup_list_with_multiple_elements("key", 2);
         let command = CommandIdentifier::new(
-            RedisCommandType::StringCommand,
             "key".to_string(),
-            "RPOP".to_string(),
+            "RPOP",
             Vec::new(),
             KeyType::List,
             Write,
         );
         let result = db.execute_command(&command);
-        assert_eq!(result.unwrap().get_response(), "+Element1\r\n");
+        assert_eq!(result.unwrap().get_response(), &Value::BulkString(Bytes::from_static(b"Element1")));
         assert_eq!(db.internal_get_length(), 1);
         assert_eq!(db.internal_get_list_length("key"), 1);
     }
@@ -529,51 +849,488 @@ mod tests {
     #[test]
     fn given_existing_list_when_lpush_then_add_to_list() {
         let db = setup_list_with_multiple_elements("key", 1);
-        let mut value = Vec::new();
-        value.push(Bytes::from("Element-Head"));
+        let value = vec![Bytes::from("Element-Head")];
         let command = CommandIdentifier::new(
-            RedisCommandType::StringCommand,
             "key".to_string(),
-            "LPUSH".to_string(),
+            "LPUSH",
            value,
             KeyType::List,
             Write,
         );
         let result = db.execute_command(&command);
-        assert_eq!(result.unwrap().get_response(), ":2\r\n");
+        assert_eq!(result.unwrap().get_response(), &Value::Integer(2));
         assert_eq!(db.internal_get_length(), 1);
         assert_eq!(db.internal_get_list_length("key"), 2);
         assert_eq!(db.internal_get_list_head("key"), Some(Bytes::from("Element-Head")));
     }
 
+    #[test]
+    fn given_several_elements_when_pushed_then_each_added_in_turn() {
+        let db = ListExecutor::new();
+        assert_eq!(run(&db, &["LPUSH", "key", "a", "b", "c"]), Value::Integer(3));
+        // like Redis, each is pushed onto the head in turn, so the last ends up first
+        assert_eq!(db.internal_get_list_head("key"), Some(Bytes::from("c")));
+
+        assert_eq!(run(&db, &["RPUSH", "key", "d", "e"]), Value::Integer(5));
+        assert_eq!(db.used_memory(), db.recount_memory());
+
+        let order: Vec<Value> = (0..5).map(|index| run(&db, &["LINDEX", "key", &index.to_string()])).collect();
+        let element = |element: &'static str| Value::BulkString(Bytes::from_static(element.as_bytes()));
+        assert_eq!(order, ["c", "b", "a", "d", "e"].map(element));
+    }
+
     // test lpop pops from the head of the list
     #[test]
     fn given_existing_list_when_lpop_pop_the_head() {
         let db = setup_list_with_multiple_elements("key", 2);
         let command = CommandIdentifier::new(
-            RedisCommandType::StringCommand,
             "key".to_string(),
-            "LPOP".to_string(),
+            "LPOP",
             Vec::new(),
             KeyType::List,
             Write,
         );
         let result = db.execute_command(&command);
-        assert_eq!(result.unwrap().get_response(), "+Element0\r\n");
+        assert_eq!(result.unwrap().get_response(), &Value::BulkString(Bytes::from_static(b"Element0")));
         assert_eq!(db.internal_get_length(), 1);
         assert_eq!(db.internal_get_list_length("key"), 1);
         assert_eq!(db.internal_get_list_head("key"), Some(Bytes::from("Element1")));
     }
 
+    #[test]
+    fn given_two_lists_when_one_renamed_over_the_other_then_it_replaces_it() {
+        let db = setup_list_with_multiple_elements("old", 2);
+        for element in ["a", "b", "c"] {
+            db.execute_command(&CommandIdentifier::new("new".to_string(), "RPUSH", vec![Bytes::from(element)], KeyType::List, Write)).unwrap();
+        }
+
+        assert!(db.rename("old", &Key::from("new")));
+        assert!(!db.rename("old", &Key::from("other")));
+
+        assert_eq!(db.keys(), [Key::from("new")]);
+        assert_eq!(db.internal_get_list_length("new"), 2);
+        assert_eq!(db.used_memory(), db.recount_memory());
+    }
+
+    #[test]
+    fn given_lists_when_each_entry_visited_then_lengths_seen_without_copying() {
+        let db = setup_list_with_multiple_elements("three", 3);
+        run(&db, &["RPUSH", "one", "element"]);
+        let head = db.internal_get_list_head("three").unwrap();
+
+        let mut seen = Vec::new();
+        db.for_each_entry(&mut |key, entry| {
+            let EntryView::List(elements) = &entry else { panic!("{} isn't a list", key) };
+            if key == "three" {
+                // the very bytes held, not a copy
+                assert_eq!(elements[0].as_ptr(), head.as_ptr());
+            }
+            seen.push((key.to_string(), entry.len()));
+        });
+
+        seen.sort();
+        assert_eq!(seen, [("one".to_string(), 1), ("three".to_string(), 3)]);
+    }
+
+
+    // The elements setup_list_with_multiple_elements pushed, as LRANGE replies with them
+    fn elements(numbers: std::ops::Range<usize>) -> Value {
+        Value::Array(numbers.map(|i| Value::BulkString(Bytes::from(format!("Element{}", i)))).collect())
+    }
+
+    #[test]
+    fn given_list_when_lrange_0_to_minus_1_then_every_element_returned() {
+        let db = setup_list_with_multiple_elements("key", 3);
+        assert_eq!(run(&db, &["LRANGE", "key", "0", "-1"]), elements(0..3));
+        assert_eq!(run(&db, &["LRANGE", "key", "1", "1"]), elements(1..2));
+        assert_eq!(run(&db, &["LRANGE", "key", "-2", "-1"]), elements(1..3));
+        let reply = run(&db, &["LRANGE", "key", "-2", "-1"]).encode(Protocol::Resp2);
+        assert_eq!(reply, "*2\r\n$8\r\nElement1\r\n$8\r\nElement2\r\n");
+    }
+
+    #[test]
+    fn given_missing_list_when_lrange_then_empty_array() {
+        let db = ListExecutor::new();
+        assert_eq!(run(&db, &["LRANGE", "key", "0", "-1"]), Value::Array(Vec::new()));
+    }
+
+    #[test]
+    fn given_range_past_the_ends_when_lrange_then_clipped_to_the_list() {
+        let db = setup_list_with_multiple_elements("key", 3);
+        assert_eq!(run(&db, &["LRANGE", "key", "1", "100"]), elements(1..3));
+        assert_eq!(run(&db, &["LRANGE", "key", "-100", "0"]), elements(0..1));
+        assert_eq!(run(&db, &["LRANGE", "key", "3", "5"]), elements(0..0));
+        assert_eq!(run(&db, &["LRANGE", "key", "2", "1"]), elements(0..0));
+        assert_eq!(run(&db, &["LRANGE", "key", "0", "-4"]), elements(0..0));
+        // the same negative indexes LINDEX and LSET take: -len is the head, -(len+1) before it
+        assert_eq!(run(&db, &["LRANGE", "key", "-3", "-3"]), elements(0..1));
+        assert_eq!(run(&db, &["LRANGE", "key", "-4", "-3"]), elements(0..1));
+        assert_eq!(run(&db, &["LRANGE", "key", "-4", "-4"]), elements(0..0));
+        assert_eq!(execute(&db, &["LRANGE", "key", "zero", "-1"]).unwrap_err(), ExecutionError::NotAnInteger);
+        assert_eq!(execute(&db, &["LRANGE", "key", "0", "1.5"]).unwrap_err(), ExecutionError::NotAnInteger);
+    }
+
+
+    #[test]
+    fn given_list_when_lset_first_and_last_then_replaced_and_memory_recounted() {
+        let db = setup_list_with_multiple_elements("key", 3);
+        assert_eq!(run(&db, &["LSET", "key", "0", "first"]), Value::Ok);
+        assert_eq!(run(&db, &["LSET", "key", "-1", "the last one"]), Value::Ok);
+        let list = |element: &'static str| Value::BulkString(Bytes::from_static(element.as_bytes()));
+        let expected = Value::Array(vec![list("first"), list("Element1"), list("the last one")]);
+        assert_eq!(run(&db, &["LRANGE", "key", "0", "-1"]), expected);
+        assert_eq!(db.used_memory(), db.recount_memory());
+    }
+
+    #[test]
+    fn given_index_out_of_bounds_or_no_list_when_lset_then_errors() {
+        let db = setup_list_with_multiple_elements("key", 3);
+        assert_eq!(execute(&db, &["LSET", "key", "3", "value"]).unwrap_err(), ExecutionError::IndexOutOfRange);
+        assert_eq!(execute(&db, &["LSET", "key", "-4", "value"]).unwrap_err(), ExecutionError::IndexOutOfRange);
+        assert_eq!(execute(&db, &["LSET", "key", "one", "value"]).unwrap_err(), ExecutionError::NotAnInteger);
+        assert_eq!(run(&db, &["LRANGE", "key", "0", "-1"]), elements(0..3));
+        assert_eq!(execute(&ListExecutor::new(), &["LSET", "key", "0", "value"]).unwrap_err(), ExecutionError::NoSuchKey);
+
+        let error = ListExecutor::build_command(&request(&["LSET", "key", "0"])).err().unwrap();
+        assert_eq!(error, ParserError::WrongNumberOfArguments("lset".to_string()));
+    }
+
+    fn list_of(elements: &[&str]) -> ListExecutor {
+        let db = ListExecutor::new();
+        let words: Vec<&str> = ["RPUSH", "key"].iter().chain(elements).copied().collect();
+        run(&db, &words);
+        db
+    }
+
+
+    fn positions(positions: &[i64]) -> Value {
+        Value::Array(positions.iter().map(|&position| Value::Integer(position)).collect())
+    }
+
+    #[test]
+    fn given_matches_when_lpos_with_rank_and_count_then_their_indexes_from_the_head() {
+        let db = list_of(&["a", "b", "c", "b", "b", "d"]);
+        assert_eq!(run(&db, &["LPOS", "key", "b"]), Value::Integer(1));
+        assert_eq!(run(&db, &["LPOS", "key", "b", "RANK", "2"]), Value::Integer(3));
+        assert_eq!(run(&db, &["LPOS", "key", "b", "RANK", "4"]), Value::Null);
+        assert_eq!(run(&db, &["LPOS", "key", "z"]), Value::Null);
+
+        assert_eq!(run(&db, &["LPOS", "key", "b", "COUNT", "2"]), positions(&[1, 3]));
+        assert_eq!(run(&db, &["LPOS", "key", "b", "count", "2", "rank", "2"]), positions(&[3, 4]));
+        assert_eq!(run(&db, &["LPOS", "key", "z", "COUNT", "2"]), positions(&[]));
+        // MAXLEN stops the scan after that many elements
+        assert_eq!(run(&db, &["LPOS", "key", "b", "COUNT", "5", "MAXLEN", "4"]), positions(&[1, 3]));
+        assert_eq!(run(&db, &["LPOS", "key", "d", "MAXLEN", "5"]), Value::Null);
+
+        let missing = ListExecutor::new();
+        assert_eq!(run(&missing, &["LPOS", "key", "b"]), Value::Null);
+        assert_eq!(run(&missing, &["LPOS", "key", "b", "COUNT", "1"]), positions(&[]));
+    }
+
+    #[test]
+    fn given_negative_rank_when_lpos_then_matches_counted_back_from_the_tail() {
+        let db = list_of(&["a", "b", "c", "b", "b", "d"]);
+        assert_eq!(run(&db, &["LPOS", "key", "b", "RANK", "-1"]), Value::Integer(4));
+        assert_eq!(run(&db, &["LPOS", "key", "b", "RANK", "-3"]), Value::Integer(1));
+        // the indexes are still from the head, nearest the tail first
+        assert_eq!(run(&db, &["LPOS", "key", "b", "RANK", "-1", "COUNT", "0"]), positions(&[4, 3, 1]));
+        assert_eq!(run(&db, &["LPOS", "key", "b", "RANK", "-2", "COUNT", "0"]), positions(&[3, 1]));
+        assert_eq!(run(&db, &["LPOS", "key", "b", "RANK", "-1", "COUNT", "0", "MAXLEN", "3"]), positions(&[4, 3]));
+
+        // a rank past the last match leaves nothing for COUNT to return
+        assert_eq!(run(&db, &["LPOS", "key", "b", "RANK", "-4", "COUNT", "0"]), positions(&[]));
+        assert_eq!(run(&db, &["LPOS", "key", "b", "RANK", "-4"]), Value::Null);
+        assert_eq!(run(&db, &["LPOS", "key", "a", "RANK", "-1", "COUNT", "1", "MAXLEN", "5"]), positions(&[]));
+    }
+
+    #[test]
+    fn given_bad_options_when_lpos_then_rejected_while_parsing() {
+        let error = |words: &[&str]| ListExecutor::build_command(&request(words)).err().unwrap().get_message();
+        assert!(error(&["LPOS", "key", "a", "RANK", "0"]).starts_with("RANK can't be zero"));
+        assert_eq!(error(&["LPOS", "key", "a", "RANK", "-9223372036854775808"]), "value is out of range");
+        assert!(ListExecutor::build_command(&request(&["LPOS", "key", "a", "RANK", "-9223372036854775807"])).is_ok());
+        assert_eq!(error(&["LPOS", "key", "a", "COUNT", "-1"]), "COUNT can't be negative");
+        assert_eq!(error(&["LPOS", "key", "a", "MAXLEN", "-1"]), "MAXLEN can't be negative");
+        assert_eq!(error(&["LPOS", "key", "a", "COUNT", "x"]), "value is not an integer or out of range");
+        assert_eq!(error(&["LPOS", "key", "a", "FIRST"]), "syntax error");
+        assert_eq!(error(&["LPOS", "key", "a", "RANK"]), "syntax error");
+    }
+
+
+    fn contents(db: &ListExecutor) -> Vec<String> {
+        let Value::Array(elements) = run(db, &["LRANGE", "key", "0", "-1"]) else { panic!("LRANGE didn't reply with an array") };
+        elements.iter().map(|element| match element {
+            Value::BulkString(element) => String::from_utf8(element.to_vec()).unwrap(),
+            other => panic!("{:?} isn't an element", other),
+        }).collect()
+    }
+
+    #[test]
+    fn given_scattered_duplicates_when_lrem_then_removed_from_the_end_the_count_gives() {
+        let db = list_of(&["a", "b", "a", "c", "a", "b", "a"]);
+        assert_eq!(run(&db, &["LREM", "key", "2", "a"]), Value::Integer(2));
+        assert_eq!(contents(&db), ["b", "c", "a", "b", "a"]);
+        assert_eq!(run(&db, &["LREM", "key", "-1", "b"]), Value::Integer(1));
+        assert_eq!(contents(&db), ["b", "c", "a", "a"]);
+        assert_eq!(run(&db, &["LREM", "key", "0", "a"]), Value::Integer(2));
+        assert_eq!(contents(&db), ["b", "c"]);
+        assert_eq!(run(&db, &["LREM", "key", "0", "z"]), Value::Integer(0));
+        assert_eq!(db.used_memory(), db.recount_memory());
+    }
+
+    #[test]
+    fn given_count_above_matches_when_lrem_then_every_match_removed() {
+        let db = list_of(&["x", "a", "x", "b"]);
+        assert_eq!(run(&db, &["LREM", "key", "10", "x"]), Value::Integer(2));
+        assert_eq!(contents(&db), ["a", "b"]);
+        assert_eq!(run(&db, &["LREM", "key", "-10", "a"]), Value::Integer(1));
+        assert_eq!(contents(&db), ["b"]);
+        assert_eq!(run(&ListExecutor::new(), &["LREM", "key", "1", "a"]), Value::Integer(0));
+        assert_eq!(execute(&db, &["LREM", "key", "one", "b"]).unwrap_err(), ExecutionError::NotAnInteger);
+    }
+
+    #[test]
+    fn given_last_elements_when_lrem_then_key_dropped_from_the_index() {
+        let index = Index::new();
+        let databases = Arc::new(Databases::new(&Config::default()));
+        run_indexed(&index, &databases, &["RPUSH", "key", "a", "a"]).unwrap();
+        assert_eq!(run_indexed(&index, &databases, &["LREM", "key", "0", "a"]).unwrap(), Value::Integer(2));
+        assert_eq!(run_indexed(&index, &databases, &["EXISTS", "key"]).unwrap(), Value::Integer(0));
+        assert_eq!(databases.list.internal_get_length(), 0);
+        assert_eq!(databases.list.used_memory(), 0);
+    }
+
+
+    #[test]
+    fn given_capped_log_when_ltrim_then_exactly_the_range_kept() {
+        let db = setup_list_with_multiple_elements("key", 10);
+        assert_eq!(run(&db, &["LTRIM", "key", "2", "7"]), Value::Ok);
+        assert_eq!(run(&db, &["LRANGE", "key", "0", "-1"]), elements(2..8));
+        assert_eq!(run(&db, &["LTRIM", "key", "-3", "100"]), Value::Ok);
+        assert_eq!(run(&db, &["LRANGE", "key", "0", "-1"]), elements(5..8));
+        assert_eq!(run(&db, &["LTRIM", "key", "0", "-1"]), Value::Ok);
+        assert_eq!(run(&db, &["LRANGE", "key", "0", "-1"]), elements(5..8));
+        assert_eq!(db.used_memory(), db.recount_memory());
+    }
+
+    #[test]
+    fn given_empty_range_when_ltrim_then_key_deleted() {
+        let db = setup_list_with_multiple_elements("key", 3);
+        assert_eq!(run(&db, &["LTRIM", "key", "2", "1"]), Value::Ok);
+        assert_eq!(db.internal_get_length(), 0);
+        assert_eq!(db.used_memory(), 0);
+
+        let db = setup_list_with_multiple_elements("key", 3);
+        assert_eq!(run(&db, &["LTRIM", "key", "5", "10"]), Value::Ok);
+        assert_eq!(db.internal_get_length(), 0);
+        // and a missing key is no error
+        assert_eq!(run(&db, &["LTRIM", "key", "0", "1"]), Value::Ok);
+    }
+
+
+    #[test]
+    fn given_pivot_when_linsert_before_head_and_after_tail_then_placed_either_side() {
+        let db = list_of(&["a", "b", "b"]);
+        assert_eq!(run(&db, &["LINSERT", "key", "BEFORE", "a", "head"]), Value::Integer(4));
+        assert_eq!(run(&db, &["LINSERT", "key", "after", "b", "middle"]), Value::Integer(5));
+        assert_eq!(contents(&db), ["head", "a", "b", "middle", "b"]);
+        assert_eq!(run(&db, &["LINSERT", "key", "After", "b", "tail"]), Value::Integer(6));
+        assert_eq!(run(&db, &["LINSERT", "key", "AFTER", "missing", "x"]), Value::Integer(-1));
+        assert_eq!(contents(&db), ["head", "a", "b", "tail", "middle", "b"]);
+        assert_eq!(db.used_memory(), db.recount_memory());
+
+        let db = list_of(&["a"]);
+        assert_eq!(run(&db, &["LINSERT", "key", "AFTER", "a", "tail"]), Value::Integer(2));
+        assert_eq!(contents(&db), ["a", "tail"]);
+    }
+
+    #[test]
+    fn given_no_list_or_bad_side_when_linsert_then_zero_or_syntax_error() {
+        let db = ListExecutor::new();
+        assert_eq!(run(&db, &["LINSERT", "key", "BEFORE", "a", "b"]), Value::Integer(0));
+        assert_eq!(db.internal_get_length(), 0);
+        let error = ListExecutor::build_command(&request(&["LINSERT", "key", "BESIDE", "a", "b"])).err().unwrap();
+        assert_eq!(error, ParserError::Syntax);
+    }
+
+    #[test]
+    fn given_index_when_linsert_then_list_changed_and_missing_key_not_created() {
+        let index = Index::new();
+        let databases = Arc::new(Databases::new(&Config::default()));
+        assert_eq!(run_indexed(&index, &databases, &["LINSERT", "key", "BEFORE", "a", "x"]).unwrap(), Value::Integer(0));
+        assert_eq!(run_indexed(&index, &databases, &["EXISTS", "key"]).unwrap(), Value::Integer(0));
+
+        run_indexed(&index, &databases, &["RPUSH", "key", "a", "b"]).unwrap();
+        assert_eq!(run_indexed(&index, &databases, &["LINSERT", "key", "BEFORE", "b", "x"]).unwrap(), Value::Integer(3));
+        assert_eq!(run_indexed(&index, &databases, &["LINSERT", "key", "AFTER", "b", "y"]).unwrap(), Value::Integer(4));
+        assert_eq!(run_indexed(&index, &databases, &["LINSERT", "key", "AFTER", "z", "y"]).unwrap(), Value::Integer(-1));
+        let list = |element: &'static str| Value::BulkString(Bytes::from_static(element.as_bytes()));
+        assert_eq!(run_indexed(&index, &databases, &["LRANGE", "key", "0", "-1"]).unwrap(), Value::Array(vec![list("a"), list("x"), list("b"), list("y")]));
+    }
+
+
+    #[test]
+    fn given_count_when_lpop_or_rpop_then_array_of_up_to_count_elements() {
+        let db = setup_list_with_multiple_elements("key", 5);
+        assert_eq!(run(&db, &["LPOP", "key", "2"]), elements(0..2));
+        // from the tail, so last first
+        let element = |element: &'static str| Value::BulkString(Bytes::from_static(element.as_bytes()));
+        assert_eq!(run(&db, &["RPOP", "key", "2"]), Value::Array(vec![element("Element4"), element("Element3")]));
+        assert_eq!(run(&db, &["LPOP", "key", "0"]), Value::Array(Vec::new()));
+        assert_eq!(run(&db, &["LPOP", "key"]), Value::BulkString(Bytes::from_static(b"Element2")));
+        assert_eq!(db.internal_get_length(), 0);
+        assert_eq!(db.used_memory(), 0);
+    }
+
+    #[test]
+    fn given_count_past_the_end_when_lpop_then_list_drained_and_deleted() {
+        let db = setup_list_with_multiple_elements("key", 2);
+        assert_eq!(run(&db, &["LPOP", "key", "10"]), elements(0..2));
+        assert_eq!(db.internal_get_length(), 0);
+        assert_eq!(run(&db, &["LPOP", "key", "10"]), Value::NullArray);
+        assert_eq!(run(&db, &["RPOP", "key"]), Value::Null);
+    }
+
+    #[test]
+    fn given_bad_count_when_lpop_then_must_be_positive() {
+        let db = setup_list_with_multiple_elements("key", 2);
+        assert_eq!(execute(&db, &["LPOP", "key", "two"]).unwrap_err(), ExecutionError::NotPositive);
+        assert_eq!(execute(&db, &["RPOP", "key", "-1"]).unwrap_err(), ExecutionError::NotPositive);
+        assert_eq!(execute(&db, &["RPOP", "key", "-1"]).unwrap_err().get_message(), "value is out of range, must be positive");
+        assert_eq!(db.internal_get_list_length("key"), 2);
+        let error = ListExecutor::build_command(&request(&["LPOP", "key", "1", "2"])).err().unwrap();
+        assert_eq!(error, ParserError::WrongNumberOfArguments("lpop".to_string()));
+    }
+
+    #[test]
+    fn given_two_lists_when_lmove_then_element_taken_from_one_end_and_put_on_the_other() {
+        let index = Index::new();
+        let databases = Arc::new(Databases::new(&Config::default()));
+        let bulk = |element: &'static str| Value::BulkString(Bytes::from_static(element.as_bytes()));
+        let list = |elements: &[&'static str]| Value::Array(elements.iter().map(|element| bulk(element)).collect());
+        run_indexed(&index, &databases, &["RPUSH", "source", "a", "b", "c"]).unwrap();
+        run_indexed(&index, &databases, &["RPUSH", "destination", "x"]).unwrap();
+
+        assert_eq!(run_indexed(&index, &databases, &["LMOVE", "source", "destination", "RIGHT", "LEFT"]).unwrap(), bulk("c"));
+        assert_eq!(run_indexed(&index, &databases, &["LRANGE", "source", "0", "-1"]).unwrap(), list(&["a", "b"]));
+        assert_eq!(run_indexed(&index, &databases, &["LRANGE", "destination", "0", "-1"]).unwrap(), list(&["c", "x"]));
+        assert_eq!(run_indexed(&index, &databases, &["lmove", "source", "destination", "left", "right"]).unwrap(), bulk("a"));
+        assert_eq!(run_indexed(&index, &databases, &["LRANGE", "destination", "0", "-1"]).unwrap(), list(&["c", "x", "a"]));
+
+        // the last element takes the source with it, and a new destination is created
+        run_indexed(&index, &databases, &["LMOVE", "source", "new", "LEFT", "LEFT"]).unwrap();
+        assert_eq!(run_indexed(&index, &databases, &["EXISTS", "source"]).unwrap(), Value::Integer(0));
+        assert_eq!(run_indexed(&index, &databases, &["LRANGE", "new", "0", "-1"]).unwrap(), list(&["b"]));
+        assert_eq!(databases.list.used_memory(), databases.list.recount_memory());
+
+        // a missing source moves nothing
+        assert_eq!(run_indexed(&index, &databases, &["LMOVE", "source", "new", "LEFT", "LEFT"]).unwrap(), Value::Null);
+        assert_eq!(run_indexed(&index, &databases, &["LMOVE", "source", "new", "LEFT", "LEFT"]).unwrap().encode(Protocol::Resp3), "_\r\n");
+        assert_eq!(run_indexed(&index, &databases, &["EXISTS", "source"]).unwrap(), Value::Integer(0));
+    }
+
+    #[test]
+    fn given_one_list_when_lmove_onto_itself_then_rotated() {
+        let index = Index::new();
+        let databases = Arc::new(Databases::new(&Config::default()));
+        run_indexed(&index, &databases, &["RPUSH", "key", "a", "b", "c"]).unwrap();
+        run_indexed(&index, &databases, &["LMOVE", "key", "key", "LEFT", "RIGHT"]).unwrap();
+        assert_eq!(run_indexed(&index, &databases, &["LINDEX", "key", "0"]).unwrap(), Value::BulkString(Bytes::from_static(b"b")));
+        assert_eq!(run_indexed(&index, &databases, &["LINDEX", "key", "2"]).unwrap(), Value::BulkString(Bytes::from_static(b"a")));
+
+        run_indexed(&index, &databases, &["RPUSH", "single", "only"]).unwrap();
+        run_indexed(&index, &databases, &["LMOVE", "single", "single", "RIGHT", "LEFT"]).unwrap();
+        assert_eq!(run_indexed(&index, &databases, &["LLEN", "single"]).unwrap(), Value::Integer(1));
+        assert_eq!(run_indexed(&index, &databases, &["EXISTS", "single"]).unwrap(), Value::Integer(1));
+
+        // RPOPLPUSH is LMOVE from the tail to the head
+        assert_eq!(run_indexed(&index, &databases, &["RPOPLPUSH", "key", "key"]).unwrap(), Value::BulkString(Bytes::from_static(b"a")));
+        assert_eq!(run_indexed(&index, &databases, &["LINDEX", "key", "0"]).unwrap(), Value::BulkString(Bytes::from_static(b"a")));
+        assert_eq!(run_indexed(&index, &databases, &["rpoplpush", "key", "other"]).unwrap(), Value::BulkString(Bytes::from_static(b"c")));
+        assert_eq!(run_indexed(&index, &databases, &["LINDEX", "other", "0"]).unwrap(), Value::BulkString(Bytes::from_static(b"c")));
+        assert_eq!(run_indexed(&index, &databases, &["RPOPLPUSH", "missing", "other"]).unwrap(), Value::Null);
+    }
+
+    #[test]
+    fn given_string_destination_or_bad_end_when_lmove_then_errors_and_nothing_moves() {
+        let index = Index::new();
+        let databases = Arc::new(Databases::new(&Config::default()));
+        run_indexed(&index, &databases, &["RPUSH", "source", "a"]).unwrap();
+        run_indexed(&index, &databases, &["SET", "string", "value"]).unwrap();
+        assert_eq!(run_indexed(&index, &databases, &["LMOVE", "source", "string", "LEFT", "LEFT"]).unwrap_err(), ExecutionError::WrongType);
+        assert_eq!(run_indexed(&index, &databases, &["LMOVE", "source", "other", "UP", "LEFT"]).unwrap_err(), ExecutionError::Syntax);
+        assert_eq!(run_indexed(&index, &databases, &["LLEN", "source"]).unwrap(), Value::Integer(1));
+    }
+
+    #[test]
+    fn given_one_list_held_when_another_list_used_then_it_is_not_held_up() {
+        let db = Arc::new(ListExecutor::new());
+        run(&db, &["RPUSH", "busy", "a"]);
+
+        // a long command on one list, such as an LRANGE over a giant one, keeps it locked
+        let (held, release) = (mpsc::channel(), mpsc::channel::<()>());
+        let holder = {
+            let (db, held, release) = (Arc::clone(&db), held.0, release.1);
+            thread::spawn(move || {
+                db.update(&Key::from("busy"), false, |_| {
+                    held.send(()).unwrap();
+                    release.recv().unwrap();
+                })
+            })
+        };
+        held.1.recv().unwrap();
+
+        let started = Instant::now();
+        for key in ["other", "another", "third"] {
+            assert_eq!(run(&db, &["RPUSH", key, "x", "y"]), Value::Integer(2));
+            assert_eq!(run(&db, &["LPOP", key]), Value::BulkString(Bytes::from_static(b"x")));
+            assert_eq!(run(&db, &["LLEN", key]), Value::Integer(1));
+        }
+        assert!(started.elapsed() < Duration::from_secs(1), "{:?}", started.elapsed());
+
+        release.0.send(()).unwrap();
+        holder.join().unwrap();
+        assert_eq!(run(&db, &["LLEN", "busy"]), Value::Integer(1));
+    }
+
+    #[test]
+    fn given_threads_pushing_and_popping_when_run_without_index_lock_then_each_progresses_and_memory_kept() {
+        let db = Arc::new(ListExecutor::new());
+        // two on keys of their own, and two taking turns emptying and recreating a shared one
+        let threads: Vec<_> = ["first", "second", "shared", "shared"]
+            .into_iter()
+            .map(|key| {
+                let db = Arc::clone(&db);
+                thread::spawn(move || {
+                    let mut popped = 0;
+                    for _ in 0..2000 {
+                        run(&db, &["RPUSH", key, "element"]);
+                        if run(&db, &["LPOP", key]) != Value::Null {
+                            popped += 1;
+                        }
+                    }
+                    run(&db, &["RPUSH", key, "last"]);
+                    popped
+                })
+            })
+            .collect();
+        let popped: usize = threads.into_iter().map(|thread| thread.join().unwrap()).sum();
+
+        assert_eq!(popped, 4 * 2000);
+        assert_eq!(db.internal_get_list_length("first"), 1);
+        assert_eq!(db.internal_get_list_length("second"), 1);
+        assert_eq!(db.internal_get_list_length("shared"), 2);
+        assert_eq!(db.internal_get_length(), 3);
+        assert_eq!(db.used_memory(), db.recount_memory());
+    }
+
     fn setup_list_with_multiple_elements(key_name: &str, size: usize) -> ListExecutor {
         let db = ListExecutor::new();
         for i in 0..size {
-            let mut value = Vec::new();
-            value.push(Bytes::from(format!("Element{}", i)));
+            let value = vec![Bytes::from(format!("Element{}", i))];
             let command = CommandIdentifier::new(
-                RedisCommandType::StringCommand,
                 key_name.to_string(),
-                "RPUSH".to_string(),
+                "RPUSH",
                 value,
                 KeyType::List,
                 Write,
@@ -1,14 +1,58 @@
-
-mod commands;
-mod tokenizer;
-mod string_executor;
-mod thread_pool;
-mod controller;
-mod index;
-mod list_executor;
+use redis_in_rust::{CommandLine, Config, Server};
 
 fn main() {
-    // ./redli -h localhost -p 6379 --debug
-    env_logger::init();
-    controller::initialize_controller();
+    // ./redis_in_rust [config file] --port 7000 --loglevel debug
+    let mut args = std::env::args();
+    let program = args.next().unwrap_or_else(|| "redis_in_rust".to_string());
+    let command_line = match CommandLine::parse(args) {
+        Ok(command_line) => command_line,
+        Err(error) => {
+            eprintln!("{}\nRun {} --help to see the settings", error, program);
+            std::process::exit(1);
+        }
+    };
+    if command_line.help {
+        print!("{}", CommandLine::usage(&program));
+        return;
+    }
+    let config = match Config::load(&command_line) {
+        Ok(config) => config,
+        Err(error) => {
+            eprintln!("{}", error);
+            std::process::exit(1);
+        }
+    };
+    // RUST_LOG, when set, refines the level from the config, e.g. to trace a single module
+    env_logger::Builder::new()
+        .filter_level(config.loglevel)
+        .parse_default_env()
+        .init();
+    if config.unixsocket.is_some() {
+        log::warn!("Unix sockets are not supported yet, so only TCP clients will be served");
+    }
+    if config.requirepass.is_some() {
+        log::warn!("AUTH is not supported yet, so clients will not be asked for the password");
+    }
+
+    let server = match Server::bind(config) {
+        Ok(server) => server,
+        Err(error) => {
+            log::error!("{}", error);
+            std::process::exit(1);
+        }
+    };
+    for address in server.local_addrs() {
+        log::info!("Listening on {}", address);
+    }
+    let on_signal = server.shutdown_signal();
+    // SIGINT and SIGTERM
+    if let Err(error) = ctrlc::set_handler(move || {
+        log::info!("Shutdown requested");
+        on_signal.request();
+    }) {
+        log::error!("Unable to install the shutdown handler: {:?}", error);
+    }
+
+    server.run();
+    log::info!("Shutting down.");
 }
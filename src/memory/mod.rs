@@ -0,0 +1,283 @@
+// What the keyspace costs in memory, roughly, and what to do once it costs more than maxmemory.
+// Each executor keeps a count of the bytes it holds, updated as it stores and removes values;
+// when a command that may need more memory arrives with the count over the limit, keys are
+// evicted according to the configured policy until it is back under.
+// The counts are reported by INFO memory and the MEMORY STATS command.
+
+use crate::commands::table;
+use crate::commands::{check_arity, ExecutionError};
+use crate::config::Config;
+use crate::index::Index;
+use crate::resp::Value;
+use bytes::Bytes;
+use std::hash::{BuildHasher, RandomState};
+use std::str::FromStr;
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+
+// Roughly what is spent on a key beyond its name and value: its slots in the index's and the
+// executor's maps, and the Strings and entries there
+pub const KEY_OVERHEAD: usize = 64;
+// and on each element of a list
+pub const ELEMENT_OVERHEAD: usize = 16;
+
+static STARTED: OnceLock<Instant> = OnceLock::new();
+static RANDOM: OnceLock<AtomicU64> = OnceLock::new();
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum EvictionPolicy {
+    // refuse the command with an OOM error
+    #[default]
+    NoEviction,
+    // evict the least recently used of a sample of keys
+    AllKeysLru,
+    AllKeysRandom,
+    // as AllKeysLru, but only keys with a TTL may go
+    VolatileLru,
+    // evict the least frequently used of a sample of keys
+    AllKeysLfu,
+    // as AllKeysLfu, but only keys with a TTL may go
+    VolatileLfu,
+}
+
+impl EvictionPolicy {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EvictionPolicy::NoEviction => "noeviction",
+            EvictionPolicy::AllKeysLru => "allkeys-lru",
+            EvictionPolicy::AllKeysRandom => "allkeys-random",
+            EvictionPolicy::VolatileLru => "volatile-lru",
+            EvictionPolicy::AllKeysLfu => "allkeys-lfu",
+            EvictionPolicy::VolatileLfu => "volatile-lfu",
+        }
+    }
+
+    // Whether keys' use is counted, for the policy and for OBJECT FREQ
+    pub fn is_lfu(&self) -> bool {
+        matches!(self, EvictionPolicy::AllKeysLfu | EvictionPolicy::VolatileLfu)
+    }
+}
+
+impl FromStr for EvictionPolicy {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_lowercase().as_str() {
+            "noeviction" => Ok(EvictionPolicy::NoEviction),
+            "allkeys-lru" => Ok(EvictionPolicy::AllKeysLru),
+            "allkeys-random" => Ok(EvictionPolicy::AllKeysRandom),
+            "volatile-lru" => Ok(EvictionPolicy::VolatileLru),
+            "allkeys-lfu" => Ok(EvictionPolicy::AllKeysLfu),
+            "volatile-lfu" => Ok(EvictionPolicy::VolatileLfu),
+            _ => Err(()),
+        }
+    }
+}
+
+// The limit and how it is kept to
+#[derive(Debug)]
+pub struct Eviction {
+    // 0 is no limit
+    pub maxmemory: usize,
+    pub policy: EvictionPolicy,
+    // how many keys are looked at to choose each one to evict
+    pub samples: usize,
+    // publish an event for each key evicted, on its keyspace channel and on the evicted channel
+    pub notify_keyspace: bool,
+    pub notify_keyevent: bool,
+    pub lfu_log_factor: u32,
+    // in minutes
+    pub lfu_decay_time: u64,
+}
+
+impl Eviction {
+    pub fn new(config: &Config) -> Eviction {
+        let events = &config.notify_keyspace_events;
+        let evicted = events.contains('e') || events.contains('A');
+        Eviction {
+            maxmemory: config.maxmemory,
+            policy: config.maxmemory_policy,
+            samples: config.maxmemory_samples,
+            notify_keyspace: evicted && events.contains('K'),
+            notify_keyevent: evicted && events.contains('E'),
+            lfu_log_factor: config.lfu_log_factor,
+            lfu_decay_time: config.lfu_decay_time,
+        }
+    }
+}
+
+// The bytes held by each type of key, as counted when the report was made, and the limit
+#[derive(Debug, Default)]
+pub struct Usage {
+    pub strings: usize,
+    pub lists: usize,
+    pub hashes: usize,
+    pub maxmemory: usize,
+    pub policy: EvictionPolicy,
+}
+
+impl Usage {
+    pub fn total(&self) -> usize {
+        self.strings + self.lists + self.hashes
+    }
+}
+
+// Commands that may store more than they remove, flagged denyoom in the command table, are
+// refused when memory is full and nothing can be evicted
+pub fn may_use_more_memory(action: &str) -> bool {
+    table::lookup(action.as_bytes()).is_some_and(|spec| spec.has_flag("denyoom"))
+}
+
+// What a key holding `value_bytes` of data is counted as
+pub fn key_size(key: &str, value_bytes: usize) -> usize {
+    KEY_OVERHEAD + key.len() + value_bytes
+}
+
+pub fn element_size(element: &[u8]) -> usize {
+    ELEMENT_OVERHEAD + element.len()
+}
+
+// Milliseconds since the server started, the clock keys' last access times are kept by
+pub fn millis_since_start() -> u64 {
+    STARTED.get_or_init(Instant::now).elapsed().as_millis() as u64
+}
+
+// A byte count as Redis reports it for people, e.g. 512B, 1.50K or 2.00M
+pub fn human_bytes(bytes: usize) -> String {
+    const UNITS: [&str; 4] = ["K", "M", "G", "T"];
+    if bytes < 1024 {
+        return format!("{}B", bytes);
+    }
+    let mut scaled = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while scaled >= 1024.0 && unit < UNITS.len() - 1 {
+        scaled /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.2}{}", scaled, UNITS[unit])
+}
+
+pub fn is_command_supported(command: &[u8]) -> bool {
+    command.eq_ignore_ascii_case(b"MEMORY")
+}
+
+pub fn execute_command(request: &[Bytes], index: &Index, usage: &Usage) -> Result<Value, ExecutionError> {
+    // support syntax: MEMORY STATS
+    if !request[1].eq_ignore_ascii_case(b"STATS") {
+        return Err(ExecutionError::new(&format!(
+            "unknown subcommand '{}'. Try MEMORY HELP.",
+            String::from_utf8_lossy(&request[1])
+        )));
+    }
+    check_arity(request, 2)?;
+    let field = |name: &'static str, value: usize| (Value::BulkString(Bytes::from_static(name.as_bytes())), Value::Integer(value as i64));
+    Ok(Value::Map(vec![
+        field("dataset.bytes", usage.total()),
+        field("strings.bytes", usage.strings),
+        field("lists.bytes", usage.lists),
+        field("hashes.bytes", usage.hashes),
+        field("keys.count", index.key_count()),
+        field("maxmemory", usage.maxmemory),
+    ]))
+}
+
+// Good enough for picking keys to sample, and needs no more crates
+pub fn random() -> u64 {
+    let state = RANDOM.get_or_init(|| AtomicU64::new(RandomState::new().hash_one(0u64) | 1));
+    let mut next = state.load(Ordering::Relaxed);
+    next ^= next << 13;
+    next ^= next >> 7;
+    next ^= next << 17;
+    state.store(next, Ordering::Relaxed);
+    next
+}
+
+// Redis's LFU counter: a count of a key's uses that rises more slowly the higher it gets, so fits
+// in 8 bits, and falls while the key goes unused. It is kept with the minute it last fell, as
+// (minute << 8) | count, and starts at LFU_INITIAL so a new key isn't the first to be evicted.
+const LFU_INITIAL: u64 = 5;
+const MILLIS_PER_MINUTE: u64 = 60 * 1000;
+
+pub fn lfu_new(now: u64) -> u64 {
+    (now / MILLIS_PER_MINUTE) << 8 | LFU_INITIAL
+}
+
+// The count once the minutes since it last fell have been taken off
+pub fn lfu_count(lfu: u64, now: u64, eviction: &Eviction) -> u64 {
+    let count = lfu & 0xff;
+    if eviction.lfu_decay_time == 0 {
+        return count;
+    }
+    let idle_minutes = (now / MILLIS_PER_MINUTE).saturating_sub(lfu >> 8);
+    count.saturating_sub(idle_minutes / eviction.lfu_decay_time)
+}
+
+// The counter after one more use: it falls for the time the key was unused, then rises by one
+// with a chance that shrinks as it grows
+pub fn lfu_used(lfu: u64, now: u64, eviction: &Eviction) -> u64 {
+    let mut count = lfu_count(lfu, now, eviction);
+    if count < 255 {
+        let above_initial = count.saturating_sub(LFU_INITIAL) as f64;
+        let chance = 1.0 / (above_initial * eviction.lfu_log_factor as f64 + 1.0);
+        // a random fraction in [0, 1), from the top 53 bits
+        if ((random() >> 11) as f64 / (1u64 << 53) as f64) < chance {
+            count += 1;
+        }
+    }
+    (now / MILLIS_PER_MINUTE) << 8 | count
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::request;
+    use crate::resp::Protocol;
+
+    #[test]
+    fn given_lfu_counter_when_used_and_left_then_rises_and_falls_by_the_minute() {
+        let minute = 60 * 1000;
+        let eviction = Eviction { lfu_log_factor: 0, ..Eviction::new(&Config::default()) };
+        let lfu = lfu_new(2 * minute);
+        assert_eq!(lfu_count(lfu, 2 * minute, &eviction), LFU_INITIAL);
+        let lfu = lfu_used(lfu_used(lfu, 2 * minute, &eviction), 2 * minute + 10, &eviction);
+        assert_eq!(lfu_count(lfu, 2 * minute, &eviction), LFU_INITIAL + 2);
+        assert_eq!(lfu_count(lfu, 6 * minute, &eviction), LFU_INITIAL - 2);
+        assert_eq!(lfu_count(lfu, 60 * minute, &eviction), 0);
+
+        // with lfu-decay-time 0 it never falls, and it never passes 255
+        let eviction = Eviction { lfu_decay_time: 0, ..eviction };
+        assert_eq!(lfu_count(lfu, 60 * minute, &eviction), LFU_INITIAL + 2);
+        let full = 255;
+        assert_eq!(lfu_used(full, 0, &eviction) & 0xff, 255);
+
+        // with the default factor, a high count rarely rises
+        let eviction = Eviction::new(&Config::default());
+        let raised = (0..100).filter(|_| lfu_used(200, 0, &eviction) & 0xff == 201).count();
+        assert!(raised < 10, "raised {} times in 100", raised);
+    }
+
+    #[test]
+    fn given_byte_counts_when_made_human_then_scaled_as_redis_does() {
+        assert_eq!(human_bytes(0), "0B");
+        assert_eq!(human_bytes(1023), "1023B");
+        assert_eq!(human_bytes(1536), "1.50K");
+        assert_eq!(human_bytes(3 * 1024 * 1024), "3.00M");
+        assert_eq!(human_bytes(5 * 1024 * 1024 * 1024), "5.00G");
+    }
+
+    #[test]
+    fn given_usage_when_memory_stats_then_fields_reported_for_the_protocol() {
+        let usage = Usage { strings: 100, lists: 20, hashes: 5, maxmemory: 1000, policy: EvictionPolicy::AllKeysLru };
+        let reply = execute_command(&request(&["MEMORY", "STATS"]), &Index::new(), &usage).unwrap().encode(Protocol::Resp3);
+        let reply = String::from_utf8(reply.to_vec()).unwrap();
+        assert!(reply.starts_with("%6\r\n$13\r\ndataset.bytes\r\n:125\r\n$13\r\nstrings.bytes\r\n:100\r\n"), "{}", reply);
+        assert!(reply.contains("$12\r\nhashes.bytes\r\n:5\r\n"), "{}", reply);
+        assert!(reply.contains("$10\r\nkeys.count\r\n:0\r\n$9\r\nmaxmemory\r\n:1000\r\n"), "{}", reply);
+        let reply = execute_command(&request(&["memory", "stats"]), &Index::new(), &usage).unwrap().encode(Protocol::Resp2);
+        assert!(reply.starts_with(b"*12\r\n"));
+
+        let error = execute_command(&request(&["MEMORY", "DOCTOR"]), &Index::new(), &usage).unwrap_err();
+        assert_eq!(error.get_message(), "unknown subcommand 'DOCTOR'. Try MEMORY HELP.");
+    }
+}
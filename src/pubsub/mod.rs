@@ -0,0 +1,186 @@
+// Registry of the channels and patterns clients are subscribed to, and delivery of published messages
+
+use crate::controller::connection::Client;
+use crate::glob;
+use crate::resp::Value;
+use bytes::Bytes;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+pub(crate) struct PubSub {
+    subscriptions: Mutex<Subscriptions>,
+}
+
+#[derive(Default)]
+struct Subscriptions {
+    // channel (or pattern) -> subscribed clients by id
+    channels: HashMap<String, HashMap<u64, Arc<Client>>>,
+    patterns: HashMap<String, HashMap<u64, Arc<Client>>>,
+}
+
+impl PubSub {
+    pub fn new() -> PubSub {
+        PubSub {
+            subscriptions: Mutex::new(Subscriptions::default()),
+        }
+    }
+
+    pub fn subscribe(&self, client: &Arc<Client>, channel: &str) {
+        let mut subscriptions = self.subscriptions.lock().unwrap();
+        subscriptions
+            .channels
+            .entry(channel.to_string())
+            .or_default()
+            .insert(client.get_id(), Arc::clone(client));
+    }
+
+    pub fn unsubscribe(&self, client_id: u64, channel: &str) {
+        let mut subscriptions = self.subscriptions.lock().unwrap();
+        Self::remove(&mut subscriptions.channels, client_id, channel);
+    }
+
+    pub fn psubscribe(&self, client: &Arc<Client>, pattern: &str) {
+        let mut subscriptions = self.subscriptions.lock().unwrap();
+        subscriptions
+            .patterns
+            .entry(pattern.to_string())
+            .or_default()
+            .insert(client.get_id(), Arc::clone(client));
+    }
+
+    pub fn punsubscribe(&self, client_id: u64, pattern: &str) {
+        let mut subscriptions = self.subscriptions.lock().unwrap();
+        Self::remove(&mut subscriptions.patterns, client_id, pattern);
+    }
+
+    // Sends the message to every client subscribed to the channel, or to a pattern matching it,
+    // returning how many deliveries were made
+    pub fn publish(&self, channel: &str, message: &Bytes) -> usize {
+        // Collect the receivers first so no client's output is written while holding the registry
+        // lock; a subscriber takes its output lock before the registry lock when subscribing.
+        let mut deliveries: Vec<(Arc<Client>, Value)> = Vec::new();
+        {
+            let subscriptions = self.subscriptions.lock().unwrap();
+            if let Some(clients) = subscriptions.channels.get(channel) {
+                for client in clients.values() {
+                    let frame = Value::Push(vec![
+                        bulk("message"),
+                        Value::BulkString(Bytes::copy_from_slice(channel.as_bytes())),
+                        Value::BulkString(message.clone()),
+                    ]);
+                    deliveries.push((Arc::clone(client), frame));
+                }
+            }
+            for (pattern, clients) in subscriptions.patterns.iter() {
+                if !glob::matches(pattern.as_bytes(), channel.as_bytes()) {
+                    continue;
+                }
+                for client in clients.values() {
+                    let frame = Value::Push(vec![
+                        bulk("pmessage"),
+                        Value::BulkString(Bytes::copy_from_slice(pattern.as_bytes())),
+                        Value::BulkString(Bytes::copy_from_slice(channel.as_bytes())),
+                        Value::BulkString(message.clone()),
+                    ]);
+                    deliveries.push((Arc::clone(client), frame));
+                }
+            }
+        }
+
+        for (client, frame) in deliveries.iter() {
+            if let Err(error) = client.write(&frame.encode(client.get_protocol())) {
+                log::debug!("Unable to deliver message to client {}: {:?}", client.get_id(), error);
+            }
+        }
+        deliveries.len()
+    }
+
+    fn remove(registry: &mut HashMap<String, HashMap<u64, Arc<Client>>>, client_id: u64, name: &str) {
+        if let Some(clients) = registry.get_mut(name) {
+            clients.remove(&client_id);
+            if clients.is_empty() {
+                registry.remove(name);
+            }
+        }
+    }
+}
+
+fn bulk(value: &'static str) -> Value {
+    Value::BulkString(Bytes::from_static(value.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::controller::connection::{next_client_id, ClientOutput};
+    use crate::resp::Protocol;
+    use std::io::Write;
+
+    #[test]
+    fn given_channel_subscriber_when_publish_then_message_delivered() {
+        let pubsub = PubSub::new();
+        let (client, output) = test_client(Protocol::Resp2);
+        pubsub.subscribe(&client, "news");
+
+        assert_eq!(pubsub.publish("news", &Bytes::from("hello")), 1);
+        assert_eq!(pubsub.publish("weather", &Bytes::from("rain")), 0);
+        assert_eq!(
+            output.contents(),
+            "*3\r\n$7\r\nmessage\r\n$4\r\nnews\r\n$5\r\nhello\r\n"
+        );
+    }
+
+    #[test]
+    fn given_pattern_subscriber_when_publish_then_pmessage_delivered_as_push() {
+        let pubsub = PubSub::new();
+        let (client, output) = test_client(Protocol::Resp3);
+        pubsub.psubscribe(&client, "news.*");
+
+        assert_eq!(pubsub.publish("news.sports", &Bytes::from("goal")), 1);
+        assert_eq!(
+            output.contents(),
+            ">4\r\n$8\r\npmessage\r\n$6\r\nnews.*\r\n$11\r\nnews.sports\r\n$4\r\ngoal\r\n"
+        );
+    }
+
+    #[test]
+    fn given_unsubscribed_client_when_publish_then_nothing_delivered() {
+        let pubsub = PubSub::new();
+        let (client, output) = test_client(Protocol::Resp2);
+        pubsub.subscribe(&client, "news");
+        pubsub.unsubscribe(client.get_id(), "news");
+
+        assert_eq!(pubsub.publish("news", &Bytes::from("hello")), 0);
+        assert_eq!(output.contents(), "");
+    }
+
+    // Collects whatever is written to a test client
+    #[derive(Clone, Default)]
+    struct SharedOutput(Arc<Mutex<Vec<u8>>>);
+
+    impl SharedOutput {
+        fn contents(&self) -> String {
+            String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+        }
+    }
+
+    impl Write for SharedOutput {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl ClientOutput for SharedOutput {}
+
+    fn test_client(protocol: Protocol) -> (Arc<Client>, SharedOutput) {
+        let output = SharedOutput::default();
+        let client = Arc::new(Client::new(next_client_id(), Box::new(output.clone()), &Config::default()));
+        client.set_protocol(protocol);
+        (client, output)
+    }
+}
@@ -0,0 +1,365 @@
+// Encoders for the RESP reply frames sent back to clients
+// See https://redis.io/docs/latest/develop/reference/protocol-spec/
+
+use bytes::{BufMut, Bytes, BytesMut};
+
+const CRLF: &[u8] = b"\r\n";
+
+// The protocol version a connection has negotiated with HELLO
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Protocol {
+    #[default]
+    Resp2,
+    Resp3,
+}
+
+// A reply whose encoding depends on the protocol in use. RESP3 has native maps, sets,
+// doubles and booleans, which are flattened to their closest RESP2 equivalents.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub enum Value {
+    // +OK, the reply of most commands that change something, which needs nothing allocated
+    Ok,
+    SimpleString(Bytes),
+    #[allow(dead_code)] // the controller encodes command errors with error() directly
+    Error(String),
+    Integer(i64),
+    BulkString(Bytes),
+    #[default]
+    Null,
+    // What a command that would reply with an array has when there is nothing to reply with
+    NullArray,
+    Array(Vec<Value>),
+    Map(Vec<(Value, Value)>),
+    Set(Vec<Value>),
+    #[allow(dead_code)] // no command replies with a double yet
+    Double(f64),
+    #[allow(dead_code)] // no command replies with a boolean yet
+    Boolean(bool),
+    Push(Vec<Value>),
+    // Human readable text, e.g. INFO, sent with its format ("txt" or "mkd")
+    Verbatim { format: &'static str, text: Bytes },
+    // An integer too large for i64, as its decimal digits
+    #[allow(dead_code)] // no command replies with one yet
+    BigNumber(String),
+}
+
+impl Value {
+    pub fn encode(&self, protocol: Protocol) -> Bytes {
+        match (self, protocol) {
+            (Value::Ok, _) => ok(),
+            (Value::SimpleString(value), _) => simple_string(value),
+            (Value::Error(message), _) => error(message),
+            (Value::Integer(value), _) => integer(*value),
+            (Value::BulkString(value), _) => bulk_string(value),
+            (Value::Null, Protocol::Resp2) => null_bulk_string(),
+            (Value::Null, Protocol::Resp3) => null(),
+            (Value::NullArray, Protocol::Resp2) => null_array(),
+            (Value::NullArray, Protocol::Resp3) => null(),
+            (Value::Array(elements), _) => array(Self::encode_all(elements, protocol)),
+            (Value::Map(entries), Protocol::Resp2) => array(
+                entries
+                    .iter()
+                    .flat_map(|(key, value)| [key.encode(protocol), value.encode(protocol)])
+                    .collect::<Vec<Bytes>>(),
+            ),
+            (Value::Map(entries), Protocol::Resp3) => map(
+                entries
+                    .iter()
+                    .map(|(key, value)| (key.encode(protocol), value.encode(protocol)))
+                    .collect::<Vec<(Bytes, Bytes)>>(),
+            ),
+            (Value::Set(elements), Protocol::Resp2) => array(Self::encode_all(elements, protocol)),
+            (Value::Set(elements), Protocol::Resp3) => set(Self::encode_all(elements, protocol)),
+            (Value::Double(value), Protocol::Resp2) => bulk_string(format_double(*value).as_bytes()),
+            (Value::Double(value), Protocol::Resp3) => double(*value),
+            (Value::Boolean(value), Protocol::Resp2) => integer(*value as i64),
+            (Value::Boolean(value), Protocol::Resp3) => boolean(*value),
+            (Value::Push(elements), Protocol::Resp2) => array(Self::encode_all(elements, protocol)),
+            (Value::Push(elements), Protocol::Resp3) => push(Self::encode_all(elements, protocol)),
+            (Value::Verbatim { text, .. }, Protocol::Resp2) => bulk_string(text),
+            (Value::Verbatim { format, text }, Protocol::Resp3) => verbatim_string(format, text),
+            (Value::BigNumber(digits), Protocol::Resp2) => bulk_string(digits.as_bytes()),
+            (Value::BigNumber(digits), Protocol::Resp3) => big_number(digits),
+        }
+    }
+
+    // A value that may be missing, such as GET's or LINDEX's: sent as a bulk string, which
+    // carries any bytes, or as null when there is none
+    pub fn bulk_or_null(value: Option<Bytes>) -> Value {
+        value.map_or(Value::Null, Value::BulkString)
+    }
+
+    fn encode_all(elements: &[Value], protocol: Protocol) -> Vec<Bytes> {
+        elements.iter().map(|element| element.encode(protocol)).collect()
+    }
+}
+
+pub fn simple_string(value: &[u8]) -> Bytes {
+    let mut buf = BytesMut::with_capacity(1 + value.len() + 2);
+    buf.put_u8(b'+');
+    buf.extend_from_slice(value);
+    buf.extend_from_slice(CRLF);
+    buf.freeze()
+}
+
+pub fn ok() -> Bytes {
+    Bytes::from_static(b"+OK\r\n")
+}
+
+// Any CR or LF in the message is replaced with a space, so text echoed back from a request
+// (e.g. a key name) can't end the error early and inject frames of its own
+pub fn error(message: &str) -> Bytes {
+    let mut buf = BytesMut::with_capacity(1 + message.len() + 2);
+    buf.put_u8(b'-');
+    buf.extend(message.bytes().map(|byte| match byte {
+        b'\r' | b'\n' => b' ',
+        byte => byte,
+    }));
+    buf.extend_from_slice(CRLF);
+    buf.freeze()
+}
+
+pub fn integer(value: i64) -> Bytes {
+    let mut buf = BytesMut::new();
+    buf.put_u8(b':');
+    buf.extend_from_slice(value.to_string().as_bytes());
+    buf.extend_from_slice(CRLF);
+    buf.freeze()
+}
+
+pub fn bulk_string(value: &[u8]) -> Bytes {
+    let length = value.len().to_string();
+    let mut buf = BytesMut::with_capacity(1 + length.len() + 2 + value.len() + 2);
+    buf.put_u8(b'$');
+    buf.extend_from_slice(length.as_bytes());
+    buf.extend_from_slice(CRLF);
+    buf.extend_from_slice(value);
+    buf.extend_from_slice(CRLF);
+    buf.freeze()
+}
+
+// The RESP3 null
+pub fn null() -> Bytes {
+    Bytes::from_static(b"_\r\n")
+}
+
+// The RESP2 null, sent as a bulk string with a length of -1
+pub fn null_bulk_string() -> Bytes {
+    Bytes::from_static(b"$-1\r\n")
+}
+
+// The RESP2 null for a missing array, sent with a length of -1
+pub fn null_array() -> Bytes {
+    Bytes::from_static(b"*-1\r\n")
+}
+
+// Wraps elements that have already been encoded (by the functions in this module) in an array
+pub fn array<I>(elements: I) -> Bytes
+where
+    I: IntoIterator<Item = Bytes>,
+    I::IntoIter: ExactSizeIterator,
+{
+    aggregate(b'*', elements)
+}
+
+// RESP3 map of already encoded key and value pairs
+pub fn map<I>(entries: I) -> Bytes
+where
+    I: IntoIterator<Item = (Bytes, Bytes)>,
+    I::IntoIter: ExactSizeIterator,
+{
+    let entries = entries.into_iter();
+    let mut buf = BytesMut::new();
+    buf.put_u8(b'%');
+    buf.extend_from_slice(entries.len().to_string().as_bytes());
+    buf.extend_from_slice(CRLF);
+    for (key, value) in entries {
+        buf.extend_from_slice(&key);
+        buf.extend_from_slice(&value);
+    }
+    buf.freeze()
+}
+
+// RESP3 set of already encoded elements
+pub fn set<I>(elements: I) -> Bytes
+where
+    I: IntoIterator<Item = Bytes>,
+    I::IntoIter: ExactSizeIterator,
+{
+    aggregate(b'~', elements)
+}
+
+// RESP3 out of band data, such as pub/sub messages, that isn't a reply to a request
+pub fn push<I>(elements: I) -> Bytes
+where
+    I: IntoIterator<Item = Bytes>,
+    I::IntoIter: ExactSizeIterator,
+{
+    aggregate(b'>', elements)
+}
+
+// RESP3 verbatim string; the format is exactly three characters, e.g. "txt"
+pub fn verbatim_string(format: &str, text: &[u8]) -> Bytes {
+    let length = (format.len() + 1 + text.len()).to_string();
+    let mut buf = BytesMut::with_capacity(1 + length.len() + 2 + format.len() + 1 + text.len() + 2);
+    buf.put_u8(b'=');
+    buf.extend_from_slice(length.as_bytes());
+    buf.extend_from_slice(CRLF);
+    buf.extend_from_slice(format.as_bytes());
+    buf.put_u8(b':');
+    buf.extend_from_slice(text);
+    buf.extend_from_slice(CRLF);
+    buf.freeze()
+}
+
+// RESP3 big number, given as its (optionally negative) decimal digits
+pub fn big_number(digits: &str) -> Bytes {
+    let mut buf = BytesMut::with_capacity(1 + digits.len() + 2);
+    buf.put_u8(b'(');
+    buf.extend_from_slice(digits.as_bytes());
+    buf.extend_from_slice(CRLF);
+    buf.freeze()
+}
+
+pub fn double(value: f64) -> Bytes {
+    let mut buf = BytesMut::new();
+    buf.put_u8(b',');
+    buf.extend_from_slice(format_double(value).as_bytes());
+    buf.extend_from_slice(CRLF);
+    buf.freeze()
+}
+
+pub fn boolean(value: bool) -> Bytes {
+    if value {
+        Bytes::from_static(b"#t\r\n")
+    } else {
+        Bytes::from_static(b"#f\r\n")
+    }
+}
+
+// Doubles use 'inf', '-inf' and 'nan' for the special values, like Redis
+pub(crate) fn format_double(value: f64) -> String {
+    if value.is_nan() {
+        "nan".to_string()
+    } else if value.is_infinite() {
+        if value > 0.0 { "inf".to_string() } else { "-inf".to_string() }
+    } else {
+        value.to_string()
+    }
+}
+
+fn aggregate<I>(prefix: u8, elements: I) -> Bytes
+where
+    I: IntoIterator<Item = Bytes>,
+    I::IntoIter: ExactSizeIterator,
+{
+    let elements = elements.into_iter();
+    let mut buf = BytesMut::new();
+    buf.put_u8(prefix);
+    buf.extend_from_slice(elements.len().to_string().as_bytes());
+    buf.extend_from_slice(CRLF);
+    for element in elements {
+        buf.extend_from_slice(&element);
+    }
+    buf.freeze()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn given_simple_values_when_encoded_then_framed_with_type_prefix() {
+        assert_eq!(simple_string(b"PONG"), "+PONG\r\n");
+        assert_eq!(ok(), "+OK\r\n");
+        assert_eq!(error("ERR unknown"), "-ERR unknown\r\n");
+        assert_eq!(integer(42), ":42\r\n");
+        assert_eq!(integer(-7), ":-7\r\n");
+        assert_eq!(null(), "_\r\n");
+        assert_eq!(null_bulk_string(), "$-1\r\n");
+        assert_eq!(null_array(), "*-1\r\n");
+        assert_eq!(boolean(true), "#t\r\n");
+        assert_eq!(boolean(false), "#f\r\n");
+    }
+
+    #[test]
+    fn given_bulk_string_containing_crlf_when_encoded_then_length_covers_it() {
+        assert_eq!(bulk_string(b"a\r\nb"), "$4\r\na\r\nb\r\n");
+        assert_eq!(bulk_string(b""), "$0\r\n\r\n");
+    }
+
+    #[test]
+    fn given_arrays_when_encoded_then_count_precedes_elements() {
+        assert_eq!(array(Vec::new()), "*0\r\n");
+        assert_eq!(
+            array(vec![bulk_string(b"a"), integer(1), null_bulk_string()]),
+            "*3\r\n$1\r\na\r\n:1\r\n$-1\r\n"
+        );
+        let nested = array(vec![array(vec![integer(1), integer(2)]), array(Vec::new())]);
+        assert_eq!(nested, "*2\r\n*2\r\n:1\r\n:2\r\n*0\r\n");
+    }
+
+    #[test]
+    fn given_maps_and_sets_when_encoded_then_resp3_frames_are_used() {
+        assert_eq!(map(vec![(bulk_string(b"a"), integer(1))]), "%1\r\n$1\r\na\r\n:1\r\n");
+        assert_eq!(map(Vec::new()), "%0\r\n");
+        assert_eq!(set(vec![bulk_string(b"x"), bulk_string(b"y")]), "~2\r\n$1\r\nx\r\n$1\r\ny\r\n");
+    }
+
+    #[test]
+    fn given_value_when_encoded_for_each_protocol_then_resp2_is_flattened() {
+        let value = Value::Map(vec![
+            (Value::BulkString(Bytes::from("score")), Value::Double(1.5)),
+            (Value::BulkString(Bytes::from("member")), Value::Set(vec![Value::Integer(1)])),
+            (Value::BulkString(Bytes::from("exists")), Value::Boolean(true)),
+            (Value::BulkString(Bytes::from("missing")), Value::Null),
+        ]);
+        assert_eq!(
+            value.encode(Protocol::Resp3),
+            "%4\r\n$5\r\nscore\r\n,1.5\r\n$6\r\nmember\r\n~1\r\n:1\r\n\
+             $6\r\nexists\r\n#t\r\n$7\r\nmissing\r\n_\r\n"
+        );
+        assert_eq!(
+            value.encode(Protocol::Resp2),
+            "*8\r\n$5\r\nscore\r\n$3\r\n1.5\r\n$6\r\nmember\r\n*1\r\n:1\r\n\
+             $6\r\nexists\r\n:1\r\n$7\r\nmissing\r\n$-1\r\n"
+        );
+    }
+
+    #[test]
+    fn given_push_when_encoded_then_resp2_uses_an_array() {
+        let value = Value::Push(vec![
+            Value::BulkString(Bytes::from("message")),
+            Value::BulkString(Bytes::from("news")),
+        ]);
+        assert_eq!(value.encode(Protocol::Resp3), ">2\r\n$7\r\nmessage\r\n$4\r\nnews\r\n");
+        assert_eq!(value.encode(Protocol::Resp2), "*2\r\n$7\r\nmessage\r\n$4\r\nnews\r\n");
+    }
+
+    #[test]
+    fn given_verbatim_string_when_encoded_then_resp2_downgrades_to_bulk() {
+        let value = Value::Verbatim { format: "txt", text: Bytes::from("Some string") };
+        assert_eq!(value.encode(Protocol::Resp3), "=15\r\ntxt:Some string\r\n");
+        assert_eq!(value.encode(Protocol::Resp2), "$11\r\nSome string\r\n");
+    }
+
+    #[test]
+    fn given_big_number_when_encoded_then_resp2_downgrades_to_bulk() {
+        let value = Value::BigNumber("3492890328409238509324850943850943825024385".to_string());
+        assert_eq!(value.encode(Protocol::Resp3), "(3492890328409238509324850943850943825024385\r\n");
+        assert_eq!(
+            value.encode(Protocol::Resp2),
+            "$43\r\n3492890328409238509324850943850943825024385\r\n"
+        );
+        assert_eq!(big_number("-18446744073709551616"), "(-18446744073709551616\r\n");
+    }
+
+    #[test]
+    fn given_doubles_when_encoded_then_special_values_are_named() {
+        assert_eq!(double(1.5), ",1.5\r\n");
+        assert_eq!(double(10.0), ",10\r\n");
+        assert_eq!(double(f64::INFINITY), ",inf\r\n");
+        assert_eq!(double(f64::NEG_INFINITY), ",-inf\r\n");
+        assert_eq!(double(f64::NAN), ",nan\r\n");
+    }
+}
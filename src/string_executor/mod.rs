@@ -1,12 +1,27 @@
-use crate::commands::{ExecutionError, ParserError};
+use crate::executor::{CommandExecutor, EntryView};
+use crate::commands::options::{self, flag, OptionSpec};
+use crate::commands::table;
+use crate::commands::{integer_argument, stored_value, syntax_error, wrong_number_of_arguments, wrong_type, ExecutionError, ParserError};
+use crate::expiration::{self, TimeUnit};
 use crate::index::IndexImpactOnCompletion::{Add, NoImpact};
-use crate::index::LockType::{Read, Write};
-use crate::index::{CommandCompleted, CommandIdentifier, KeyType, LockType, RedisCommandType};
-use bytes::{Bytes, BytesMut};
+use crate::index::{CommandCompleted, CommandIdentifier, Key, KeyImpact, KeyType, TypeCheck};
+use crate::memory;
+use crate::resp::Value;
+use bytes::Bytes;
 use std::collections::HashMap;
-use std::sync::Mutex;
+use std::convert::Infallible;
+use std::hash::{BuildHasher, RandomState};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, MutexGuard};
+use std::time::Instant;
 
-const REDIS_STRING_COMMANDS: [&str; 6] = ["GET", "SET", "INCR", "INCRBY", "DECR", "DECRBY"];
+const REDIS_STRING_COMMANDS: [&str; 13] = ["GET", "MGET", "SET", "SETNX", "SETEX", "PSETEX", "GETSET", "MSET", "MSETNX", "INCR", "INCRBY", "DECR", "DECRBY"];
+
+// SET's options; an old value may be returned whether or not the new one is stored
+const SET_OPTIONS: [OptionSpec; 3] = [flag("NX", &["XX"]), flag("XX", &[]), flag("GET", &[])];
+
+// How many parts the values are split into, each behind its own lock
+const SHARDS: usize = 16;
 
 pub (crate) struct StringExecutor {
     data: InternalStorage,
@@ -19,104 +34,70 @@ impl StringExecutor {
         }
     }
 
-    pub fn is_command_supported(command: &str) -> bool {
-        REDIS_STRING_COMMANDS
-            .iter()
-            .any(|&cmd| cmd.eq_ignore_ascii_case(command))
-    }
-
-    pub fn build_command(command: &Vec<String>) -> Result<CommandIdentifier, ParserError> {
+    pub fn build_command(command: &[Bytes]) -> Result<CommandIdentifier, ParserError> {
         // support syntax: GET name
-        //                 SET name value
+        //                 MGET name [name ...]
+        //                 SET name value [NX | XX] [GET]
+        //                 SETNX name value
+        //                 SETEX name seconds value
+        //                 PSETEX name milliseconds value
+        //                 GETSET name value
+        //                 MSET name value [name value ...]
+        //                 MSETNX name value [name value ...]
         //                 INCR name
         //                 INCRBY name increment
         //                 DECR name
         //                 DECRBY name decrement
 
-        if command.len() < 2 {
-            return Err(ParserError::new(
-                "Not enough identifiers provided for string command",
-            ));
-        }
-
-        let command_type: RedisCommandType;
-        let target: String;
-        let action: String;
-        let lock_type: LockType;
+        // The table has checked the arity, and says where the key is and how it is locked
+        let spec = table::validate(command)?.ok_or_else(|| ParserError::new("Unsupported string command type"))?;
         let mut params: Vec<Bytes> = Vec::new();
+        let mut type_check = TypeCheck::SameType;
 
-        match command[0].to_uppercase().as_str() {
-            "GET" => {
-                if command.len() != 2 {
-                    return Err(ParserError::new(
-                        "GET command requires exactly one parameter",
-                    ));
-                }
-                command_type = RedisCommandType::StringCommand;
-                action = "GET".to_string();
-                target = command[1].clone();
-                // not no params for GET command
-                lock_type = Read
-            }
+        match spec.name {
+            "GET" | "INCR" | "DECR" => {}
+            // a key holding another type reads as missing rather than failing the lot
+            "MGET" => type_check = TypeCheck::AnyType,
             "SET" => {
-                if command.len() != 3 {
-                    return Err(ParserError::new("SET command requires two parameter"));
+                let (set_options, rest) = options::parse(&SET_OPTIONS, &command[3..])?;
+                if !rest.is_empty() {
+                    return Err(syntax_error());
                 }
-                command_type = RedisCommandType::StringCommand;
-                action = "SET".to_string();
-                target = command[1].clone();
-                params.push(command[2].as_bytes().to_vec().into());
-                lock_type = Write
+                // the value, followed by the names of the options given
+                params.push(command[2].clone());
+                params.extend(set_options.names().map(|name| Bytes::from_static(name.as_bytes())));
+                // SET replaces a key of any type, but GET can only return a string, and NX
+                // leaves whatever is there alone
+                type_check = match (set_options.has("GET"), set_options.has("NX")) {
+                    (true, _) => TypeCheck::SameType,
+                    (false, true) => TypeCheck::UnlessExists(Value::Null),
+                    (false, false) => TypeCheck::Overwrite,
+                };
             }
-            "INCR" => {
-                if command.len() != 2 {
-                    return Err(ParserError::new("INCR command requires one parameter"));
-                }
-                command_type = RedisCommandType::StringCommand;
-                action = "INCR".to_string();
-                target = command[1].clone();
-                lock_type = Write
+            "SETEX" | "PSETEX" => {
+                // the value, then how long it lasts; like SET, it replaces a key of any type
+                params.push(command[3].clone());
+                params.push(command[2].clone());
+                type_check = TypeCheck::Overwrite;
             }
-            "INCRBY" => {
-                if command.len() != 3 {
-                    return Err(ParserError::new("INCRBY command requires two parameter"));
+            "MSET" | "MSETNX" => {
+                // the table has found the keys; the values are the words between them
+                if command.len().is_multiple_of(2) {
+                    return Err(wrong_number_of_arguments(&command[0]));
                 }
-                command_type = RedisCommandType::StringCommand;
-                action = "INCRBY".to_string();
-                target = command[1].clone();
-                params.push(command[2].as_bytes().to_vec().into());
-                lock_type = Write
+                params.extend(command[2..].iter().step_by(2).cloned());
+                // MSETNX sets nothing if any of the keys exists, whatever its type
+                type_check = if spec.name == "MSET" { TypeCheck::Overwrite } else { TypeCheck::UnlessExists(Value::Integer(0)) };
             }
-            "DECR" => {
-                if command.len() != 2 {
-                    return Err(ParserError::new("INCR command requires one parameter"));
-                }
-                command_type = RedisCommandType::StringCommand;
-                action = "DECR".to_string();
-                target = command[1].clone();
-                lock_type = Write
-            }
-            "DECRBY" => {
-                if command.len() != 3 {
-                    return Err(ParserError::new("DECRBY command requires two parameter"));
-                }
-                command_type = RedisCommandType::StringCommand;
-                action = "DECRBY".to_string();
-                target = command[1].clone();
-                params.push(command[2].as_bytes().to_vec().into());
-                lock_type = Write
+            "SETNX" => {
+                params.push(command[2].clone());
+                type_check = TypeCheck::UnlessExists(Value::Integer(0));
             }
+            "GETSET" | "INCRBY" | "DECRBY" => params.push(command[2].clone()),
             _ => return Err(ParserError::new("Unsupported string command type")),
         }
 
-        Ok(CommandIdentifier::new(
-            command_type,
-            target,
-            action,
-            params,
-            KeyType::String,
-            lock_type,
-        ))
+        Ok(CommandIdentifier::from_spec(spec, command, params)?.with_type_check(type_check))
     }
 
     pub fn execute_command(
@@ -125,216 +106,492 @@ impl StringExecutor {
     ) -> Result<CommandCompleted, ExecutionError> {
 
         match command.get_action() {
-            "GET" => {
-                match self.data.get(&command.get_target()) {
-                    Some(value) => {
-                        let mut buf = BytesMut::with_capacity(1 + value.len() + 2);
-                        buf.extend_from_slice(b"+");
-                        buf.extend_from_slice(&value);
-                        buf.extend_from_slice(b"\r\n");
-                        Ok(CommandCompleted::new(
-                            command.get_target(),
-                            KeyType::String,
-                            NoImpact,
-                            buf.freeze(),
-                        ))
-                    }
-                    None => Ok(CommandCompleted::new(
-                        command.get_target(),
-                        KeyType::String,
-                        NoImpact,
-                        Bytes::from("+(nil)\r\n"),
-                    )),
-                }
+            "GET" => Ok(CommandCompleted::new(
+                command.get_target(),
+                KeyType::String,
+                NoImpact,
+                Value::bulk_or_null(self.data.get(command.get_target())),
+            )),
+            "MGET" => {
+                let values = command
+                    .get_keys()
+                    .iter()
+                    .map(|key| Value::bulk_or_null(self.data.get(key)))
+                    .collect();
+                Ok(CommandCompleted::with_impacts(Vec::new(), Value::Array(values)))
             }
-            "SET" => {
-                let value = command.get_params()[0].clone();
-                self.data.set(&command.get_target(), &value);
-                Ok(CommandCompleted::new(
-                    command.get_target(),
-                    KeyType::String,
-                    Add,
-                    Bytes::from("+OK\r\n"),
-                ))
+            "SET" => self.set(command),
+            "SETNX" => {
+                // looked for and stored under the one lock, so only one of two racing SETNXs stores
+                let value = &command.get_params()[0];
+                let Ok(old_value) = self.data.update(command.get_target(), |old_value| {
+                    Ok::<_, Infallible>(old_value.is_none().then(|| stored_value(value)))
+                });
+                let (impact, stored) = if old_value.is_none() { (Add, 1) } else { (NoImpact, 0) };
+                Ok(CommandCompleted::new(command.get_target(), KeyType::String, impact, Value::Integer(stored)))
+            }
+            "SETEX" | "PSETEX" => {
+                let params = command.get_params();
+                let expires_at = expiry_time(command.get_action(), &params[1])?;
+                self.data.set(command.get_target(), stored_value(&params[0]));
+                let impact = KeyImpact::new(command.get_target(), KeyType::String, Add).expiring_at(expires_at);
+                Ok(CommandCompleted::with_impacts(vec![impact], Value::Ok))
             }
+            "GETSET" => {
+                // read and replaced under the one lock, so no other write comes in between
+                // a new value, so like SET's it takes away any deadline the key had
+                let old_value = self.data.set(command.get_target(), stored_value(&command.get_params()[0]));
+                Ok(CommandCompleted::new(command.get_target(), KeyType::String, Add, Value::bulk_or_null(old_value)))
+            }
+            "MSET" => self.set_all(command, false),
+            "MSETNX" => self.set_all(command, true),
             "INCR" => {
                self.adjust_value_if_exists(command, 1)
             }
             "INCRBY" => {
-                let value = command.get_params()[0].clone();
-                let adjustment = std::str::from_utf8(&value).unwrap().parse::<i64>().unwrap();
+                let adjustment = integer_argument(&command.get_params()[0])?;
                 self.adjust_value_if_exists(command, adjustment)
             }
             "DECR" => {
                 self.adjust_value_if_exists(command, -1)
             }
             "DECRBY" => {
-                let value = command.get_params()[0].clone();
-                let adjustment = std::str::from_utf8(&value).unwrap().parse::<i64>().unwrap();
-                self.adjust_value_if_exists(command, -adjustment)
+                let adjustment = integer_argument(&command.get_params()[0])?
+                    .checked_neg()
+                    .ok_or(ExecutionError::OutOfRange("decrement"))?;
+                self.adjust_value_if_exists(command, adjustment)
             }
             _ => {
-                Err(ExecutionError::new(
-                    "-WRONGTYPE Operation against a key holding the wrong kind of value",
-                ))
+                Err(wrong_type())
             }
         }
 
     }
 
-    fn adjust_value_if_exists(&self, command: &CommandIdentifier, adjustment: i64) -> Result<CommandCompleted, ExecutionError> {
-        let updated_value: Bytes;
-        let mut impact_on_index = NoImpact;
-        match self.data.get(&command.get_target()) {
-            Some(value) => {
-                match std::str::from_utf8(&value) {
-                    Ok(str_val) => {
-                        match str_val.parse::<i64>() {
-                            Ok(int_val) => {
-                                let new_val = int_val + adjustment;
-                                updated_value = Bytes::from(new_val.to_string());
-                                self.data.set(&command.get_target(), &updated_value);
-                            }
-                            Err(_) => {
-                                return Err(ExecutionError::new(
-                                    "-ERR value is not an integer or out of range",
-                                ));
-                            }
-                        }
-                    }
-                    Err(_) => {
-                        return Err(ExecutionError::new(
-                            "-ERR value is not an integer or out of range",
-                        ));
-                    }
-                }
-            }
-            None => {
-                updated_value = Bytes::from(adjustment.to_string());
-                impact_on_index = Add;
-                self.data.set(&command.get_target(), &updated_value);
-            }
+    fn set(&self, command: &CommandIdentifier) -> Result<CommandCompleted, ExecutionError> {
+        let params = command.get_params();
+        let given = |name: &str| params[1..].iter().any(|option| option == name.as_bytes());
+        let (only_if_new, only_if_exists, get) = (given("NX"), given("XX"), given("GET"));
+        let may_store = |exists: bool| !(only_if_new && exists || only_if_exists && !exists);
+
+        // deciding and storing happen under one lock, so nothing can come in between
+        let old_value = self.data.update(command.get_target(), |old_value| {
+            Ok::<_, ExecutionError>(may_store(old_value.is_some()).then(|| stored_value(&params[0])))
+        })?;
+        let stored = may_store(old_value.is_some());
+        let response = match (get, old_value) {
+            (true, old_value) => Value::bulk_or_null(old_value),
+            (false, _) if stored => Value::Ok,
+            (false, _) => Value::Null,
+        };
+        let impact = if stored { Add } else { NoImpact };
+        Ok(CommandCompleted::new(command.get_target(), KeyType::String, impact, response))
+    }
+
+    // The index holds the locks on every key throughout, so nothing sees some of them set and not
+    // the others, nor a key created between MSETNX looking and setting
+    fn set_all(&self, command: &CommandIdentifier, only_if_none_exist: bool) -> Result<CommandCompleted, ExecutionError> {
+        let keys = command.get_keys();
+        if only_if_none_exist && keys.iter().any(|key| self.data.exists(key)) {
+            return Ok(CommandCompleted::with_impacts(Vec::new(), Value::Integer(0)));
         }
+        let impacts = keys
+            .iter()
+            .zip(command.get_params())
+            .map(|(key, value)| {
+                self.data.set(key, stored_value(value));
+                KeyImpact::new(key, KeyType::String, Add)
+            })
+            .collect();
+        let response = if only_if_none_exist { Value::Integer(1) } else { Value::Ok };
+        Ok(CommandCompleted::with_impacts(impacts, response))
+    }
+
+    fn adjust_value_if_exists(&self, command: &CommandIdentifier, adjustment: i64) -> Result<CommandCompleted, ExecutionError> {
+        let mut updated_value = 0;
+        // read, changed and written back under one lock, so no update can be lost to another
+        let old_value = self.data.update(command.get_target(), |old_value| {
+            let old = match old_value {
+                Some(value) => std::str::from_utf8(value)
+                    .ok()
+                    .and_then(|text| text.parse::<i64>().ok())
+                    .ok_or(ExecutionError::NotAnInteger)?,
+                None => 0,
+            };
+            updated_value = old
+                .checked_add(adjustment)
+                .ok_or(ExecutionError::OutOfRange("increment or decrement"))?;
+            Ok::<_, ExecutionError>(Some(Bytes::from(updated_value.to_string())))
+        })?;
+        let impact_on_index = if old_value.is_some() { NoImpact } else { Add };
 
-        let mut buf = BytesMut::with_capacity(1 + updated_value.len() + 2);
-        buf.extend_from_slice(b"+");
-        buf.extend_from_slice(&updated_value);
-        buf.extend_from_slice(b"\r\n");
         Ok(CommandCompleted::new(
             command.get_target(),
             KeyType::String,
             impact_on_index,
-            buf.freeze(),
+            Value::Integer(updated_value),
         ))
     }
     
+    pub fn used_memory(&self) -> usize {
+        self.data.used_memory.load(Ordering::Relaxed)
+    }
+
+    #[cfg(test)]
+    pub fn recount_memory(&self) -> usize {
+        let mut used = 0;
+        self.data.visit_all(|key, value| used += memory::key_size(key, value.len()));
+        used
+    }
+
     pub fn delete(&self, key: &str) -> u16{
         self.data.del(key);
         1 // removed the single key
     }
 
-    pub fn rename(&self, old_key: &str, new_key: &str) -> bool {
-        if let Some(value) = self.data.get(old_key) {
-            self.data.set(new_key, &value);
-            self.data.del(old_key);
+    pub fn rename(&self, old_key: &str, new_key: &Key) -> bool {
+        if let Some(value) = self.data.del(old_key) {
+            self.data.set(new_key, value);
             true
         } else {
             false
         }
     }
 
+    #[cfg(test)]
     pub fn internal_exists(&self, key: &str) -> bool {
         // This is kind of ugly, but we need a way to confirm that the Index actually removed this key vs. only from its internal storage
-        self.data.get(key).is_some()
+        self.data.exists(key)
+    }
+
+}
+
+impl CommandExecutor for StringExecutor {
+    fn supported_commands(&self) -> &'static [&'static str] {
+        &REDIS_STRING_COMMANDS
     }
+    fn key_type(&self) -> KeyType {
+        KeyType::String
+    }
+    fn build_command(&self, command: &[Bytes]) -> Result<CommandIdentifier, ParserError> {
+        StringExecutor::build_command(command)
+    }
+    fn execute(&self, command: &CommandIdentifier) -> Result<CommandCompleted, ExecutionError> {
+        self.execute_command(command)
+    }
+    fn delete(&self, key: &str) -> u16 {
+        StringExecutor::delete(self, key)
+    }
+    fn rename(&self, old_key: &str, new_key: &Key) -> bool {
+        StringExecutor::rename(self, old_key, new_key)
+    }
+    fn keys(&self) -> Vec<Key> {
+        StringExecutor::keys(self)
+    }
+    fn for_each_entry(&self, visit: &mut dyn FnMut(&str, EntryView)) {
+        StringExecutor::for_each_entry(self, visit)
+    }
+}
 
+// When a key given SETEX's seconds or PSETEX's milliseconds expires, which must be in the future
+fn expiry_time(command: &str, argument: &[u8]) -> Result<Instant, ExecutionError> {
+    let (unit, name) = if command == "SETEX" { (TimeUnit::Seconds, "SETEX") } else { (TimeUnit::Milliseconds, "PSETEX") };
+    let invalid = ExecutionError::InvalidExpireTime(name);
+    let millis = unit.millis(integer_argument(argument)?).filter(|millis| *millis > 0).ok_or(invalid.clone())?;
+    expiration::deadline_after(millis as u64).ok_or(invalid)
 }
 
 #[derive(Debug)]
 struct Entry {
     data: Bytes,
 }
+// The values, split into a fixed number of shards by a hash of their keys so that commands on
+// different keys don't all queue for one lock. This is the pattern for an executor's storage:
+//  - each operation on a key locks only the shard it hashes to, and holds it no longer than a
+//    single get, update or del; a read-modify-write such as INCR is one update, so it can't be
+//    interleaved with another command on the same key even without the Index's lock on it
+//  - nothing ever holds two shards at once, so there is no order to get wrong
+//  - iterating locks one shard at a time, so a key added or removed meanwhile may or may not be
+//    seen, but every key there throughout is seen exactly once
+//  - the bytes held are counted as values are stored and removed, for maxmemory
 #[derive(Debug)]
 struct InternalStorage {
-    entries: Mutex<HashMap<String, Entry>>,
+    shards: [Mutex<HashMap<Key, Entry>>; SHARDS],
+    hasher: RandomState,
+    used_memory: AtomicUsize,
 }
 
 impl InternalStorage {
     fn new() -> InternalStorage {
         InternalStorage {
-            entries: Mutex::new(HashMap::new()),
+            shards: std::array::from_fn(|_| Mutex::new(HashMap::new())),
+            hasher: RandomState::new(),
+            used_memory: AtomicUsize::new(0),
         }
     }
+    fn shard(&self, key: &str) -> MutexGuard<'_, HashMap<Key, Entry>> {
+        let shard = self.hasher.hash_one(key) as usize % SHARDS;
+        self.shards[shard].lock().unwrap()
+    }
     pub fn get(&self, key: &str) -> Option<Bytes> {
-        let values = self.entries.lock().unwrap();
-        values.get(key).map(|entry| entry.data.clone())
-    }
-    pub fn set(&self, key: &str, value: &Bytes) {
-        let mut entries = self.entries.lock().unwrap();
-        entries.insert(
-            key.to_string(),
-            Entry {
-                data: value.clone(),
-            },
-        );
+        self.shard(key).get(key).map(|entry| entry.data.clone())
+    }
+    pub fn exists(&self, key: &str) -> bool {
+        self.shard(key).contains_key(key)
+    }
+    // Stores the value, returning the one it replaced
+    pub fn set(&self, key: &Key, value: Bytes) -> Option<Bytes> {
+        let Ok(replaced) = self.update(key, |_| Ok::<_, Infallible>(Some(value)));
+        replaced
+    }
+    // Calls `change` with the key's value, if it has one, and stores whatever it returns in its
+    // place, leaving the key as it was if that is None. The shard stays locked throughout, so a
+    // read-modify-write such as INCR is atomic even without the Index's lock on the key. Returns
+    // the value that was there before.
+    pub fn update<E>(
+        &self,
+        key: &Key,
+        change: impl FnOnce(Option<&Bytes>) -> Result<Option<Bytes>, E>,
+    ) -> Result<Option<Bytes>, E> {
+        let mut shard = self.shard(key);
+        // an existing key keeps its name, and a new one shares the command's
+        if let Some(entry) = shard.get_mut(key) {
+            let Some(value) = change(Some(&entry.data))? else {
+                return Ok(Some(entry.data.clone()));
+            };
+            self.used_memory.fetch_add(memory::key_size(key, value.len()), Ordering::Relaxed);
+            let replaced = std::mem::replace(&mut entry.data, value);
+            self.used_memory.fetch_sub(memory::key_size(key, replaced.len()), Ordering::Relaxed);
+            Ok(Some(replaced))
+        } else {
+            if let Some(value) = change(None)? {
+                self.used_memory.fetch_add(memory::key_size(key, value.len()), Ordering::Relaxed);
+                shard.insert(Key::clone(key), Entry { data: value });
+            }
+            Ok(None)
+        }
+    }
+    // Removes the key, returning its value
+    pub fn del(&self, key: &str) -> Option<Bytes> {
+        let removed = self.shard(key).remove(key)?;
+        self.used_memory.fetch_sub(memory::key_size(key, removed.data.len()), Ordering::Relaxed);
+        Some(removed.data)
     }
-    pub fn del(&self, key: &str) {
-        let mut entries = self.entries.lock().unwrap();
-        entries.remove(key);
+    // Calls `visit` with every key and its value, a shard at a time
+    pub fn visit_all(&self, mut visit: impl FnMut(&Key, &Bytes)) {
+        for shard in &self.shards {
+            for (key, entry) in shard.lock().unwrap().iter() {
+                visit(key, &entry.data);
+            }
+        }
+    }
+}
+
+impl StringExecutor {
+    pub(crate) fn keys(&self) -> Vec<Key> {
+        let mut keys = Vec::new();
+        self.data.visit_all(|key, _| keys.push(Key::clone(key)));
+        keys
+    }
+
+    // A shard at a time, each locked while `visit` sees its keys, so `visit` must not use the executor
+    pub fn for_each_entry(&self, visit: &mut dyn FnMut(&str, EntryView)) {
+        self.data.visit_all(|key, value| visit(key, EntryView::String(value)));
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use crate::commands::{request, ExecutionError, ParserError};
     use crate::index::LockType::{Read, Write};
-    use crate::index::{CommandIdentifier, KeyType, RedisCommandType};
+    use crate::index::{CommandCompleted, CommandIdentifier, Key, KeyType};
+    use crate::resp::{Protocol, Value};
     use crate::string_executor::StringExecutor;
     use bytes::Bytes;
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+
+    fn execute(db: &StringExecutor, words: &[&str]) -> Result<CommandCompleted, ExecutionError> {
+        let command = StringExecutor::build_command(&request(words)).unwrap();
+        db.execute_command(&command)
+    }
+
+    fn run(db: &StringExecutor, words: &[&str]) -> Value {
+        execute(db, words).unwrap().get_response().clone()
+    }
+
+    #[test]
+    fn given_wrong_number_of_arguments_when_build_command_then_redis_message() {
+        let error = StringExecutor::build_command(&request(&["GET"])).err().unwrap();
+        assert_eq!(error, ParserError::WrongNumberOfArguments("get".to_string()));
+        let error = StringExecutor::build_command(&request(&["incrby", "key"])).err().unwrap();
+        assert_eq!(error, ParserError::WrongNumberOfArguments("incrby".to_string()));
+    }
+
+    #[test]
+    fn given_unknown_set_option_when_build_command_then_syntax_error() {
+        let error = StringExecutor::build_command(&request(&["SET", "key", "value", "KEEP"])).err().unwrap();
+        assert_eq!(error, ParserError::Syntax);
+        let error = StringExecutor::build_command(&request(&["SET", "key", "value", "NX", "xx"])).err().unwrap();
+        assert_eq!(error, ParserError::Syntax);
+    }
+
+    #[test]
+    fn given_set_options_when_executed_then_stored_only_as_they_allow() {
+        let db = StringExecutor::new();
+        assert_eq!(run(&db, &["SET", "key", "first", "XX"]), Value::Null);
+        assert!(!db.internal_exists("key"));
+        assert_eq!(run(&db, &["SET", "key", "first", "nx"]), Value::Ok);
+        assert_eq!(run(&db, &["SET", "key", "second", "NX"]), Value::Null);
+        // GET returns the old value whether or not the new one is stored
+        assert_eq!(run(&db, &["SET", "key", "second", "GET", "NX"]), Value::BulkString(Bytes::from_static(b"first")));
+        assert_eq!(run(&db, &["SET", "key", "second", "XX", "GET"]), Value::BulkString(Bytes::from_static(b"first")));
+        assert_eq!(run(&db, &["SET", "key", "third", "get"]), Value::BulkString(Bytes::from_static(b"second")));
+        assert_eq!(run(&db, &["SET", "other", "value", "GET"]), Value::Null);
+        assert!(db.internal_exists("other"));
+        assert_eq!(db.used_memory(), db.recount_memory());
+    }
 
     #[test]
     fn given_valid_key_when_get_return_value() {
         let obj = StringExecutor::new();
         setup_db_with_string(&obj);
         let command = CommandIdentifier::new(
-            RedisCommandType::StringCommand,
             "key".to_string(),
-            "GET".to_string(),
+            "GET",
             Vec::new(),
             KeyType::String,
             Read,
         );
         let result = obj.execute_command(&command);
-        assert_eq!(result.unwrap().get_response(), "+value\r\n".as_bytes());
+        assert_eq!(result.unwrap().get_response(), &Value::BulkString(Bytes::from_static(b"value")));
     }
 
     #[test]
     fn given_empty_db_when_get_return_empty_string() {
         let db = StringExecutor::new();
         let command = CommandIdentifier::new(
-            RedisCommandType::StringCommand,
             "key".to_string(),
-            "GET".to_string(),
+            "GET",
             Vec::new(),
             KeyType::String,
             Read,
         );
         let result = db.execute_command(&command);
-        assert_eq!(result.unwrap().get_response(), "+(nil)\r\n".as_bytes());
+        assert_eq!(result.unwrap().get_response(), &Value::Null);
+    }
+
+    #[test]
+    fn given_some_keys_present_when_mget_then_values_in_order_with_nulls_for_the_missing() {
+        let db = StringExecutor::new();
+        let bulk = |value: &'static str| Value::BulkString(Bytes::from_static(value.as_bytes()));
+
+        assert_eq!(run(&db, &["MGET", "a", "b"]), Value::Array(vec![Value::Null, Value::Null]));
+        run(&db, &["SET", "a", "1"]);
+        run(&db, &["SET", "c", "3"]);
+        assert_eq!(run(&db, &["MGET", "a", "b", "c"]), Value::Array(vec![bulk("1"), Value::Null, bulk("3")]));
+        assert_eq!(run(&db, &["mget", "c", "a", "c"]), Value::Array(vec![bulk("3"), bulk("1"), bulk("3")]));
+        assert_eq!(run(&db, &["MGET", "b"]).encode(Protocol::Resp2), "*1\r\n$-1\r\n");
+
+        let error = StringExecutor::build_command(&request(&["MGET"])).err().unwrap();
+        assert_eq!(error, ParserError::WrongNumberOfArguments("mget".to_string()));
+    }
+
+    #[test]
+    fn given_key_new_or_existing_when_getset_then_old_value_returned_and_new_stored() {
+        let db = StringExecutor::new();
+
+        let created = run(&db, &["GETSET", "key", "first"]);
+        assert_eq!(created, Value::Null);
+        assert_eq!(created.encode(Protocol::Resp3), "_\r\n");
+        assert_eq!(run(&db, &["GET", "key"]), Value::BulkString(Bytes::from_static(b"first")));
+        assert_eq!(run(&db, &["getset", "key", "second"]), Value::BulkString(Bytes::from_static(b"first")));
+        assert_eq!(run(&db, &["GET", "key"]), Value::BulkString(Bytes::from_static(b"second")));
+        assert_eq!(db.used_memory(), db.recount_memory());
+    }
+
+    #[test]
+    fn given_ttl_when_setex_or_psetex_then_value_stored_like_set() {
+        let db = StringExecutor::new();
+
+        assert_eq!(run(&db, &["SETEX", "seconds", "100", "value"]), Value::Ok);
+        assert_eq!(run(&db, &["PSETEX", "millis", "100000", "other"]), Value::Ok);
+        assert_eq!(run(&db, &["GET", "seconds"]), Value::BulkString(Bytes::from_static(b"value")));
+        assert_eq!(run(&db, &["GET", "millis"]), Value::BulkString(Bytes::from_static(b"other")));
+        assert_eq!(db.used_memory(), db.recount_memory());
+    }
+
+    #[test]
+    fn given_time_not_in_the_future_when_setex_then_error_and_nothing_stored() {
+        let db = StringExecutor::new();
+
+        let error = execute(&db, &["SETEX", "key", "0", "value"]).err().unwrap();
+        assert_eq!(error.get_message(), "invalid expire time in 'setex' command");
+        let error = execute(&db, &["psetex", "key", "-5", "value"]).err().unwrap();
+        assert_eq!(error.get_message(), "invalid expire time in 'psetex' command");
+        let error = execute(&db, &["SETEX", "key", &i64::MAX.to_string(), "value"]).err().unwrap();
+        assert_eq!(error, ExecutionError::InvalidExpireTime("SETEX"));
+        assert_eq!(execute(&db, &["SETEX", "key", "soon", "value"]).err().unwrap(), ExecutionError::NotAnInteger);
+        assert!(!db.internal_exists("key"));
+        let error = StringExecutor::build_command(&request(&["SETEX", "key", "10"])).err().unwrap();
+        assert_eq!(error, ParserError::WrongNumberOfArguments("setex".to_string()));
+    }
+
+    #[test]
+    fn given_pairs_when_mset_then_all_stored_and_mget_returns_them() {
+        let db = StringExecutor::new();
+        let bulk = |value: &'static str| Value::BulkString(Bytes::from_static(value.as_bytes()));
+
+        assert_eq!(run(&db, &["MSET", "a", "1", "b", "2", "c", "3"]), Value::Ok);
+        assert_eq!(run(&db, &["MGET", "a", "b", "c"]), Value::Array(vec![bulk("1"), bulk("2"), bulk("3")]));
+        // replaces what was there, and the last of a repeated key wins
+        assert_eq!(run(&db, &["mset", "a", "one", "a", "uno"]), Value::Ok);
+        assert_eq!(run(&db, &["GET", "a"]), bulk("uno"));
+        assert_eq!(db.used_memory(), db.recount_memory());
+
+        let error = StringExecutor::build_command(&request(&["MSET", "a", "1", "b"])).err().unwrap();
+        assert_eq!(error, ParserError::WrongNumberOfArguments("mset".to_string()));
+        let error = StringExecutor::build_command(&request(&["MSETNX", "a"])).err().unwrap();
+        assert_eq!(error, ParserError::WrongNumberOfArguments("msetnx".to_string()));
+    }
+
+    #[test]
+    fn given_one_key_existing_when_msetnx_then_none_set() {
+        let db = StringExecutor::new();
+
+        run(&db, &["SET", "b", "old"]);
+        assert_eq!(run(&db, &["MSETNX", "a", "1", "b", "2", "c", "3"]), Value::Integer(0));
+        assert!(!db.internal_exists("a"));
+        assert!(!db.internal_exists("c"));
+        assert_eq!(db.data.get("b"), Some(Bytes::from_static(b"old")));
+
+        assert_eq!(run(&db, &["MSETNX", "a", "1", "c", "3"]), Value::Integer(1));
+        assert_eq!(db.data.get("c"), Some(Bytes::from_static(b"3")));
+    }
+
+    #[test]
+    fn given_completed_command_when_encoded_then_rendered_for_each_protocol() {
+        let db = StringExecutor::new();
+        let command = CommandIdentifier::new("key", "GET", Vec::new(), KeyType::String, Read);
+        let completed = db.execute_command(&command).unwrap();
+        assert_eq!(completed.get_response().encode(Protocol::Resp2), "$-1\r\n");
+        assert_eq!(completed.get_response().encode(Protocol::Resp3), "_\r\n");
     }
 
     #[test]
     fn given_key_does_not_exist_when_incr_create_key_with_value_1() {
         let db = StringExecutor::new();
         let command = CommandIdentifier::new(
-            RedisCommandType::StringCommand,
             "key".to_string(),
-            "INCR".to_string(),
+            "INCR",
             Vec::new(),
             KeyType::String,
             Write,
         );
         let result = db.execute_command(&command);
-        assert_eq!(result.unwrap().get_response(), "+1\r\n");
+        assert_eq!(result.unwrap().get_response(), &Value::Integer(1));
     }
 
     #[test]
@@ -342,15 +599,14 @@ mod tests {
         let db = StringExecutor::new();
         setup_db_with_int(&db);
         let command = CommandIdentifier::new(
-            RedisCommandType::StringCommand,
             "key".to_string(),
-            "INCR".to_string(),
+            "INCR",
             Vec::new(),
             KeyType::String,
             Write,
         );
         let result = db.execute_command(&command);
-        assert_eq!(result.unwrap().get_response(), "+11\r\n");
+        assert_eq!(result.unwrap().get_response(), &Value::Integer(11));
     }
 
     #[test]
@@ -358,18 +614,16 @@ mod tests {
         let db = StringExecutor::new();
         setup_db_with_int(&db);
 
-        let mut value = Vec::new();
-        value.push(Bytes::from("10"));
+        let value = vec![Bytes::from("10")];
         let command = CommandIdentifier::new(
-            RedisCommandType::StringCommand,
             "key".to_string(),
-            "INCRBY".to_string(),
+            "INCRBY",
             value,
             KeyType::String,
             Write,
         );
         let result = db.execute_command(&command);
-        assert_eq!(result.unwrap().get_response(), "+20\r\n");
+        assert_eq!(result.unwrap().get_response(), &Value::Integer(20));
     }
 
     #[test]
@@ -377,30 +631,28 @@ mod tests {
         let db = StringExecutor::new();
         setup_db_with_int(&db);
         let command = CommandIdentifier::new(
-            RedisCommandType::StringCommand,
             "key".to_string(),
-            "DECR".to_string(),
+            "DECR",
             Vec::new(),
             KeyType::String,
             Write,
         );
         let result = db.execute_command(&command);
-        assert_eq!(result.unwrap().get_response(), "+9\r\n");
+        assert_eq!(result.unwrap().get_response(), &Value::Integer(9));
     }
 
     #[test]
     fn given_key_does_not_exist_when_decr_create_key_with_value_minus_1() {
         let db = StringExecutor::new();
         let command = CommandIdentifier::new(
-            RedisCommandType::StringCommand,
             "key".to_string(),
-            "DECR".to_string(),
+            "DECR",
             Vec::new(),
             KeyType::String,
             Write,
         );
         let result = db.execute_command(&command);
-        assert_eq!(result.unwrap().get_response(), "+-1\r\n");
+        assert_eq!(result.unwrap().get_response(), &Value::Integer(-1));
     }
 
     #[test]
@@ -408,35 +660,31 @@ mod tests {
         let db = StringExecutor::new();
         setup_db_with_int(&db);
 
-        let mut value = Vec::new();
-        value.push(Bytes::from("4"));
+        let value = vec![Bytes::from("4")];
         let command = CommandIdentifier::new(
-            RedisCommandType::StringCommand,
             "key".to_string(),
-            "DECRBY".to_string(),
+            "DECRBY",
             value,
             KeyType::String,
             Write,
         );
         let result = db.execute_command(&command);
-        assert_eq!(result.unwrap().get_response(), "+6\r\n");
+        assert_eq!(result.unwrap().get_response(), &Value::Integer(6));
     }
 
     #[test]
     fn given_no_key_exists_when_decrby_decrease_value() {
         let db = StringExecutor::new();
-        let mut value = Vec::new();
-        value.push(Bytes::from("4"));
+        let value = vec![Bytes::from("4")];
         let command = CommandIdentifier::new(
-            RedisCommandType::StringCommand,
             "key".to_string(),
-            "DECRBY".to_string(),
+            "DECRBY",
             value,
             KeyType::String,
             Write,
         );
         let result = db.execute_command(&command);
-        assert_eq!(result.unwrap().get_response(), "+-4\r\n");
+        assert_eq!(result.unwrap().get_response(), &Value::Integer(-4));
     }
 
     #[test]
@@ -444,9 +692,8 @@ mod tests {
         let db = StringExecutor::new();
         setup_db_with_string(&db);
         let command = CommandIdentifier::new(
-            RedisCommandType::StringCommand,
             "key".to_string(),
-            "INCR".to_string(),
+            "INCR",
             Vec::new(),
             KeyType::String,
             Write,
@@ -454,7 +701,7 @@ mod tests {
         let incr_result = db.execute_command(&command);
         assert!(incr_result.is_err());
         let err = incr_result.err().unwrap();
-        assert_eq!(err.get_message(), "-ERR value is not an integer or out of range");
+        assert_eq!(err, ExecutionError::NotAnInteger);
     }
 
 
@@ -465,9 +712,8 @@ mod tests {
 
         // Now try to INCR the non-numeric value
         let incr_command = CommandIdentifier::new(
-            RedisCommandType::StringCommand,
             "key".to_string(),
-            "INCR".to_string(),
+            "INCR",
             Vec::new(),
             KeyType::String,
             Write,
@@ -475,39 +721,133 @@ mod tests {
         let incr_result = db.execute_command(&incr_command);
         assert!(incr_result.is_err());
         let err = incr_result.err().unwrap();
-        assert_eq!(err.get_message(), "-ERR value is not an integer or out of range");
+        assert_eq!(err, ExecutionError::NotAnInteger);
     }
 
+    #[test]
+    fn given_many_threads_when_incr_same_key_without_index_lock_then_no_update_lost() {
+        let db = Arc::new(StringExecutor::new());
+        let threads: Vec<_> = (0..8)
+            .map(|_| {
+                let db = Arc::clone(&db);
+                thread::spawn(move || {
+                    let incr = CommandIdentifier::new("counter".to_string(), "INCR", Vec::new(), KeyType::String, Write);
+                    for _ in 0..1000 {
+                        db.execute_command(&incr).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(db.data.get("counter"), Some(Bytes::from("8000")));
+        assert_eq!(db.used_memory(), db.recount_memory());
+    }
 
+    #[test]
+    fn given_key_missing_or_present_when_setnx_then_set_only_if_missing() {
+        let db = StringExecutor::new();
+
+        assert_eq!(run(&db, &["SETNX", "key", "first"]), Value::Integer(1));
+        assert_eq!(run(&db, &["setnx", "key", "second"]), Value::Integer(0));
+        assert_eq!(run(&db, &["GET", "key"]), Value::BulkString(Bytes::from_static(b"first")));
+        assert_eq!(db.used_memory(), db.recount_memory());
+    }
+
+    #[test]
+    fn given_two_threads_when_setnx_same_key_without_index_lock_then_exactly_one_stores() {
+        for _ in 0..100 {
+            let db = Arc::new(StringExecutor::new());
+            let threads: Vec<_> = ["first", "second"]
+                .into_iter()
+                .map(|value| {
+                    let db = Arc::clone(&db);
+                    thread::spawn(move || {
+                        let setnx = CommandIdentifier::new("key", "SETNX", vec![Bytes::from(value)], KeyType::String, Write);
+                        (value, db.execute_command(&setnx).unwrap().get_response().clone())
+                    })
+                })
+                .collect();
+            let results: Vec<_> = threads.into_iter().map(|thread| thread.join().unwrap()).collect();
+
+            let winners: Vec<_> = results.iter().filter(|(_, reply)| *reply == Value::Integer(1)).collect();
+            assert_eq!(winners.len(), 1, "{:?}", results);
+            assert_eq!(db.data.get("key"), Some(Bytes::from(winners[0].0)));
+        }
+    }
+
+    #[test]
+    fn given_value_when_set_or_removed_then_value_replaced_returned() {
+        let db = StringExecutor::new();
+        let key = Key::from("key");
+        assert_eq!(db.data.set(&key, Bytes::from("first")), None);
+        assert_eq!(db.data.set(&key, Bytes::from("second")), Some(Bytes::from("first")));
+        assert!(db.data.exists("key"));
+        assert_eq!(db.data.del("key"), Some(Bytes::from("second")));
+        assert!(!db.data.exists("key"));
+        assert_eq!(db.data.del("key"), None);
+        assert_eq!(db.used_memory(), 0);
+    }
+
+    #[test]
+    fn given_writes_going_on_when_visiting_all_then_every_settled_key_seen_once() {
+        let db = Arc::new(StringExecutor::new());
+        for key in 0..1000 {
+            db.data.set(&Key::from(format!("settled:{}", key)), Bytes::from("value"));
+        }
+        let stop = Arc::new(AtomicBool::new(false));
+        let writers: Vec<_> = (0..2)
+            .map(|writer| {
+                let (db, stop) = (Arc::clone(&db), Arc::clone(&stop));
+                thread::spawn(move || {
+                    let mut round = 0;
+                    while !stop.load(Ordering::Relaxed) {
+                        let key = Key::from(format!("churn:{}:{}", writer, round % 100));
+                        db.data.set(&key, Bytes::from("value"));
+                        db.data.del(&format!("churn:{}:{}", writer, (round + 50) % 100));
+                        round += 1;
+                    }
+                })
+            })
+            .collect();
+
+        for _ in 0..20 {
+            let mut seen: HashMap<String, usize> = HashMap::new();
+            db.data.visit_all(|key, _| *seen.entry(key.to_string()).or_default() += 1);
+            assert!(seen.values().all(|&times| times == 1));
+            assert_eq!(seen.keys().filter(|key| key.starts_with("settled:")).count(), 1000);
+        }
+        stop.store(true, Ordering::Relaxed);
+        for writer in writers {
+            writer.join().unwrap();
+        }
+    }
 
     fn setup_db_with_string(db: &StringExecutor) {
-        let mut value = Vec::new();
-        value.push(Bytes::from("value"));
+        let value = vec![Bytes::from("value")];
         let command = CommandIdentifier::new(
-            RedisCommandType::StringCommand,
             "key".to_string(),
-            "SET".to_string(),
+            "SET",
             value,
             KeyType::String,
             Write,
         );
         let result = db.execute_command(&command);
-        assert_eq!(result.unwrap().get_response(), "+OK\r\n".as_bytes());
+        assert_eq!(result.unwrap().get_response(), &Value::Ok);
     }
 
     fn setup_db_with_int(db: &StringExecutor) {
-        let mut value = Vec::new();
-        value.push(Bytes::from("10"));
+        let value = vec![Bytes::from("10")];
         let command = CommandIdentifier::new(
-            RedisCommandType::StringCommand,
             "key".to_string(),
-            "SET".to_string(),
+            "SET",
             value,
             KeyType::String,
             Write,
         );
         let result = db.execute_command(&command);
-        assert_eq!(result.unwrap().get_response(), "+OK\r\n".as_bytes());
+        assert_eq!(result.unwrap().get_response(), &Value::Ok);
     }
 
 }
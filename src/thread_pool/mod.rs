@@ -1,66 +1,295 @@
+use crate::controller::panic_message;
+use crate::info;
 use std::{
-    sync::{Arc, Mutex, mpsc},
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        mpsc,
+    },
     thread,
 };
 
 // Borrowed from the Rust book example: https://doc.rust-lang.org/book/ch20-02-multithreaded.html
 // This is a simple thread pool implementation in Rust.
 
+const DEFAULT_QUEUE_CAPACITY: usize = 1024;
+
 pub struct ThreadPool {
+    // every worker started, including any retired since, which are joined at shutdown
     workers: Vec<Worker>,
-    sender: Option<mpsc::Sender<Job>>,
+    // the size the pool was last asked to be, which the workers running converge on
+    size: usize,
+    // bounded, so a flood of work waits for room rather than piling up in memory
+    sender: Option<mpsc::SyncSender<Message>>,
+    policy: ShutdownPolicy,
+    thread_name: &'static str,
+    shared: Arc<Shared>,
+}
+
+// What happens to the jobs still queued when the pool is shut down
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ShutdownPolicy {
+    // every queued job runs before the workers stop
+    #[default]
+    Finish,
+    // jobs that haven't started are dropped without running, and counted; the ones already
+    // running are left to finish
+    Discard,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct ThreadPoolOptions {
+    // the most jobs waiting for a free worker
+    pub queue_capacity: usize,
+    pub shutdown_policy: ShutdownPolicy,
+    // the workers are named after it, numbered from 0, e.g. redis-worker-3
+    pub thread_name: &'static str,
+}
+
+impl Default for ThreadPoolOptions {
+    fn default() -> Self {
+        ThreadPoolOptions {
+            queue_capacity: DEFAULT_QUEUE_CAPACITY,
+            shutdown_policy: ShutdownPolicy::Finish,
+            thread_name: "redis-worker",
+        }
+    }
+}
+
+// Why try_execute didn't take a job
+#[derive(Debug, PartialEq, Eq)]
+pub enum SubmitError {
+    // the queue is at capacity; the caller can wait, or turn the work away
+    Full,
+    ShutDown,
+}
+
+// What the pool and its workers share
+struct Shared {
+    receiver: Mutex<mpsc::Receiver<Message>>,
+    // workers started and not yet exited
+    running: AtomicUsize,
+    // jobs submitted that no worker has taken yet
+    queued: AtomicUsize,
+    // set when shutting down under ShutdownPolicy::Discard, so the workers stop running jobs
+    discarding: AtomicBool,
+    discarded: AtomicUsize,
+    // for the metrics, counted without taking a lock; the list of workers is only locked when
+    // one starts or exits, and when the metrics are read
+    busy: AtomicUsize,
+    jobs_executed: AtomicU64,
+    panics_recovered: AtomicU64,
+    workers: Mutex<Vec<Arc<WorkerStats>>>,
+}
+
+struct WorkerStats {
+    name: String,
+    busy: AtomicBool,
+    jobs_executed: AtomicU64,
+}
+
+// The pool as INFO reports it, at the moment it was asked
+#[derive(Debug)]
+pub struct PoolMetrics {
+    pub size: usize,
+    pub busy: usize,
+    pub queued: usize,
+    // jobs that ran to the end; the ones that panicked are counted as recovered instead
+    pub jobs_executed: u64,
+    pub panics_recovered: u64,
+    pub workers: Vec<WorkerMetrics>,
+}
+
+#[derive(Debug)]
+pub struct WorkerMetrics {
+    pub name: String,
+    pub busy: bool,
+    pub jobs_executed: u64,
+}
+
+// Reads the metrics of a pool from anywhere, e.g. INFO on a client's thread, without a
+// reference to the pool itself. Outlives the pool, reporting no workers once it is gone.
+#[derive(Clone)]
+pub struct PoolMonitor(Arc<Shared>);
+
+impl PoolMonitor {
+    pub fn metrics(&self) -> PoolMetrics {
+        let shared = &self.0;
+        let workers = shared
+            .workers
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|worker| WorkerMetrics {
+                name: worker.name.clone(),
+                busy: worker.busy.load(Ordering::Relaxed),
+                jobs_executed: worker.jobs_executed.load(Ordering::Relaxed),
+            })
+            .collect();
+        PoolMetrics {
+            size: shared.running.load(Ordering::SeqCst),
+            busy: shared.busy.load(Ordering::Relaxed),
+            queued: shared.queued.load(Ordering::SeqCst),
+            jobs_executed: shared.jobs_executed.load(Ordering::Relaxed),
+            panics_recovered: shared.panics_recovered.load(Ordering::Relaxed),
+            workers,
+        }
+    }
 }
 
 type Job = Box<dyn FnOnce() + Send + 'static>;
 
+enum Message {
+    Job(Job),
+    // the worker that takes this exits, once it has finished the job it was running, and
+    // after the jobs queued ahead of it have been taken
+    Retire,
+}
+
 impl ThreadPool {
     /// Create a new ThreadPool.
     ///
-    /// The size is the number of threads in the pool.
+    /// The size is the number of threads in the pool. The options size its queue, and say
+    /// what becomes of the jobs still queued at shutdown.
     ///
     /// # Panics
     ///
-    /// The `new` function will panic if the size is zero.
-    pub fn new(size: usize) -> ThreadPool {
+    /// The `with_options` function will panic if the size is zero.
+    pub fn with_options(size: usize, options: ThreadPoolOptions) -> ThreadPool {
         assert!(size > 0);
 
-        let (sender, receiver) = mpsc::channel();
+        let (sender, receiver) = mpsc::sync_channel(options.queue_capacity);
 
-        let receiver = Arc::new(Mutex::new(receiver));
+        let shared = Arc::new(Shared {
+            receiver: Mutex::new(receiver),
+            running: AtomicUsize::new(0),
+            queued: AtomicUsize::new(0),
+            discarding: AtomicBool::new(false),
+            discarded: AtomicUsize::new(0),
+            busy: AtomicUsize::new(0),
+            jobs_executed: AtomicU64::new(0),
+            panics_recovered: AtomicU64::new(0),
+            workers: Mutex::new(Vec::with_capacity(size)),
+        });
 
-        let mut workers = Vec::with_capacity(size);
+        let mut pool = ThreadPool {
+            workers: Vec::with_capacity(size),
+            size: 0,
+            sender: Some(sender),
+            policy: options.shutdown_policy,
+            thread_name: options.thread_name,
+            shared,
+        };
+        pool.resize(size);
+        pool
+    }
 
-        for id in 0..size {
-            workers.push(Worker::new(id, Arc::clone(&receiver)));
+    // Grows the pool straight away, or shrinks it as workers finish what they are running:
+    // none is interrupted, and jobs already queued are still run
+    #[allow(dead_code)] // the server runs one event loop per worker, so can't resize yet
+    pub fn resize(&mut self, size: usize) {
+        assert!(size > 0);
+        let Some(sender) = &self.sender else {
+            return;
+        };
+        while self.size < size {
+            let id = self.workers.len();
+            self.workers.push(Worker::new(id, self.thread_name, Arc::clone(&self.shared)));
+            self.size += 1;
         }
-
-        ThreadPool {
-            workers,
-            sender: Some(sender),
+        while self.size > size {
+            if sender.send(Message::Retire).is_err() {
+                break;
+            }
+            self.size -= 1;
         }
+        // forget the workers that have already retired
+        self.workers
+            .retain(|worker| worker.thread.as_ref().is_some_and(|thread| !thread.is_finished()));
+    }
+
+    // How many workers are running, which after shrinking falls to the new size as the
+    // retiring workers finish. The server reads this through the pool's monitor.
+    #[cfg(test)]
+    pub fn size(&self) -> usize {
+        self.shared.running.load(Ordering::SeqCst)
     }
 
+    // Waits for room in the queue if it is full
     pub fn execute<F>(&self, f: F)
     where
         F: FnOnce() + Send + 'static,
     {
         let job = Box::new(f);
 
-        self.sender.as_ref().unwrap().send(job).unwrap();
+        self.shared.queued.fetch_add(1, Ordering::SeqCst);
+        self.sender.as_ref().unwrap().send(Message::Job(job)).unwrap();
     }
-}
 
-impl Drop for ThreadPool {
-    fn drop(&mut self) {
-        drop(self.sender.take());
+    // Queues the job only if there is room for it straight away
+    pub fn try_execute<F>(&self, f: F) -> Result<(), SubmitError>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let sender = self.sender.as_ref().ok_or(SubmitError::ShutDown)?;
+        self.shared.queued.fetch_add(1, Ordering::SeqCst);
+        sender.try_send(Message::Job(Box::new(f))).map_err(|error| {
+            self.shared.queued.fetch_sub(1, Ordering::SeqCst);
+            match error {
+                mpsc::TrySendError::Full(_) => SubmitError::Full,
+                mpsc::TrySendError::Disconnected(_) => SubmitError::ShutDown,
+            }
+        })
+    }
+
+    pub fn monitor(&self) -> PoolMonitor {
+        PoolMonitor(Arc::clone(&self.shared))
+    }
+
+    // How many jobs are waiting for a worker, counting any execute is still waiting to queue.
+    // The server reads this through the pool's monitor.
+    #[cfg(test)]
+    pub fn queued(&self) -> usize {
+        self.shared.queued.load(Ordering::SeqCst)
+    }
+
+    /// Stop taking jobs and wait for every worker thread to exit, dealing with the jobs still
+    /// queued by the pool's shutdown policy. Returns how many queued jobs were dropped without
+    /// running.
+    ///
+    /// Shutting down a pool that is already shut down does nothing, and returns 0.
+    pub fn shutdown(&mut self) -> usize {
+        // closing the channel is the workers' signal to stop, once it has been emptied
+        let Some(sender) = self.sender.take() else {
+            return 0;
+        };
+        if self.policy == ShutdownPolicy::Discard {
+            self.shared.discarding.store(true, Ordering::SeqCst);
+        }
+        drop(sender);
 
         for worker in &mut self.workers {
             log::info!("Shutting down worker {}", worker.id);
 
-            if let Some(thread) = worker.thread.take() {
-                thread.join().unwrap();
+            if let Some(thread) = worker.thread.take()
+                && thread.join().is_err()
+            {
+                log::error!("Worker {} panicked", worker.id);
             }
         }
+
+        let discarded = self.shared.discarded.swap(0, Ordering::SeqCst);
+        if discarded > 0 {
+            log::warn!("{} queued jobs were dropped at shutdown", discarded);
+        }
+        discarded
+    }
+}
+
+impl Drop for ThreadPool {
+    fn drop(&mut self) {
+        self.shutdown();
     }
 }
 
@@ -70,16 +299,49 @@ struct Worker {
 }
 
 impl Worker {
-    fn new(id: usize, receiver: Arc<Mutex<mpsc::Receiver<Job>>>) -> Worker {
-        let thread = thread::spawn(move || {
+    fn new(id: usize, thread_name: &str, shared: Arc<Shared>) -> Worker {
+        let stats = Arc::new(WorkerStats {
+            // so the worker can be told apart in a debugger or top
+            name: format!("{thread_name}-{id}"),
+            busy: AtomicBool::new(false),
+            jobs_executed: AtomicU64::new(0),
+        });
+        shared.running.fetch_add(1, Ordering::SeqCst);
+        shared.workers.lock().unwrap().push(Arc::clone(&stats));
+        let builder = thread::Builder::new().name(stats.name.clone());
+        let thread = builder.spawn(move || {
             loop {
-                let message = receiver.lock().unwrap().recv();
+                let message = shared.receiver.lock().unwrap().recv();
+                if let Ok(Message::Job(_)) = message {
+                    shared.queued.fetch_sub(1, Ordering::SeqCst);
+                }
 
                 match message {
-                    Ok(job) => {
+                    Ok(Message::Retire) => {
+                        log::info!("Worker {id} retired.");
+                        break;
+                    }
+                    Ok(Message::Job(_)) if shared.discarding.load(Ordering::SeqCst) => {
+                        shared.discarded.fetch_add(1, Ordering::SeqCst);
+                    }
+                    Ok(Message::Job(job)) => {
                         log::info!("Worker {id} got a job; executing.");
 
-                        job();
+                        stats.busy.store(true, Ordering::Relaxed);
+                        shared.busy.fetch_add(1, Ordering::Relaxed);
+                        // a job that panics takes only itself down: the worker, and with it the
+                        // pool's capacity, carries on
+                        let result = panic::catch_unwind(AssertUnwindSafe(job));
+                        shared.busy.fetch_sub(1, Ordering::Relaxed);
+                        stats.busy.store(false, Ordering::Relaxed);
+                        if let Err(payload) = result {
+                            log::error!("Worker {id}: job panicked: {}", panic_message(payload.as_ref()));
+                            shared.panics_recovered.fetch_add(1, Ordering::Relaxed);
+                            info::record_recovered_panic();
+                            continue;
+                        }
+                        stats.jobs_executed.fetch_add(1, Ordering::Relaxed);
+                        shared.jobs_executed.fetch_add(1, Ordering::Relaxed);
                         log::info!("Worker {id} completed a job.");
                     }
                     Err(_) => {
@@ -88,11 +350,286 @@ impl Worker {
                     }
                 }
             }
+            shared.workers.lock().unwrap().retain(|worker| !Arc::ptr_eq(worker, &stats));
+            shared.running.fetch_sub(1, Ordering::SeqCst);
         });
+        // the pool can't do without its threads, any more than thread::spawn could
+        let thread = thread.expect("Unable to start a worker thread");
 
         Worker {
             id,
             thread: Some(thread),
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::sync::Barrier;
+    use std::time::Duration;
+
+    fn pool(size: usize) -> ThreadPool {
+        ThreadPool::with_options(size, ThreadPoolOptions::default())
+    }
+
+    // Counts the worker threads that have exited, from a thread-local dropped as each one does
+    struct ExitWatch(Arc<AtomicUsize>);
+
+    impl Drop for ExitWatch {
+        fn drop(&mut self) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    thread_local! {
+        static EXIT_WATCH: RefCell<Option<ExitWatch>> = const { RefCell::new(None) };
+    }
+
+    // Gives every worker one job that waits until all of them are running, so each worker gets
+    // exactly one, and leaves behind a watch that counts the worker's thread when it exits
+    fn watch_workers(pool: &ThreadPool, size: usize) -> Arc<AtomicUsize> {
+        let exited = Arc::new(AtomicUsize::new(0));
+        let barrier = Arc::new(Barrier::new(size));
+        for _ in 0..size {
+            let (exited, barrier) = (Arc::clone(&exited), Arc::clone(&barrier));
+            pool.execute(move || {
+                barrier.wait();
+                EXIT_WATCH.with(|watch| *watch.borrow_mut() = Some(ExitWatch(exited)));
+            });
+        }
+        exited
+    }
+
+    #[test]
+    fn given_queued_jobs_when_pool_dropped_then_all_jobs_run_and_workers_joined() {
+        let pool = pool(4);
+        let exited = watch_workers(&pool, 4);
+        let ran = Arc::new(AtomicUsize::new(0));
+        for _ in 0..100 {
+            let ran = Arc::clone(&ran);
+            pool.execute(move || {
+                thread::sleep(Duration::from_micros(100));
+                ran.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+        drop(pool);
+        assert_eq!(ran.load(Ordering::SeqCst), 100);
+        assert_eq!(exited.load(Ordering::SeqCst), 4);
+        // nothing is left holding the jobs' state either
+        assert_eq!(Arc::strong_count(&ran), 1);
+    }
+
+    #[test]
+    fn given_discard_policy_when_shutdown_then_queued_jobs_dropped_and_counted() {
+        let options = ThreadPoolOptions { shutdown_policy: ShutdownPolicy::Discard, ..ThreadPoolOptions::default() };
+        let mut pool = ThreadPool::with_options(1, options);
+        let (started_sender, started) = mpsc::channel();
+        let (release, released) = mpsc::channel::<()>();
+        pool.execute(move || {
+            started_sender.send(()).unwrap();
+            released.recv().unwrap();
+        });
+        started.recv().unwrap();
+        let ran = Arc::new(AtomicUsize::new(0));
+        for _ in 0..5 {
+            let ran = Arc::clone(&ran);
+            pool.execute(move || {
+                ran.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+        // the running job is let go once the shutdown has begun
+        let releaser = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            release.send(()).unwrap();
+        });
+        assert_eq!(pool.shutdown(), 5);
+        releaser.join().unwrap();
+        assert_eq!(ran.load(Ordering::SeqCst), 0);
+        assert_eq!(Arc::strong_count(&ran), 1);
+    }
+
+    #[test]
+    fn given_pool_shut_down_when_shut_down_again_and_dropped_then_nothing_happens() {
+        let mut pool = pool(2);
+        let exited = watch_workers(&pool, 2);
+        assert_eq!(pool.shutdown(), 0);
+        assert_eq!(exited.load(Ordering::SeqCst), 2);
+        assert_eq!(pool.shutdown(), 0);
+        drop(pool);
+        assert_eq!(exited.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn given_panicking_job_when_more_jobs_than_workers_follow_then_all_complete() {
+        let pool = pool(2);
+        pool.execute(|| panic!("job failed"));
+        let ran = Arc::new(AtomicUsize::new(0));
+        for _ in 0..10 {
+            let ran = Arc::clone(&ran);
+            pool.execute(move || {
+                ran.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+
+        // both workers are still there: two jobs that each wait for the other can both run
+        let (running, release) = (Arc::new(AtomicUsize::new(0)), Arc::new(AtomicBool::new(false)));
+        for _ in 0..2 {
+            let (running, release) = (Arc::clone(&running), Arc::clone(&release));
+            pool.execute(move || {
+                running.fetch_add(1, Ordering::SeqCst);
+                while !release.load(Ordering::SeqCst) {
+                    thread::sleep(Duration::from_millis(1));
+                }
+            });
+        }
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        while running.load(Ordering::SeqCst) < 2 && std::time::Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(1));
+        }
+        release.store(true, Ordering::SeqCst);
+        assert_eq!(running.load(Ordering::SeqCst), 2);
+        assert_eq!(pool.monitor().metrics().panics_recovered, 1);
+        drop(pool);
+        assert_eq!(ran.load(Ordering::SeqCst), 10);
+    }
+
+    #[test]
+    fn given_full_queue_when_try_execute_then_saturated_until_jobs_finish() {
+        let options = ThreadPoolOptions { queue_capacity: 2, ..ThreadPoolOptions::default() };
+        let mut pool = ThreadPool::with_options(1, options);
+        let (started_sender, started) = mpsc::channel();
+        let (release, released) = mpsc::channel::<()>();
+        pool.execute(move || {
+            started_sender.send(()).unwrap();
+            released.recv().unwrap();
+        });
+        started.recv().unwrap();
+
+        let ran = Arc::new(AtomicUsize::new(0));
+        let job = |ran: &Arc<AtomicUsize>| {
+            let ran = Arc::clone(ran);
+            move || {
+                ran.fetch_add(1, Ordering::SeqCst);
+            }
+        };
+        assert_eq!(pool.try_execute(job(&ran)), Ok(()));
+        assert_eq!(pool.try_execute(job(&ran)), Ok(()));
+        assert_eq!(pool.try_execute(job(&ran)), Err(SubmitError::Full));
+        assert_eq!(pool.queued(), 2);
+
+        // once the worker is free it works through the queue, making room again
+        release.send(()).unwrap();
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        while pool.queued() > 0 && std::time::Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(pool.queued(), 0);
+        assert_eq!(pool.try_execute(job(&ran)), Ok(()));
+
+        pool.shutdown();
+        assert_eq!(ran.load(Ordering::SeqCst), 3);
+        assert_eq!(pool.try_execute(job(&ran)), Err(SubmitError::ShutDown));
+    }
+
+    // Waits up to a few seconds for the condition, so a failure is reported rather than hung
+    fn eventually(condition: impl Fn() -> bool) -> bool {
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        while !condition() && std::time::Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(1));
+        }
+        condition()
+    }
+
+    // Submits `count` jobs that only finish once they are all running at the same time
+    fn run_together(pool: &ThreadPool, count: usize) -> Arc<AtomicUsize> {
+        let (running, finished) = (Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0)));
+        for _ in 0..count {
+            let (running, finished) = (Arc::clone(&running), Arc::clone(&finished));
+            pool.execute(move || {
+                running.fetch_add(1, Ordering::SeqCst);
+                let deadline = std::time::Instant::now() + Duration::from_secs(5);
+                while running.load(Ordering::SeqCst) < count && std::time::Instant::now() < deadline {
+                    thread::sleep(Duration::from_millis(1));
+                }
+                if running.load(Ordering::SeqCst) >= count {
+                    finished.fetch_add(1, Ordering::SeqCst);
+                }
+            });
+        }
+        finished
+    }
+
+    #[test]
+    fn given_pool_when_resized_then_workers_grow_and_shrink_without_losing_jobs() {
+        let mut pool = pool(2);
+        assert_eq!(pool.size(), 2);
+
+        pool.resize(4);
+        assert_eq!(pool.size(), 4);
+        let together = run_together(&pool, 4);
+        assert!(eventually(|| together.load(Ordering::SeqCst) == 4));
+
+        let ran = Arc::new(AtomicUsize::new(0));
+        for _ in 0..20 {
+            let ran = Arc::clone(&ran);
+            pool.execute(move || {
+                thread::sleep(Duration::from_millis(1));
+                ran.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+        pool.resize(1);
+        assert!(eventually(|| pool.size() == 1));
+        for _ in 0..5 {
+            let ran = Arc::clone(&ran);
+            pool.execute(move || {
+                ran.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+        assert!(eventually(|| ran.load(Ordering::SeqCst) == 25));
+        assert_eq!(pool.size(), 1);
+
+        pool.shutdown();
+        assert_eq!(pool.size(), 0);
+    }
+
+    #[test]
+    fn given_jobs_when_executed_then_metrics_follow_them() {
+        let pool = pool(2);
+        let monitor = pool.monitor();
+        let metrics = monitor.metrics();
+        assert_eq!((metrics.size, metrics.busy, metrics.queued, metrics.jobs_executed), (2, 0, 0, 0));
+        let mut names: Vec<String> = metrics.workers.iter().map(|worker| worker.name.clone()).collect();
+        names.sort();
+        assert_eq!(names, ["redis-worker-0", "redis-worker-1"]);
+
+        // one job holds a worker until it is released
+        let (started_sender, started) = mpsc::channel();
+        let (release, released) = mpsc::channel::<()>();
+        pool.execute(move || {
+            started_sender.send(thread::current().name().map(String::from)).unwrap();
+            released.recv().unwrap();
+        });
+        let name = started.recv().unwrap().unwrap();
+        assert!(name.starts_with("redis-worker-"), "{}", name);
+        let metrics = monitor.metrics();
+        assert_eq!(metrics.busy, 1);
+        assert!(metrics.workers.iter().any(|worker| worker.name == name && worker.busy));
+
+        for _ in 0..10 {
+            pool.execute(|| {});
+        }
+        release.send(()).unwrap();
+        assert!(eventually(|| monitor.metrics().jobs_executed == 11));
+        let metrics = monitor.metrics();
+        assert_eq!((metrics.busy, metrics.queued, metrics.panics_recovered), (0, 0, 0));
+        let per_worker: u64 = metrics.workers.iter().map(|worker| worker.jobs_executed).sum();
+        assert_eq!(per_worker, 11);
+
+        drop(pool);
+        let metrics = monitor.metrics();
+        assert_eq!(metrics.size, 0);
+        assert!(metrics.workers.is_empty());
+    }
+}
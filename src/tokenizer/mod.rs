@@ -1,336 +1,595 @@
 
-use crate::commands::ParserError;
-
-const EMPTY_REQUEST: &str = "Request is empty";
-const NO_TOKENS_FOUND: &str = "No tokens found in the request";
-const INVALID_REQUEST_STRUCTURE: &str =
-    "Invalid request structure, expected an array indicator '*' at the start";
-const INVALID_TOKEN_FORMAT: &str = "Invalid token format, expected newline after carriage return";
-const EMPTY_TOKEN_VALUE: &str =
-    "Empty token value; expected at least one character before carriage return";
-const TOKEN_SIZE_NOT_A_BYTE: &'static str = "Unable to determine size of Token";
-const TOKEN_SIZE_NOT_A_NUMBER: &'static str = "Token size is not a valid number";
-const SIZE_CANNOT_BE_ZERO: &'static str = "Array size cannot be zero";
-const IDENTIFIER_IS_WRONG_SIZE: &'static str = "Identifier size is less than expected";
-
-const TOKEN_IS_NOT_VALID_UTF8: &'static str = "Identifiers are not valid UTF-8 bytes";
-const INVALID_NO_SIZE_TOKEN: &'static str = "Expected size token '$' before identifier";
-const INVALID_NO_IDENTIFIER: &'static str = "Expected identifier after size token";
-const INVALID_REQUEST_INCORRECT_SIZE: &'static str =
-    "Invalid structure, number of identifiers does not match expected size";
-struct Token {
-    value: Vec<u8>,
-    size: usize,
+use crate::commands::{Limit, ParserError};
+use crate::config::Config;
+use bytes::{Bytes, BytesMut};
+use std::ops::Range;
+
+// The first request in the buffer
+#[derive(Debug, PartialEq)]
+pub enum ParsedRequest {
+    // An empty or null array (*0 / *-1): nothing to execute, and nothing to reply
+    NoOp,
+    // The command name then its arguments. A null bulk string ($-1) is None, distinct from
+    // an empty one ($0), which is Some("")
+    Command(Vec<Option<Bytes>>),
 }
-pub fn identify_command(request: &[u8]) -> Result<Vec<String>, ParserError> {
-    if request.is_empty() {
-        return Err(ParserError::new(EMPTY_REQUEST));
-    }
-    let tokens = match tokenize_request(request) {
-        Ok(tokens) => tokens,
-        Err(e) => return Err(ParserError::new(e)),
-    };
-    let response = validate_request_structure(&tokens)?;
-    Ok(response)
+
+// Result of reading a '*' or '$' size header while checking if a request has fully arrived
+enum SizeHeader {
+    Found { size: usize, next: usize },
+    // the header was -1
+    Null { next: usize },
+    Truncated,
+    Malformed(ParserError),
+    // the declared size is over the configured limit
+    TooLarge,
 }
 
-fn validate_request_structure(tokens: &[Token]) -> Result<Vec<String>, ParserError> {
-    if tokens.is_empty() {
-        return Err(ParserError::new(NO_TOKENS_FOUND));
+// How much of the buffer the first request occupies
+enum RequestLength {
+    // where each element's bytes are (None for a null bulk string), or None for an empty or null array
+    Complete { length: usize, elements: Option<Vec<Option<Range<usize>>>> },
+    Incomplete { missing_bytes: usize },
+    Malformed(ParserError),
+}
+
+// Splits the first request off the front of the buffer, leaving anything after it (the next,
+// pipelined, request) in place. The arguments are slices of the request's own bytes, so they
+// can be handed on and stored without being copied. Only the command name has to be UTF-8;
+// the arguments are binary safe.
+pub fn identify_command(buffer: &mut BytesMut, config: &Config) -> Result<ParsedRequest, ParserError> {
+    if buffer.is_empty() {
+        return Err(ParserError::Empty);
     }
-    if tokens[0].value.is_empty() || tokens[0].value[0] != b'*' {
-        return Err(ParserError::new(INVALID_REQUEST_STRUCTURE));
+    let (length, elements) = match measure_request(buffer, config) {
+        RequestLength::Complete { length, elements } => (length, elements),
+        RequestLength::Incomplete { missing_bytes } => {
+            return Err(ParserError::Incomplete { missing_bytes });
+        }
+        RequestLength::Malformed(error) => return Err(error),
+    };
+    if let Some(Some(name)) = elements.as_ref().and_then(|elements| elements.first())
+        && std::str::from_utf8(&buffer[name.clone()]).is_err()
+    {
+        return Err(ParserError::NotUtf8Command { offset: name.start });
     }
-    let mut response: Vec<String> = Vec::new();
-    let num_children = get_number_of_chars(&tokens[0])?;
+    let request = buffer.split_to(length).freeze();
+    let Some(elements) = elements else {
+        return Ok(ParsedRequest::NoOp);
+    };
+    Ok(ParsedRequest::Command(
+        elements
+            .into_iter()
+            .map(|element| element.map(|range| request.slice(range)))
+            .collect(),
+    ))
+}
 
-    for index in (1..tokens.len()).step_by(2) {
-        if tokens[index].value[0] != b'$' {
-            return Err(ParserError::new(INVALID_NO_SIZE_TOKEN));
+// Walks the array and bulk string headers to find where the first request ends, or, if it
+// hasn't fully arrived, how many more bytes are needed at minimum. Every header and element
+// must end in CRLF, so the request ends exactly where its last element does.
+// Sizes are checked against the configured limits as their digits arrive, so the missing
+// bytes reported (and reserved by the caller) never exceed proto-max-bulk-len; the array
+// count itself never causes an allocation, as each element is measured as it arrives.
+fn measure_request(request: &[u8], config: &Config) -> RequestLength {
+    let (count, mut position) = match read_size_header(request, 0, b'*', config.proto_max_multibulk_len) {
+        SizeHeader::Found { size: 0, next } | SizeHeader::Null { next } => {
+            return RequestLength::Complete { length: next, elements: None };
         }
-        let size = get_number_of_chars(&tokens[index])?;
-        if index + 1 >= tokens.len() {
-            return Err(ParserError::new(INVALID_NO_IDENTIFIER));
+        SizeHeader::Found { size, next } => (size, next),
+        SizeHeader::Truncated => return RequestLength::Incomplete { missing_bytes: 1 },
+        SizeHeader::Malformed(error) => return RequestLength::Malformed(error),
+        SizeHeader::TooLarge => {
+            return RequestLength::Malformed(ParserError::TooLarge { limit: Limit::MultibulkLength, offset: 0 });
         }
-        let identifier = String::from_utf8(tokens[index + 1].value[0..].to_vec())
-            .map_err(|_| ParserError::new(TOKEN_IS_NOT_VALID_UTF8))?;
-        if identifier.is_empty() || identifier.len() != size {
-            return Err(ParserError::new(IDENTIFIER_IS_WRONG_SIZE));
+    };
+    let mut elements = Vec::new();
+    for _ in 0..count {
+        let (size, next) = match read_size_header(request, position, b'$', config.proto_max_bulk_len) {
+            SizeHeader::Found { size, next } => (size, next),
+            SizeHeader::Null { next } => {
+                elements.push(None);
+                position = next;
+                continue;
+            }
+            SizeHeader::Truncated => return RequestLength::Incomplete { missing_bytes: 1 },
+            SizeHeader::Malformed(error) => return RequestLength::Malformed(error),
+            SizeHeader::TooLarge => {
+                return RequestLength::Malformed(ParserError::TooLarge { limit: Limit::BulkLength, offset: position });
+            }
+        };
+        let end = next.saturating_add(size);
+        if end.saturating_add(2) > config.client_query_buffer_limit {
+            return RequestLength::Malformed(ParserError::TooLarge { limit: Limit::QueryBuffer, offset: position });
         }
-        response.push(identifier);
-    }
-    // validate the number of identifiers matches the expected array size
-    if response.len() != num_children {
-        return Err(ParserError::new(INVALID_REQUEST_INCORRECT_SIZE));
-    }
-
-    Ok(response)
-}
-
-fn get_number_of_chars(token: &Token) -> Result<usize, ParserError> {
-    let num_elements_str = String::from_utf8(token.value[1..].to_vec())
-        .map_err(|_| ParserError::new(TOKEN_SIZE_NOT_A_BYTE))?;
-    let size = num_elements_str
-        .parse::<usize>()
-        .map_err(|_| ParserError::new(TOKEN_SIZE_NOT_A_NUMBER))?;
-    if size == 0 {
-        return Err(ParserError::new(SIZE_CANNOT_BE_ZERO));
-    }
-    Ok(size)
-}
-
-fn tokenize_request(request: &[u8]) -> Result<Vec<Token>, &str> {
-    let mut tokens = Vec::new();
-    let mut start = 0;
-
-    while start < request.len() {
-        match get_token(request, start) {
-            Ok(token) => {
-                start += token.size;
-                tokens.push(token);
+        position = end + 2; // +2 for \r\n
+        // check whatever part of the terminator has arrived, so a bad one is caught straight away
+        for (offset, expected) in (end..position).zip(b"\r\n") {
+            match request.get(offset) {
+                Some(byte) if byte != expected => {
+                    return RequestLength::Malformed(ParserError::LengthMismatch { offset });
+                }
+                _ => {}
             }
-            Err(e) => return Err(e),
         }
+        if position > request.len() {
+            return RequestLength::Incomplete {
+                missing_bytes: position - request.len(),
+            };
+        }
+        elements.push(Some(next..end));
     }
-    Ok(tokens)
+    RequestLength::Complete { length: position, elements: Some(elements) }
 }
 
-fn get_token(input: &[u8], start: usize) -> Result<Token, &str> {
-    if input.is_empty() || start >= input.len() {
-        return Err(EMPTY_REQUEST);
+fn read_size_header(request: &[u8], start: usize, prefix: u8, max: usize) -> SizeHeader {
+    if start >= request.len() {
+        return SizeHeader::Truncated;
+    }
+    if request[start] != prefix {
+        return SizeHeader::Malformed(ParserError::InvalidPrefix {
+            expected: prefix,
+            found: request[start],
+            offset: start,
+        });
     }
-    let mut count_of_characters = 0;
-    for index in start..input.len() {
-        let byte = input[index];
-        if byte == b'\r' {
-            if count_of_characters == 0 {
-                return Err(EMPTY_TOKEN_VALUE);
+    if request.get(start + 1) == Some(&b'-') {
+        // the only negative size allowed is -1, a null
+        for (offset, expected) in (start + 2..start + 5).zip(b"1\r\n") {
+            match request.get(offset) {
+                None => return SizeHeader::Truncated,
+                Some(byte) if byte != expected && offset == start + 4 => {
+                    return SizeHeader::Malformed(ParserError::InvalidTerminator { offset });
+                }
+                Some(byte) if byte != expected => {
+                    return SizeHeader::Malformed(ParserError::InvalidLength { offset });
+                }
+                _ => {}
             }
-            if index + 1 >= input.len() || input[index + 1] != b'\n' {
-                return Err(INVALID_TOKEN_FORMAT); // TODO - make sure we have a test case for this
+        }
+        return SizeHeader::Null { next: start + 5 };
+    }
+    let mut size: usize = 0;
+    for index in start + 1..request.len() {
+        match request[index] {
+            b'\r' => {
+                if index == start + 1 {
+                    return SizeHeader::Malformed(ParserError::InvalidLength { offset: index });
+                }
+                if index + 1 >= request.len() {
+                    return SizeHeader::Truncated;
+                }
+                if request[index + 1] != b'\n' {
+                    return SizeHeader::Malformed(ParserError::InvalidTerminator { offset: index + 1 });
+                }
+                return SizeHeader::Found { size, next: index + 2 };
             }
-            break;
+            digit @ b'0'..=b'9' => match size
+                .checked_mul(10)
+                .and_then(|size| size.checked_add((digit - b'0') as usize))
+            {
+                Some(value) if value <= max => size = value,
+                Some(_) => return SizeHeader::TooLarge,
+                None => return SizeHeader::Malformed(ParserError::InvalidLength { offset: index }),
+            },
+            _ => return SizeHeader::Malformed(ParserError::InvalidLength { offset: index }),
         }
-        count_of_characters += 1;
     }
-    Ok(Token {
-        value: input[start..start + count_of_characters].to_vec(),
-        size: count_of_characters + 2, // +2 for \r\n
-    })
+    SizeHeader::Truncated
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::tokenizer::{EMPTY_REQUEST, INVALID_REQUEST_STRUCTURE};
+    use proptest::prelude::*;
+
+    // Parses the first request from a copy of `request`, returning it and how many bytes it took up
+    fn parse_first(request: &[u8], config: &Config) -> Result<(ParsedRequest, usize), ParserError> {
+        let mut buffer = BytesMut::from(request);
+        let command = identify_command(&mut buffer, config)?;
+        Ok((command, request.len() - buffer.len()))
+    }
 
     #[test]
     fn given_empty_request_when_parse_request_then_returns_error() {
         let request: &[u8] = b"";
-        let command = identify_command(request);
+        let command = parse_first(request, &Config::default());
         match command {
             Ok(_) => panic!("Expected error, got command"),
-            Err(e) => assert_eq!(e.get_message(), EMPTY_REQUEST),
+            Err(e) => assert_eq!(e, ParserError::Empty),
         }
     }
 
     #[test]
     fn given_missing_array_indicator_when_parse_request_then_returns_error() {
         let request = b"$2\r\n$4\r\nLLEN\r\n$6\r\nmylist\r\n"; // Missing the initial '*'
-        let command = identify_command(request);
+        let command = parse_first(request, &Config::default());
         match command {
             Ok(_) => panic!("Expected error, got command"),
-            Err(e) => assert_eq!(e.get_message(), INVALID_REQUEST_STRUCTURE),
+            Err(e) => assert_eq!(e, ParserError::InvalidPrefix { expected: b'*', found: b'$', offset: 0 }),
         }
     }
 
     #[test]
-    fn given_byte_array_when_asked_return_integer_value() {
-        let input = b"*22";
-        let token = Token {
-            value: input.to_vec(),
-            size: input.len(),
-        };
+    fn given_partial_request_when_parse_request_then_returns_incomplete() {
+        let partial_requests: [&[u8]; 5] = [
+            b"*",
+            b"*2\r",
+            b"*2\r\n$4\r\nLLEN\r\n",
+            b"*2\r\n$4\r\nLLEN\r\n$6\r\nmyl",
+            b"*2\r\n$4\r\nLLEN\r\n$6\r\nmylist\r",
+        ];
+        for request in partial_requests {
+            match parse_first(request, &Config::default()) {
+                Ok(_) => panic!("Expected error, got command"),
+                Err(e) => assert!(matches!(e, ParserError::Incomplete { .. }), "{:?} should be incomplete", request),
+            }
+        }
+    }
 
-        let result = get_number_of_chars(&token);
-        match result {
-            Ok(num) => assert_eq!(num, 22),
-            Err(e) => panic!("Expected number, got error: {}", e.get_message()),
+    #[test]
+    fn given_partial_bulk_string_when_parse_request_then_reports_missing_bytes() {
+        let request = b"*3\r\n$3\r\nSET\r\n$3\r\nkey\r\n$100\r\nabc";
+        match parse_first(request, &Config::default()) {
+            Ok(_) => panic!("Expected error, got command"),
+            Err(e) => assert_eq!(e, ParserError::Incomplete { missing_bytes: 99 }), // 97 value bytes plus \r\n
         }
     }
 
     #[test]
-    fn test_get_token() {
-        let input = b"$3\r\nSET\r\n";
-        let result = get_token(input, 0);
-        assert!(result.is_ok());
-        let token = result.unwrap();
-        assert_eq!(String::from_utf8(token.value.to_vec()).unwrap(), "$3");
-        assert_eq!(token.size, 4); // $3\r\n
+    fn given_malformed_prefix_when_parse_request_then_error_is_not_incomplete() {
+        let request = b"LLEN mylist"; // no array indicator, and no terminator yet
+        match parse_first(request, &Config::default()) {
+            Ok(_) => panic!("Expected error, got command"),
+            Err(e) => {
+                assert!(e.is_protocol_error());
+                assert_eq!(e, ParserError::InvalidPrefix { expected: b'*', found: b'L', offset: 0 })
+            }
+        }
     }
 
     #[test]
-    fn test_get_token_empty() {
-        let input: &[u8] = b"";
-        let result = get_token(input, 0);
-        assert!(result.is_err());
-        assert_eq!(result.err(), Some(EMPTY_REQUEST));
+    fn given_bulk_length_over_limit_when_parse_request_then_rejected_before_payload() {
+        let config = Config { proto_max_bulk_len: 1024, ..Config::default() };
+        let request = b"*3\r\n$3\r\nSET\r\n$3\r\nkey\r\n$1025\r\nabc";
+        match parse_first(request, &config) {
+            Ok(_) => panic!("Expected error, got command"),
+            Err(e) => {
+                assert!(e.is_protocol_error());
+                assert_eq!(e, ParserError::TooLarge { limit: Limit::BulkLength, offset: 22 });
+            }
+        }
+        // rejected as soon as the digits exceed the limit, without waiting for the header to end
+        let error = parse_first(b"*1\r\n$99999", &config).err().unwrap();
+        assert!(error.is_protocol_error());
+
+        let request = b"*3\r\n$3\r\nSET\r\n$3\r\nkey\r\n$1024\r\nabc";
+        assert_eq!(
+            parse_first(request, &config).err().unwrap(),
+            ParserError::Incomplete { missing_bytes: 1023 }
+        );
     }
 
     #[test]
-    fn test_multiple_tokens() {
-        let input = b"$3\r\nSET\r\n$5\r\nkey1\r\n$5\r\nvalue1\r\n";
-        let tokens = tokenize_request(input).unwrap();
-        assert_eq!(tokens.len(), 6);
-
-        assert_eq!(String::from_utf8(tokens[0].value.to_vec()).unwrap(), "$3");
-        assert_eq!(String::from_utf8(tokens[1].value.to_vec()).unwrap(), "SET");
-        assert_eq!(String::from_utf8(tokens[2].value.to_vec()).unwrap(), "$5");
-        assert_eq!(String::from_utf8(tokens[3].value.to_vec()).unwrap(), "key1");
-        assert_eq!(String::from_utf8(tokens[4].value.to_vec()).unwrap(), "$5");
+    fn given_argument_count_over_limit_when_parse_request_then_rejected_immediately() {
+        let config = Config::default();
+        let request = format!("*{}\r\n", config.proto_max_multibulk_len + 1);
+        let error = parse_first(request.as_bytes(), &config).err().unwrap();
+        assert!(error.is_protocol_error());
+        assert_eq!(error, ParserError::TooLarge { limit: Limit::MultibulkLength, offset: 0 });
+
+        let request = format!("*{}\r\n", config.proto_max_multibulk_len);
         assert_eq!(
-            String::from_utf8(tokens[5].value.to_vec()).unwrap(),
-            "value1"
+            parse_first(request.as_bytes(), &config).err().unwrap(),
+            ParserError::Incomplete { missing_bytes: 1 }
         );
     }
 
     #[test]
-    fn test_validate_request_structure_empty_request() {
-        let tokens: Vec<Token> = vec![];
-        let result = validate_request_structure(&tokens);
-        assert!(result.is_err());
-        assert_eq!(result.err().unwrap().get_message(), NO_TOKENS_FOUND);
+    fn given_large_request_under_limits_when_parse_request_then_accepted() {
+        let mut request = String::from("*20001\r\n$4\r\nMSET\r\n");
+        for index in 0..10000 {
+            let key = format!("key{}", index);
+            let value = format!("value{}", index);
+            request.push_str(&format!("${}\r\n{}\r\n${}\r\n{}\r\n", key.len(), key, value.len(), value));
+        }
+        let (command, consumed) = parse_first(request.as_bytes(), &Config::default()).unwrap();
+        assert!(matches!(command, ParsedRequest::Command(arguments) if arguments.len() == 20001));
+        assert_eq!(consumed, request.len());
     }
 
     #[test]
-    fn test_validate_request_structure_no_leading_star() {
-        let tokens = vec![Token {
-            value: b"$2".to_vec(),
-            size: 2,
-        }];
-        let result = validate_request_structure(&tokens);
-        assert!(result.is_err());
+    fn given_request_over_query_buffer_limit_when_parse_request_then_rejected() {
+        let config = Config { client_query_buffer_limit: 64, ..Config::default() };
+        let request = b"*3\r\n$3\r\nSET\r\n$3\r\nkey\r\n$50\r\nabc";
+        let error = parse_first(request, &config).err().unwrap();
+        assert!(error.is_protocol_error());
+        assert_eq!(error, ParserError::TooLarge { limit: Limit::QueryBuffer, offset: 22 });
+    }
+
+    #[test]
+    fn given_trailing_junk_after_element_when_parse_request_then_protocol_error() {
+        let request = b"*2\r\n$3\r\nGET\r\n$1\r\nkjunk\r\n";
+        let error = parse_first(request, &Config::default()).err().unwrap();
+        assert!(error.is_protocol_error());
+        assert_eq!(error, ParserError::LengthMismatch { offset: 18 });
+
+        // junk after a complete request is left for the next parse, which rejects it
+        let request = b"*2\r\n$3\r\nGET\r\n$1\r\nk\r\njunk";
+        let (_, consumed) = parse_first(request, &Config::default()).unwrap();
+        let error = parse_first(&request[consumed..], &Config::default()).err().unwrap();
+        assert!(error.is_protocol_error());
+        assert_eq!(error, ParserError::InvalidPrefix { expected: b'*', found: b'j', offset: 0 });
+    }
+
+    #[test]
+    fn given_bad_terminator_on_last_element_when_parse_request_then_protocol_error() {
+        let malformed: [&[u8]; 3] = [
+            b"*2\r\n$3\r\nGET\r\n$1\r\nkx",
+            b"*2\r\n$3\r\nGET\r\n$1\r\nk\rx",
+            b"*2\r\n$3\r\nGET\r\n$1\r\nk\n\r",
+        ];
+        for request in malformed {
+            let error = parse_first(request, &Config::default()).err().unwrap();
+            assert!(error.is_protocol_error(), "{:?} should be rejected", request);
+        }
+        let error = parse_first(b"*1\rx$3\r\nGET\r\n", &Config::default()).err().unwrap();
+        assert_eq!(error, ParserError::InvalidTerminator { offset: 3 });
+    }
+
+    #[test]
+    fn given_malformed_requests_when_parse_request_then_kind_and_offset_reported() {
+        let cases: [(&[u8], ParserError); 6] = [
+            (b"*1\r\n#3\r\nGET\r\n", ParserError::InvalidPrefix { expected: b'$', found: b'#', offset: 4 }),
+            (b"*1\r\n$x\r\nGET\r\n", ParserError::InvalidLength { offset: 5 }),
+            (b"*1\r\n$\r\nGET\r\n", ParserError::InvalidLength { offset: 5 }),
+            (b"*1\r\n$3\rxGET", ParserError::InvalidTerminator { offset: 7 }),
+            (b"*1\r\n$3\r\nGETxx", ParserError::LengthMismatch { offset: 11 }),
+            (b"*1\r\n$2\r\n\xff\xfe\r\n", ParserError::NotUtf8Command { offset: 8 }),
+        ];
+        for (request, expected) in cases {
+            let error = parse_first(request, &Config::default()).err().unwrap();
+            assert_eq!(error, expected, "for {:?}", request);
+            assert!(error.is_protocol_error());
+        }
         assert_eq!(
-            result.err().unwrap().get_message(),
-            INVALID_REQUEST_STRUCTURE
+            ParserError::InvalidPrefix { expected: b'$', found: b'\n', offset: 4 }.to_string(),
+            "expected '$', got '\\n' at byte 4"
         );
     }
 
     #[test]
-    fn test_validate_request_structure_no_dollar_before_identifier() {
-        let tokens = vec![
-            Token {
-                value: b"*1".to_vec(),
-                size: 2,
-            },
-            Token {
-                value: b"SET".to_vec(),
-                size: 3,
-            }, // Should be $3
-        ];
-        let result = validate_request_structure(&tokens);
-        assert!(result.is_err());
-        assert_eq!(result.err().unwrap().get_message(), INVALID_NO_SIZE_TOKEN);
+    fn given_complete_request_when_parse_request_then_returns_identifiers() {
+        let request = b"*2\r\n$4\r\nLLEN\r\n$6\r\nmylist\r\n";
+        let (command, consumed) = parse_first(request, &Config::default()).unwrap();
+        assert_eq!(command, parsed(&["LLEN", "mylist"]));
+        assert_eq!(consumed, request.len());
     }
 
     #[test]
-    fn test_validate_request_structure_no_identifier_after_dollar() {
-        let tokens = vec![
-            Token {
-                value: b"*1".to_vec(),
-                size: 2,
-            },
-            Token {
-                value: b"$3".to_vec(),
-                size: 2,
-            },
-            // Missing identifier token
+    fn given_pipelined_requests_when_parse_request_then_returns_first_and_its_length() {
+        let first = b"*2\r\n$3\r\nGET\r\n$1\r\na\r\n";
+        let second = b"*2\r\n$3\r\nGET\r\n$1\r\nb\r\n";
+        let request = [first.as_slice(), second.as_slice()].concat();
+
+        let (command, consumed) = parse_first(&request, &Config::default()).unwrap();
+        assert_eq!(command, parsed(&["GET", "a"]));
+        assert_eq!(consumed, first.len());
+
+        let (command, consumed) = parse_first(&request[consumed..], &Config::default()).unwrap();
+        assert_eq!(command, parsed(&["GET", "b"]));
+        assert_eq!(consumed, second.len());
+    }
+
+    #[test]
+    fn given_every_truncation_of_valid_requests_when_parsed_then_never_panics() {
+        let requests: [&[u8]; 3] = [
+            b"*3\r\n$3\r\nSET\r\n$3\r\nkey\r\n$5\r\nvalue\r\n",
+            b"*2\r\n$4\r\nLLEN\r\n$6\r\nmylist\r\n*1\r\n$4\r\nPING\r\n",
+            b"*1\r\n$1\r\n\r\r\n",
         ];
-        let result = validate_request_structure(&tokens);
-        assert!(result.is_err());
-        assert_eq!(result.err().unwrap().get_message(), INVALID_NO_IDENTIFIER);
+        for request in requests {
+            for end in 0..=request.len() {
+                let truncated = &request[..end];
+                // a buffer ending in a bare '\r' used to be the risky case for the lookahead
+                let _ = parse_first(truncated, &Config::default());
+            }
+        }
     }
 
     #[test]
-    fn test_validate_request_structure_identifier_wrong_size() {
-        let tokens = vec![
-            Token {
-                value: b"*1".to_vec(),
-                size: 2,
-            },
-            Token {
-                value: b"$4".to_vec(),
-                size: 2,
-            },
-            Token {
-                value: b"SET".to_vec(),
-                size: 3,
-            }, // Should be 4 bytes
+    fn given_buffer_ending_in_carriage_return_when_parse_request_then_incomplete_not_panic() {
+        for request in [b"*1\r".as_slice(), b"*1\r\n$3\r", b"*1\r\n$1\r\n\r", b"*1\r\n$1\r\n\r\r"] {
+            assert!(matches!(
+                parse_first(request, &Config::default()),
+                Err(ParserError::Incomplete { .. })
+            ));
+        }
+    }
+
+    // Inputs found while property testing the parser, kept as regressions
+    #[test]
+    fn given_corpus_of_hostile_inputs_when_parsed_then_rejected_cleanly() {
+        let corpus: [(&[u8], ParserError); 7] = [
+            (b"*1\r\n$+3\r\nGET\r\n", ParserError::InvalidLength { offset: 5 }),
+            (b"*+1\r\n$3\r\nGET\r\n", ParserError::InvalidLength { offset: 1 }),
+            (b"*1\r\n$\xff\r\nGET\r\n", ParserError::InvalidLength { offset: 5 }),
+            (b"*1\r\n$3\xc3\xa9\r\nGET\r\n", ParserError::InvalidLength { offset: 6 }),
+            (b"*-2\r\n", ParserError::InvalidLength { offset: 2 }),
+            (b"*1\r\n$3\r\nGET\n\r", ParserError::LengthMismatch { offset: 11 }),
+            (b"*99999999999999999999999\r\n", ParserError::TooLarge { limit: Limit::MultibulkLength, offset: 0 }),
         ];
-        let result = validate_request_structure(&tokens);
-        assert!(result.is_err());
+        for (request, expected) in corpus {
+            assert_eq!(parse_first(request, &Config::default()).err(), Some(expected), "for {:?}", request);
+        }
+    }
+
+    fn encode(arguments: &[String]) -> Vec<u8> {
+        let mut request = format!("*{}\r\n", arguments.len()).into_bytes();
+        for argument in arguments {
+            request.extend_from_slice(format!("${}\r\n{}\r\n", argument.len(), argument).as_bytes());
+        }
+        request
+    }
+
+    // Whatever the outcome, the parser must not panic, claim more than it was given,
+    // or ask the caller to make room for more than a single bulk string
+    fn assert_sane(request: &[u8]) {
+        let config = Config::default();
+        match parse_first(request, &config) {
+            Ok((_, consumed)) => {
+                assert!(consumed <= request.len());
+            }
+            Err(ParserError::Incomplete { missing_bytes }) => {
+                assert!(missing_bytes > 0 && missing_bytes <= config.proto_max_bulk_len + 2);
+            }
+            Err(_) => {}
+        }
+    }
+
+    fn resp_byte() -> impl Strategy<Value = u8> {
+        prop_oneof![Just(b'*'), Just(b'$'), Just(b'\r'), Just(b'\n'), b'0'..=b'9', any::<u8>()]
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(512))]
+
+        #[test]
+        fn arbitrary_bytes_never_panic(request in proptest::collection::vec(any::<u8>(), 0..256)) {
+            assert_sane(&request);
+        }
+
+        #[test]
+        fn resp_like_bytes_never_panic(request in proptest::collection::vec(resp_byte(), 0..128)) {
+            assert_sane(&request);
+        }
+
+        #[test]
+        fn valid_requests_round_trip(arguments in proptest::collection::vec("[ -~\r\n]{0,16}", 1..8)) {
+            let request = encode(&arguments);
+            let (command, consumed) = parse_first(&request, &Config::default()).unwrap();
+            prop_assert_eq!(
+                command,
+                ParsedRequest::Command(arguments.into_iter().map(|argument| Some(Bytes::from(argument))).collect())
+            );
+            prop_assert_eq!(consumed, request.len());
+        }
+
+        #[test]
+        fn mutated_requests_never_panic(
+            arguments in proptest::collection::vec("[a-zA-Z0-9]{1,16}", 1..8),
+            position in any::<prop::sample::Index>(),
+            byte in resp_byte(),
+            mutation in 0..3,
+        ) {
+            let mut request = encode(&arguments);
+            let index = position.index(request.len());
+            match mutation {
+                0 => request.truncate(index),
+                1 => request[index] = byte,
+                _ => {
+                    request.remove(index);
+                }
+            }
+            assert_sane(&request);
+        }
+    }
+
+    #[test]
+    fn given_size_header_when_read_then_number_and_next_position_returned() {
+        assert!(matches!(
+            read_size_header(b"*22\r\n", 0, b'*', usize::MAX),
+            SizeHeader::Found { size: 22, next: 5 }
+        ));
+        assert!(matches!(read_size_header(b"$-1\r\n", 0, b'$', usize::MAX), SizeHeader::Null { next: 5 }));
+        assert!(matches!(read_size_header(b"$-1\r", 0, b'$', usize::MAX), SizeHeader::Truncated));
+    }
+
+    #[test]
+    fn given_element_shorter_than_its_header_when_parse_request_then_length_mismatch() {
+        let request = b"*1\r\n$4\r\nSET\r\n"; // should be 4 bytes
         assert_eq!(
-            result.err().unwrap().get_message(),
-            IDENTIFIER_IS_WRONG_SIZE
+            parse_first(request, &Config::default()).err(),
+            Some(ParserError::LengthMismatch { offset: 12 })
         );
     }
 
     #[test]
-    fn test_validate_request_structure_identifier_count_mismatch() {
-        let tokens = vec![
-            Token {
-                value: b"*2".to_vec(),
-                size: 2,
-            },
-            Token {
-                value: b"$3".to_vec(),
-                size: 2,
-            },
-            Token {
-                value: b"SET".to_vec(),
-                size: 3,
-            },
-        ];
-        let result = validate_request_structure(&tokens);
-        assert!(result.is_err());
+    fn given_fewer_elements_than_declared_when_parse_request_then_waits_for_the_rest() {
+        let request = b"*2\r\n$3\r\nSET\r\n";
         assert_eq!(
-            result.err().unwrap().get_message(),
-            INVALID_REQUEST_INCORRECT_SIZE
+            parse_first(request, &Config::default()).err(),
+            Some(ParserError::Incomplete { missing_bytes: 1 })
         );
     }
 
     #[test]
-    fn test_validate_request_structure_valid_request() {
-        let tokens = vec![
-            Token {
-                value: b"*2".to_vec(),
-                size: 2,
-            },
-            Token {
-                value: b"$3".to_vec(),
-                size: 2,
-            },
-            Token {
-                value: b"SET".to_vec(),
-                size: 3,
-            },
-            Token {
-                value: b"$4".to_vec(),
-                size: 2,
-            },
-            Token {
-                value: b"key1".to_vec(),
-                size: 4,
-            },
-        ];
-        let result = validate_request_structure(&tokens);
-        match result {
-            Ok(identifiers) => {
-                assert_eq!(identifiers.len(), 2);
-                assert_eq!(identifiers[0], "SET");
-                assert_eq!(identifiers[1], "key1");
-            }
-            Err(e) => panic!("Expected valid identifiers, got error: {}", e.get_message()),
+    fn given_empty_and_null_bulk_strings_when_parse_request_then_kept_distinct() {
+        let request = b"*4\r\n$3\r\nSET\r\n$3\r\nkey\r\n$0\r\n\r\n$-1\r\n";
+        let (command, consumed) = parse_first(request, &Config::default()).unwrap();
+        assert_eq!(
+            command,
+            ParsedRequest::Command(vec![
+                Some(Bytes::from_static(b"SET")),
+                Some(Bytes::from_static(b"key")),
+                Some(Bytes::new()),
+                None
+            ])
+        );
+        assert_eq!(consumed, request.len());
+    }
+
+    #[test]
+    fn given_empty_or_null_array_when_parse_request_then_no_op() {
+        for request in [b"*0\r\n".as_slice(), b"*-1\r\n".as_slice()] {
+            let (command, consumed) = parse_first(request, &Config::default()).unwrap();
+            assert_eq!(command, ParsedRequest::NoOp);
+            assert_eq!(consumed, request.len());
         }
     }
+
+    #[test]
+    fn given_value_containing_crlf_when_parse_request_then_kept_whole() {
+        let request = b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$4\r\na\r\nb\r\n";
+        let (command, _) = parse_first(request, &Config::default()).unwrap();
+        assert_eq!(command, parsed(&["SET", "k", "a\r\nb"]));
+    }
+
+    #[test]
+    fn given_pipelined_requests_when_identified_then_arguments_share_the_read_buffer() {
+        let mut buffer = BytesMut::from(&b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$5\r\nvalue\r\n*1\r\n$4\r\nPING\r\n"[..]);
+        let start = buffer.as_ptr() as usize;
+        let ParsedRequest::Command(arguments) = identify_command(&mut buffer, &Config::default()).unwrap() else {
+            panic!("expected a command");
+        };
+        let value = arguments[2].as_ref().unwrap();
+        assert_eq!(value, "value");
+        assert_eq!(value.as_ptr() as usize, start + 24, "the value should not have been copied");
+        assert_eq!(buffer, &b"*1\r\n$4\r\nPING\r\n"[..]);
+    }
+
+    #[test]
+    fn given_binary_argument_when_parse_request_then_accepted() {
+        let request = b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$2\r\n\xff\x00\r\n";
+        let (command, _) = parse_first(request, &Config::default()).unwrap();
+        assert_eq!(
+            command,
+            ParsedRequest::Command(vec![
+                Some(Bytes::from_static(b"SET")),
+                Some(Bytes::from_static(b"k")),
+                Some(Bytes::from_static(b"\xff\x00")),
+            ])
+        );
+    }
+
+    #[test]
+    fn given_malformed_request_when_identified_then_buffer_left_untouched() {
+        let mut buffer = BytesMut::from(&b"*1\r\n$2\r\n\xff\xfe\r\n"[..]);
+        assert!(identify_command(&mut buffer, &Config::default()).is_err());
+        assert_eq!(buffer.len(), 12);
+    }
+
+    fn parsed(arguments: &[&str]) -> ParsedRequest {
+        ParsedRequest::Command(arguments.iter().map(|argument| Some(Bytes::copy_from_slice(argument.as_bytes()))).collect())
+    }
 }
@@ -0,0 +1,39 @@
+// Starting the server in-process through the library, as a program embedding it would
+
+use redis_in_rust::{Config, Server};
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+#[test]
+fn given_server_on_ephemeral_port_when_shut_down_then_served_clients_and_left_no_threads() {
+    let threads_before = thread_count();
+    let server = Server::bind(Config { port: 0, thread_pool_size: 2, ..Config::default() }).unwrap();
+    let address = server.local_addr();
+    assert_ne!(address.port(), 0);
+    let server = server.spawn().unwrap();
+
+    let mut client = TcpStream::connect(address).unwrap();
+    client.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+    client.write_all(b"*3\r\n$3\r\nSET\r\n$3\r\nkey\r\n$5\r\nvalue\r\n").unwrap();
+    client.write_all(b"*2\r\n$3\r\nGET\r\n$3\r\nkey\r\n").unwrap();
+    let mut reply = [0u8; 16];
+    client.read_exact(&mut reply).unwrap();
+    assert_eq!(&reply, b"+OK\r\n$5\r\nvalue\r\n");
+
+    server.shutdown();
+    let mut rest = Vec::new();
+    client.read_to_end(&mut rest).unwrap();
+    assert!(rest.is_empty(), "the client should have been closed");
+    assert!(TcpStream::connect(address).is_err(), "the port should have been released");
+    if let Some(threads_before) = threads_before {
+        assert_eq!(thread_count(), Some(threads_before));
+    }
+}
+
+// The threads in this process, where the OS makes that easy to find out
+fn thread_count() -> Option<usize> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with("Threads:"))?;
+    line["Threads:".len()..].trim().parse().ok()
+}
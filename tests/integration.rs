@@ -0,0 +1,155 @@
+// Drives the server with a real Redis client, so every reply has to be what Redis itself would
+// send: a reply in the wrong format fails to convert rather than being compared as text
+
+use redis::{Commands, Connection, ErrorKind, RedisResult};
+use redis_in_rust::{Config, Server, ServerHandle};
+
+fn start_server() -> (ServerHandle, Connection) {
+    let server = Server::bind(Config { port: 0, thread_pool_size: 2, ..Config::default() })
+        .unwrap()
+        .spawn()
+        .unwrap();
+    let client = redis::Client::open(format!("redis://{}/", server.local_addr())).unwrap();
+    let connection = client.get_connection().unwrap();
+    (server, connection)
+}
+
+#[test]
+fn given_string_value_when_set_then_get_returns_it() {
+    let (server, mut connection) = start_server();
+    let _: () = connection.set("greeting", "hello world").unwrap();
+    let value: String = connection.get("greeting").unwrap();
+    assert_eq!(value, "hello world");
+
+    let _: () = connection.set("empty", "").unwrap();
+    let value: String = connection.get("empty").unwrap();
+    assert_eq!(value, "");
+
+    let binary: Vec<u8> = vec![0, 13, 10, 255];
+    let _: () = connection.set("binary", &binary).unwrap();
+    let value: Vec<u8> = connection.get("binary").unwrap();
+    assert_eq!(value, binary);
+    server.shutdown();
+}
+
+#[test]
+fn given_missing_key_when_get_then_nil() {
+    let (server, mut connection) = start_server();
+    let value: Option<String> = connection.get("missing").unwrap();
+    assert_eq!(value, None);
+    server.shutdown();
+}
+
+#[test]
+fn given_counter_when_incremented_and_decremented_then_integers_returned() {
+    let (server, mut connection) = start_server();
+    let value: i64 = connection.incr("counter", 1).unwrap();
+    assert_eq!(value, 1);
+    let value: i64 = connection.incr("counter", 10).unwrap();
+    assert_eq!(value, 11);
+    let value: i64 = connection.decr("counter", 1).unwrap();
+    assert_eq!(value, 10);
+    let value: i64 = connection.decr("counter", 15).unwrap();
+    assert_eq!(value, -5);
+    let value: i64 = redis::cmd("DECR").arg("counter").query(&mut connection).unwrap();
+    assert_eq!(value, -6);
+    // the counter is still a string holding the number
+    let value: String = connection.get("counter").unwrap();
+    assert_eq!(value, "-6");
+    server.shutdown();
+}
+
+#[test]
+fn given_non_integer_when_incremented_then_error() {
+    let (server, mut connection) = start_server();
+    let _: () = connection.set("text", "abc").unwrap();
+    let error = connection.incr::<_, _, i64>("text", 1).unwrap_err();
+    assert!(error.to_string().contains("not an integer or out of range"), "{}", error);
+
+    let error = redis::cmd("INCRBY").arg("counter").arg("ten").query::<i64>(&mut connection).unwrap_err();
+    assert!(error.to_string().contains("not an integer or out of range"), "{}", error);
+
+    let _: () = connection.set("max", i64::MAX).unwrap();
+    let error = connection.incr::<_, _, i64>("max", 1).unwrap_err();
+    assert!(error.to_string().contains("overflow"), "{}", error);
+    // nothing was changed by the failed increment
+    let value: i64 = connection.get("max").unwrap();
+    assert_eq!(value, i64::MAX);
+    server.shutdown();
+}
+
+#[test]
+fn given_keys_when_exists_del_and_rename_then_keyspace_follows() {
+    let (server, mut connection) = start_server();
+    let _: () = connection.set("first", "1").unwrap();
+    let exists: bool = connection.exists("first").unwrap();
+    assert!(exists);
+
+    let _: () = connection.rename("first", "second").unwrap();
+    let exists: bool = connection.exists("first").unwrap();
+    assert!(!exists);
+    let value: String = connection.get("second").unwrap();
+    assert_eq!(value, "1");
+
+    let removed: i64 = connection.del("second").unwrap();
+    assert_eq!(removed, 1);
+    let exists: bool = connection.exists("second").unwrap();
+    assert!(!exists);
+    let value: Option<String> = connection.get("second").unwrap();
+    assert_eq!(value, None);
+
+    let error = connection.rename::<_, _, ()>("second", "third").unwrap_err();
+    assert!(error.to_string().contains("no such key"), "{}", error);
+    server.shutdown();
+}
+
+#[test]
+fn given_string_key_when_used_as_list_then_wrongtype() {
+    let (server, mut connection) = start_server();
+    let _: () = connection.set("name", "value").unwrap();
+    let result: RedisResult<i64> = connection.lpush("name", "item");
+    let error = result.unwrap_err();
+    assert_eq!(error.code(), Some("WRONGTYPE"), "{}", error);
+    // the connection is still usable afterwards
+    let value: String = connection.get("name").unwrap();
+    assert_eq!(value, "value");
+    server.shutdown();
+}
+
+#[test]
+fn given_wrong_number_of_arguments_when_sent_then_error() {
+    let (server, mut connection) = start_server();
+    let error = redis::cmd("GET").query::<String>(&mut connection).unwrap_err();
+    assert_eq!(error.kind(), ErrorKind::Server(redis::ServerErrorKind::ResponseError), "{}", error);
+    assert!(error.to_string().contains("wrong number of arguments for 'get' command"), "{}", error);
+
+    let error = redis::cmd("SET").arg("key").query::<()>(&mut connection).unwrap_err();
+    assert!(error.to_string().contains("wrong number of arguments for 'set' command"), "{}", error);
+    server.shutdown();
+}
+
+#[test]
+fn given_pipeline_when_sent_then_replies_arrive_in_order() {
+    let (server, mut connection) = start_server();
+    let (set, first, count, second, missing): (String, String, i64, i64, Option<String>) = redis::pipe()
+        .set("a", "1")
+        .get("a")
+        .incr("n", 5)
+        .incr("n", 1)
+        .get("b")
+        .query(&mut connection)
+        .unwrap();
+    assert_eq!((set.as_str(), first.as_str(), count, second, missing), ("OK", "1", 5, 6, None));
+
+    // a larger batch, written and answered as a whole
+    let mut pipeline = redis::pipe();
+    for i in 0..500 {
+        pipeline.set(format!("key{}", i), i).ignore();
+    }
+    for i in 0..500 {
+        pipeline.get(format!("key{}", i));
+    }
+    let values: Vec<i64> = pipeline.query(&mut connection).unwrap();
+    assert_eq!(values, (0..500).collect::<Vec<i64>>());
+    server.shutdown();
+}